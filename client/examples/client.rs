@@ -56,7 +56,7 @@ async fn run() -> anyhow::Result<()> {
     let address = args.address.clone();
     let builder = if let Some(key_file) = args.key_file {
         let password = args.key_password.clone();
-        let secret = load_or_generate(&key_file, password);
+        let secret = load_or_generate(key_file, password)?;
         ClientBuilder::from_url(address).crypto(FallbackCryptoProvider::new(secret))
     } else {
         ClientBuilder::from_url(address)
@@ -546,7 +546,9 @@ async fn build_client(
     session_expiration: Duration,
     session_request_timeout: Duration,
 ) -> Result<Client> {
-    let secret = key_file.map(|key_file| load_or_generate(key_file, password));
+    let secret = key_file
+        .map(|key_file| load_or_generate(key_file, password))
+        .transpose()?;
     let provider = if let Some(secret_key) = secret {
         FallbackCryptoProvider::new(secret_key)
     } else {
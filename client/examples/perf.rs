@@ -116,7 +116,7 @@ mod util {
         let address = args.address.clone();
         let builder = if let Some(key_file) = &args.key_file {
             let password = args.key_password.clone();
-            let secret = load_or_generate(key_file, password);
+            let secret = load_or_generate(key_file, password)?;
             ClientBuilder::from_url(address).crypto(FallbackCryptoProvider::new(secret))
         } else {
             ClientBuilder::from_url(address)
@@ -584,7 +584,6 @@ mod relaying {
     use std::sync::atomic::Ordering::SeqCst;
     use std::sync::atomic::{AtomicBool, AtomicUsize};
     use std::sync::Arc;
-    use tokio_stream::wrappers::UnboundedReceiverStream;
     use ya_relay_client::model::Payload;
     use ya_relay_core::NodeId;
 
@@ -970,19 +969,18 @@ mod relaying {
 
         let received_ = received.clone();
         tokio::task::spawn_local(async move {
-            UnboundedReceiverStream::new(rx)
-                .for_each(|item| {
-                    let received = received_.clone();
-                    let finish_tx = finish_tx.clone();
-                    async move {
-                        let payload_len = item.payload.len();
-                        let sum = received.fetch_add(payload_len, SeqCst) + payload_len;
-                        if sum == expected {
-                            finish_tx.send(()).await.ok();
-                        }
+            rx.for_each(|item| {
+                let received = received_.clone();
+                let finish_tx = finish_tx.clone();
+                async move {
+                    let payload_len = item.payload.len();
+                    let sum = received.fetch_add(payload_len, SeqCst) + payload_len;
+                    if sum == expected {
+                        finish_tx.send(()).await.ok();
                     }
-                })
-                .await;
+                }
+            })
+            .await;
         });
 
         finish_rx.recv().await;
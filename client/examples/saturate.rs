@@ -16,7 +16,6 @@ use structopt::{clap, StructOpt};
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncWriteExt;
 use tokio::sync::RwLock;
-use tokio_stream::wrappers::UnboundedReceiverStream;
 
 use ya_relay_client::channels::*;
 use ya_relay_client::*;
@@ -151,7 +150,7 @@ impl State {
 }
 
 fn receive(receiver: ForwardReceiver, state: State) -> impl Future<Output = ()> + 'static {
-    UnboundedReceiverStream::new(receiver).for_each(move |fwd| {
+    receiver.for_each(move |fwd| {
         let state = state.clone();
         async move {
             let mut inner = state.inner.write().await;
@@ -360,7 +359,7 @@ async fn run() -> anyhow::Result<()> {
     let cli: Cli = Cli::from_args();
     let mut builder = if let Some(ref key_file) = cli.key_file {
         let password = cli.key_password.clone();
-        let secret = load_or_generate(key_file, password);
+        let secret = load_or_generate(key_file, password)?;
         ClientBuilder::from_url(cli.relay)
             .crypto(FallbackCryptoProvider::new(secret))
             .listen(cli.listen)
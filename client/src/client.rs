@@ -1,12 +1,12 @@
 use anyhow::{anyhow, bail};
 use futures::future::{join_all, AbortHandle};
-use futures::{FutureExt, TryFutureExt};
+use futures::{FutureExt, Stream, TryFutureExt};
 use parking_lot::Mutex;
 use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::future::Future;
 use std::iter::zip;
-use std::net::SocketAddr;
+use std::net::{Ipv6Addr, SocketAddr};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::thread::sleep;
@@ -22,9 +22,11 @@ pub use crate::config::{ClientBuilder, ClientConfig, FailFast};
 pub use crate::error::SessionError;
 pub use crate::model::{SessionDesc, SocketDesc, SocketState};
 pub use crate::transport::transport_sender::{ForwardSender, GenericSender};
-pub use crate::transport::{ForwardReceiver, TransportLayer};
+pub use crate::transport::{DropPolicy, ForwardReceiver, TransportLayer};
+use crate::transport::{VirtualListener, VirtualStream, VirtualUdpSocket};
 
 use crate::direct_session::DirectSession;
+use crate::events::ClientEvent;
 use crate::metrics::ChannelMetrics;
 pub use ya_relay_core::server_session::TransportType;
 
@@ -194,17 +196,24 @@ impl Client {
                 None => continue,
                 Some(session) => session,
             };
-            let node_id = match self.remote_id(&session.raw.remote).await {
+            let node_id = match self.remote_id(&session.raw.remote()).await {
                 Some(node_id) => node_id,
                 None => continue,
             };
 
+            let mut dropped = ChannelMetrics::default();
+            self.transport
+                .add_dropped_metrics(Some(node_id), &mut dropped);
+            if dropped.rx_dropped > 0 {
+                session_metrics.insert(node_id, dropped);
+            }
+
             let virt_node = match self.transport.virtual_tcp.resolve_node(node_id).await {
                 Ok(virt_node) => virt_node,
                 Err(_) => continue,
             };
 
-            sockets
+            let metrics = sockets
                 .iter()
                 .filter_map(|(desc, metrics)| {
                     desc.remote
@@ -213,15 +222,22 @@ impl Client {
                         .filter(|endpoint| endpoint.addr == virt_node.address)
                         .map(|_| metrics.clone().inner_mut().clone())
                 })
-                .reduce(|acc, item| acc + item)
-                .and_then(|metrics| session_metrics.insert(node_id, metrics));
+                .reduce(|acc, item| acc + item);
+
+            if let Some(metrics) = metrics {
+                let entry = session_metrics.entry(node_id).or_default();
+                *entry = metrics + entry.clone();
+            }
         }
         session_metrics
     }
 
-    #[inline]
+    /// Metrics of virtual TCP traffic, including counters of unreliable packets
+    /// dropped, because `ForwardReceiver` consumer didn't keep up.
     pub fn metrics(&self) -> ChannelMetrics {
-        self.transport.virtual_tcp.metrics()
+        let mut metrics = self.transport.virtual_tcp.metrics();
+        self.transport.add_dropped_metrics(None, &mut metrics);
+        metrics
     }
 
     pub async fn forward_receiver(&self) -> Option<ForwardReceiver> {
@@ -290,6 +306,55 @@ impl Client {
         self.transport.forward_transfer(node_id).await
     }
 
+    /// Returns sender for numbered virtual TCP channel, configured by `ClientBuilder::channel`.
+    /// Received packets have channel number set in `Forwarded::channel`.
+    pub async fn forward_channel(
+        &self,
+        node_id: NodeId,
+        channel_id: u16,
+    ) -> anyhow::Result<ForwardSender> {
+        log::trace!(
+            "Forward channel {channel_id} from [{}] to [{}]",
+            self.config.node_id,
+            node_id
+        );
+        self.transport.forward_channel(node_id, channel_id).await
+    }
+
+    /// Opens virtual TCP connection to port, on which other Node listens (see [`Client::listen`]).
+    /// Data sent over the stream doesn't go through `ForwardReceiver`.
+    pub async fn connect_stream(
+        &self,
+        node_id: NodeId,
+        port: u16,
+    ) -> anyhow::Result<VirtualStream> {
+        log::trace!(
+            "Connect stream from [{}] to [{}] port {port}",
+            self.config.node_id,
+            node_id
+        );
+        self.transport.connect_stream(node_id, port).await
+    }
+
+    /// Listens for stream connections from other Nodes on virtual TCP port.
+    /// Ports of configured channels can't be used.
+    pub fn listen(&self, port: u16) -> anyhow::Result<VirtualListener> {
+        self.transport.listen(port)
+    }
+
+    /// Virtual IPv6 address of the Node. Applications using TUN device (see
+    /// `ClientBuilder::tun_device`) can reach the Node by this address, which
+    /// is routed through the device from now on.
+    pub async fn virtual_address(&self, node_id: NodeId) -> anyhow::Result<Ipv6Addr> {
+        self.transport.virtual_address(node_id).await
+    }
+
+    /// Binds virtual UDP socket to port. Datagrams sent over the socket
+    /// don't go through `ForwardReceiver`.
+    pub fn bind_udp(&self, port: u16) -> anyhow::Result<VirtualUdpSocket> {
+        self.transport.bind_udp(port)
+    }
+
     pub async fn forward_unreliable(&self, node_id: NodeId) -> anyhow::Result<ForwardSender> {
         log::trace!(
             "Forward unreliable from [{}] to [{}]",
@@ -341,6 +406,47 @@ impl Client {
         self.transport.session_layer.disconnect(node_id).await
     }
 
+    /// Adds secondary identity of this Node. `CryptoProvider` must be able to sign
+    /// with `node_id`. The new identity set is pushed to relays and p2p peers,
+    /// so other Nodes can reach us using the new alias without restarting the client.
+    pub async fn add_identity(&self, node_id: NodeId) -> anyhow::Result<()> {
+        if node_id == self.config.node_id {
+            bail!("[{node_id}] is the default identity");
+        }
+        self.config.crypto.get(node_id).await?;
+        self.transport
+            .session_layer
+            .update_aliases(|aliases| {
+                if !aliases.contains(&node_id) {
+                    aliases.push(node_id);
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    /// Removes secondary identity of this Node and announces the new identity set
+    /// to relays and p2p peers.
+    pub async fn remove_identity(&self, node_id: NodeId) -> anyhow::Result<()> {
+        self.transport
+            .session_layer
+            .update_aliases(|aliases| {
+                let len = aliases.len();
+                aliases.retain(|alias| *alias != node_id);
+                match aliases.len() < len {
+                    true => Ok(()),
+                    false => bail!("[{node_id}] is not an alias of this Node"),
+                }
+            })
+            .await
+    }
+
+    /// Subscribes to changes of sessions with other Nodes, relay servers
+    /// and virtual TCP channels. Only events occurring after this call are returned.
+    pub fn events(&self) -> impl Stream<Item = ClientEvent> + 'static {
+        self.transport.session_layer.registry.events().subscribe()
+    }
+
     pub async fn is_p2p(&self, node_id: NodeId) -> bool {
         self.transport.session_layer.is_p2p(node_id).await
     }
@@ -535,4 +641,6 @@ pub struct Forwarded {
     pub transport: TransportType,
     pub node_id: NodeId,
     pub payload: Payload,
+    /// Virtual TCP channel, on which packet was received. Zero for unreliable packets.
+    pub channel: u16,
 }
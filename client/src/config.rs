@@ -1,3 +1,5 @@
+use anyhow::bail;
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::rc::Rc;
 use std::time::Duration;
@@ -5,14 +7,36 @@ use url::Url;
 
 use ya_relay_core::crypto::{CryptoProvider, FallbackCryptoProvider, PublicKey};
 use ya_relay_core::error::InternalError;
-use ya_relay_core::udp_stream::resolve_max_payload_overhead_size;
+use ya_relay_core::faults::FaultConfig;
+use ya_relay_core::udp_stream::{resolve_max_payload_overhead_size, OsUdpBinder, UdpBinder};
 use ya_relay_core::utils::parse_udp_url;
 use ya_relay_core::NodeId;
-use ya_relay_proto::proto::{Forward, MAX_TAG_SIZE};
+use ya_relay_proto::proto::{Endpoint, Forward, Protocol, MAX_TAG_SIZE};
 use ya_relay_stack::StackConfig;
 
 use crate::client::Client;
 use crate::session::network_view::NetworkViewConfig;
+use crate::transport::tcp_registry::{ChannelType, MAX_CHANNEL_ID};
+use crate::transport::DropPolicy;
+
+/// Relays are identified by the last byte of their artificial `NodeId`.
+const MAX_RELAYS: usize = 256;
+const DEFAULT_INGRESS_QUEUE_SIZE: usize = 1024;
+const MESSAGES_PRIORITY: u8 = 128;
+const TRANSFER_PRIORITY: u8 = 64;
+
+/// Relay servers don't have identities, so we give them artificial ids.
+/// The primary relay gets default `NodeId` (0x00..).
+pub(crate) fn relay_id(idx: usize) -> NodeId {
+    let mut id = [0u8; 20];
+    id[19] = idx as u8;
+    NodeId::from(id)
+}
+
+pub(crate) fn is_relay_id(node_id: &NodeId) -> bool {
+    let id: &[u8] = node_id.as_ref();
+    id[..19].iter().all(|byte| *byte == 0)
+}
 
 #[derive(Clone, Copy)]
 pub enum FailFast {
@@ -28,22 +52,62 @@ pub struct ClientConfig {
     pub challenge_difficulty: u64,
 
     pub bind_url: Url,
+    /// Relay servers in order of preference. Client keeps sessions with all of them
+    /// and falls back to the next relay, when the previous one is unreachable.
+    pub srv_addrs: Vec<SocketAddr>,
+    /// Primary relay server, same as the first address from `srv_addrs`.
+    /// Used only when `srv_addrs` is empty.
+    #[deprecated(since = "0.7.0", note = "Use `srv_addrs` listing all relay servers")]
     pub srv_addr: SocketAddr,
+    /// Transport used to reach relays from `srv_addrs`. Relays reachable only over TCP
+    /// are placed after UDP ones, so they are used when UDP session init fails.
+    pub srv_protocols: Vec<Protocol>,
     pub auto_connect: bool,
     pub auto_connect_fail_fast: bool,
     pub session_expiration: Duration,
     pub stack_config: StackConfig,
     pub ping_measure_interval: Duration,
     pub server_session_reconnect_max_interval: Duration,
+    /// Total time spent trying relays one after another, before giving up.
+    pub relay_failover_timeout: Duration,
+    /// How often to check, if local address used to reach relay changed.
+    pub address_check_interval: Duration,
 
     pub session_request_timeout: Duration,
     pub challenge_request_timeout: Duration,
 
     pub reverse_connection_tmp_timeout: Duration,
     pub reverse_connection_real_timeout: Duration,
+    /// Try coordinated UDP hole punching, when neither side has public IP.
+    pub hole_punching: bool,
+    /// Exchange plaintext packets with Nodes, with which encryption can't be negotiated.
+    pub allow_plaintext: bool,
+    pub hole_punch_timeout: Duration,
     pub incoming_session_timeout: Duration,
     pub neighbourhood_ttl: Duration,
     pub registry_config: NetworkViewConfig,
+    /// Additional endpoints, on which Node can be reached (e.g. port forwards).
+    /// Relay verifies them before publishing to other Nodes.
+    pub advertised_endpoints: Vec<Endpoint>,
+    /// Maximum number of received packets waiting in `ForwardReceiver`.
+    pub ingress_queue_size: usize,
+    pub unreliable_drop_policy: DropPolicy,
+    /// Virtual TCP channels with their priorities. Packets waiting for sending
+    /// on channels with higher priority go first.
+    pub channels: BTreeMap<u16, u8>,
+    /// TUN device, through which applications can reach other Nodes by their virtual IPs.
+    pub tun: Option<TunConfig>,
+    /// Faults injected into UDP traffic, for testing.
+    pub faults: Option<FaultConfig>,
+    /// Binds the UDP socket and resolves local address used to reach relays.
+    pub udp_binder: Rc<dyn UdpBinder>,
+}
+
+/// Linux TUN device with the virtual IPv6 address of the Node. Only virtual IPs
+/// of other Nodes are routed through the device, other traffic isn't affected.
+#[derive(Clone, Debug)]
+pub struct TunConfig {
+    pub name: String,
 }
 
 /// The `ClientBuilder` struct provides a builder pattern for constructing a `Client` object.
@@ -76,26 +140,56 @@ pub struct ClientConfig {
 ///
 pub struct ClientBuilder {
     bind_url: Option<Url>,
-    srv_url: Url,
+    srv_urls: Vec<Url>,
     crypto: Option<Rc<dyn CryptoProvider>>,
     auto_connect: bool,
     auto_connect_fail_fast: bool,
     session_expiration: Option<Duration>,
     session_request_timeout: Option<Duration>,
+    relay_failover_timeout: Option<Duration>,
+    hole_punching: bool,
+    allow_plaintext: bool,
+    advertised_endpoints: Vec<Endpoint>,
+    ingress_queue_size: usize,
+    unreliable_drop_policy: DropPolicy,
+    channels: BTreeMap<u16, u8>,
+    tun: Option<TunConfig>,
     stack_config: StackConfig,
+    faults: Option<FaultConfig>,
+    udp_binder: Option<Rc<dyn UdpBinder>>,
 }
 
 impl ClientBuilder {
     pub fn from_url(url: Url) -> ClientBuilder {
+        ClientBuilder::from_urls(vec![url])
+    }
+
+    /// Creates builder with many relay servers. The first relay on the list is the primary one,
+    /// the rest of them will be used as a fallback, when the primary relay is unreachable.
+    /// Relays given as `tcp://` urls are tried after all `udp://` relays.
+    pub fn from_urls(urls: impl IntoIterator<Item = Url>) -> ClientBuilder {
         ClientBuilder {
             bind_url: None,
-            srv_url: url,
+            srv_urls: urls.into_iter().collect(),
             crypto: None,
             auto_connect: false,
             auto_connect_fail_fast: false,
             session_expiration: None,
             session_request_timeout: None,
+            relay_failover_timeout: None,
+            hole_punching: false,
+            allow_plaintext: false,
+            advertised_endpoints: vec![],
+            ingress_queue_size: DEFAULT_INGRESS_QUEUE_SIZE,
+            unreliable_drop_policy: Default::default(),
+            channels: BTreeMap::from([
+                (ChannelType::MESSAGES.port(), MESSAGES_PRIORITY),
+                (ChannelType::TRANSFER.port(), TRANSFER_PRIORITY),
+            ]),
+            tun: None,
             stack_config: Default::default(),
+            faults: None,
+            udp_binder: None,
         }
     }
 
@@ -134,6 +228,95 @@ impl ClientBuilder {
         self
     }
 
+    /// Limits total time of trying consecutive relay servers, when establishing
+    /// session with relay.
+    pub fn relay_failover_timeout(mut self, timeout: Duration) -> Self {
+        self.relay_failover_timeout = Some(timeout);
+        self
+    }
+
+    /// Enables UDP hole punching coordinated by relay server, which is attempted
+    /// before falling back to relayed connection.
+    pub fn hole_punching(mut self, enabled: bool) -> Self {
+        self.hole_punching = enabled;
+        self
+    }
+
+    /// Allows sending packets in plaintext, when encryption can't be negotiated with
+    /// the other Node. Relays forwarding these packets can read and modify them.
+    pub fn allow_plaintext(mut self) -> Self {
+        self.allow_plaintext = true;
+        self
+    }
+
+    /// Advertises endpoint given as `udp://` or `tcp://` url to relays, for example
+    /// a forwarded port. Relay publishes the endpoint after verifying that it is reachable.
+    pub fn advertise(mut self, url: Url) -> anyhow::Result<Self> {
+        let protocol = match url.scheme() {
+            "udp" => Protocol::Udp,
+            "tcp" => Protocol::Tcp,
+            scheme => bail!("Unsupported endpoint protocol: {scheme}"),
+        };
+        let addr: SocketAddr = parse_udp_url(&url)?.parse()?;
+
+        self.advertised_endpoints.push(Endpoint {
+            protocol: protocol.into(),
+            address: addr.ip().to_string(),
+            port: addr.port().into(),
+        });
+        Ok(self)
+    }
+
+    /// Limits the number of received packets waiting to be read from `ForwardReceiver`.
+    /// When the limit is reached, reliable and transfer senders are slowed down,
+    /// and unreliable packets are dropped according to `unreliable_drop_policy`.
+    pub fn ingress_queue_size(mut self, size: usize) -> Self {
+        self.ingress_queue_size = size;
+        self
+    }
+
+    pub fn unreliable_drop_policy(mut self, policy: DropPolicy) -> Self {
+        self.unreliable_drop_policy = policy;
+        self
+    }
+
+    /// Adds virtual TCP channel available through `Client::forward_channel` or changes priority
+    /// of existing one. Channel `1` is used by `forward_reliable` and `2` by `forward_transfer`.
+    /// Both Nodes have to configure the channel to communicate over it.
+    pub fn channel(mut self, id: u16, priority: u8) -> anyhow::Result<Self> {
+        if id == 0 || id > MAX_CHANNEL_ID {
+            bail!("Channel id {id} out of range 1..={MAX_CHANNEL_ID}");
+        }
+        self.channels.insert(id, priority);
+        Ok(self)
+    }
+
+    /// Attaches virtual network to Linux TUN device `name`. The device gets virtual IP
+    /// of the Node, so ordinary applications can reach other Nodes by their virtual IPs
+    /// (see `Client::virtual_address`). Requires `CAP_NET_ADMIN`.
+    #[cfg(all(feature = "tun", target_os = "linux"))]
+    pub fn tun_device(mut self, name: impl ToString) -> Self {
+        self.tun = Some(TunConfig {
+            name: name.to_string(),
+        });
+        self
+    }
+
+    /// Drops, delays, duplicates, reorders or corrupts UDP packets sent and received
+    /// by the client, for testing how it copes with bad networks. Test builds read
+    /// faults from `YA_NET_FAULTS` variable, when they aren't set here.
+    pub fn faults(mut self, faults: FaultConfig) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
+    /// Replaces OS sockets, for example with the simulated network
+    /// (`ya_relay_core::testing::sim::SimNetwork`) in tests.
+    pub fn udp_binder(mut self, binder: impl UdpBinder + 'static) -> Self {
+        self.udp_binder = Some(Rc::new(binder));
+        self
+    }
+
     pub fn tcp_max_recv_buffer_size(mut self, max: usize) -> anyhow::Result<Self> {
         self.stack_config.tcp_mem.rx.set_max(max)?;
         Ok(self)
@@ -165,33 +348,80 @@ impl ClientBuilder {
         let default_id = crypto.default_id().await?;
         let default_pub_key = crypto.get(default_id).await?.public_key().await?;
 
+        if self.srv_urls.is_empty() {
+            bail!("At least one relay server url is required");
+        }
+        if self.srv_urls.len() > MAX_RELAYS {
+            bail!(
+                "Too many relay servers: {} (max {MAX_RELAYS})",
+                self.srv_urls.len()
+            );
+        }
+        let mut relays = self
+            .srv_urls
+            .iter()
+            .map(|url| {
+                let protocol = match url.scheme() {
+                    "udp" => Protocol::Udp,
+                    "tcp" => Protocol::Tcp,
+                    scheme => bail!("Unsupported relay protocol: {scheme}"),
+                };
+                Ok((parse_udp_url(url)?.parse()?, protocol))
+            })
+            .collect::<anyhow::Result<Vec<(SocketAddr, Protocol)>>>()?;
+        relays.sort_by_key(|(_, protocol)| *protocol == Protocol::Tcp);
+        let (srv_addrs, srv_protocols): (Vec<_>, _) = relays.into_iter().unzip();
+        let srv_addr = srv_addrs[0];
+
+        #[cfg(feature = "test-utils")]
+        if self.faults.is_none() {
+            self.faults = FaultConfig::from_env()?;
+        }
+
         self.stack_config.max_transmission_unit =
             resolve_max_payload_overhead_size(MAX_TAG_SIZE + Forward::header_size()).await?;
 
+        #[allow(deprecated)]
         Ok(ClientConfig {
             node_id: default_id,
             node_pub_key: default_pub_key,
             crypto,
             challenge_difficulty: 1,
             bind_url,
-            srv_addr: parse_udp_url(&self.srv_url)?.parse()?,
+            srv_addrs,
+            srv_addr,
+            srv_protocols,
             auto_connect: self.auto_connect,
             auto_connect_fail_fast: self.auto_connect_fail_fast,
             session_expiration: self
                 .session_expiration
                 .unwrap_or_else(|| Duration::from_secs(25)),
             server_session_reconnect_max_interval: Duration::from_secs(300),
+            relay_failover_timeout: self
+                .relay_failover_timeout
+                .unwrap_or_else(|| Duration::from_secs(20)),
             stack_config: self.stack_config,
             ping_measure_interval: Duration::from_secs(300),
+            address_check_interval: Duration::from_secs(10),
             session_request_timeout: self
                 .session_request_timeout
                 .unwrap_or_else(|| Duration::from_millis(3000)),
             challenge_request_timeout: Duration::from_millis(8000),
             reverse_connection_tmp_timeout: Duration::from_secs(3),
             reverse_connection_real_timeout: Duration::from_secs(13),
+            hole_punching: self.hole_punching,
+            allow_plaintext: self.allow_plaintext,
+            hole_punch_timeout: Duration::from_secs(3),
             incoming_session_timeout: Duration::from_secs(16),
             neighbourhood_ttl: Duration::from_secs(300),
             registry_config: Default::default(),
+            advertised_endpoints: self.advertised_endpoints,
+            ingress_queue_size: self.ingress_queue_size,
+            unreliable_drop_policy: self.unreliable_drop_policy,
+            channels: self.channels,
+            tun: self.tun,
+            faults: self.faults,
+            udp_binder: self.udp_binder.unwrap_or_else(|| Rc::new(OsUdpBinder)),
         })
     }
 
@@ -204,6 +434,27 @@ impl ClientBuilder {
 }
 
 impl ClientConfig {
+    /// Relay servers with artificial ids used to identify them in `SessionLayer`.
+    pub fn relays(&self) -> impl Iterator<Item = (NodeId, SocketAddr)> + '_ {
+        #[allow(deprecated)]
+        let addrs = match self.srv_addrs.is_empty() {
+            true => std::slice::from_ref(&self.srv_addr),
+            false => self.srv_addrs.as_slice(),
+        };
+        addrs
+            .iter()
+            .enumerate()
+            .map(|(idx, addr)| (relay_id(idx), *addr))
+    }
+
+    /// Transport used to communicate with relay of given artificial id.
+    pub fn relay_protocol(&self, relay_id: &NodeId) -> Protocol {
+        self.relays()
+            .position(|(id, _)| id == *relay_id)
+            .and_then(|idx| self.srv_protocols.get(idx).copied())
+            .unwrap_or(Protocol::Udp)
+    }
+
     pub async fn public_key(&self) -> Result<PublicKey, InternalError> {
         let crypto = self
             .crypto
@@ -7,7 +7,9 @@ use ya_relay_core::identity::Identity;
 use ya_relay_core::server_session::TransportType;
 use ya_relay_core::sync::Actuator;
 use ya_relay_core::NodeId;
-use ya_relay_proto::proto::{Forward, Payload, SlotId, FORWARD_SLOT_ID};
+use ya_relay_proto::proto::{
+    Forward, Payload, SlotId, ENCRYPTED_FLAG, FORWARD_SLOT_ID, KEY_EXCHANGE_FLAG, UNRELIABLE_FLAG,
+};
 
 use crate::error::SessionError;
 use crate::metrics::{RELAY_ID, SOURCE_ID, TARGET_ID};
@@ -165,26 +167,13 @@ impl DirectSession {
         transport: TransportType,
         encrypted: bool,
     ) -> anyhow::Result<()> {
-        let router_id = self.owner.default_id;
-        let slot = if router_id == target {
-            FORWARD_SLOT_ID
-        } else {
-            self.find_slot(&target)
-                .ok_or(SessionError::Internal(format!(
-                    "Session with [{router_id}] doesn't allow to forward packets for [{target}]"
-                )))?
-        };
-
-        let mut forward = match transport {
-            TransportType::Unreliable => Forward::unreliable(self.raw.id, slot, packet),
-            TransportType::Reliable => Forward::new(self.raw.id, slot, packet),
-            TransportType::Transfer => Forward::new(self.raw.id, slot, packet),
+        let forward = Forward {
+            session_id: self.raw.id.into(),
+            slot: self.target_slot(&target)?,
+            flags: Self::forward_flags(transport, encrypted),
+            payload: packet,
         };
-
         let size = forward.encoded_len();
-        if encrypted {
-            forward.set_encrypted();
-        }
 
         self.wait_for_resume().await;
         self.raw.send(forward).await?;
@@ -193,6 +182,47 @@ impl DirectSession {
         Ok(())
     }
 
+    /// Sends session key agreement message to `target`.
+    pub async fn send_key_exchange(&self, target: NodeId, packet: Payload) -> anyhow::Result<()> {
+        let forward = Forward {
+            session_id: self.raw.id.into(),
+            slot: self.target_slot(&target)?,
+            flags: KEY_EXCHANGE_FLAG,
+            payload: packet,
+        };
+        self.raw.send(forward).await?;
+        Ok(())
+    }
+
+    /// Flags set in `Forward` packets sent by `DirectSession::send`.
+    pub fn forward_flags(transport: TransportType, encrypted: bool) -> u16 {
+        let mut flags = match transport {
+            TransportType::Unreliable => UNRELIABLE_FLAG,
+            TransportType::Reliable | TransportType::Transfer => 0,
+        };
+        if encrypted {
+            flags |= ENCRYPTED_FLAG;
+        }
+        flags
+    }
+
+    /// Packets for the owner of this session are sent directly, without forwarding.
+    pub fn is_direct(&self, target: &NodeId) -> bool {
+        self.owner.default_id == *target
+    }
+
+    fn target_slot(&self, target: &NodeId) -> anyhow::Result<SlotId> {
+        if self.is_direct(target) {
+            return Ok(FORWARD_SLOT_ID);
+        }
+        let router_id = self.owner.default_id;
+        Ok(self
+            .find_slot(target)
+            .ok_or(SessionError::Internal(format!(
+                "Session with [{router_id}] doesn't allow to forward packets for [{target}]"
+            )))?)
+    }
+
     pub fn remove_by_slot(&self, id: SlotId) -> anyhow::Result<NodeId> {
         let mut forwards = self.forwards.write().unwrap();
         forwards.remove_by_slot(id).ok_or(anyhow!(
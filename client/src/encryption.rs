@@ -1,22 +1,821 @@
+use parking_lot::Mutex;
+use std::convert::TryInto;
 use std::rc::Rc;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
 
-use ya_relay_core::crypto::CryptoProvider;
-use ya_relay_proto::proto::Payload;
+use ya_relay_core::crypto::{
+    key_exchange_tag, new_session_nonce, verify_key_exchange_tag, CipherError, CryptoProvider,
+    SessionCipher, SessionNonce, ENCRYPTION_SCHEME, KEY_EXCHANGE_TAG_SIZE, SESSION_NONCE_SIZE,
+};
+use ya_relay_core::identity::Identity;
+use ya_relay_core::NodeId;
+use ya_relay_proto::proto::{Payload, ENCRYPTED_FLAG};
 
 use crate::error::EncryptionError;
 
-/// Encrypting packets, solving challenges, proving identity.
+/// Interval between key agreement attempts.
+pub const KEY_EXCHANGE_RETRY: Duration = Duration::from_millis(500);
+/// Number of key agreement attempts before sending fails.
+pub const KEY_EXCHANGE_ATTEMPTS: usize = 10;
+
+const KEY_EXCHANGE_INIT: u8 = 1;
+const KEY_EXCHANGE_RESPONSE: u8 = 2;
+const KEY_EXCHANGE_CONFIRM: u8 = 3;
+
+/// Encryption of packets exchanged with single remote Node.
+///
+/// Shared secret is agreed using ECDH on default identities of both Nodes,
+/// so relay servers forwarding our packets see only ciphertext.
+/// Schemes of the other Node come from relay or from unauthenticated handshake, so their
+/// absence doesn't prove, that the other Node can't encrypt. Plaintext is used only, when
+/// it was explicitly allowed in `ClientConfig`.
+///
+/// Keys are derived separately for each session from the shared secret and fresh nonces
+/// exchanged in `Forward` packets with `KEY_EXCHANGE_FLAG`. The initiator sends its nonce
+/// and the responder answers with its own one, which is generated anew for every exchange.
+/// This way keys of the new session never match keys of the old one and packets captured
+/// earlier can't be replayed.
+///
+/// Key exchange messages are authenticated with a tag derived from the shared secret, so
+/// the relay can't forge them. Replayed request is still answered, but the responder keeps
+/// using previous keys, until the initiator confirms the new ones or sends packet
+/// encrypted with them. Likewise the initiator accepts packets encrypted with previous keys,
+/// until the responder starts using the new ones.
 #[derive(Clone)]
 pub struct Encryption {
-    pub crypto: Rc<dyn CryptoProvider>,
+    session: Option<Arc<EncryptedSession>>,
+}
+
+struct EncryptedSession {
+    local_id: NodeId,
+    remote_id: NodeId,
+    shared_secret: [u8; 32],
+    state: Mutex<KeyState>,
+    ready: Notify,
+}
+
+#[derive(Default)]
+struct KeyState {
+    cipher: Option<SessionCipher>,
+    /// Keys agreed as the responder, which replace `cipher` once the initiator confirms them.
+    candidate: Option<SessionCipher>,
+    /// Keys replaced as the initiator, used for decryption until the responder switches.
+    previous: Option<SessionCipher>,
+    /// Nonce of the key exchange initiated by us, until the other Node responds.
+    pending: Option<SessionNonce>,
+    last_init: Option<Instant>,
+    /// Nonces of the initiator and ours from the last exchange, to which we responded.
+    /// Kept to answer retransmitted requests with the same keys.
+    responded: Option<(SessionNonce, SessionNonce)>,
+}
+
+enum KeyExchange {
+    Init(SessionNonce),
+    Response {
+        nonce: SessionNonce,
+        init: SessionNonce,
+    },
+    Confirm {
+        nonce: SessionNonce,
+        init: SessionNonce,
+    },
 }
 
 impl Encryption {
-    pub async fn encrypt(&self, packet: Payload) -> Result<Payload, EncryptionError> {
-        Ok(packet)
+    /// Encryption schemes supported by this Node in order of preference.
+    /// Nothing is advertised, if `CryptoProvider` can't compute ECDH for our default
+    /// identity, so the other side won't expect encrypted packets.
+    pub async fn supported_schemes(crypto: Rc<dyn CryptoProvider>, node_id: NodeId) -> Vec<String> {
+        let probe = async {
+            let crypto = crypto.get(node_id).await?;
+            let public_key = crypto.public_key().await?;
+            crypto.ecdh(&public_key).await
+        };
+        match probe.await {
+            Ok(_) => vec![ENCRYPTION_SCHEME.to_string()],
+            Err(e) => {
+                log::debug!("Payload encryption unsupported by [{node_id}]: {e}");
+                vec![]
+            }
+        }
+    }
+
+    /// Packets will be sent in plaintext.
+    pub fn none() -> Encryption {
+        Encryption { session: None }
+    }
+
+    /// Chooses encryption scheme supported by both sides and computes shared secret.
+    /// Session keys are agreed later with `Encryption::initiate`.
+    /// Fails, if encryption can't be used and `allow_plaintext` isn't set.
+    pub async fn negotiate(
+        crypto: Rc<dyn CryptoProvider>,
+        node_id: NodeId,
+        remote: &Identity,
+        remote_schemes: &[String],
+        allow_plaintext: bool,
+    ) -> Result<Encryption, EncryptionError> {
+        let secret = async {
+            if !remote_schemes
+                .iter()
+                .any(|scheme| scheme == ENCRYPTION_SCHEME)
+            {
+                anyhow::bail!(
+                    "Node [{}] doesn't support encryption scheme: {ENCRYPTION_SCHEME}",
+                    remote.node_id
+                );
+            }
+            let crypto = crypto.get(node_id).await?;
+            crypto
+                .ecdh(&remote.public_key)
+                .await
+                .map_err(|e| anyhow::anyhow!("Key agreement with [{}] failed: {e}", remote.node_id))
+        };
+        match secret.await {
+            Ok(shared_secret) => Ok(Encryption {
+                session: Some(Arc::new(EncryptedSession {
+                    local_id: node_id,
+                    remote_id: remote.node_id,
+                    shared_secret,
+                    state: Default::default(),
+                    ready: Notify::new(),
+                })),
+            }),
+            Err(e) if allow_plaintext => {
+                log::warn!("{e}. Falling back to plaintext.");
+                Ok(Encryption::none())
+            }
+            Err(e) => {
+                log::error!("{e}. Plaintext isn't allowed.");
+                Err(EncryptionError::Generic(e.to_string()))
+            }
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// Plaintext connections are always ready.
+    pub fn is_ready(&self) -> bool {
+        match &self.session {
+            Some(session) => session.state.lock().cipher.is_some(),
+            None => true,
+        }
+    }
+
+    /// Both instances encrypt with the same secret, so one can replace the other
+    /// without agreeing new session keys.
+    pub fn same_secret(&self, other: &Encryption) -> bool {
+        match (&self.session, &other.session) {
+            (Some(session), Some(other)) => {
+                session.local_id == other.local_id
+                    && session.remote_id == other.remote_id
+                    && session.shared_secret == other.shared_secret
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns key exchange request for the other Node. Retransmissions reuse the nonce
+    /// of the pending request. `None` if packets aren't encrypted.
+    pub fn initiate(&self) -> Option<Payload> {
+        let session = self.session.as_ref()?;
+        let mut state = session.state.lock();
+        let nonce = *state.pending.get_or_insert_with(new_session_nonce);
+        state.last_init = Some(Instant::now());
+        Some(session.encode(KeyExchange::Init(nonce)))
+    }
+
+    /// Like `Encryption::initiate`, but returns `None` if request was sent recently.
+    /// Used when packets from the other Node can't be decrypted, which can be triggered
+    /// by anyone able to send us packets.
+    pub fn initiate_throttled(&self) -> Option<Payload> {
+        let session = self.session.as_ref()?;
+        let recent = session
+            .state
+            .lock()
+            .last_init
+            .map(|last| last.elapsed() < KEY_EXCHANGE_RETRY)
+            .unwrap_or(false);
+        match recent {
+            true => None,
+            false => self.initiate(),
+        }
+    }
+
+    /// Waits until session keys are agreed. Returns `false` on timeout.
+    pub async fn wait_ready(&self, timeout: Duration) -> bool {
+        let session = match &self.session {
+            Some(session) => session,
+            None => return true,
+        };
+        let notified = session.ready.notified();
+        if self.is_ready() {
+            return true;
+        }
+        tokio::time::timeout(timeout, notified).await.is_ok() || self.is_ready()
+    }
+
+    /// Handles key exchange message from the other Node. Returns response to send back.
+    pub fn on_key_exchange(&self, packet: &[u8]) -> Result<Option<Payload>, EncryptionError> {
+        let session = self.session.as_ref().ok_or_else(|| {
+            EncryptionError::Generic("Key exchange, but no encryption was negotiated".to_string())
+        })?;
+        let message = session.decode(packet)?;
+        let mut state = session.state.lock();
+
+        match message {
+            KeyExchange::Init(init) => {
+                let initiating = state.pending.is_some()
+                    && state
+                        .last_init
+                        .map(|last| last.elapsed() < 2 * KEY_EXCHANGE_RETRY)
+                        .unwrap_or(false);
+                // Both sides started the exchange at the same time. Only the request
+                // of the Node with lower id is answered, so both end up with the same keys.
+                if initiating && session.local_id.into_array() < session.remote_id.into_array() {
+                    log::trace!(
+                        "Ignoring key exchange from [{}], waiting for response to ours",
+                        session.remote_id
+                    );
+                    return Ok(None);
+                }
+
+                if let Some((responded, nonce)) = state.responded {
+                    if responded == init {
+                        return Ok(Some(session.encode(KeyExchange::Response { nonce, init })));
+                    }
+                }
+
+                let nonce = new_session_nonce();
+                let cipher = SessionCipher::new(
+                    &session.shared_secret,
+                    (session.local_id, &nonce),
+                    (session.remote_id, &init),
+                );
+                // Request could be replayed, so working keys are replaced only after confirmation.
+                match state.cipher {
+                    Some(_) => state.candidate = Some(cipher),
+                    None => {
+                        state.cipher = Some(cipher);
+                        state.candidate = None;
+                        session.ready.notify_waiters();
+                    }
+                }
+                state.pending = None;
+                state.previous = None;
+                state.responded = Some((init, nonce));
+
+                log::debug!(
+                    "Agreed session keys with [{}] (responder)",
+                    session.remote_id
+                );
+                Ok(Some(session.encode(KeyExchange::Response { nonce, init })))
+            }
+            KeyExchange::Response { nonce, init } => {
+                // Each request is answered with a single nonce, so responses to requests
+                // other than the pending one are stale or replayed.
+                if state.pending != Some(init) {
+                    log::trace!(
+                        "Ignoring unexpected key exchange response from [{}]",
+                        session.remote_id
+                    );
+                    return Ok(None);
+                }
+
+                let cipher = SessionCipher::new(
+                    &session.shared_secret,
+                    (session.local_id, &init),
+                    (session.remote_id, &nonce),
+                );
+                state.previous = state.cipher.replace(cipher);
+                state.candidate = None;
+                state.pending = None;
+                state.responded = None;
+                session.ready.notify_waiters();
+
+                log::debug!(
+                    "Agreed session keys with [{}] (initiator)",
+                    session.remote_id
+                );
+                Ok(Some(session.encode(KeyExchange::Confirm { nonce, init })))
+            }
+            KeyExchange::Confirm { nonce, init } => {
+                if state.responded == Some((init, nonce)) {
+                    if let Some(candidate) = state.candidate.take() {
+                        state.cipher = Some(candidate);
+                        log::debug!("Session keys confirmed by [{}]", session.remote_id);
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// `direct` tells whether packet is sent without relays and `flags` are
+    /// flags of the `Forward` packet. Both are authenticated together with the payload.
+    pub async fn encrypt(
+        &self,
+        packet: Payload,
+        direct: bool,
+        flags: u16,
+    ) -> Result<Payload, EncryptionError> {
+        match &self.session {
+            Some(session) => {
+                let aad = associated_data(session.local_id, session.remote_id, direct, flags);
+                let mut state = session.state.lock();
+                let cipher = state.cipher.as_mut().ok_or(EncryptionError::NoKeys)?;
+                Ok(Payload::from(cipher.encrypt(packet.as_ref(), &aad)?))
+            }
+            None => Ok(packet),
+        }
+    }
+
+    /// `direct` and `flags` come from the received `Forward` packet. Plaintext packets
+    /// are rejected, when encryption was negotiated, to prevent relay from injecting them.
+    pub async fn decrypt(
+        &self,
+        packet: Payload,
+        direct: bool,
+        flags: u16,
+    ) -> Result<Payload, EncryptionError> {
+        let encrypted = flags & ENCRYPTED_FLAG == ENCRYPTED_FLAG;
+        match (&self.session, encrypted) {
+            (Some(session), true) => {
+                let aad = associated_data(session.remote_id, session.local_id, direct, flags);
+                let mut state = session.state.lock();
+                Ok(Payload::from(state.decrypt(packet.as_ref(), &aad)?))
+            }
+            (None, false) => Ok(packet),
+            (Some(_), false) => Err(EncryptionError::Generic(
+                "Unencrypted packet, despite encryption was negotiated".to_string(),
+            )),
+            (None, true) => Err(EncryptionError::Generic(
+                "Encrypted packet, but no encryption was negotiated".to_string(),
+            )),
+        }
+    }
+}
+
+impl KeyState {
+    /// Packet encrypted with keys, which weren't confirmed yet, completes the key exchange.
+    fn decrypt(&mut self, packet: &[u8], aad: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let cipher = self.cipher.as_mut().ok_or(EncryptionError::NoKeys)?;
+        let error = match cipher.decrypt(packet, aad) {
+            Ok(plaintext) => {
+                // The responder switched to the new keys.
+                self.previous = None;
+                return Ok(plaintext);
+            }
+            Err(e) => e,
+        };
+
+        if let Some(candidate) = self.candidate.as_mut() {
+            if let Ok(plaintext) = candidate.decrypt(packet, aad) {
+                self.cipher = self.candidate.take();
+                return Ok(plaintext);
+            }
+        }
+        if let Some(previous) = self.previous.as_mut() {
+            if let Ok(plaintext) = previous.decrypt(packet, aad) {
+                return Ok(plaintext);
+            }
+        }
+        Err(EncryptionError::Cipher(error))
+    }
+}
+
+impl EncryptedSession {
+    /// Encodes message with tag authenticating it, together with ids of both Nodes.
+    fn encode(&self, message: KeyExchange) -> Payload {
+        let mut buf = message.encode();
+        let tag = key_exchange_tag(&self.shared_secret, self.local_id, self.remote_id, &buf);
+        buf.extend_from_slice(&tag);
+        Payload::from(buf)
+    }
+
+    fn decode(&self, packet: &[u8]) -> Result<KeyExchange, EncryptionError> {
+        let split = packet
+            .len()
+            .checked_sub(KEY_EXCHANGE_TAG_SIZE)
+            .ok_or_else(|| KeyExchange::invalid(packet))?;
+        let (message, tag) = packet.split_at(split);
+        if !verify_key_exchange_tag(
+            &self.shared_secret,
+            self.remote_id,
+            self.local_id,
+            message,
+            tag,
+        ) {
+            return Err(EncryptionError::Cipher(CipherError::Authentication));
+        }
+        KeyExchange::decode(message)
+    }
+}
+
+/// `Forward` header fields authenticated together with the payload. Relays rewrite slot
+/// from the receiver's to the sender's one, so instead of raw slot number, ids of Nodes
+/// to which it resolves are authenticated, together with information if packet was forwarded.
+fn associated_data(sender: NodeId, receiver: NodeId, direct: bool, flags: u16) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(2 * 20 + 1 + 2);
+    aad.extend_from_slice(&sender.into_array());
+    aad.extend_from_slice(&receiver.into_array());
+    aad.push(direct as u8);
+    aad.extend_from_slice(&flags.to_be_bytes());
+    aad
+}
+
+impl KeyExchange {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(1 + 2 * SESSION_NONCE_SIZE + KEY_EXCHANGE_TAG_SIZE);
+        match self {
+            KeyExchange::Init(nonce) => {
+                buf.push(KEY_EXCHANGE_INIT);
+                buf.extend_from_slice(nonce);
+            }
+            KeyExchange::Response { nonce, init } => {
+                buf.push(KEY_EXCHANGE_RESPONSE);
+                buf.extend_from_slice(nonce);
+                buf.extend_from_slice(init);
+            }
+            KeyExchange::Confirm { nonce, init } => {
+                buf.push(KEY_EXCHANGE_CONFIRM);
+                buf.extend_from_slice(nonce);
+                buf.extend_from_slice(init);
+            }
+        }
+        buf
+    }
+
+    fn decode(packet: &[u8]) -> Result<KeyExchange, EncryptionError> {
+        let nonce = |offset: usize| -> Result<SessionNonce, EncryptionError> {
+            packet
+                .get(offset..offset + SESSION_NONCE_SIZE)
+                .and_then(|nonce| nonce.try_into().ok())
+                .ok_or_else(|| KeyExchange::invalid(packet))
+        };
+
+        match packet.first() {
+            Some(&KEY_EXCHANGE_INIT) if packet.len() == 1 + SESSION_NONCE_SIZE => {
+                Ok(KeyExchange::Init(nonce(1)?))
+            }
+            Some(&KEY_EXCHANGE_RESPONSE) if packet.len() == 1 + 2 * SESSION_NONCE_SIZE => {
+                Ok(KeyExchange::Response {
+                    nonce: nonce(1)?,
+                    init: nonce(1 + SESSION_NONCE_SIZE)?,
+                })
+            }
+            Some(&KEY_EXCHANGE_CONFIRM) if packet.len() == 1 + 2 * SESSION_NONCE_SIZE => {
+                Ok(KeyExchange::Confirm {
+                    nonce: nonce(1)?,
+                    init: nonce(1 + SESSION_NONCE_SIZE)?,
+                })
+            }
+            _ => Err(KeyExchange::invalid(packet)),
+        }
+    }
+
+    fn invalid(packet: &[u8]) -> EncryptionError {
+        EncryptionError::Generic(format!("Invalid key exchange ({} B)", packet.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::LocalBoxFuture;
+    use futures::FutureExt;
+    use ya_relay_core::crypto::{Crypto, FallbackCryptoProvider, PublicKey, Signature};
+
+    async fn identity(provider: &FallbackCryptoProvider) -> Identity {
+        let crypto = provider.get(provider.default_node_id()).await.unwrap();
+        Identity {
+            node_id: provider.default_node_id(),
+            public_key: crypto.public_key().await.unwrap(),
+        }
+    }
+
+    async fn negotiated_pair() -> (Encryption, Encryption) {
+        let provider1 = FallbackCryptoProvider::default();
+        let provider2 = FallbackCryptoProvider::default();
+        let id1 = identity(&provider1).await;
+        let id2 = identity(&provider2).await;
+        let schemes = Encryption::supported_schemes(Rc::new(provider1.clone()), id1.node_id).await;
+
+        let enc1 = Encryption::negotiate(Rc::new(provider1), id1.node_id, &id2, &schemes, false)
+            .await
+            .unwrap();
+        let enc2 = Encryption::negotiate(Rc::new(provider2), id2.node_id, &id1, &schemes, false)
+            .await
+            .unwrap();
+        (enc1, enc2)
+    }
+
+    /// Runs key exchange initiated by `initiator`.
+    fn agree(initiator: &Encryption, responder: &Encryption) {
+        let request = initiator.initiate().unwrap();
+        let response = responder
+            .on_key_exchange(request.as_ref())
+            .unwrap()
+            .unwrap();
+        let confirm = initiator
+            .on_key_exchange(response.as_ref())
+            .unwrap()
+            .unwrap();
+        assert!(responder
+            .on_key_exchange(confirm.as_ref())
+            .unwrap()
+            .is_none());
+    }
+
+    /// Checks, that packets sent in both directions are decrypted.
+    async fn check_traffic(enc1: &Encryption, enc2: &Encryption) {
+        let payload = Payload::from(b"task data".to_vec());
+        for (sender, receiver) in [(enc1, enc2), (enc2, enc1)] {
+            let encrypted = sender
+                .encrypt(payload.clone(), false, ENCRYPTED_FLAG)
+                .await
+                .unwrap();
+            assert_eq!(
+                receiver
+                    .decrypt(encrypted, false, ENCRYPTED_FLAG)
+                    .await
+                    .unwrap(),
+                payload
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_negotiated_encryption() {
+        let (enc1, enc2) = negotiated_pair().await;
+        assert!(enc1.is_enabled());
+        assert!(!enc1.is_ready());
+
+        let payload = Payload::from(b"task data".to_vec());
+        assert_eq!(
+            enc1.encrypt(payload.clone(), true, ENCRYPTED_FLAG).await,
+            Err(EncryptionError::NoKeys)
+        );
+
+        agree(&enc1, &enc2);
+        assert!(enc1.is_ready());
+        assert!(enc2.is_ready());
+
+        let encrypted = enc1
+            .encrypt(payload.clone(), true, ENCRYPTED_FLAG)
+            .await
+            .unwrap();
+        assert_ne!(encrypted, payload);
+        assert_eq!(
+            enc2.decrypt(encrypted, true, ENCRYPTED_FLAG).await.unwrap(),
+            payload
+        );
+
+        // Relay shouldn't be able to inject plaintext packets.
+        assert!(enc2.decrypt(payload, true, 0).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_replayed_packets_rejected() {
+        let (enc1, enc2) = negotiated_pair().await;
+        agree(&enc1, &enc2);
+
+        let payload = Payload::from(b"task data".to_vec());
+        let encrypted = enc1
+            .encrypt(payload.clone(), false, ENCRYPTED_FLAG)
+            .await
+            .unwrap();
+        assert_eq!(
+            enc2.decrypt(encrypted.clone(), false, ENCRYPTED_FLAG)
+                .await
+                .unwrap(),
+            payload
+        );
+        assert_eq!(
+            enc2.decrypt(encrypted.clone(), false, ENCRYPTED_FLAG).await,
+            Err(EncryptionError::Cipher(CipherError::Replayed(1)))
+        );
+
+        // Packet can't be sent back to its sender.
+        assert!(enc1
+            .decrypt(encrypted.clone(), false, ENCRYPTED_FLAG)
+            .await
+            .is_err());
+
+        // Forward header is authenticated.
+        let encrypted = enc1
+            .encrypt(payload.clone(), false, ENCRYPTED_FLAG)
+            .await
+            .unwrap();
+        let unreliable = ENCRYPTED_FLAG | ya_relay_proto::proto::UNRELIABLE_FLAG;
+        assert!(enc2
+            .decrypt(encrypted.clone(), false, unreliable)
+            .await
+            .is_err());
+        assert!(enc2
+            .decrypt(encrypted.clone(), true, ENCRYPTED_FLAG)
+            .await
+            .is_err());
+
+        // Packets from the previous session can't be replayed into the new one,
+        // even if the relay replays the key exchange request.
+        let request = enc1.initiate().unwrap();
+        let response = enc2.on_key_exchange(request.as_ref()).unwrap().unwrap();
+        enc1.on_key_exchange(response.as_ref()).unwrap();
+        let old = enc1
+            .encrypt(payload.clone(), false, ENCRYPTED_FLAG)
+            .await
+            .unwrap();
+
+        agree(&enc1, &enc2);
+        assert!(enc2.on_key_exchange(request.as_ref()).unwrap().is_some());
+        assert!(enc1.on_key_exchange(response.as_ref()).unwrap().is_none());
+        assert!(enc2.decrypt(old, false, ENCRYPTED_FLAG).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_forged_key_exchange() {
+        let (enc1, enc2) = negotiated_pair().await;
+        agree(&enc1, &enc2);
+        check_traffic(&enc1, &enc2).await;
+
+        // Relay doesn't know the shared secret, so it can't forge requests.
+        let mut forged = vec![KEY_EXCHANGE_INIT];
+        forged.extend_from_slice(&new_session_nonce());
+        forged.extend_from_slice(&[0u8; KEY_EXCHANGE_TAG_SIZE]);
+        assert!(enc2.on_key_exchange(&forged).is_err());
+        let mut tampered = enc1.initiate().unwrap().as_ref().to_vec();
+        tampered[1] ^= 1;
+        assert!(enc2.on_key_exchange(&tampered).is_err());
+        check_traffic(&enc1, &enc2).await;
+
+        // Replayed request is answered, but keys in use are kept.
+        let request = enc1.initiate().unwrap();
+        let response = enc2.on_key_exchange(request.as_ref()).unwrap().unwrap();
+        let confirm = enc1.on_key_exchange(response.as_ref()).unwrap().unwrap();
+        enc2.on_key_exchange(confirm.as_ref()).unwrap();
+        check_traffic(&enc1, &enc2).await;
+        let response = enc2.on_key_exchange(request.as_ref()).unwrap().unwrap();
+        check_traffic(&enc1, &enc2).await;
+        assert!(enc1.on_key_exchange(response.as_ref()).unwrap().is_none());
+        check_traffic(&enc1, &enc2).await;
+
+        // Message can't be reflected back to its sender.
+        assert!(enc2.on_key_exchange(response.as_ref()).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_keys_replaced_after_confirmation() {
+        let (enc1, enc2) = negotiated_pair().await;
+        agree(&enc1, &enc2);
+
+        // Until the responder gets confirmation, both sides understand each other.
+        let request = enc1.initiate().unwrap();
+        let response = enc2.on_key_exchange(request.as_ref()).unwrap().unwrap();
+        enc1.on_key_exchange(response.as_ref()).unwrap().unwrap();
+        check_traffic(&enc2, &enc1).await;
+        check_traffic(&enc1, &enc2).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_simultaneous_key_exchange() {
+        let (enc1, enc2) = negotiated_pair().await;
+
+        let request1 = enc1.initiate().unwrap();
+        let request2 = enc2.initiate().unwrap();
+        let responses = [
+            enc1.on_key_exchange(request2.as_ref()).unwrap(),
+            enc2.on_key_exchange(request1.as_ref()).unwrap(),
+        ];
+        // Only one of requests is answered.
+        assert_eq!(responses.iter().flatten().count(), 1);
+        let [response1, response2] = responses;
+        if let Some(response) = response1 {
+            let confirm = enc2.on_key_exchange(response.as_ref()).unwrap().unwrap();
+            enc1.on_key_exchange(confirm.as_ref()).unwrap();
+        }
+        if let Some(response) = response2 {
+            let confirm = enc1.on_key_exchange(response.as_ref()).unwrap().unwrap();
+            enc2.on_key_exchange(confirm.as_ref()).unwrap();
+        }
+        assert!(enc1.is_ready());
+        assert!(enc2.is_ready());
+
+        let payload = Payload::from(b"task data".to_vec());
+        let encrypted = enc1
+            .encrypt(payload.clone(), true, ENCRYPTED_FLAG)
+            .await
+            .unwrap();
+        assert_eq!(
+            enc2.decrypt(encrypted, true, ENCRYPTED_FLAG).await.unwrap(),
+            payload
+        );
     }
 
-    pub async fn decrypt(&self, packet: Payload) -> Result<Payload, EncryptionError> {
-        Ok(packet)
+    #[actix_rt::test]
+    async fn test_plaintext_fallback() {
+        let provider1 = FallbackCryptoProvider::default();
+        let provider2 = FallbackCryptoProvider::default();
+        let id2 = identity(&provider2).await;
+
+        // Missing scheme could be stripped by relay.
+        assert!(Encryption::negotiate(
+            Rc::new(provider1.clone()),
+            provider1.default_node_id(),
+            &id2,
+            &[],
+            false,
+        )
+        .await
+        .is_err());
+
+        let enc = Encryption::negotiate(
+            Rc::new(provider1.clone()),
+            provider1.default_node_id(),
+            &id2,
+            &[],
+            true,
+        )
+        .await
+        .unwrap();
+        assert!(!enc.is_enabled());
+        assert!(enc.is_ready());
+
+        let payload = Payload::from(b"task data".to_vec());
+        assert_eq!(
+            enc.encrypt(payload.clone(), true, 0).await.unwrap(),
+            payload
+        );
+        assert_eq!(
+            enc.decrypt(payload.clone(), true, 0).await.unwrap(),
+            payload
+        );
+        assert!(enc.decrypt(payload, true, ENCRYPTED_FLAG).await.is_err());
+    }
+
+    /// `Crypto` implementation, which relies on the default `ecdh`.
+    struct SignOnly(Rc<dyn Crypto>);
+
+    impl Crypto for SignOnly {
+        fn public_key<'a>(&self) -> LocalBoxFuture<'a, anyhow::Result<PublicKey>> {
+            self.0.public_key()
+        }
+
+        fn sign<'a>(&self, message: &'a [u8]) -> LocalBoxFuture<'a, anyhow::Result<Signature>> {
+            self.0.sign(message)
+        }
+
+        fn encrypt<'a>(
+            &self,
+            message: &'a [u8],
+            remote_key: &'a PublicKey,
+        ) -> LocalBoxFuture<'a, anyhow::Result<Vec<u8>>> {
+            self.0.encrypt(message, remote_key)
+        }
+    }
+
+    struct SignOnlyProvider(FallbackCryptoProvider);
+
+    impl CryptoProvider for SignOnlyProvider {
+        fn default_id<'a>(&self) -> LocalBoxFuture<'a, anyhow::Result<NodeId>> {
+            self.0.default_id()
+        }
+
+        fn aliases<'a>(&self) -> LocalBoxFuture<'a, anyhow::Result<Vec<NodeId>>> {
+            self.0.aliases()
+        }
+
+        fn get<'a>(&self, node_id: NodeId) -> LocalBoxFuture<'a, anyhow::Result<Rc<dyn Crypto>>> {
+            let crypto = self.0.get(node_id);
+            async move { Ok(Rc::new(SignOnly(crypto.await?)) as Rc<dyn Crypto>) }.boxed_local()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_crypto_without_ecdh() {
+        let provider1 = FallbackCryptoProvider::default();
+        let provider2 = FallbackCryptoProvider::default();
+        let id2 = identity(&provider2).await;
+        let node_id = provider1.default_node_id();
+        let provider: Rc<dyn CryptoProvider> = Rc::new(SignOnlyProvider(provider1));
+
+        assert!(Encryption::supported_schemes(provider.clone(), node_id)
+            .await
+            .is_empty());
+
+        let schemes = Encryption::supported_schemes(Rc::new(provider2), id2.node_id).await;
+        assert!(
+            Encryption::negotiate(provider.clone(), node_id, &id2, &schemes, false)
+                .await
+                .is_err()
+        );
+        let enc = Encryption::negotiate(provider, node_id, &id2, &schemes, true)
+            .await
+            .unwrap();
+        assert!(!enc.is_enabled());
     }
 }
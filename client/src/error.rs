@@ -1,6 +1,7 @@
 use anyhow::Error;
 use std::net::SocketAddr;
 
+use ya_relay_core::crypto::CipherError;
 use ya_relay_core::server_session::SessionId;
 use ya_relay_core::NodeId;
 
@@ -107,6 +108,10 @@ pub enum RequestError {
 pub enum EncryptionError {
     #[error("{0}")]
     Generic(String),
+    #[error("Session keys weren't agreed yet")]
+    NoKeys,
+    #[error(transparent)]
+    Cipher(#[from] CipherError),
 }
 
 #[derive(thiserror::Error, Clone, Debug, PartialEq)]
@@ -0,0 +1,62 @@
+//! Notifications about changes of sessions and connections with other Nodes.
+use futures::Stream;
+use std::net::SocketAddr;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use ya_relay_core::NodeId;
+
+use crate::raw_session::SessionType;
+
+/// Number of events buffered for each subscriber. Subscriber, which doesn't keep up,
+/// loses the oldest events.
+const EVENTS_CAPACITY: usize = 256;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientEvent {
+    /// Session with Node was established.
+    NodeReachable { node_id: NodeId, route: SessionType },
+    /// Established session with Node was replaced, for example p2p session
+    /// took over forwarding through relay server.
+    RouteChanged { node_id: NodeId, route: SessionType },
+    /// Session with Node was closed.
+    NodeUnreachable { node_id: NodeId },
+    /// Session with relay server was established.
+    RelayConnected { addr: SocketAddr },
+    /// Session with relay server was closed.
+    RelayDisconnected { addr: SocketAddr },
+    /// Virtual TCP channel to Node was connected.
+    ChannelConnected { node_id: NodeId, channel: u16 },
+    /// Virtual TCP channel to Node was closed.
+    ChannelClosed { node_id: NodeId, channel: u16 },
+}
+
+#[derive(Clone)]
+pub(crate) struct EventSender(broadcast::Sender<ClientEvent>);
+
+impl Default for EventSender {
+    fn default() -> Self {
+        EventSender(broadcast::channel(EVENTS_CAPACITY).0)
+    }
+}
+
+impl EventSender {
+    pub fn send(&self, event: ClientEvent) {
+        log::trace!("Client event: {event:?}");
+        // Error means, that nobody is subscribed.
+        self.0.send(event).ok();
+    }
+
+    /// Events sent after this call. Events lost by lagging subscriber are skipped.
+    pub fn subscribe(&self) -> impl Stream<Item = ClientEvent> + 'static {
+        futures::stream::unfold(self.0.subscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    Err(RecvError::Lagged(n)) => log::warn!("Lost {n} client events"),
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+}
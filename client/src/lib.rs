@@ -8,13 +8,15 @@ mod direct_session;
 mod dispatch;
 mod encryption;
 mod error;
+mod events;
 pub mod metrics;
 mod raw_session;
 mod routing_session;
 mod session;
 mod transport;
 
-pub use client::{Client, ClientBuilder, FailFast, GenericSender, SessionError};
+pub use client::{Client, ClientBuilder, DropPolicy, FailFast, GenericSender, SessionError};
+pub use events::ClientEvent;
 
 /// This module is a public re-export cryptographic abstractions.
 pub use ya_relay_core::crypto;
@@ -39,7 +41,7 @@ pub mod model {
     #[doc(inline)]
     pub use ya_relay_core::session::Session;
 
-    pub use crate::raw_session::SessionDesc;
+    pub use crate::raw_session::{SessionDesc, SessionType};
 
     pub use ya_relay_core::server_session::SessionId;
 
@@ -50,7 +52,9 @@ pub mod model {
 /// Re-exports several channel related items from the client module and proto.
 pub mod channels {
     #[doc(inline)]
-    pub use crate::client::{ForwardReceiver, ForwardSender, Forwarded};
+    pub use crate::client::{DropPolicy, ForwardReceiver, ForwardSender, Forwarded};
+    #[doc(inline)]
+    pub use crate::transport::{Datagram, VirtualListener, VirtualStream, VirtualUdpSocket};
 
     #[doc(inline)]
     pub use ya_relay_proto::codec::forward::PrefixedStream;
@@ -20,6 +20,7 @@ pub(crate) fn register_metrics() {
     register_counter!("ya-relay.packet.udp.outgoing.num");
     register_counter!("ya-relay.packet.udp.incoming.size");
     register_counter!("ya-relay.packet.udp.incoming.num");
+    register_counter!("ya-relay.packet.udp.incoming.dropped");
     register_gauge!("ya-relay.client.session.type");
     register_counter!("ya-relay.client.session.established");
     register_counter!("ya-relay.client.session.closed");
@@ -35,6 +36,11 @@ pub(crate) fn register_metrics() {
         Unit::Count,
         "Number of outgoing tcp packets"
     );
+    describe_counter!(
+        "ya-relay.packet.udp.incoming.dropped",
+        Unit::Count,
+        "Number of unreliable packets dropped, because ingress queue was full"
+    );
     describe_gauge!(
         "ya-relay.client.session.type",
         "Type of established session with Node. Check `ConnectionMethod` for numbers meaning."
@@ -1,7 +1,8 @@
 use derive_more::Display;
 use futures::future::LocalBoxFuture;
 use futures::{FutureExt, SinkExt};
-use std::convert::TryInto;
+use rand::{thread_rng, Rng};
+use std::convert::{TryFrom, TryInto};
 use std::net::SocketAddr;
 use std::process::id;
 use std::sync::{Arc, Mutex};
@@ -10,6 +11,8 @@ use tokio::time::{Duration, Instant};
 use crate::dispatch::{Dispatched, Dispatcher};
 use crate::error::RequestError;
 
+use ya_relay_core::challenge::sign_rebind;
+use ya_relay_core::crypto::Crypto;
 use ya_relay_core::server_session::SessionId;
 use ya_relay_core::udp_stream::OutStream;
 use ya_relay_core::NodeId;
@@ -18,6 +21,11 @@ use ya_relay_proto::{codec, proto};
 
 const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_millis(3000);
 const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(2);
+/// Time given to the other side for signing the rebind nonce.
+const REBIND_TIMEOUT: Duration = Duration::from_secs(10);
+const REBIND_NONCE_SIZE: usize = 32;
+/// Limit of addresses, to which the other side can be moving the session at the same time.
+const MAX_PENDING_REBINDS: usize = 4;
 
 pub type DropHandler = Box<dyn FnOnce() + Send>;
 
@@ -35,7 +43,8 @@ pub enum SessionType {
 /// This layer isn't aware of Nodes identities.
 #[derive(Clone)]
 pub struct RawSession {
-    pub remote: SocketAddr,
+    /// Can change, when the other side migrates to a new address.
+    remote: Arc<Mutex<Remote>>,
     pub id: SessionId,
     pub created: Instant,
 
@@ -44,6 +53,20 @@ pub struct RawSession {
     pub(crate) drop_handler: Arc<Mutex<Option<DropHandler>>>,
 }
 
+struct Remote {
+    addr: SocketAddr,
+    /// Nonces sent to new addresses of the other side, which wants to move the session there.
+    rebinds: Vec<PendingRebind>,
+    /// Timestamp of the last accepted identities announcement.
+    identities: u64,
+}
+
+struct PendingRebind {
+    addr: SocketAddr,
+    nonce: Vec<u8>,
+    created: Instant,
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct SessionDesc {
     pub remote: SocketAddr,
@@ -56,7 +79,7 @@ pub struct SessionDesc {
 impl<'a> From<&'a RawSession> for SessionDesc {
     fn from(session: &'a RawSession) -> Self {
         SessionDesc {
-            remote: session.remote,
+            remote: session.remote(),
             id: session.id,
             last_seen: session.dispatcher.last_seen().into_std(),
             last_ping: session.dispatcher.last_ping(),
@@ -70,7 +93,11 @@ impl RawSession {
         log::trace!("Creating new `RawSession` {id} ({remote_addr})");
 
         Arc::new(Self {
-            remote: remote_addr,
+            remote: Arc::new(Mutex::new(Remote {
+                addr: remote_addr,
+                rebinds: Vec::new(),
+                identities: 0,
+            })),
             id,
             sink,
             created: Instant::now(),
@@ -79,6 +106,59 @@ impl RawSession {
         })
     }
 
+    pub fn remote(&self) -> SocketAddr {
+        self.remote.lock().unwrap().addr
+    }
+
+    /// Nonce, which the other side has to sign to move the session to `addr`.
+    /// Retransmitted requests get the same nonce. Returns `None`, if too many
+    /// addresses are waiting for the move.
+    pub(crate) fn rebind_nonce(&self, addr: SocketAddr) -> Option<Vec<u8>> {
+        let mut remote = self.remote.lock().unwrap();
+        remote
+            .rebinds
+            .retain(|p| p.created.elapsed() < REBIND_TIMEOUT);
+
+        if let Some(pending) = remote.rebinds.iter().find(|p| p.addr == addr) {
+            return Some(pending.nonce.clone());
+        }
+        if remote.rebinds.len() >= MAX_PENDING_REBINDS {
+            return None;
+        }
+        let nonce = thread_rng().gen::<[u8; REBIND_NONCE_SIZE]>().to_vec();
+        remote.rebinds.push(PendingRebind {
+            addr,
+            nonce: nonce.clone(),
+            created: Instant::now(),
+        });
+        Some(nonce)
+    }
+
+    /// Removes nonce sent to `addr`. Nonce can be used only once, but requests
+    /// from other addresses can't invalidate it.
+    pub(crate) fn take_rebind_nonce(&self, addr: SocketAddr) -> Option<Vec<u8>> {
+        let mut remote = self.remote.lock().unwrap();
+        let idx = remote.rebinds.iter().position(|p| p.addr == addr)?;
+        let pending = remote.rebinds.swap_remove(idx);
+        (pending.created.elapsed() < REBIND_TIMEOUT).then_some(pending.nonce)
+    }
+
+    /// Changes address of the other side. Returns previous address.
+    pub(crate) fn migrate(&self, addr: SocketAddr) -> SocketAddr {
+        std::mem::replace(&mut self.remote.lock().unwrap().addr, addr)
+    }
+
+    /// Records identities announcement of the other side. Returns false for announcements
+    /// not newer than the last accepted one.
+    pub(crate) fn accept_identities(&self, timestamp: u64) -> bool {
+        let mut remote = self.remote.lock().unwrap();
+        if timestamp <= remote.identities {
+            return false;
+        }
+        remote.identities = timestamp;
+        true
+    }
+
     pub fn dispatcher(&self) -> Dispatcher {
         self.dispatcher.clone()
     }
@@ -86,29 +166,106 @@ impl RawSession {
     pub async fn register_endpoints(
         &self,
         endpoints: Vec<proto::Endpoint>,
+        hole_punching: bool,
     ) -> Result<Vec<proto::Endpoint>, RequestError> {
-        log::info!("Registering endpoints on {}.", self.remote);
+        log::info!("Registering endpoints on {}.", self.remote());
 
         let response = self
             .request::<proto::response::Register>(
-                proto::request::Register { endpoints }.into(),
+                proto::request::Register {
+                    endpoints,
+                    hole_punching,
+                }
+                .into(),
                 self.id.to_vec(),
                 DEFAULT_REQUEST_TIMEOUT,
             )
             .await?
             .packet;
 
-        log::info!("Endpoints registration finished on {}.", self.remote);
+        log::info!("Endpoints registration finished on {}.", self.remote());
 
         Ok(response.endpoints)
     }
 
+    /// Moves session to the address, from which this Node sends packets now.
+    /// Nothing changes, if the other side sees the same address as before.
+    pub async fn rebind(&self, crypto: impl Crypto) -> Result<(), RequestError> {
+        let nonce = self.rebind_request(vec![], vec![]).await?;
+        if nonce.is_empty() {
+            return Ok(());
+        }
+
+        log::info!(
+            "Moving session {} with {} to the new address.",
+            self.id,
+            self.remote()
+        );
+
+        let signature = sign_rebind(&self.id.to_vec(), &nonce, crypto)
+            .await
+            .map_err(|e| RequestError::Generic(format!("Signing rebind request: {e}")))?;
+        self.rebind_request(nonce, signature).await?;
+        Ok(())
+    }
+
+    async fn rebind_request(
+        &self,
+        nonce: Vec<u8>,
+        signature: Vec<u8>,
+    ) -> Result<Vec<u8>, RequestError> {
+        let response = self
+            .request::<proto::response::Rebind>(
+                proto::request::Rebind { nonce, signature }.into(),
+                self.id.to_vec(),
+                DEFAULT_REQUEST_TIMEOUT,
+            )
+            .await?;
+
+        match proto::StatusCode::try_from(response.code) {
+            Ok(proto::StatusCode::Ok) => Ok(response.packet.nonce),
+            _ => Err(RequestError::Generic(format!(
+                "Rebind rejected by {} with code {}",
+                self.remote(),
+                response.code
+            ))),
+        }
+    }
+
+    /// Replaces identities of this session on relay server.
+    pub async fn update_identities(
+        &self,
+        timestamp: u64,
+        signatures: Vec<Vec<u8>>,
+    ) -> Result<(), RequestError> {
+        let response = self
+            .request::<proto::response::Identities>(
+                proto::request::Identities {
+                    timestamp,
+                    signatures,
+                }
+                .into(),
+                self.id.to_vec(),
+                DEFAULT_REQUEST_TIMEOUT,
+            )
+            .await?;
+
+        match proto::StatusCode::try_from(response.code) {
+            Ok(proto::StatusCode::Ok) => Ok(()),
+            _ => Err(RequestError::Generic(format!(
+                "Identities rejected by relay {} with code {}",
+                self.remote(),
+                response.code
+            ))),
+        }
+    }
+
     pub async fn find_node(&self, node_id: NodeId) -> anyhow::Result<proto::response::Node> {
         log::debug!(
             "Finding Node info [{}], using session {} ({}).",
             node_id,
             self.id,
-            self.remote
+            self.remote()
         );
 
         let packet = proto::request::Node {
@@ -191,6 +348,22 @@ impl RawSession {
         Ok(())
     }
 
+    /// Asks relay server to send both us and `node_id` each other's endpoints,
+    /// so we can start hole punching at the same time.
+    pub async fn hole_punch(&self, node_id: NodeId) -> Result<(), RequestError> {
+        let packet = proto::request::HolePunch {
+            node_id: node_id.into_array().to_vec(),
+        };
+        self.request::<proto::response::HolePunch>(
+            packet.into(),
+            self.id.to_vec(),
+            DEFAULT_REQUEST_TIMEOUT,
+        )
+        .await?;
+
+        Ok(())
+    }
+
     /// Check if any packet was seen during expiration period.
     /// If it wasn't, ping will be sent.
     /// Function returns timestamp of last seen packet from remote Node,
@@ -306,13 +479,13 @@ impl RawSession {
     #[inline]
     pub async fn send(&self, packet: impl Into<codec::PacketKind>) -> anyhow::Result<()> {
         let mut sink = self.sink.clone();
-        Ok(sink.send((packet.into(), self.remote)).await?)
+        Ok(sink.send((packet.into(), self.remote())).await?)
     }
 }
 
 impl Drop for RawSession {
     fn drop(&mut self) {
-        log::trace!("Dropping `RawSession` {} ({}).", self.id, self.remote);
+        log::trace!("Dropping `RawSession` {} ({}).", self.id, self.remote());
 
         let on_drop = self.drop_handler.lock().unwrap().take();
         if let Some(f) = on_drop {
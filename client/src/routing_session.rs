@@ -6,7 +6,7 @@ use ya_relay_core::NodeId;
 use ya_relay_proto::proto::Payload;
 
 use crate::direct_session::{DirectSession, NodeEntry};
-use crate::encryption::Encryption;
+use crate::encryption::{Encryption, KEY_EXCHANGE_ATTEMPTS, KEY_EXCHANGE_RETRY};
 use crate::error::SessionError;
 use crate::raw_session::SessionType;
 use crate::session::SessionLayer;
@@ -17,8 +17,8 @@ use crate::session::SessionLayer;
 /// Nodes are using always their default NodeId to communicate. Secondary ids will be resolved
 /// to defaults, so each node will have the same entry for all identities.
 ///
-/// TODO: Encryption should be implemented on this layer, since we have access to public
-///       key of destination Node.
+/// Encryption is implemented on this layer, since we have access to public key of destination Node.
+/// This way payload is encrypted end to end, independently of the route.
 #[derive(Clone)]
 pub struct NodeRouting {
     pub node: NodeEntry<Identity>,
@@ -26,7 +26,7 @@ pub struct NodeRouting {
     /// If `NodeRouting` is relayed session, than we have Relay Server `DirectSession` here.
     /// `DirectSession` contains all info (for example SlotID) required to send packets using this session.  
     pub route: Weak<DirectSession>,
-    encryption: Encryption,
+    pub(crate) encryption: Encryption,
 }
 
 impl NodeRouting {
@@ -55,18 +55,23 @@ impl NodeRouting {
                 transport,
                 self.node.default_id.node_id,
                 direct.owner.default_id,
-                direct.raw.remote,
+                direct.raw.remote(),
                 direct.raw.id
             );
 
+            let target = self.node.default_id.node_id;
+            let encrypted = self.encryption.is_enabled();
+            let flags = DirectSession::forward_flags(transport, encrypted);
+
+            self.agree_keys(&direct).await?;
             let packet = self
                 .encryption
-                .encrypt(packet)
+                .encrypt(packet, direct.is_direct(&target), flags)
                 .await
                 .map_err(|e| SessionError::Internal(e.to_string()))?;
 
             direct
-                .send(self.node.default_id.node_id, packet, transport, false)
+                .send(target, packet, transport, encrypted)
                 .await
                 .map_err(|e| {
                     SessionError::Network(format!("Sending packet to p2p routing session: {e}"))
@@ -77,6 +82,39 @@ impl NodeRouting {
             "Routing session closed unexpectedly.".to_string(),
         ))
     }
+
+    /// Sends key exchange message to the Node through the current route.
+    pub async fn send_key_exchange(&self, packet: Payload) -> Result<(), SessionError> {
+        let direct = self.route.upgrade().ok_or_else(|| {
+            SessionError::Unexpected("Routing session closed unexpectedly.".to_string())
+        })?;
+        direct
+            .send_key_exchange(self.node.default_id.node_id, packet)
+            .await
+            .map_err(|e| SessionError::Network(format!("Sending key exchange: {e}")))
+    }
+
+    /// Agrees session keys with the Node, if they weren't agreed yet.
+    async fn agree_keys(&self, direct: &DirectSession) -> Result<(), SessionError> {
+        let target = self.node.default_id.node_id;
+        for _ in 0..KEY_EXCHANGE_ATTEMPTS {
+            if self.encryption.is_ready() {
+                return Ok(());
+            }
+            if let Some(request) = self.encryption.initiate() {
+                direct
+                    .send_key_exchange(target, request)
+                    .await
+                    .map_err(|e| SessionError::Network(format!("Sending key exchange: {e}")))?;
+            }
+            if self.encryption.wait_ready(KEY_EXCHANGE_RETRY).await {
+                return Ok(());
+            }
+        }
+        Err(SessionError::Network(format!(
+            "Node [{target}] didn't respond to key exchange"
+        )))
+    }
 }
 
 /// Interface structure for sending packets to other Nodes.
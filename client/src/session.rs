@@ -1,5 +1,6 @@
 mod expire;
 mod keep_alive;
+mod migration;
 pub mod network_view;
 pub mod session_initializer;
 pub mod session_state;
@@ -8,6 +9,7 @@ pub mod session_traits;
 use anyhow::{anyhow, bail};
 use async_trait::async_trait;
 use derive_more::Display;
+use futures::channel::oneshot;
 use futures::future::{AbortHandle, LocalBoxFuture};
 use futures::{FutureExt, SinkExt, TryFutureExt};
 use metrics::{gauge, increment_counter};
@@ -20,32 +22,38 @@ use std::net::SocketAddr;
 use std::sync::{Arc, Weak};
 use std::thread::sleep;
 use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::sync::RwLock;
 
 use self::expire::track_sessions_expiration;
 use self::keep_alive::keep_alive_server_session;
+use self::migration::track_address_changes;
 use self::network_view::{NetworkView, SessionLock, SessionPermit, Validity};
 use self::session_state::{RelayedState, ReverseState, SessionState};
 use crate::client::{ClientConfig, Forwarded};
+use crate::config::is_relay_id;
 use crate::direct_session::{DirectSession, NodeEntry};
 use crate::dispatch::{dispatch, Handler};
 use crate::encryption::Encryption;
 use crate::error::{
-    ProtocolError, ResultExt, SessionError, SessionInitError, SessionResult, TransitionError,
+    EncryptionError, ProtocolError, ResultExt, SessionError, SessionInitError, SessionResult,
+    TransitionError,
 };
 use crate::metrics::{metric_session_established, TARGET_ID};
 use crate::raw_session::{RawSession, SessionType};
 use crate::routing_session::{NodeRouting, RoutingSender};
 use crate::session::session_initializer::SessionInitializer;
-use crate::transport::ForwardReceiver;
 
 use crate::error::SenderError::Session;
 use crate::session::session_state::SessionState::{Closed, FailedEstablish};
 use crate::session::session_traits::{SessionDeregistration, SessionRegistration};
 use crate::SessionError::Network;
+use ya_relay_core::crypto::CipherError;
+use ya_relay_core::faults::inject_faults;
 use ya_relay_core::identity::Identity;
 use ya_relay_core::server_session::{Endpoint, NodeInfo, SessionId, TransportType};
-use ya_relay_core::udp_stream::{udp_bind, OutStream};
+use ya_relay_core::tcp_stream::{with_tcp, TcpConnections};
+use ya_relay_core::udp_stream::OutStream;
 use ya_relay_core::utils::spawn_local_abortable;
 use ya_relay_core::{challenge, NodeId};
 use ya_relay_proto::codec::PacketKind;
@@ -64,18 +72,21 @@ pub enum ConnectionMethod {
     Direct = 1,
     Reverse = 2,
     Relay = 3,
-    // Here should be NAT punching type(s) defined in the future
+    HolePunch = 4,
 }
 
 /// Responsible for establishing/receiving connections from other Nodes and providing API,
 /// that hides details, how the packets are routed to desired location.
 ///
-/// There are 3 methods `SessionLayer` can use, to establish communication with other Node:
+/// There are 4 methods `SessionLayer` can use, to establish communication with other Node:
 /// - Direct p2p connection - method is used when other Node has public ports and we can connect
 ///   with him directly
 /// - Reverse connection - used when we have public ports but other Node doesn't. In this scenario
 ///   we use relay server to facilitate the connection. Our Node sends `ReverseConnection` message
 ///   which is proxied to other Node by relay. Than other Node tries to connect to us.
+/// - Hole punching - used when neither Node has public ports (must be enabled in config).
+///   Relay sends `HolePunch` message with endpoints observed by the server to both Nodes,
+///   so they can send probes to each other at the same time, to open mappings on their NATs.
 /// - Relayed connection - we use relay server to forward packets to destination Node. This method
 ///   is used when other options are not available.
 ///
@@ -87,6 +98,8 @@ pub enum ConnectionMethod {
 /// If session with one relay will be closed, [`RoutingSender`] can update it's routing information
 /// in a transparent way, so external layers won't notice the change, when sending subsequent packets.
 /// Thanks to this [`TcpLayer`] doesn't have to close Tcp connection even if underlying session is closed.
+/// Relayed traffic goes through single relay at the time (see [`SessionLayer::server_session`]),
+/// the rest of relays from configuration are used as a fallback.
 #[derive(Clone)]
 pub struct SessionLayer {
     pub config: Arc<ClientConfig>,
@@ -101,6 +114,8 @@ pub struct SessionLayer {
 
     // TODO: Could be per `Session`?
     processed_requests: Arc<Mutex<VecDeque<ReqFingerprint>>>,
+    /// Serializes identities changes, so each of them starts from the last accepted aliases.
+    identities_lock: Arc<tokio::sync::Mutex<()>>,
 }
 
 #[derive(Default)]
@@ -115,8 +130,19 @@ pub struct SessionLayerState {
     pub nodes: HashMap<NodeId, Arc<NodeRouting>>,
     pub p2p_sessions: HashMap<SocketAddr, Arc<DirectSession>>,
     pub p2p_nodes: HashMap<NodeId, Arc<DirectSession>>,
+    /// Index of relay server from `ClientConfig::srv_addrs` used to forward packets
+    /// and query information about other Nodes.
+    pub(crate) active_relay: usize,
+    /// Hole punching attempts waiting for endpoints of the other Node from relay.
+    pub(crate) hole_punch_waiters: HashMap<NodeId, oneshot::Sender<Vec<SocketAddr>>>,
 
     pub(crate) init_protocol: Option<SessionInitializer>,
+    /// Connections to relays reachable only over TCP.
+    pub(crate) tcp: Option<TcpConnections>,
+    /// Aliases changed at runtime. Until then `CryptoProvider::aliases` are used.
+    pub(crate) aliases: Option<Vec<NodeId>>,
+    /// Timestamp of the last identities change announced to relays and p2p peers.
+    pub(crate) identities_ts: u64,
 
     // Collection of background tasks that must be stopped on shutdown.
     pub handles: Vec<AbortHandle>,
@@ -131,6 +157,7 @@ impl SessionRegistration for SessionLayer {
         id: SessionId,
         node_id: NodeId,
         identities: Vec<Identity>,
+        supported_encryptions: Vec<String>,
     ) -> anyhow::Result<Arc<DirectSession>> {
         log::trace!("Calling register_session {id} [{node_id}] ({addr})");
 
@@ -160,23 +187,28 @@ impl SessionRegistration for SessionLayer {
             ));
 
         let routing = match default_id {
-            Ok(default_id) => Some(NodeRouting::new(
-                NodeEntry::<Identity> {
-                    default_id,
-                    identities,
-                },
-                direct.clone(),
-                Encryption {
-                    crypto: self.config.crypto.clone(),
-                },
-            )),
+            Ok(default_id) => {
+                let encryption = self
+                    .negotiate_encryption(&default_id, &supported_encryptions)
+                    .await
+                    .map_err(|e| anyhow!("Session with node [{node_id}] ({addr}): {e}"))?;
+
+                Some(NodeRouting::new(
+                    NodeEntry::<Identity> {
+                        default_id,
+                        identities,
+                    },
+                    direct.clone(),
+                    encryption,
+                ))
+            }
             Err(_) if is_relay => None,
             Err(e) => bail!(e),
         };
 
         {
             let mut state = self.state.lock();
-            state.p2p_sessions.insert(session.remote, direct.clone());
+            state.p2p_sessions.insert(session.remote(), direct.clone());
 
             for id in &direct.owner.identities {
                 state.p2p_nodes.insert(*id, direct.clone());
@@ -203,7 +235,7 @@ impl SessionRegistration for SessionLayer {
             .ok_or(anyhow!("`DirectSession` was closed"))?;
 
         let server_id = route.owner.default_id;
-        let addr = route.raw.remote;
+        let addr = route.raw.remote();
 
         let mut state = self.state.lock();
         for id in &routing.node.identities {
@@ -216,6 +248,14 @@ impl SessionRegistration for SessionLayer {
         }
         Ok(())
     }
+
+    async fn aliases(&self) -> anyhow::Result<Vec<NodeId>> {
+        let aliases = self.state.lock().aliases.clone();
+        match aliases {
+            Some(aliases) => Ok(aliases),
+            None => self.config.crypto.aliases().await,
+        }
+    }
 }
 
 #[async_trait(?Send)]
@@ -233,21 +273,36 @@ impl SessionDeregistration for SessionLayer {
                 let direct = state.p2p_nodes.get(&node_id).cloned();
 
                 if let Some(routing) = &routing {
-                    ids.extend(routing.node.identities.iter().map(|entry| entry.node_id))
+                    ids.extend(routing.node.identities.iter().map(|entry| entry.node_id));
+                    // Aliases announced after the session was established.
+                    ids.extend(
+                        state
+                            .nodes
+                            .iter()
+                            .filter(|(_, other)| Arc::ptr_eq(other, routing))
+                            .map(|(id, _)| *id),
+                    );
                 }
 
                 if let Some(direct) = &direct {
                     log::debug!(
                         "Disconnecting [{node_id}] - removing session: {} ({})",
                         direct.raw.id,
-                        direct.raw.remote
+                        direct.raw.remote()
                     );
 
-                    state.p2p_sessions.remove(&direct.raw.remote);
+                    state.p2p_sessions.remove(&direct.raw.remote());
 
                     // List of ids should be the same in `NodeRouting` and `DirectSession`
                     // we are using both to make sure we removed everything.
-                    ids.extend(direct.owner.identities.iter())
+                    ids.extend(direct.owner.identities.iter());
+                    ids.extend(
+                        state
+                            .p2p_nodes
+                            .iter()
+                            .filter(|(_, other)| Arc::ptr_eq(other, direct))
+                            .map(|(id, _)| *id),
+                    );
                 }
 
                 for id in ids {
@@ -295,14 +350,14 @@ impl SessionDeregistration for SessionLayer {
             "Closing session {} with [{}] ({})",
             session.raw.id,
             session.owner.default_id,
-            session.raw.remote
+            session.raw.remote()
         );
 
         // Notifies other Node that we are closing connection. This is only graceful optimization.
         // Node should handle disconnected Nodes properly even if he won't be notified.
         session.raw.disconnect().await.ok();
 
-        if session.owner.default_id == NodeId::default() {
+        if is_relay_id(&session.owner.default_id) {
             let f = session.list();
             log::trace!(
                 "[close_session]: lost session with server - remove {} forwards",
@@ -324,7 +379,7 @@ impl SessionDeregistration for SessionLayer {
                 state.p2p_nodes.remove(id);
                 state.nodes.remove(id);
             }
-            state.p2p_sessions.remove(&session.raw.remote);
+            state.p2p_sessions.remove(&session.raw.remote());
 
             for id in forwards.iter().flat_map(|entry| entry.identities.iter()) {
                 state.nodes.remove(id);
@@ -339,7 +394,7 @@ impl SessionDeregistration for SessionLayer {
             "Session {} with [{}] ({}) closed",
             session.raw.id,
             session.owner.default_id,
-            session.raw.remote
+            session.raw.remote()
         );
     }
 
@@ -378,6 +433,7 @@ impl SessionLayer {
             registry: Default::default(),
             ingress_channel: Default::default(),
             processed_requests: Arc::new(Mutex::new(VecDeque::new())),
+            identities_lock: Default::default(),
         }
     }
 
@@ -390,7 +446,12 @@ impl SessionLayer {
         &mut self,
         handler: impl Handler + Clone + 'static,
     ) -> anyhow::Result<SocketAddr> {
-        let (stream, sink, bind_addr) = udp_bind(&self.config.bind_url).await?;
+        let (stream, sink, bind_addr) = self.config.udp_binder.bind(&self.config.bind_url).await?;
+        let (stream, sink) = match &self.config.faults {
+            Some(faults) => inject_faults(stream, sink, faults),
+            None => (stream, sink),
+        };
+        let (stream, sink, tcp) = with_tcp(stream, sink);
 
         {
             *self.sink.lock() = Some(sink.clone());
@@ -399,12 +460,17 @@ impl SessionLayer {
         let mut handles: Vec<AbortHandle> = Vec::from([
             spawn_local_abortable(dispatch(handler, stream)),
             spawn_local_abortable(track_sessions_expiration(self.clone())),
+            spawn_local_abortable(track_address_changes(self.clone())),
         ]);
 
         if self.config.auto_connect && !self.config.auto_connect_fail_fast {
-            handles.push(spawn_local_abortable(keep_alive_server_session(
-                self.clone(),
-            )));
+            for (relay_id, addr) in self.config.relays() {
+                handles.push(spawn_local_abortable(keep_alive_server_session(
+                    self.clone(),
+                    relay_id,
+                    addr,
+                )));
+            }
         } else {
             log::debug!("Keep alive server session not started");
         };
@@ -413,6 +479,7 @@ impl SessionLayer {
             let mut state = self.state.lock();
 
             state.bind_addr.replace(bind_addr);
+            state.tcp = Some(tcp);
             for h in handles {
                 state.handles.push(h);
             }
@@ -501,7 +568,7 @@ impl SessionLayer {
         self.state.lock().bind_addr
     }
 
-    pub fn receiver(&self) -> Option<ForwardReceiver> {
+    pub fn receiver(&self) -> Option<UnboundedReceiver<Forwarded>> {
         self.ingress_channel.receiver()
     }
 
@@ -717,17 +784,91 @@ impl SessionLayer {
         }
     }
 
+    /// Returns session with currently used relay server. If the relay is unreachable,
+    /// subsequent relays from configuration are tried. Already established sessions
+    /// are preferred over initializing new ones.
     pub async fn server_session(&self) -> Result<Arc<DirectSession>, SessionError> {
-        // A little bit dirty hack, that we give default NodeId (0x00) for relay server.
-        // TODO: In the future relays should have regular NodeId
-        let remote_id = NodeId::default();
-        let addr = self.config.srv_addr;
+        let relays = self.config.relays().collect::<Vec<_>>();
+        let active = { self.state.lock().active_relay };
+        let ordered = relays
+            .iter()
+            .cycle()
+            .skip(active)
+            .take(relays.len())
+            .collect::<Vec<_>>();
+
+        let existing = {
+            let state = self.state.lock();
+            ordered
+                .iter()
+                .find_map(|(id, addr)| state.p2p_sessions.get(addr).map(|s| (*id, s.clone())))
+        };
+        if let Some((relay_id, session)) = existing {
+            self.set_active_relay(relay_id);
+            return Ok(session);
+        }
+
+        // Unreachable relays fail only after their requests time out, so trying
+        // them one after another could block callers for a long time.
+        let deadline = tokio::time::Instant::now() + self.config.relay_failover_timeout;
+        let mut result = Err(SessionError::Relay(
+            "No relay servers configured".to_string(),
+        ));
+        for (relay_id, addr) in ordered {
+            result = tokio::time::timeout_at(deadline, self.relay_session(*relay_id, *addr))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(SessionError::Timeout(format!(
+                        "Relay failover didn't finish within {:?}",
+                        self.config.relay_failover_timeout
+                    )))
+                });
+            match &result {
+                Ok(_) => {
+                    self.set_active_relay(*relay_id);
+                    break;
+                }
+                Err(e) => {
+                    log::warn!("Relay server [{relay_id}] ({addr}) unavailable. {e}");
+                    if tokio::time::Instant::now() >= deadline {
+                        break;
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    fn set_active_relay(&self, relay_id: NodeId) {
+        let (idx, addr) = match self
+            .config
+            .relays()
+            .enumerate()
+            .find(|(_, (id, _))| *id == relay_id)
+        {
+            Some((idx, (_, addr))) => (idx, addr),
+            None => return,
+        };
+
+        let mut state = self.state.lock();
+        if state.active_relay != idx {
+            log::info!("Switching relay server to [{relay_id}] ({addr})");
+            state.active_relay = idx;
+        }
+    }
+
+    /// Returns session with chosen relay server. Session is established if necessary.
+    pub async fn relay_session(
+        &self,
+        remote_id: NodeId,
+        addr: SocketAddr,
+    ) -> Result<Arc<DirectSession>, SessionError> {
         let this = self.clone();
 
         log::trace!("Requested Relay server session with [{remote_id}] ({addr}).");
 
         if let Some(session) = { self.state.lock().p2p_sessions.get(&addr).cloned() } {
-            log::trace!("Resolving Relay server session. Returning already existing connection ([{}] ({})).", session.owner.default_id, session.raw.remote);
+            log::trace!("Resolving Relay server session. Returning already existing connection ([{}] ({})).", session.owner.default_id, session.raw.remote());
             return Ok(session);
         }
 
@@ -832,6 +973,28 @@ impl SessionLayer {
             log::debug!("Omitting attempt to establish reverse p2p connection with [{node_id}].");
         }
 
+        if !dont_use.contains(&ConnectionMethod::HolePunch) {
+            log::debug!("Attempting to punch a hole to [{node_id}].");
+
+            match self.try_hole_punching(node_id, permit).await {
+                Ok(session) => {
+                    metric_session_established(node_id, ConnectionMethod::HolePunch);
+                    return Ok(session);
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Failed to establish p2p session with [{node_id}] using hole punching. {e}"
+                    );
+                    permit
+                        .registry
+                        .transition(SessionState::RestartConnect)
+                        .await?;
+                }
+            }
+        } else {
+            log::debug!("Omitting attempt to punch a hole to [{node_id}].");
+        }
+
         log::debug!("All attempts to establish direct session with node [{node_id}] failed");
 
         // TODO: If one party has public IP, but previous resolution attempts failed, then we should
@@ -866,20 +1029,42 @@ impl SessionLayer {
         permit: &SessionPermit,
     ) -> SessionResult<Arc<DirectSession>> {
         let protocol = self.get_protocol()?;
+        if self.config.relay_protocol(&node_id) == proto::Protocol::Tcp {
+            self.connect_tcp(addr).await?;
+        }
+
         let session = match protocol.init_server_session(addr, permit).await {
             Ok(session) => session,
             Err(SessionInitError::Relay(_, e)) | Err(SessionInitError::P2P(_, e)) => return Err(e),
         };
 
-        let endpoints = session.raw.register_endpoints(vec![]).await?;
+        let endpoints = session
+            .raw
+            .register_endpoints(
+                self.config.advertised_endpoints.clone(),
+                self.config.hole_punching,
+            )
+            .await?;
 
-        // If there is any (correct) endpoint on the list, that means we have public IP.
+        // If there is any (correct) UDP endpoint on the list, that means we have public IP.
         if let Some(addr) = endpoints
             .into_iter()
+            .filter(|endpoint| endpoint.protocol == proto::Protocol::Udp as i32)
             .find_map(|endpoint| endpoint.try_into().ok())
         {
             gauge!("ya-relay.client.public-address", 1.0);
-            self.set_public_addr(Some(addr)).await;
+            let previous = { self.state.lock().public_addr.replace(addr) };
+            if let Some(previous) = previous.filter(|previous| *previous != addr) {
+                log::info!("Public address changed from {previous} to {addr}. Notifying peers.");
+                let myself = self.clone();
+                tokio::task::spawn_local(async move {
+                    myself
+                        .announce_address_change()
+                        .await
+                        .map_err(|e| log::warn!("Announcing address change failed: {e}"))
+                        .ok();
+                });
+            }
         } else {
             gauge!("ya-relay.client.public-address", 0.0);
         }
@@ -888,6 +1073,18 @@ impl SessionLayer {
         Ok(session)
     }
 
+    async fn connect_tcp(&self, addr: SocketAddr) -> SessionResult<()> {
+        let tcp = self
+            .state
+            .lock()
+            .tcp
+            .clone()
+            .ok_or_else(|| SessionError::Internal("SessionLayer not started".to_string()))?;
+        tcp.connect(addr, self.config.session_request_timeout)
+            .await
+            .map_err(|e| SessionError::Network(format!("TCP connection to relay failed: {e}")))
+    }
+
     pub async fn try_direct_session(
         &self,
         node_id: NodeId,
@@ -994,6 +1191,392 @@ impl SessionLayer {
         }
     }
 
+    async fn try_hole_punching(
+        &self,
+        node_id: NodeId,
+        permit: &SessionPermit,
+    ) -> Result<Arc<DirectSession>, SessionError> {
+        if !self.config.hole_punching {
+            return Err(SessionError::NotApplicable(
+                "Hole punching disabled.".to_string(),
+            ));
+        }
+
+        // Waiter must be registered before sending request, because relay can send
+        // `HolePunch` message before the response.
+        let (tx, rx) = oneshot::channel();
+        self.state.lock().hole_punch_waiters.insert(node_id, tx);
+
+        let endpoints = async {
+            let server_session = self.server_session().await?;
+            server_session.raw.hole_punch(node_id).await?;
+
+            tokio::time::timeout(self.config.hole_punch_timeout, rx)
+                .await
+                .map_err(|_| {
+                    SessionError::Timeout(format!(
+                        "Timeout ({}) elapsed when waiting for `HolePunch` endpoints of [{node_id}]",
+                        humantime::format_duration(self.config.hole_punch_timeout)
+                    ))
+                })?
+                .map_err(|_| SessionError::Internal("Hole punching cancelled".to_string()))
+        }
+        .await;
+        self.state.lock().hole_punch_waiters.remove(&node_id);
+
+        let endpoints = endpoints?;
+        if endpoints.is_empty() {
+            return Err(SessionError::NotApplicable(format!(
+                "Relay has no endpoints of [{node_id}]."
+            )));
+        }
+
+        log::info!(
+            "Punching hole. me={}, remote={node_id}, endpoints={endpoints:?}",
+            self.config.node_id,
+        );
+
+        // Other Node is sending probes to us at the same time, so packets sent by both
+        // sides should create mappings on NATs, that let the session handshake through.
+        self.send_probes(&endpoints).await;
+
+        let protocol = self.get_protocol()?;
+        for addr in endpoints {
+            match protocol.init_p2p_session(addr, permit).await {
+                Ok(session) => return Ok(session),
+                Err(SessionInitError::Relay(_, e)) | Err(SessionInitError::P2P(_, e)) => {
+                    log::debug!(
+                        "Failed to establish p2p session with node [{node_id}] after hole punching, using address: {addr}. Error: {e}"
+                    );
+                }
+            }
+        }
+
+        Err(SessionError::Generic(format!(
+            "All attempts to punch a hole to node [{node_id}] failed"
+        )))
+    }
+
+    /// Sends a few `HolePunch` packets to the other Node. They are meant to open
+    /// mappings on our NAT, receiver ignores them.
+    async fn send_probes(&self, endpoints: &[SocketAddr]) {
+        const PROBES: usize = 3;
+        const PROBE_INTERVAL: Duration = Duration::from_millis(50);
+
+        let probe = proto::Packet::control(
+            vec![],
+            proto::control::HolePunch {
+                node_id: self.config.node_id.into_array().to_vec(),
+                endpoints: vec![],
+            },
+        );
+
+        for i in 0..PROBES {
+            if i > 0 {
+                tokio::time::sleep(PROBE_INTERVAL).await;
+            }
+            for addr in endpoints {
+                self.send(probe.clone(), *addr)
+                    .await
+                    .map_err(|e| log::debug!("Failed to send hole punching probe to {addr}: {e}"))
+                    .ok();
+            }
+        }
+    }
+
+    pub async fn on_hole_punch(
+        &self,
+        from: SocketAddr,
+        message: proto::control::HolePunch,
+    ) -> anyhow::Result<()> {
+        // Probes sent by other Nodes are only meant to open NAT mappings.
+        if !self.config.relays().any(|(_, addr)| addr == from) {
+            log::trace!("Hole punching probe from {from}");
+            return Ok(());
+        }
+
+        let node_id = NodeId::try_from(&message.node_id)
+            .map_err(|e| anyhow!("HolePunch with invalid NodeId: {:?}. {e}", message.node_id))?;
+        let endpoints = message
+            .endpoints
+            .into_iter()
+            .filter_map(|e| e.try_into().ok())
+            .collect::<Vec<SocketAddr>>();
+
+        log::debug!("Got HolePunch message from {from}. node={node_id}, endpoints={endpoints:?}");
+
+        // We are initiator. Session will be established by `try_hole_punching`.
+        let waiter = self.state.lock().hole_punch_waiters.remove(&node_id);
+        if let Some(waiter) = waiter {
+            waiter.send(endpoints).ok();
+            return Ok(());
+        }
+
+        if !self.config.hole_punching {
+            log::debug!("Ignoring HolePunch from [{node_id}], hole punching is disabled.");
+            return Ok(());
+        }
+
+        // Other Node initiated hole punching and will start session with us,
+        // we only need to open mapping on our NAT.
+        self.send_probes(&endpoints).await;
+        Ok(())
+    }
+
+    /// Notifies p2p peers and relays, that our address changed, so they can send packets
+    /// to the new address without closing sessions. Sessions are moved using `Rebind`
+    /// request, which proves that we can receive packets on the new address.
+    pub async fn announce_address_change(&self) -> anyhow::Result<()> {
+        let crypto = self.config.crypto.get(self.config.node_id).await?;
+        let sessions = {
+            let state = self.state.lock();
+            state.p2p_sessions.values().cloned().collect::<Vec<_>>()
+        };
+
+        let announcements = sessions.into_iter().map(|session| {
+            let crypto = crypto.clone();
+            async move {
+                if let Err(e) = session.raw.rebind(crypto).await {
+                    log::warn!(
+                        "Failed to announce address change to [{}] ({}): {e}",
+                        session.owner.default_id,
+                        session.raw.remote()
+                    );
+                }
+            }
+        });
+        futures::future::join_all(announcements).await;
+        Ok(())
+    }
+
+    /// Changes aliases of this Node and announces the new identity set. Relays replace
+    /// identities of our sessions, p2p peers get signed `Identities` message.
+    /// Fails if the relay rejected the change, failures of p2p announcements are only logged.
+    pub async fn update_aliases(
+        &self,
+        update: impl FnOnce(&mut Vec<NodeId>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let _guard = self.identities_lock.lock().await;
+
+        let mut aliases = SessionRegistration::aliases(self).await?;
+        update(&mut aliases)?;
+
+        let (timestamp, sessions) = {
+            let state = self.state.lock();
+            let now = chrono::Utc::now().timestamp_millis() as u64;
+            let sessions = state.p2p_sessions.values().cloned().collect::<Vec<_>>();
+            (max(now, state.identities_ts + 1), sessions)
+        };
+        let (relays, peers): (Vec<_>, Vec<_>) = sessions
+            .into_iter()
+            .partition(|session| is_relay_id(&session.owner.default_id));
+
+        let mut crypto_vec = vec![self.config.crypto.get(self.config.node_id).await?];
+        for alias in &aliases {
+            crypto_vec.push(self.config.crypto.get(*alias).await?);
+        }
+        log::info!("Announcing identities change. aliases={aliases:?}");
+
+        // Aliases are changed only after relays accepted them, otherwise we would
+        // advertise identities, which other Nodes can't find.
+        let updates = relays.into_iter().map(|session| {
+            let crypto_vec = crypto_vec.clone();
+            async move {
+                let session_id = session.raw.id.to_vec();
+                let signatures =
+                    challenge::sign_identities(&session_id, timestamp, crypto_vec).await?;
+                session
+                    .raw
+                    .update_identities(timestamp, signatures)
+                    .await
+                    .map_err(anyhow::Error::from)
+            }
+        });
+        futures::future::try_join_all(updates).await?;
+
+        {
+            let mut state = self.state.lock();
+            state.aliases = Some(aliases);
+            state.identities_ts = timestamp;
+        }
+
+        let announcements = peers.into_iter().map(|session| {
+            let crypto_vec = crypto_vec.clone();
+            async move {
+                let session_id = session.raw.id.to_vec();
+                let result = async {
+                    let signatures =
+                        challenge::sign_identities(&session_id, timestamp, crypto_vec).await?;
+                    let message = proto::control::Identities {
+                        timestamp,
+                        signatures,
+                    };
+                    session
+                        .raw
+                        .send(proto::Packet::control(session_id, message))
+                        .await
+                        .map_err(anyhow::Error::from)
+                }
+                .await;
+                if let Err(e) = result {
+                    log::warn!(
+                        "Failed to announce identities to [{}] ({}): {e}",
+                        session.owner.default_id,
+                        session.raw.remote()
+                    );
+                }
+            }
+        });
+        futures::future::join_all(announcements).await;
+        Ok(())
+    }
+
+    pub async fn on_identities(
+        &self,
+        session_id: Vec<u8>,
+        from: SocketAddr,
+        message: proto::control::Identities,
+    ) -> anyhow::Result<()> {
+        let id = SessionId::try_from(session_id.clone())?;
+        let session = self
+            .find_session_by_id(&id)
+            .ok_or_else(|| anyhow!("Identities from {from} for unknown session {id}"))?;
+        let node_id = session.owner.default_id;
+        if is_relay_id(&node_id) {
+            bail!("Relay server can't change identities of session {id}");
+        }
+
+        let identities = challenge::recover_identities_from_signatures(
+            &session_id,
+            message.timestamp,
+            &message.signatures,
+            node_id,
+        )?;
+        if !session.raw.accept_identities(message.timestamp) {
+            log::debug!("Ignoring outdated Identities for session {id} from {from}");
+            return Ok(());
+        }
+
+        let node_ids = identities
+            .iter()
+            .map(|ident| ident.node_id)
+            .collect::<Vec<_>>();
+        self.registry.update_identities(node_id, identities).await?;
+
+        {
+            let mut state = self.state.lock();
+            let routing = state.nodes.get(&node_id).cloned();
+            state
+                .p2p_nodes
+                .retain(|id, other| !Arc::ptr_eq(other, &session) || node_ids.contains(id));
+            if let Some(routing) = &routing {
+                state
+                    .nodes
+                    .retain(|id, other| !Arc::ptr_eq(other, routing) || node_ids.contains(id));
+            }
+            for id in &node_ids {
+                state.p2p_nodes.insert(*id, session.clone());
+                if let Some(routing) = &routing {
+                    state.nodes.insert(*id, routing.clone());
+                }
+            }
+        }
+
+        log::info!("Node [{node_id}] changed identities to {node_ids:?}");
+        Ok(())
+    }
+
+    /// Moves p2p session to the new address of the other Node. The first request
+    /// from the new address gets a nonce, which has to come back signed by the
+    /// default identity of the session from the same address.
+    pub async fn on_rebind(
+        &self,
+        session_id: Vec<u8>,
+        request_id: RequestId,
+        from: SocketAddr,
+        request: proto::request::Rebind,
+    ) -> anyhow::Result<()> {
+        let (code, nonce) = match self.rebind_session(&session_id, from, request) {
+            Ok(nonce) => (proto::StatusCode::Ok, nonce),
+            Err(code) => (code, vec![]),
+        };
+        let packet = proto::Packet::response(
+            request_id,
+            session_id,
+            code,
+            proto::response::Rebind { nonce },
+        );
+        self.send(packet, from).await
+    }
+
+    fn rebind_session(
+        &self,
+        session_id: &[u8],
+        from: SocketAddr,
+        request: proto::request::Rebind,
+    ) -> Result<Vec<u8>, proto::StatusCode> {
+        let id =
+            SessionId::try_from(session_id.to_vec()).map_err(|_| proto::StatusCode::BadRequest)?;
+        let session = match self.find_session_by_id(&id) {
+            Some(session) if !is_relay_id(&session.owner.default_id) => session,
+            _ => {
+                log::debug!("Rebind from {from} for unknown session {id}");
+                return Err(proto::StatusCode::Unauthorized);
+            }
+        };
+
+        let previous = session.raw.remote();
+        if previous == from {
+            return Ok(vec![]);
+        }
+
+        if request.nonce.is_empty() {
+            return session.raw.rebind_nonce(from).ok_or_else(|| {
+                log::debug!("Too many pending rebinds of session {id}, rejecting {from}");
+                proto::StatusCode::TooManyRequests
+            });
+        }
+
+        let valid = match session.raw.take_rebind_nonce(from) {
+            Some(nonce) if nonce == request.nonce => {
+                challenge::recover_rebind_node_id(session_id, &request.nonce, &request.signature)
+                    .map(|node_id| node_id == session.owner.default_id)
+                    .unwrap_or(false)
+            }
+            _ => false,
+        };
+        if !valid {
+            log::debug!("Invalid rebind of session {id} to {from}");
+            return Err(proto::StatusCode::Unauthorized);
+        }
+
+        session.raw.migrate(from);
+        {
+            let mut state = self.state.lock();
+            if let Some(existing) = state.p2p_sessions.get(&previous) {
+                if Arc::ptr_eq(existing, &session) {
+                    state.p2p_sessions.remove(&previous);
+                }
+            }
+            state.p2p_sessions.insert(from, session.clone());
+        }
+
+        log::info!(
+            "Node [{}] moved session {id} from {previous} to {from}",
+            session.owner.default_id
+        );
+        Ok(vec![])
+    }
+
+    fn find_session_by_id(&self, id: &SessionId) -> Option<Arc<DirectSession>> {
+        let state = self.state.lock();
+        state
+            .p2p_sessions
+            .values()
+            .find(|session| session.raw.id == *id)
+            .cloned()
+    }
+
     async fn try_relayed_connection(
         &self,
         node_id: NodeId,
@@ -1004,8 +1587,7 @@ impl SessionLayer {
             .transition(SessionState::Relayed(RelayedState::Initializing))
             .await?;
 
-        // Currently only single server supported. In the future we could use multiple
-        // relays or use other p2p Nodes to forward traffic.
+        // In the future we could use other p2p Nodes to forward traffic.
         // We could even route traffic through many Nodes/Servers at the same time.
         let server = self
             .server_session()
@@ -1013,10 +1595,16 @@ impl SessionLayer {
             .map_err(|e| SessionError::Relay(e.to_string()))?;
 
         let server_id = server.owner.default_id;
-        let addr = server.raw.remote;
+        let addr = server.raw.remote();
 
-        // Information should be already cached in registry.
-        let node = permit.registry.info().await.just_get();
+        // Slots are assigned by each relay independently, so we can't use information
+        // cached in registry, which could come from different relay.
+        let node = server
+            .raw
+            .find_node(node_id)
+            .await
+            .and_then(NodeInfo::try_from)
+            .map_err(|e| SessionError::Relay(format!("Querying relay [{server_id}]: {e}")))?;
         let slot: SlotId = node.slot;
         let ids = permit
             .registry
@@ -1026,15 +1614,16 @@ impl SessionLayer {
 
         log::info!("Using relay server [{server_id}] ({addr}) to forward packets to [{node_id}] (slot {slot})");
 
+        let encryption = self
+            .negotiate_encryption(&ids.default_id, &node.supported_encryption)
+            .await
+            .map_err(|e| {
+                SessionError::Generic(format!("Relayed connection to [{node_id}]: {e}"))
+            })?;
+
         server.register(ids.clone().into(), slot);
 
-        let routing = NodeRouting::new(
-            ids.clone(),
-            server.clone(),
-            Encryption {
-                crypto: self.config.crypto.clone(),
-            },
-        );
+        let routing = NodeRouting::new(ids.clone(), server.clone(), encryption);
 
         self.register_routing(routing)
             .await
@@ -1047,6 +1636,31 @@ impl SessionLayer {
         Ok(server)
     }
 
+    /// Keys agreed with the Node earlier are kept, when only the route to it changes,
+    /// so packets in flight can still be decrypted.
+    async fn negotiate_encryption(
+        &self,
+        remote: &Identity,
+        remote_schemes: &[String],
+    ) -> Result<Encryption, EncryptionError> {
+        let encryption = Encryption::negotiate(
+            self.config.crypto.clone(),
+            self.config.node_id,
+            remote,
+            remote_schemes,
+            self.config.allow_plaintext,
+        )
+        .await?;
+
+        let current = { self.state.lock().nodes.get(&remote.node_id).cloned() };
+        Ok(match current {
+            Some(routing) if routing.encryption.same_secret(&encryption) => {
+                routing.encryption.clone()
+            }
+            _ => encryption,
+        })
+    }
+
     pub(crate) async fn await_connected(&self, node_id: NodeId) -> Result<(), SessionError> {
         log::trace!("[await_connected]: Session with Node [{node_id}] is registered.");
 
@@ -1244,6 +1858,7 @@ impl SessionLayer {
         };
 
         // Don't try to use `ReverseConnection`, when handling `ReverseConnection`.
+        // Hole punching isn't necessary, since other Node has public IP.
         // We don't want `Relayed` connection as well, because other Node can use it if he wants.
         permit
             .collect_results(
@@ -1251,7 +1866,11 @@ impl SessionLayer {
                     .run_abortable(self.resolve(
                         node_id,
                         &permit,
-                        &[ConnectionMethod::Reverse, ConnectionMethod::Relay],
+                        &[
+                            ConnectionMethod::Reverse,
+                            ConnectionMethod::HolePunch,
+                            ConnectionMethod::Relay,
+                        ],
                     ))
                     .await,
             )
@@ -1356,6 +1975,17 @@ impl Handler for SessionLayer {
                     });
                     return None;
                 }
+                ya_relay_proto::proto::control::Kind::HolePunch(message) => {
+                    let myself = self;
+                    tokio::task::spawn_local(async move {
+                        myself
+                            .on_hole_punch(from, message)
+                            .await
+                            .map_err(|e| log::warn!("Error handling `HolePunch`: {e}"))
+                            .ok();
+                    });
+                    return None;
+                }
                 ya_relay_proto::proto::control::Kind::PauseForwarding(_) => async move {
                     match self.find_session(from).await {
                         Some(session) => {
@@ -1383,6 +2013,17 @@ impl Handler for SessionLayer {
                     }
                 }
                 .boxed_local(),
+                ya_relay_proto::proto::control::Kind::Identities(message) => {
+                    let myself = self;
+                    async move {
+                        myself
+                            .on_identities(session_id, from, message)
+                            .await
+                            .map_err(|e| log::debug!("Handling `Identities`: {e}"))
+                            .ok();
+                    }
+                    .boxed_local()
+                }
                 ya_relay_proto::proto::control::Kind::Disconnected(
                     proto::control::Disconnected { by: Some(by) },
                 ) => {
@@ -1436,6 +2077,13 @@ impl Handler for SessionLayer {
                 async move { self.on_ping(session_id, request_id, from, request).await }
                     .boxed_local()
             }
+            proto::request::Kind::Rebind(request) => async move {
+                self.on_rebind(session_id, request_id, from, request)
+                    .await
+                    .map_err(|e| log::debug!("Handling `Rebind` request: {e}"))
+                    .ok();
+            }
+            .boxed_local(),
             proto::request::Kind::Session(request) => async move {
                 self.dispatch_session(session_id, request_id, from, request)
                     .await
@@ -1456,7 +2104,7 @@ impl Handler for SessionLayer {
         session: Option<Arc<DirectSession>>,
     ) -> Option<LocalBoxFuture<'static, ()>> {
         let reliable = forward.is_reliable();
-        let _encrypted = forward.is_encrypted();
+        let flags = forward.flags;
         let slot = forward.slot;
         let channel = self.ingress_channel.clone();
 
@@ -1470,6 +2118,11 @@ impl Handler for SessionLayer {
             );
 
             let session = match session {
+                None if myself.find_session_by_id(&SessionId::from(forward.session_id)).is_some() => {
+                    // Other Node changed address and didn't move the session with `Rebind` yet.
+                    log::debug!("Forward packet for known session from new address: {from}. Dropping.");
+                    return Ok(());
+                },
                 None => {
                     // In this case we can't establish session, because we don't have
                     // neither NodeId nor SlotId.
@@ -1492,7 +2145,7 @@ impl Handler for SessionLayer {
                             "Forwarding from unknown Node (slot {slot}) through session [{from}]. Resolving.."
                         );
 
-                        let session = myself.server_session().await?;
+                        // Slot must be resolved by the relay, that forwarded this packet.
                         let node = session.raw.find_slot(slot).await?;
                         let ident = Identity::try_from(&node)?;
 
@@ -1501,7 +2154,7 @@ impl Handler for SessionLayer {
                         log::debug!("Attempting to establish connection to Node {} (slot {})", ident.node_id, node.slot);
 
                         let session = myself
-                            .session_filtered_connection_methods(ident.node_id, vec![ConnectionMethod::Reverse, ConnectionMethod::Direct])
+                            .session_filtered_connection_methods(ident.node_id, vec![ConnectionMethod::Reverse, ConnectionMethod::Direct, ConnectionMethod::HolePunch])
                             .await.map_err(|e| anyhow!("Failed to resolve node with slot {slot}. {e}"))?;
 
                         session.target()
@@ -1509,9 +2162,37 @@ impl Handler for SessionLayer {
                 }
             };
 
-            // Decryption
-
             let size = forward.encoded_len();
+            let routing = { myself.state.lock().nodes.get(&sender).cloned() }
+                .ok_or_else(|| anyhow!("No routing for Node [{sender}]. Can't decrypt packet."))?;
+
+            if forward.is_key_exchange() {
+                let response = routing
+                    .encryption
+                    .on_key_exchange(forward.payload.as_ref())
+                    .map_err(|e| anyhow!("Key exchange with [{sender}]: {e}"))?;
+                if let Some(response) = response {
+                    routing.send_key_exchange(response).await?;
+                }
+                return Ok(());
+            }
+
+            let payload = match routing
+                .encryption
+                .decrypt(forward.payload, is_direct_message(slot), flags)
+                .await
+            {
+                Ok(payload) => payload,
+                Err(e) => {
+                    // The other Node could have lost the keys, for example after restart.
+                    if matches!(e, EncryptionError::NoKeys | EncryptionError::Cipher(CipherError::Authentication)) {
+                        if let Some(request) = routing.encryption.initiate_throttled() {
+                            routing.send_key_exchange(request).await.ok();
+                        }
+                    }
+                    bail!("Decrypting packet from [{sender}]: {e}");
+                }
+            };
             let transport = match reliable {
                 true => TransportType::Reliable,
                 false => TransportType::Unreliable,
@@ -1519,7 +2200,8 @@ impl Handler for SessionLayer {
             let packet = Forwarded {
                 transport,
                 node_id: sender,
-                payload: forward.payload,
+                payload,
+                channel: 0,
             };
 
             channel.tx.send(packet).map_err(|e| anyhow!("SessionLayer can't pass packet to other layers: {e}"))?;
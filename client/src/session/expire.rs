@@ -73,7 +73,7 @@ async fn close_sessions(
         log::info!(
             "Closing session {} ({}) not responding to ping.",
             session.raw.id,
-            session.raw.remote
+            session.raw.remote()
         );
 
         layer.close_session(session.clone()).await;
@@ -8,6 +8,7 @@ use backoff::Error::Transient;
 use backoff::{Error, ExponentialBackoff};
 use futures::future::err;
 use log::trace;
+use std::net::SocketAddr;
 use std::time::Duration;
 use ya_relay_core::NodeId;
 
@@ -29,12 +30,17 @@ impl ServerSessionAnchor {
         }
     }
 
-    async fn establish_server_session(&self, layer: &SessionLayer) {
+    async fn establish_server_session(
+        &self,
+        layer: &SessionLayer,
+        relay_id: NodeId,
+        addr: SocketAddr,
+    ) {
         let mut backoff_strategy = self.backoff_strategy.clone();
         backoff_strategy.reset();
 
         let mut establish_server_session_once = || async {
-            let server_session = layer.server_session().await;
+            let server_session = layer.relay_session(relay_id, addr).await;
             Ok(server_session?)
         };
 
@@ -46,9 +52,12 @@ impl ServerSessionAnchor {
             .await;
     }
 
-    async fn get_awaiting_notifier(&self, layer: &SessionLayer) -> Option<NodeAwaiting> {
-        let server_node_id = NodeId::default();
-        let entry = layer.registry.get_entry(server_node_id).await;
+    async fn get_awaiting_notifier(
+        &self,
+        layer: &SessionLayer,
+        relay_id: NodeId,
+    ) -> Option<NodeAwaiting> {
+        let entry = layer.registry.get_entry(relay_id).await;
         loop {
             if entry.is_some() {
                 return entry.as_ref().map(|entry| entry.awaiting_notifier());
@@ -59,14 +68,22 @@ impl ServerSessionAnchor {
     }
 }
 
-pub async fn keep_alive_server_session(layer: SessionLayer) {
+/// Keeps session with single relay server alive. Each relay from configuration
+/// has it's own task, so we can switch to other relay immediately, when one goes down.
+pub async fn keep_alive_server_session(layer: SessionLayer, relay_id: NodeId, addr: SocketAddr) {
     let mut awaiting_notifier: Option<NodeAwaiting> = None;
     let mut anchor = ServerSessionAnchor::new(layer.config.server_session_reconnect_max_interval);
 
+    // Backup relays aren't connected on `Client` startup, so we need to do this here.
+    // For the primary relay this returns already established session.
+    anchor
+        .establish_server_session(&layer, relay_id, addr)
+        .await;
+
     loop {
         // Get awaiting notifier for server session, this will poll with sleep if needed
         if awaiting_notifier.is_none() {
-            awaiting_notifier = anchor.get_awaiting_notifier(&layer).await;
+            awaiting_notifier = anchor.get_awaiting_notifier(&layer, relay_id).await;
         }
 
         //Once server session is established, then wait until it is closed or failed.
@@ -78,6 +95,8 @@ pub async fn keep_alive_server_session(layer: SessionLayer) {
 
         log::trace!("[keep-alive]: establishing server session");
         //Re-establish server session using retry policy with exponential backoff.
-        let server_session = anchor.establish_server_session(&layer).await;
+        let server_session = anchor
+            .establish_server_session(&layer, relay_id, addr)
+            .await;
     }
 }
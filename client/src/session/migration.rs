@@ -0,0 +1,37 @@
+use std::net::IpAddr;
+
+use crate::session::SessionLayer;
+
+/// Detects changes of local IP address used to reach relay server (for example after
+/// switching networks) and announces new address to p2p peers.
+pub async fn track_address_changes(layer: SessionLayer) {
+    let mut known: Option<IpAddr> = None;
+
+    loop {
+        let endpoints = {
+            let state = layer.state.lock();
+            let relay = layer
+                .config
+                .relays()
+                .nth(state.active_relay)
+                .map(|(_, addr)| addr);
+            state.bind_addr.zip(relay)
+        };
+
+        if let Some((bind, relay)) = endpoints {
+            if let Some(ip) = layer.config.udp_binder.route(bind, relay).await {
+                if let Some(previous) = known.filter(|previous| *previous != ip) {
+                    log::info!("Local address changed from {previous} to {ip}. Notifying peers.");
+                    layer
+                        .announce_address_change()
+                        .await
+                        .map_err(|e| log::warn!("Announcing address change failed: {e}"))
+                        .ok();
+                }
+                known = Some(ip);
+            }
+        }
+
+        tokio::time::sleep(layer.config.address_check_interval).await;
+    }
+}
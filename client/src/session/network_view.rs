@@ -4,13 +4,16 @@ use futures::future::{AbortHandle, Abortable};
 use std::collections::HashMap;
 use std::future::Future;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::{broadcast, RwLock};
 
 use super::session_state::{InitState, ReverseState, SessionState};
+use crate::config::is_relay_id;
 use crate::direct_session::{DirectSession, NodeEntry};
 use crate::error::{SessionError, TransitionError};
+use crate::events::{ClientEvent, EventSender};
+use crate::raw_session::SessionType;
 use crate::session::session_traits::SessionDeregistration;
 
 use crate::session::session_state::SessionState::Closed;
@@ -34,6 +37,7 @@ pub struct NetworkViewConfig {
 pub struct NetworkView {
     config: Arc<NetworkViewConfig>,
     state: Arc<RwLock<NetworkViewState>>,
+    events: EventSender,
 }
 
 /// TODO: We never remove entries from State. In general we should keep entries
@@ -50,9 +54,14 @@ impl NetworkView {
         NetworkView {
             config,
             state: Arc::new(Default::default()),
+            events: Default::default(),
         }
     }
 
+    pub(crate) fn events(&self) -> &EventSender {
+        &self.events
+    }
+
     /// Returns `SessionGuard` for other Node. Operation is atomic,
     /// you should get the same object in all places in the code.
     /// `SessionGuard` should be stored even if connection was closed,
@@ -63,7 +72,12 @@ impl NetworkView {
             return target;
         }
 
-        let target = NodeView::new(node_id, addrs.to_vec(), self.config.clone());
+        let target = NodeView::new(
+            node_id,
+            addrs.to_vec(),
+            self.config.clone(),
+            self.events.clone(),
+        );
 
         state.by_node_id.insert(target.id, target.clone());
         for addr in addrs.iter() {
@@ -77,7 +91,7 @@ impl NetworkView {
         log::trace!("[remove_node]: node_id {}", node_id);
         let mut state = self.state.write().await;
         if let Some(target) = state.find(node_id, &[]) {
-            if node_id != NodeId::default() {
+            if !is_relay_id(&node_id) {
                 state.by_node_id.remove(&node_id).is_some();
                 state.by_addr.retain(|_, node_view| node_view.id != node_id);
             }
@@ -121,7 +135,12 @@ impl NetworkView {
             }
             entries[0].clone()
         } else {
-            NodeView::new(info.default_node_id(), addrs.clone(), self.config.clone())
+            NodeView::new(
+                info.default_node_id(),
+                addrs.clone(),
+                self.config.clone(),
+                self.events.clone(),
+            )
         };
 
         for id in &info.identities {
@@ -136,6 +155,32 @@ impl NetworkView {
         Ok(())
     }
 
+    /// Replaces identities of the Node, after it announced their change.
+    /// Removed aliases stop pointing to the Node's `NodeView`.
+    pub async fn update_identities(
+        &self,
+        node_id: NodeId,
+        identities: Vec<Identity>,
+    ) -> anyhow::Result<()> {
+        let mut state = self.state.write().await;
+        let entry = match state.find(node_id, &[]) {
+            Some(entry) => entry,
+            None => bail!("No `NodeView` for [{node_id}]"),
+        };
+
+        state.by_node_id.retain(|id, view| {
+            !Arc::ptr_eq(&view.state, &entry.state)
+                || identities.iter().any(|ident| ident.node_id == *id)
+        });
+        for ident in &identities {
+            state.by_node_id.insert(ident.node_id, entry.clone());
+        }
+        drop(state);
+
+        entry.state.write().await.node = identities;
+        Ok(())
+    }
+
     pub async fn get_entry(&self, node_id: NodeId) -> Option<NodeView> {
         let state = self.state.read().await;
         state.find(node_id, &[])
@@ -229,6 +274,7 @@ pub struct NodeView {
 
     state: Arc<RwLock<NodeViewState>>,
     state_notifier: Arc<broadcast::Sender<SessionState>>,
+    events: EventSender,
 
     /// Last update of information about Node.
     last_info_update: LastSeen,
@@ -271,12 +317,14 @@ impl NodeView {
         &self,
         new_state: SessionState,
     ) -> Result<SessionState, TransitionError> {
-        {
+        let event = {
             let mut target = self.state.write().await;
+            let prev = target.state.clone();
             target.state.transition(new_state.clone())?;
-        }
+            target.event(self.id, &prev)
+        };
 
-        self.notify_change(new_state.clone());
+        self.notify_change(new_state.clone(), event);
         Ok(new_state)
     }
 
@@ -284,12 +332,14 @@ impl NodeView {
         &self,
         new_state: InitState,
     ) -> Result<SessionState, TransitionError> {
-        let new_state = {
+        let (new_state, event) = {
             let mut target = self.state.write().await;
-            target.state.transition_incoming(new_state.clone())?
+            let prev = target.state.clone();
+            let new_state = target.state.transition_incoming(new_state.clone())?;
+            (new_state, target.event(self.id, &prev))
         };
 
-        self.notify_change(new_state.clone());
+        self.notify_change(new_state.clone(), event);
         Ok(new_state)
     }
 
@@ -297,12 +347,14 @@ impl NodeView {
         &self,
         new_state: InitState,
     ) -> Result<SessionState, TransitionError> {
-        let new_state = {
+        let (new_state, event) = {
             let mut target = self.state.write().await;
-            target.state.transition_outgoing(new_state.clone())?
+            let prev = target.state.clone();
+            let new_state = target.state.transition_outgoing(new_state.clone())?;
+            (new_state, target.event(self.id, &prev))
         };
 
-        self.notify_change(new_state.clone());
+        self.notify_change(new_state.clone(), event);
         Ok(new_state)
     }
 
@@ -364,7 +416,12 @@ impl NodeView {
         // TODO: We should distinguish between public IPs and addresses assigned temporarily
         //       by routers. `NetworkView` contains addresses from which we received packets.
         //       Here we assign only public, but earlier (`NetworkView::guard`) we added mapped addresses
-        state.addresses = info.endpoints.into_iter().map(|e| e.address).collect();
+        state.addresses = info
+            .endpoints
+            .into_iter()
+            .filter(|e| e.protocol == proto::Protocol::Udp)
+            .map(|e| e.address)
+            .collect();
         Ok(())
     }
 
@@ -381,13 +438,17 @@ impl NodeView {
         })
     }
 
-    fn notify_change(&self, new_state: SessionState) {
+    fn notify_change(&self, new_state: SessionState, event: Option<ClientEvent>) {
         log::trace!(
             "State changed to {} for session with [{}]",
             new_state,
             self.id
         );
 
+        if let Some(event) = event {
+            self.events.send(event);
+        }
+
         self.state_notifier
             .send(new_state)
             .map_err(|_| log::trace!("Notifying state change for [{}]: No listeners", self.id))
@@ -432,10 +493,61 @@ impl NodeViewState {
             supported_encryption: self.supported_encryption.clone(),
         }
     }
+
+    /// Event describing transition from `prev` to the current state.
+    fn event(&self, node_id: NodeId, prev: &SessionState) -> Option<ClientEvent> {
+        let relay = is_relay_id(&node_id);
+        match (prev, &self.state) {
+            (SessionState::Established(prev), SessionState::Established(session)) if !relay => {
+                let next = route(node_id, session)?;
+                match route(node_id, prev) {
+                    Some(prev) if prev == next => None,
+                    _ => Some(ClientEvent::RouteChanged {
+                        node_id,
+                        route: next,
+                    }),
+                }
+            }
+            (SessionState::Established(_), SessionState::Established(_)) => None,
+            (_, SessionState::Established(session)) => match relay {
+                true => Some(ClientEvent::RelayConnected {
+                    addr: session.upgrade()?.raw.remote(),
+                }),
+                false => Some(ClientEvent::NodeReachable {
+                    node_id,
+                    route: route(node_id, session)?,
+                }),
+            },
+            (SessionState::Established(session), _) => match relay {
+                true => Some(ClientEvent::RelayDisconnected {
+                    addr: session
+                        .upgrade()
+                        .map(|session| session.raw.remote())
+                        .or_else(|| self.addresses.first().copied())?,
+                }),
+                false => Some(ClientEvent::NodeUnreachable { node_id }),
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Established session is either p2p session with the Node or session with relay server.
+fn route(node_id: NodeId, session: &Weak<DirectSession>) -> Option<SessionType> {
+    let session = session.upgrade()?;
+    Some(match session.owner.identities.contains(&node_id) {
+        true => SessionType::P2P,
+        false => SessionType::Relay,
+    })
 }
 
 impl NodeView {
-    fn new(node_id: NodeId, addresses: Vec<SocketAddr>, config: Arc<NetworkViewConfig>) -> Self {
+    fn new(
+        node_id: NodeId,
+        addresses: Vec<SocketAddr>,
+        config: Arc<NetworkViewConfig>,
+        events: EventSender,
+    ) -> Self {
         let (notify_msg, _) = broadcast::channel(10);
 
         Self {
@@ -450,6 +562,7 @@ impl NodeView {
                 abort_handle: vec![],
             })),
             state_notifier: Arc::new(notify_msg),
+            events,
             config,
         }
     }
@@ -1158,4 +1271,51 @@ mod tests {
             }
         }
     }
+
+    #[actix_rt::test]
+    async fn test_network_view_route_events() {
+        use futures::StreamExt;
+
+        let view = NetworkView::default();
+        let mut events = Box::pin(view.events().subscribe());
+        let node = view.guard(*NODE_ID2, &[*ADDR2]).await;
+        let node_id = *NODE_ID2;
+
+        // Session with relay server is used to forward packets to the Node.
+        let relay = mock_session_from_id_addr(*NODE_ID1, *ADDR1).await;
+        for state in [
+            SessionState::Outgoing(InitState::ConnectIntent),
+            SessionState::Relayed(RelayedState::Initializing),
+            SessionState::Relayed(RelayedState::Ready),
+            SessionState::Established(Arc::downgrade(&relay)),
+        ] {
+            node.transition(state).await.unwrap();
+        }
+        assert_eq!(
+            events.next().await,
+            Some(ClientEvent::NodeReachable {
+                node_id,
+                route: SessionType::Relay
+            })
+        );
+
+        let p2p = mock_session_from_id_addr(*NODE_ID2, *ADDR2).await;
+        node.transition(SessionState::Established(Arc::downgrade(&p2p)))
+            .await
+            .unwrap();
+        assert_eq!(
+            events.next().await,
+            Some(ClientEvent::RouteChanged {
+                node_id,
+                route: SessionType::P2P
+            })
+        );
+
+        node.transition(SessionState::Closing).await.unwrap();
+        node.transition(SessionState::Closed).await.unwrap();
+        assert_eq!(
+            events.next().await,
+            Some(ClientEvent::NodeUnreachable { node_id })
+        );
+    }
 }
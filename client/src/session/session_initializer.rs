@@ -20,6 +20,7 @@ use ya_relay_proto::proto::RequestId;
 use super::network_view::SessionPermit;
 use crate::client::ClientConfig;
 use crate::direct_session::DirectSession;
+use crate::encryption::Encryption;
 use crate::error::{ProtocolError, RequestError, SessionError, SessionInitError, SessionResult};
 use crate::raw_session::RawSession;
 use crate::session::session_state::InitState;
@@ -149,6 +150,11 @@ impl SessionInitializer {
                     .await
                     .map_err(|e| SessionError::Internal(e.to_string()))?,
             ),
+            supported_encryptions: Encryption::supported_schemes(
+                self.config.crypto.clone(),
+                self.config.node_id,
+            )
+            .await,
             ..Default::default()
         };
 
@@ -185,7 +191,8 @@ impl SessionInitializer {
                     Some(node_id),
                 )
             } else {
-                Ok(Default::default())
+                // Relay servers don't have identities.
+                Ok((node_id, vec![]))
             }
         } {
             Ok(tuple) => tuple,
@@ -211,7 +218,13 @@ impl SessionInitializer {
         // after we send ResumeForwarding. That's why we register session before.
         let session = self
             .layer
-            .register_session(addr, session_id, remote_id, identities)
+            .register_session(
+                addr,
+                session_id,
+                remote_id,
+                identities,
+                response.packet.supported_encryptions,
+            )
             .await
             .map_err(|e| {
                 SessionError::Internal(format!("Failed to register session. Error: {e}"))
@@ -459,6 +472,11 @@ impl SessionInitializer {
 
             let packet = proto::response::Session {
                 challenge_resp: Some(challenge),
+                supported_encryptions: Encryption::supported_schemes(
+                    self.config.crypto.clone(),
+                    self.config.node_id,
+                )
+                .await,
                 ..Default::default()
             };
 
@@ -467,7 +485,13 @@ impl SessionInitializer {
             // to immediately send us Forward packet.
             let session = self
                 .layer
-                .register_session(with, session_id, node_id, identities)
+                .register_session(
+                    with,
+                    session_id,
+                    node_id,
+                    identities,
+                    session.supported_encryptions,
+                )
                 .await
                 .map_err(|e| {
                     SessionError::Internal(format!("Failed to register session. Error: {e}"))
@@ -596,6 +620,8 @@ impl SessionInitializer {
         }
 
         request.identities = identities;
+        request.supported_encryptions =
+            Encryption::supported_schemes(self.config.crypto.clone(), self.config.node_id).await;
 
         if !challenge {
             request.challenge_req = None;
@@ -609,7 +635,7 @@ impl SessionInitializer {
             Ok(crypto) => vec![crypto],
             Err(e) => bail!(e),
         };
-        let aliases = match self.config.crypto.aliases().await {
+        let aliases = match self.layer.aliases().await {
             Ok(aliases) => aliases,
             Err(e) => bail!(e),
         };
@@ -20,9 +20,13 @@ pub(crate) trait SessionRegistration: 'static {
         id: SessionId,
         node_id: NodeId,
         identities: Vec<Identity>,
+        supported_encryptions: Vec<String>,
     ) -> anyhow::Result<Arc<DirectSession>>;
 
     async fn register_routing(&self, routing: Arc<NodeRouting>) -> anyhow::Result<()>;
+
+    /// Secondary identities announced in new sessions.
+    async fn aliases(&self) -> anyhow::Result<Vec<NodeId>>;
 }
 
 /// Trait for decoupling `SessionPermit` from `SessionLayer`.
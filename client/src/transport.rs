@@ -1,29 +1,36 @@
+mod datagram;
+mod ingress;
+mod stream;
 pub(crate) mod tcp_registry;
 pub mod transport_sender;
+#[cfg(all(feature = "tun", target_os = "linux"))]
+mod tun;
 mod virtual_layer;
 
 use anyhow::bail;
 use futures::StreamExt;
 use parking_lot::Mutex;
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{Ipv6Addr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::sync::RwLock;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
 use ya_relay_core::server_session::TransportType;
 use ya_relay_core::NodeId;
-use ya_relay_stack::Channel;
 
+use self::ingress::IngressChannel;
 use self::tcp_registry::ChannelType;
 use self::virtual_layer::TcpLayer;
 use crate::client::{ClientConfig, ForwardSender, Forwarded, GenericSender};
+use crate::metrics::ChannelMetrics;
 use crate::session::SessionLayer;
 
-/// TODO: Consider using bounded channel. Tcp could have impression that we are receiving
-///       messages, despite we are only putting them into channel.
-pub type ForwardReceiver = tokio::sync::mpsc::UnboundedReceiver<Forwarded>;
+pub use self::datagram::{Datagram, VirtualUdpSocket};
+pub use self::ingress::{DropPolicy, ForwardReceiver};
+pub use self::stream::{VirtualListener, VirtualStream};
 
 /// Responsible for sending data. Handles different kinds of transport types:
 /// - Unreliable [`TransportLayer::forward_unreliable`] - send raw packets without any delivery
@@ -33,6 +40,8 @@ pub type ForwardReceiver = tokio::sync::mpsc::UnboundedReceiver<Forwarded>;
 /// - Transfer [`TransportLayer::forward_transfer`] - uses the same transport as reliable protocol,
 ///   but should be used for heavier transfers. Packets are sent using separate channel, what helps
 ///   with avoiding blocking more important messages in sending queue.
+/// - Channel [`TransportLayer::forward_channel`] - reliable transport over numbered channel
+///   configured by user. Each channel has its own priority.
 #[derive(Clone)]
 pub struct TransportLayer {
     pub config: Arc<ClientConfig>,
@@ -42,21 +51,21 @@ pub struct TransportLayer {
 
     state: Arc<Mutex<TransportLayerState>>,
 
-    /// Shared channel with TcpLayer for sending processed packets to external layers.
-    ingress_channel: Channel<Forwarded>,
+    /// Shared bounded queue with TcpLayer for sending processed packets to external layers.
+    ingress_channel: IngressChannel,
 }
 
 #[derive(Default)]
 struct TransportLayerState {
     /// Every default and secondary NodeId has separate entry here.
     forward_unreliable: HashMap<NodeId, ForwardSender>,
-    forward_transfer: HashMap<NodeId, ForwardSender>,
-    forward_reliable: HashMap<NodeId, ForwardSender>,
+    /// Senders for virtual TCP channels, including reliable and transfer ones.
+    forward_channels: HashMap<(NodeId, ChannelType), ForwardSender>,
 }
 
 impl TransportLayer {
     pub fn new(config: Arc<ClientConfig>) -> TransportLayer {
-        let out = Channel::<Forwarded>::default();
+        let out = IngressChannel::new(config.ingress_queue_size, config.unreliable_drop_policy);
         let session_layer = SessionLayer::new(config.clone());
         let virtual_tcp = TcpLayer::new(
             &config.node_pub_key,
@@ -88,17 +97,15 @@ impl TransportLayer {
         let channels = {
             let mut state = self.state.lock();
 
-            let unreliable = state.forward_unreliable.drain().collect::<Vec<_>>();
-            let transfer = state.forward_transfer.drain().collect::<Vec<_>>();
-            let reliable = state.forward_reliable.drain().collect::<Vec<_>>();
+            let unreliable = std::mem::take(&mut state.forward_unreliable);
+            let channels = std::mem::take(&mut state.forward_channels);
 
             unreliable
-                .into_iter()
-                .chain(transfer.into_iter())
-                .chain(reliable.into_iter())
+                .into_values()
+                .chain(channels.into_values())
                 .collect::<Vec<_>>()
         };
-        for (_, mut channel) in channels {
+        for mut channel in channels {
             channel.disconnect().await.ok();
         }
 
@@ -138,6 +145,11 @@ impl TransportLayer {
         self.ingress_channel.tx.send(forward).ok();
     }
 
+    /// Adds counters of packets dropped from the ingress queue to `metrics`.
+    pub fn add_dropped_metrics(&self, node_id: Option<NodeId>, metrics: &mut ChannelMetrics) {
+        self.ingress_channel.tx.add_dropped(node_id, metrics)
+    }
+
     async fn spawn_ingress_handler(&self) -> anyhow::Result<()> {
         let ingress_rx = self
             .session_layer
@@ -148,7 +160,7 @@ impl TransportLayer {
         Ok(())
     }
 
-    async fn ingress_handler(self, ingress_rx: ForwardReceiver) {
+    async fn ingress_handler(self, ingress_rx: UnboundedReceiver<Forwarded>) {
         UnboundedReceiverStream::new(ingress_rx)
             .for_each(move |forwarded| {
                 let myself = self.clone();
@@ -159,35 +171,35 @@ impl TransportLayer {
             .await
     }
 
-    fn get_forward_channel(
-        &self,
-        node_id: NodeId,
-        channel: TransportType,
-    ) -> Option<ForwardSender> {
+    fn get_forward_channel(&self, node_id: NodeId, channel: ChannelType) -> Option<ForwardSender> {
         let state = self.state.lock();
-        match channel {
-            TransportType::Reliable => state.forward_reliable.get(&node_id).cloned(),
-            TransportType::Transfer => state.forward_transfer.get(&node_id).cloned(),
-            TransportType::Unreliable => state.forward_unreliable.get(&node_id).cloned(),
-        }
+        state.forward_channels.get(&(node_id, channel)).cloned()
     }
 
-    fn set_forward_channel(&self, node_id: NodeId, channel: TransportType, tx: ForwardSender) {
+    fn set_forward_channel(&self, node_id: NodeId, channel: ChannelType, tx: ForwardSender) {
         let mut state = self.state.lock();
-        match channel {
-            TransportType::Reliable => state.forward_reliable.insert(node_id, tx),
-            TransportType::Transfer => state.forward_transfer.insert(node_id, tx),
-            TransportType::Unreliable => state.forward_unreliable.insert(node_id, tx),
-        };
+        state.forward_channels.insert((node_id, channel), tx);
     }
 
     pub async fn forward_reliable(&self, node_id: NodeId) -> anyhow::Result<ForwardSender> {
-        self.forward_virtual_tcp(node_id, TransportType::Reliable)
+        self.forward_virtual_tcp(node_id, ChannelType::MESSAGES)
             .await
     }
 
     pub async fn forward_transfer(&self, node_id: NodeId) -> anyhow::Result<ForwardSender> {
-        self.forward_virtual_tcp(node_id, TransportType::Transfer)
+        self.forward_virtual_tcp(node_id, ChannelType::TRANSFER)
+            .await
+    }
+
+    pub async fn forward_channel(
+        &self,
+        node_id: NodeId,
+        channel_id: u16,
+    ) -> anyhow::Result<ForwardSender> {
+        if !self.config.channels.contains_key(&channel_id) {
+            bail!("Channel {channel_id} is not configured");
+        }
+        self.forward_virtual_tcp(node_id, ChannelType(channel_id))
             .await
     }
 
@@ -196,7 +208,7 @@ impl TransportLayer {
     pub async fn forward_virtual_tcp(
         &self,
         node_id: NodeId,
-        channel: TransportType,
+        channel: ChannelType,
     ) -> anyhow::Result<ForwardSender> {
         match self.get_forward_channel(node_id, channel) {
             // If connection was closed in the meantime, it will be initialized on demand.
@@ -208,7 +220,8 @@ impl TransportLayer {
             Some(tx) => Ok(tx),
             None => {
                 // Check if this isn't secondary identity. TcpLayer should always get default id.
-                // TODO: Consider how to handle changing identities.
+                // Identities changed at runtime are announced in `Identities` message,
+                // which updates `NodeView` used here.
                 // TODO: Maybe we should call `self.session_layer::session` and pass it to `connect`.
                 let info = self.session_layer.query_node_info(node_id).await?;
 
@@ -217,15 +230,9 @@ impl TransportLayer {
                     return Ok(tx);
                 }
 
-                let channel_port = match channel {
-                    TransportType::Reliable => ChannelType::Messages,
-                    TransportType::Transfer => ChannelType::Transfer,
-                    _ => bail!("Programming error: `forward_generic` shouldn't been used for unreliable connection.")
-                };
-
                 let sender: ForwardSender = self
                     .virtual_tcp
-                    .connect(info.default_node_id(), channel_port)
+                    .connect(info.default_node_id(), channel)
                     .await?
                     .into();
 
@@ -235,6 +242,35 @@ impl TransportLayer {
         }
     }
 
+    /// NodeId can be either default or secondary.
+    pub async fn connect_stream(
+        &self,
+        node_id: NodeId,
+        port: u16,
+    ) -> anyhow::Result<VirtualStream> {
+        let info = self.session_layer.query_node_info(node_id).await?;
+        self.virtual_tcp
+            .connect_stream(info.default_node_id(), port)
+            .await
+    }
+
+    pub fn listen(&self, port: u16) -> anyhow::Result<VirtualListener> {
+        self.virtual_tcp.listen(port)
+    }
+
+    pub fn bind_udp(&self, port: u16) -> anyhow::Result<VirtualUdpSocket> {
+        self.virtual_tcp.bind_udp(port)
+    }
+
+    /// NodeId can be either default or secondary.
+    pub async fn virtual_address(&self, node_id: NodeId) -> anyhow::Result<Ipv6Addr> {
+        let info = self.session_layer.query_node_info(node_id).await?;
+        Ok(self
+            .virtual_tcp
+            .virtual_address(info.default_node_id())
+            .await)
+    }
+
     /// NodeId can be either default or secondary.
     /// TODO: Make this function resistant to dropping future
     pub async fn forward_unreliable(&self, node_id: NodeId) -> anyhow::Result<ForwardSender> {
@@ -242,7 +278,7 @@ impl TransportLayer {
         // These lines are not necessary, because code below would do the job,
         // but this way we avoid querying write lock and asking session layer for `RoutingSender`
         // on every attempt to send message.
-        if let Some(tx) = self.get_forward_channel(node_id, TransportType::Unreliable) {
+        if let Some(tx) = { self.state.lock().forward_unreliable.get(&node_id).cloned() } {
             return Ok(tx);
         }
 
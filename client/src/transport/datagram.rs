@@ -0,0 +1,104 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use tokio::sync::mpsc;
+
+use ya_relay_core::NodeId;
+use ya_relay_stack::smoltcp::iface::SocketHandle;
+
+use super::virtual_layer::TcpLayer;
+
+/// Number of datagrams waiting for `VirtualUdpSocket::recv_from`, before next ones are dropped.
+const DATAGRAM_QUEUE_SIZE: usize = 256;
+
+/// Datagram received from port of other Node.
+pub type Datagram = (Vec<u8>, NodeId, u16);
+
+/// Virtual UDP sockets bound to ports of the embedded network stack.
+#[derive(Clone, Default)]
+pub(crate) struct Datagrams {
+    sockets: Rc<RefCell<HashMap<u16, mpsc::Sender<Datagram>>>>,
+}
+
+impl Datagrams {
+    pub fn is_bound(&self, port: u16) -> bool {
+        self.sockets.borrow().contains_key(&port)
+    }
+
+    pub fn add_socket(&self, port: u16) -> mpsc::Receiver<Datagram> {
+        let (tx, rx) = mpsc::channel(DATAGRAM_QUEUE_SIZE);
+        self.sockets.borrow_mut().insert(port, tx);
+        rx
+    }
+
+    pub fn remove_socket(&self, port: u16) {
+        self.sockets.borrow_mut().remove(&port);
+    }
+
+    /// Passes datagram to the socket bound on `port`. Datagrams are dropped, if
+    /// nobody listens on the port or the socket doesn't keep up with reading.
+    pub fn receive(&self, port: u16, datagram: Datagram) {
+        match self.sockets.borrow().get(&port) {
+            Some(tx) => {
+                if tx.try_send(datagram).is_err() {
+                    log::trace!("[VirtualUdp] Dropping datagram on port {port}: queue full");
+                }
+            }
+            None => log::trace!("[VirtualUdp] Dropping datagram: port {port} not bound"),
+        }
+    }
+
+    /// Closes all sockets.
+    pub fn close(&self) {
+        self.sockets.borrow_mut().clear();
+    }
+}
+
+/// Virtual UDP socket bound to a port. Datagrams are addressed by `NodeId` and port
+/// of the other Node, so several datagram services can share single `Client`.
+/// The port is released, when `VirtualUdpSocket` is dropped.
+pub struct VirtualUdpSocket {
+    port: u16,
+    handle: SocketHandle,
+    rx: mpsc::Receiver<Datagram>,
+    layer: TcpLayer,
+}
+
+impl VirtualUdpSocket {
+    pub(crate) fn new(
+        port: u16,
+        handle: SocketHandle,
+        rx: mpsc::Receiver<Datagram>,
+        layer: TcpLayer,
+    ) -> VirtualUdpSocket {
+        VirtualUdpSocket {
+            port,
+            handle,
+            rx,
+            layer,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Sends datagram to port of other Node. NodeId can be either default or secondary.
+    /// Delivery isn't guaranteed.
+    pub async fn send_to(&self, data: &[u8], node_id: NodeId, port: u16) -> anyhow::Result<()> {
+        self.layer
+            .send_datagram(self.handle, self.port, data.to_vec(), node_id, port)
+            .await
+    }
+
+    /// Waits for next datagram. Returns `None`, when `Client` was shutdown.
+    pub async fn recv_from(&mut self) -> Option<Datagram> {
+        self.rx.recv().await
+    }
+}
+
+impl Drop for VirtualUdpSocket {
+    fn drop(&mut self) {
+        self.layer.unbind_udp(self.port);
+    }
+}
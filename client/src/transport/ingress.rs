@@ -0,0 +1,418 @@
+use futures::task::AtomicWaker;
+use futures::Stream;
+use metrics::increment_counter;
+use parking_lot::Mutex;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::Notify;
+
+use ya_relay_core::server_session::TransportType;
+use ya_relay_core::NodeId;
+
+use crate::client::Forwarded;
+use crate::metrics::{ChannelMetrics, SOURCE_ID};
+
+/// Decides which unreliable packet is discarded, when ingress queue is full.
+/// Reliable and transfer packets are never dropped, instead virtual TCP stops reading
+/// from sockets until the queue has free space.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Discard the oldest queued unreliable packet to make space for the new one.
+    #[default]
+    DropOldest,
+    /// Discard the incoming packet.
+    DropNewest,
+}
+
+/// Receiving end of the bounded ingress queue, returned by `Client::forward_receiver`.
+///
+/// Replaced `tokio::sync::mpsc::UnboundedReceiver<Forwarded>` in 0.7.0. `recv`, `try_recv`
+/// and `poll_recv` keep their signatures, except `try_recv` returns `Option`.
+pub struct ForwardReceiver {
+    shared: Arc<Shared>,
+}
+
+/// Sending end of the bounded ingress queue, shared by `TransportLayer` and `TcpLayer`.
+pub(crate) struct IngressSender {
+    shared: Arc<Shared>,
+}
+
+/// Mirrors `ya_relay_stack::Channel`: receiver can be taken only once.
+#[derive(Clone)]
+pub(crate) struct IngressChannel {
+    pub tx: IngressSender,
+    rx: Rc<RefCell<Option<ForwardReceiver>>>,
+}
+
+struct Shared {
+    capacity: usize,
+    policy: DropPolicy,
+    state: Mutex<QueueState>,
+    senders: AtomicUsize,
+    receiver_closed: AtomicBool,
+    recv_waker: AtomicWaker,
+    space: Notify,
+}
+
+#[derive(Default)]
+struct QueueState {
+    items: VecDeque<Forwarded>,
+    /// Number of queued packets per Node.
+    queued: HashMap<NodeId, usize>,
+    closed: bool,
+    dropped: HashMap<NodeId, Dropped>,
+}
+
+impl QueueState {
+    fn push(&mut self, packet: Forwarded) {
+        *self.queued.entry(packet.node_id).or_default() += 1;
+        self.items.push_back(packet);
+    }
+
+    fn remove(&mut self, idx: usize) -> Option<Forwarded> {
+        let packet = self.items.remove(idx)?;
+        if let Some(count) = self.queued.get_mut(&packet.node_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.queued.remove(&packet.node_id);
+            }
+        }
+        Some(packet)
+    }
+
+    fn clear(&mut self) {
+        self.items.clear();
+        self.queued.clear();
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Dropped {
+    packets: u64,
+    bytes: u64,
+}
+
+impl IngressChannel {
+    pub fn new(capacity: usize, policy: DropPolicy) -> Self {
+        let shared = Arc::new(Shared {
+            capacity: capacity.max(1),
+            policy,
+            state: Default::default(),
+            senders: AtomicUsize::new(1),
+            receiver_closed: Default::default(),
+            recv_waker: Default::default(),
+            space: Notify::new(),
+        });
+
+        IngressChannel {
+            tx: IngressSender {
+                shared: shared.clone(),
+            },
+            rx: Rc::new(RefCell::new(Some(ForwardReceiver { shared }))),
+        }
+    }
+
+    pub fn receiver(&self) -> Option<ForwardReceiver> {
+        self.rx.borrow_mut().take()
+    }
+}
+
+impl IngressSender {
+    /// Queues packet. Unreliable packets are subject to `DropPolicy` when the queue is full,
+    /// other packets are always accepted and the caller should stop producing
+    /// them, when `is_full` returns true.
+    /// Returns packet back, if receiver was dropped.
+    pub fn send(&self, packet: Forwarded) -> Result<(), Forwarded> {
+        if self.shared.receiver_closed.load(Ordering::Relaxed) {
+            return Err(packet);
+        }
+
+        let dropped = {
+            let mut state = self.shared.state.lock();
+            let dropped = match packet.transport {
+                TransportType::Unreliable if state.items.len() >= self.shared.capacity => {
+                    match self.shared.policy {
+                        DropPolicy::DropNewest => Some(packet),
+                        DropPolicy::DropOldest => {
+                            let oldest = state
+                                .items
+                                .iter()
+                                .position(|p| p.transport == TransportType::Unreliable);
+                            match oldest.and_then(|idx| state.remove(idx)) {
+                                Some(oldest) => {
+                                    state.push(packet);
+                                    Some(oldest)
+                                }
+                                // Queue is filled with reliable packets only.
+                                None => Some(packet),
+                            }
+                        }
+                    }
+                }
+                _ => {
+                    state.push(packet);
+                    None
+                }
+            };
+
+            if let Some(packet) = &dropped {
+                let counter = state.dropped.entry(packet.node_id).or_default();
+                counter.packets += 1;
+                counter.bytes += packet.payload.len() as u64;
+            }
+            dropped
+        };
+
+        match dropped {
+            Some(packet) => {
+                log::trace!(
+                    "Ingress queue full, dropped unreliable packet from [{}]",
+                    packet.node_id
+                );
+                increment_counter!("ya-relay.packet.udp.incoming.dropped", SOURCE_ID => packet.node_id.to_string());
+            }
+            None => self.shared.recv_waker.wake(),
+        }
+        Ok(())
+    }
+
+    /// Queue is full and the Node occupies at least its fair share of it, so reading
+    /// from the Node should be paused. Nodes sending less than others aren't slowed down
+    /// by the ones which filled the queue.
+    pub fn is_full(&self, node_id: NodeId) -> bool {
+        if self.shared.receiver_closed.load(Ordering::Relaxed) {
+            return false;
+        }
+        let state = self.shared.state.lock();
+        let queued = state.queued.get(&node_id).copied().unwrap_or_default();
+        state.items.len() >= self.shared.capacity
+            && queued > 0
+            && queued >= self.shared.capacity / state.queued.len()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.receiver_closed.load(Ordering::Relaxed)
+    }
+
+    /// Resolves after receiver consumed a packet from a full queue, or was dropped.
+    pub async fn space_available(&self) {
+        self.shared.space.notified().await
+    }
+
+    /// Adds counters of dropped packets to `metrics`. Drops from all Nodes are counted,
+    /// if `node_id` is not specified.
+    pub fn add_dropped(&self, node_id: Option<NodeId>, metrics: &mut ChannelMetrics) {
+        let state = self.shared.state.lock();
+        let dropped = state
+            .dropped
+            .iter()
+            .filter(|(id, _)| node_id.map(|node_id| node_id == **id).unwrap_or(true))
+            .map(|(_, dropped)| *dropped);
+
+        for dropped in dropped {
+            metrics.rx_dropped += dropped.packets;
+            metrics.rx_dropped_bytes += dropped.bytes;
+        }
+    }
+}
+
+impl Clone for IngressSender {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        IngressSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for IngressSender {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.state.lock().closed = true;
+            self.shared.recv_waker.wake();
+        }
+    }
+}
+
+impl ForwardReceiver {
+    pub async fn recv(&mut self) -> Option<Forwarded> {
+        futures::StreamExt::next(self).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<Forwarded>> {
+        Pin::new(self).poll_next(cx)
+    }
+
+    /// Returns queued packet without waiting.
+    pub fn try_recv(&mut self) -> Option<Forwarded> {
+        let mut state = self.shared.state.lock();
+        let was_full = state.items.len() >= self.shared.capacity;
+        let packet = state.remove(0);
+        if was_full && packet.is_some() {
+            self.shared.space.notify_one();
+        }
+        packet
+    }
+
+    /// Number of packets waiting in the queue.
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn is_terminated(&self) -> bool {
+        let state = self.shared.state.lock();
+        state.closed && state.items.is_empty()
+    }
+}
+
+impl Stream for ForwardReceiver {
+    type Item = Forwarded;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(packet) = self.try_recv() {
+            return Poll::Ready(Some(packet));
+        }
+        if self.is_terminated() {
+            return Poll::Ready(None);
+        }
+
+        self.shared.recv_waker.register(cx.waker());
+
+        // Packet could have been queued before registering the waker.
+        match self.try_recv() {
+            Some(packet) => Poll::Ready(Some(packet)),
+            None if self.is_terminated() => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for ForwardReceiver {
+    fn drop(&mut self) {
+        self.shared.receiver_closed.store(true, Ordering::Relaxed);
+        self.shared.state.lock().clear();
+        self.shared.space.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(transport: TransportType, byte: u8) -> Forwarded {
+        node_packet(NodeId::from([1u8; 20]), transport, byte)
+    }
+
+    fn node_packet(node_id: NodeId, transport: TransportType, byte: u8) -> Forwarded {
+        Forwarded {
+            transport,
+            node_id,
+            payload: vec![byte].into(),
+            channel: 0,
+        }
+    }
+
+    fn drain(rx: &mut ForwardReceiver) -> Vec<u8> {
+        std::iter::from_fn(|| rx.try_recv())
+            .map(|p| p.payload.as_ref()[0])
+            .collect()
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let channel = IngressChannel::new(2, DropPolicy::DropOldest);
+        let mut rx = channel.receiver().unwrap();
+
+        channel.tx.send(packet(TransportType::Reliable, 1)).unwrap();
+        channel
+            .tx
+            .send(packet(TransportType::Unreliable, 2))
+            .unwrap();
+        channel
+            .tx
+            .send(packet(TransportType::Unreliable, 3))
+            .unwrap();
+        assert!(channel.tx.is_full(NodeId::from([1u8; 20])));
+        assert_eq!(drain(&mut rx), vec![1, 3]);
+
+        let mut metrics = ChannelMetrics::default();
+        channel.tx.add_dropped(None, &mut metrics);
+        assert_eq!(metrics.rx_dropped, 1);
+        assert_eq!(metrics.rx_dropped_bytes, 1);
+    }
+
+    #[test]
+    fn test_drop_newest() {
+        let channel = IngressChannel::new(2, DropPolicy::DropNewest);
+        let mut rx = channel.receiver().unwrap();
+
+        channel
+            .tx
+            .send(packet(TransportType::Unreliable, 1))
+            .unwrap();
+        channel
+            .tx
+            .send(packet(TransportType::Unreliable, 2))
+            .unwrap();
+        channel
+            .tx
+            .send(packet(TransportType::Unreliable, 3))
+            .unwrap();
+        // Reliable packets are queued above the limit.
+        channel.tx.send(packet(TransportType::Transfer, 4)).unwrap();
+        assert_eq!(drain(&mut rx), vec![1, 2, 4]);
+        assert!(!channel.tx.is_full(NodeId::from([1u8; 20])));
+
+        let mut metrics = ChannelMetrics::default();
+        channel
+            .tx
+            .add_dropped(Some(NodeId::from([2u8; 20])), &mut metrics);
+        assert_eq!(metrics.rx_dropped, 0);
+    }
+
+    #[test]
+    fn test_full_per_node() {
+        let node1 = NodeId::from([1u8; 20]);
+        let node2 = NodeId::from([2u8; 20]);
+        let channel = IngressChannel::new(4, DropPolicy::DropOldest);
+        let mut rx = channel.receiver().unwrap();
+
+        for byte in 0..3 {
+            channel
+                .tx
+                .send(node_packet(node1, TransportType::Reliable, byte))
+                .unwrap();
+        }
+        channel
+            .tx
+            .send(node_packet(node2, TransportType::Reliable, 3))
+            .unwrap();
+
+        // Only the Node, which filled the queue, is paused.
+        assert!(channel.tx.is_full(node1));
+        assert!(!channel.tx.is_full(node2));
+
+        assert_eq!(drain(&mut rx), vec![0, 1, 2, 3]);
+        assert!(!channel.tx.is_full(node1));
+    }
+
+    #[test]
+    fn test_receiver_dropped() {
+        let channel = IngressChannel::new(1, DropPolicy::DropOldest);
+        channel.tx.send(packet(TransportType::Reliable, 1)).unwrap();
+        assert!(channel.tx.is_full(NodeId::from([1u8; 20])));
+
+        drop(channel.receiver());
+        assert!(!channel.tx.is_full(NodeId::from([1u8; 20])));
+        assert!(channel.tx.send(packet(TransportType::Reliable, 2)).is_err());
+    }
+}
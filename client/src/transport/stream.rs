@@ -0,0 +1,394 @@
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{ready, Context, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{mpsc, Notify};
+
+use ya_relay_core::NodeId;
+use ya_relay_stack::connection::ConnectionMeta;
+use ya_relay_stack::smoltcp::iface::SocketHandle;
+use ya_relay_stack::smoltcp::wire::IpAddress;
+use ya_relay_stack::{Connection, Network};
+
+use super::virtual_layer::TcpLayer;
+
+/// Amount of received data waiting to be read from a stream, after which
+/// virtual TCP stops reading from sockets connected to the Node.
+const STREAM_BUFFER_LIMIT: usize = 256 * 1024;
+
+/// Virtual TCP connections exposed as byte streams, and ports listening for them.
+#[derive(Clone, Default)]
+pub(crate) struct Streams {
+    state: Rc<RefCell<StreamsState>>,
+    space: Rc<Notify>,
+}
+
+#[derive(Default)]
+struct StreamsState {
+    listeners: HashMap<u16, mpsc::UnboundedSender<VirtualStream>>,
+    /// Buffer is removed, when the read side of the stream is closed.
+    /// The connection is forgotten after it's disconnected.
+    connections: HashMap<ConnectionMeta, Option<Rc<RefCell<StreamBuffer>>>>,
+}
+
+/// Data received on the connection, until it is read from the stream.
+#[derive(Default)]
+struct StreamBuffer {
+    chunks: VecDeque<Vec<u8>>,
+    /// Position in the first chunk.
+    offset: usize,
+    len: usize,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+impl StreamBuffer {
+    fn push(&mut self, data: Vec<u8>) {
+        self.len += data.len();
+        self.chunks.push_back(data);
+        self.wake();
+    }
+
+    fn read(&mut self, buf: &mut ReadBuf<'_>) {
+        while buf.remaining() > 0 {
+            let chunk = match self.chunks.front() {
+                Some(chunk) => chunk,
+                None => break,
+            };
+            let len = buf.remaining().min(chunk.len() - self.offset);
+            buf.put_slice(&chunk[self.offset..self.offset + len]);
+            self.offset += len;
+            self.len -= len;
+
+            if self.offset >= chunk.len() {
+                self.chunks.pop_front();
+                self.offset = 0;
+            }
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.len >= STREAM_BUFFER_LIMIT
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Streams {
+    pub fn is_listening(&self, port: u16) -> bool {
+        self.state.borrow().listeners.contains_key(&port)
+    }
+
+    pub fn add_listener(&self, port: u16) -> mpsc::UnboundedReceiver<VirtualStream> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.state.borrow_mut().listeners.insert(port, tx);
+        rx
+    }
+
+    pub fn remove_listener(&self, port: u16) {
+        self.state.borrow_mut().listeners.remove(&port);
+    }
+
+    /// Creates stream for established connection. Data received on the connection
+    /// will be passed to the stream instead of `ForwardReceiver`.
+    pub fn add_stream(
+        &self,
+        node_id: NodeId,
+        connection: Connection,
+        net: Network,
+    ) -> VirtualStream {
+        let buffer = Rc::new(RefCell::new(StreamBuffer::default()));
+        self.state
+            .borrow_mut()
+            .connections
+            .insert(connection.meta, Some(buffer.clone()));
+
+        VirtualStream {
+            node_id,
+            connection,
+            net,
+            streams: self.clone(),
+            buffer,
+            sending: None,
+            shutdown: false,
+        }
+    }
+
+    /// Passes inbound connection to the listener. Returns `false`, if nobody listens on the port.
+    pub fn accept(&self, node_id: NodeId, connection: Connection, net: Network) -> bool {
+        let port = connection.meta.local.port;
+        let listener = match self.state.borrow().listeners.get(&port) {
+            Some(listener) => listener.clone(),
+            None => return false,
+        };
+
+        let stream = self.add_stream(node_id, connection, net);
+        if listener.send(stream).is_err() {
+            self.remove_listener(port);
+        }
+        true
+    }
+
+    /// Passes data to the stream. Returns payload back, if connection doesn't belong to any stream.
+    /// Data is always accepted, the caller should stop reading from the Node,
+    /// when `is_full` returns true.
+    pub fn receive(&self, meta: &ConnectionMeta, payload: Vec<u8>) -> Option<Vec<u8>> {
+        match self.state.borrow().connections.get(meta) {
+            Some(Some(buffer)) => {
+                buffer.borrow_mut().push(payload);
+                None
+            }
+            Some(None) => None,
+            None => Some(payload),
+        }
+    }
+
+    /// Any stream connected to `remote` has too much unread data.
+    pub fn is_full(&self, remote: &IpAddress) -> bool {
+        self.state
+            .borrow()
+            .connections
+            .iter()
+            .filter(|(meta, _)| meta.remote.addr == *remote)
+            .any(|(_, buffer)| {
+                buffer
+                    .as_ref()
+                    .map(|b| b.borrow().is_full())
+                    .unwrap_or(false)
+            })
+    }
+
+    /// Waits until some stream, which had too much unread data, was read or closed.
+    pub async fn space_available(&self) {
+        self.space.notified().await
+    }
+
+    /// Stops all listeners and closes read side of all streams.
+    pub fn close(&self) {
+        let mut state = self.state.borrow_mut();
+        state.listeners.clear();
+        for buffer in state.connections.drain().filter_map(|(_, buffer)| buffer) {
+            buffer.borrow_mut().close();
+        }
+        self.space.notify_one();
+    }
+
+    /// Closes read side of the stream. Returns `false`, if connection doesn't belong to any stream.
+    pub fn finished(&self, meta: &ConnectionMeta) -> bool {
+        match self.state.borrow_mut().connections.get_mut(meta) {
+            Some(buffer) => {
+                if let Some(buffer) = buffer.take() {
+                    buffer.borrow_mut().close();
+                    self.space.notify_one();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forgets the connection. Returns `false`, if connection doesn't belong to any stream.
+    pub fn disconnected(&self, meta: &ConnectionMeta) -> bool {
+        match self.state.borrow_mut().connections.remove(meta) {
+            Some(buffer) => {
+                if let Some(buffer) = buffer {
+                    buffer.borrow_mut().close();
+                    self.space.notify_one();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Accepts virtual TCP connections from other Nodes on a port.
+/// The port stops listening, when `VirtualListener` is dropped.
+pub struct VirtualListener {
+    port: u16,
+    rx: mpsc::UnboundedReceiver<VirtualStream>,
+    layer: TcpLayer,
+}
+
+impl VirtualListener {
+    pub(crate) fn new(
+        port: u16,
+        rx: mpsc::UnboundedReceiver<VirtualStream>,
+        layer: TcpLayer,
+    ) -> VirtualListener {
+        VirtualListener { port, rx, layer }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Waits for next incoming connection. Returns `None`, when `Client` was shutdown.
+    pub async fn accept(&mut self) -> Option<VirtualStream> {
+        self.rx.recv().await
+    }
+}
+
+impl Drop for VirtualListener {
+    fn drop(&mut self) {
+        self.layer.unlisten(self.port);
+    }
+}
+
+/// Virtual TCP connection with other Node, implementing `AsyncRead` and `AsyncWrite`.
+///
+/// Received data is buffered until it is read from the stream. When the buffer is full,
+/// data stays in virtual TCP receive buffers, which slows down the sender.
+pub struct VirtualStream {
+    node_id: NodeId,
+    connection: Connection,
+    net: Network,
+    streams: Streams,
+    buffer: Rc<RefCell<StreamBuffer>>,
+    sending: Option<LocalBoxFuture<'static, ya_relay_stack::Result<()>>>,
+    shutdown: bool,
+}
+
+impl VirtualStream {
+    /// Default id of the other Node.
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.connection.meta.local.port
+    }
+
+    pub fn remote_port(&self) -> u16 {
+        self.connection.meta.remote.port
+    }
+
+    fn poll_sending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(sending) = self.sending.as_mut() {
+            let result = ready!(sending.as_mut().poll(cx));
+            self.sending = None;
+            result.map_err(broken_pipe)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn close(&mut self) {
+        if !self.shutdown {
+            self.shutdown = true;
+
+            let net = self.net.clone();
+            let handle = self.connection.handle;
+            match self.sending.take() {
+                // Disconnecting now would drop the data, which didn't fit into the socket yet.
+                Some(sending) => {
+                    tokio::task::spawn_local(async move {
+                        if let Err(e) = sending.await {
+                            log::debug!("Sending to closed stream failed: {e}");
+                        }
+                        disconnect(&net, handle);
+                    });
+                }
+                None => disconnect(&net, handle),
+            }
+        }
+    }
+}
+
+impl AsyncRead for VirtualStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let mut buffer = this.buffer.borrow_mut();
+        if buffer.chunks.is_empty() {
+            // Connection closed by the other side.
+            if buffer.closed {
+                return Poll::Ready(Ok(()));
+            }
+            buffer.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let was_full = buffer.is_full();
+        buffer.read(buf);
+        if was_full && !buffer.is_full() {
+            this.streams.space.notify_one();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for VirtualStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_sending(cx))?;
+
+        if this.shutdown {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        // Data is written to the socket directly, since `Network::send` completes before
+        // the data reaches the socket buffer and shutdown could overtake it.
+        // If the socket buffer is full, the result is reported by the next write or flush.
+        let net = this.net.clone();
+        let mut sending = this
+            .net
+            .stack
+            .send(buf.to_vec(), this.connection, move || net.poll())
+            .boxed_local();
+        match sending.as_mut().poll(cx) {
+            Poll::Ready(result) => result.map_err(broken_pipe)?,
+            Poll::Pending => this.sending = Some(sending),
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_sending(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_sending(cx))?;
+        this.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for VirtualStream {
+    fn drop(&mut self) {
+        self.streams.finished(&self.connection.meta);
+        self.close();
+    }
+}
+
+/// Closes only sending side, we can still receive data until the other side closes.
+fn disconnect(net: &Network, handle: SocketHandle) {
+    drop(net.stack.disconnect(handle));
+    net.poll();
+}
+
+fn broken_pipe(e: ya_relay_stack::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, e.to_string())
+}
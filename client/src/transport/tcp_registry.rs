@@ -1,6 +1,7 @@
 use anyhow::anyhow;
 use derive_more::Display;
 use educe::Educe;
+use parking_lot::Mutex;
 use std::collections::HashMap;
 use std::net::Ipv6Addr;
 use std::sync::{Arc, Weak};
@@ -14,15 +15,29 @@ use ya_relay_stack::Connection;
 
 use super::virtual_layer::TcpLayer;
 use crate::error::{ResultExt, TcpError, TcpTransitionError};
+use crate::events::{ClientEvent, EventSender};
 use crate::routing_session::RoutingSender;
 use crate::session::SessionLayer;
 
+/// Ports from this value up are allocated by the stack for outgoing connections,
+/// so they can't be used as channels.
+pub const MAX_CHANNEL_ID: u16 = 999;
+
 // TODO: Try to unify with `TransportType`.
-// TODO: We could support any number of channels. User of the library could decide.
+/// Numbered virtual TCP channel. Each channel listens on its own port, so traffic
+/// on one channel doesn't block the others.
 #[derive(Clone, Copy, Display, Debug, PartialEq, Hash, Eq)]
-pub enum ChannelType {
-    Messages = 1,
-    Transfer = 2,
+pub struct ChannelType(pub u16);
+
+impl ChannelType {
+    /// Channel used by `forward_reliable`.
+    pub const MESSAGES: ChannelType = ChannelType(1);
+    /// Channel used by `forward_transfer`.
+    pub const TRANSFER: ChannelType = ChannelType(2);
+
+    pub fn port(&self) -> u16 {
+        self.0
+    }
 }
 
 #[derive(Clone, Copy, Display, Debug, PartialEq, Hash, Eq)]
@@ -31,9 +46,8 @@ pub enum ChannelDirection {
     In,
 }
 
-/// For communication with single Node we have max 4 channels:
-/// Separate channels for Messages and Transfer and each of them establishes
-/// separate connection in each direction.
+/// Each channel used for communication with single Node establishes separate
+/// connection in each direction.
 ///
 /// Having one channel for both directions would be difficult, because you need to synchronize in cases
 /// when both parties are initializing connection at the same time. So maybe this choice requires more
@@ -48,9 +62,9 @@ pub struct VirtNode {
     pub address: IpAddress,
     pub routing: RoutingSender,
 
-    /// We could support arbitrary number of channels, but this would require
-    /// having dynamic data structure with locks, what is not worth at this moment.
-    pub channels: [VirtChannel; 4],
+    /// Channels are created on first use.
+    pub channels: Arc<Mutex<HashMap<ChannelDesc, VirtChannel>>>,
+    events: EventSender,
 }
 
 #[derive(Clone)]
@@ -82,32 +96,14 @@ impl VirtChannel {
 impl VirtNode {
     pub fn new(id: NodeId, layer: SessionLayer) -> VirtNode {
         let ip = IpAddress::from(to_ipv6(id.into_array()));
+        let events = layer.registry.events().clone();
         let routing = RoutingSender::empty(id, layer);
 
-        let msg_in_channel =
-            VirtChannel::new(ChannelDesc(ChannelType::Messages, ChannelDirection::In));
-        let msg_out_channel =
-            VirtChannel::new(ChannelDesc(ChannelType::Messages, ChannelDirection::Out));
-        let trans_in_channel =
-            VirtChannel::new(ChannelDesc(ChannelType::Transfer, ChannelDirection::In));
-        let trans_out_channel =
-            VirtChannel::new(ChannelDesc(ChannelType::Transfer, ChannelDirection::Out));
-
-        // This code should place channels according to index values returned by `ChannelDesc::index` function.
-        // We could use HashMap instead, but than we should handle case, when ChannelDesc is not in the array,
-        // what pollutes code with unnecessary Results.
-        // Assumption is checked by `test_virt_node_creation_assumption` test.
-        let channels = [
-            trans_in_channel,
-            trans_out_channel,
-            msg_in_channel,
-            msg_out_channel,
-        ];
-
         VirtNode {
             address: ip,
             routing,
-            channels,
+            channels: Default::default(),
+            events,
         }
     }
 
@@ -120,10 +116,23 @@ impl VirtNode {
         channel: ChannelDesc,
         new_state: TcpState,
     ) -> Result<(), TcpTransitionError> {
+        let desc = channel;
         let channel = self.channel(channel);
         let mut state = channel.state.write().await;
-
-        state.transition(new_state)
+        let connected = matches!(*state, TcpState::Connected(_));
+        state.transition(new_state)?;
+
+        let (node_id, channel) = (self.id(), desc.0.port());
+        match (connected, &*state) {
+            (false, TcpState::Connected(_)) => self
+                .events
+                .send(ClientEvent::ChannelConnected { node_id, channel }),
+            (true, TcpState::Closing | TcpState::Closed) => self
+                .events
+                .send(ClientEvent::ChannelClosed { node_id, channel }),
+            _ => (),
+        }
+        Ok(())
     }
 
     pub fn notifier(
@@ -134,7 +143,21 @@ impl VirtNode {
     }
 
     pub fn channel(&self, channel: ChannelDesc) -> VirtChannel {
-        self.channels[channel.index()].clone()
+        self.channels
+            .lock()
+            .entry(channel)
+            .or_insert_with(|| VirtChannel::new(channel))
+            .clone()
+    }
+
+    /// Channels initialized by us.
+    fn outgoing(&self) -> Vec<ChannelDesc> {
+        self.channels
+            .lock()
+            .keys()
+            .filter(|desc| desc.1 == ChannelDirection::Out)
+            .cloned()
+            .collect()
     }
 }
 
@@ -206,8 +229,9 @@ impl TcpRegistry {
                 .await
                 .on_err(|e| {
                     log::warn!(
-                        "Error closing channel for Node: {} (Messages): {}",
+                        "Error closing channel for Node: {} ({}): {}",
                         node.id(),
+                        channel,
                         e
                     )
                 })
@@ -219,11 +243,9 @@ impl TcpRegistry {
         // Removing all channels. Consider if we should remove channels one by one.
         log::trace!("[remove_node] Removing node: {node_id}", node_id = node_id);
         if let Ok(node) = self.resolve_node(node_id).await {
-            let messages_out = (ChannelType::Messages, ChannelDirection::Out).into();
-            let transfer_out = (ChannelType::Transfer, ChannelDirection::Out).into();
-
-            self.close_channel(&node, messages_out).await;
-            self.close_channel(&node, transfer_out).await;
+            for channel in node.outgoing() {
+                self.close_channel(&node, channel).await;
+            }
         };
 
         // let mut state = self.state.write().await;
@@ -470,7 +492,7 @@ impl TcpSender {
 }
 
 pub(crate) fn channel_endpoint(id: NodeId, channel: ChannelType) -> IpEndpoint {
-    (to_ipv6(id), channel as u16).into()
+    (to_ipv6(id), channel.port()).into()
 }
 
 pub(crate) fn to_ipv6(bytes: impl AsRef<[u8]>) -> Ipv6Addr {
@@ -498,17 +520,14 @@ impl From<(ChannelType, ChannelDirection)> for ChannelDesc {
     }
 }
 
-impl ChannelDesc {
-    pub fn index(&self) -> usize {
-        match self {
-            ChannelDesc(ChannelType::Transfer, ChannelDirection::In) => 0,
-            ChannelDesc(ChannelType::Transfer, ChannelDirection::Out) => 1,
-            ChannelDesc(ChannelType::Messages, ChannelDirection::In) => 2,
-            ChannelDesc(ChannelType::Messages, ChannelDirection::Out) => 3,
-        }
+impl From<u16> for ChannelType {
+    fn from(port: u16) -> Self {
+        ChannelType(port)
     }
+}
 
+impl ChannelDesc {
     pub fn port(&self) -> u16 {
-        self.0 as u16
+        self.0.port()
     }
 }
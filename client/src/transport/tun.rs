@@ -0,0 +1,259 @@
+//! Linux TUN device attaching the virtual network to the operating system.
+use anyhow::{bail, Context};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::ffi::CStr;
+use std::fs::OpenOptions;
+use std::io;
+use std::net::Ipv6Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::rc::Rc;
+use tokio::io::unix::AsyncFd;
+use tokio::task::JoinHandle;
+
+use ya_relay_stack::packet::{IpV6Field, IpV6Packet};
+
+const TUN_PATH: &str = "/dev/net/tun";
+
+/// TUN device together with the task routing packets read from it.
+/// Dropping the bridge stops the task and removes the device.
+pub(crate) struct TunBridge {
+    pub device: Rc<TunDevice>,
+    pub router: JoinHandle<()>,
+}
+
+impl Drop for TunBridge {
+    fn drop(&mut self) {
+        log::debug!("[Tun] Detaching device {}", self.device.name());
+        self.router.abort();
+    }
+}
+
+/// TUN device with the IPv6 address of our Node. Reads and writes whole IP packets.
+///
+/// Virtual IPs of Nodes don't share common prefix, so only addresses of Nodes known
+/// to the virtual network are routed through the device, each with its own host route.
+/// Routes are removed by the kernel together with the device.
+pub(crate) struct TunDevice {
+    name: String,
+    index: libc::c_int,
+    fd: AsyncFd<OwnedFd>,
+    routes: RefCell<HashSet<Ipv6Addr>>,
+}
+
+impl TunDevice {
+    /// Creates device (or attaches to existing one) and brings it up with `address/128`.
+    pub fn create(name: &str, address: Ipv6Addr, mtu: usize) -> anyhow::Result<TunDevice> {
+        if name.len() >= libc::IFNAMSIZ {
+            bail!("TUN device name too long: {name}");
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(TUN_PATH)
+            .with_context(|| format!("Opening {TUN_PATH}"))?;
+        let fd = OwnedFd::from(file);
+
+        let mut req = ifreq(name);
+        req.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+        ioctl(fd.as_raw_fd(), libc::TUNSETIFF as _, &mut req).context("Creating TUN device")?;
+        // Kernel fills the name, when pattern like `tun%d` was used.
+        let name = unsafe { CStr::from_ptr(req.ifr_name.as_ptr()) }
+            .to_string_lossy()
+            .to_string();
+
+        let index = configure(&name, address, mtu)
+            .with_context(|| format!("Configuring TUN device {name}"))?;
+
+        log::info!("[Tun] Device {name} attached with address {address}/128");
+        Ok(TunDevice {
+            name,
+            index,
+            fd: AsyncFd::new(fd)?,
+            routes: Default::default(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Routes traffic to virtual IP of other Node through the device.
+    pub fn add_route(&self, address: Ipv6Addr) -> anyhow::Result<()> {
+        if self.routes.borrow().contains(&address) {
+            return Ok(());
+        }
+
+        let control = control_socket()?;
+        let mut route = In6RtMsg {
+            dst: libc::in6_addr {
+                s6_addr: address.octets(),
+            },
+            src: libc::in6_addr { s6_addr: [0; 16] },
+            gateway: libc::in6_addr { s6_addr: [0; 16] },
+            kind: 0,
+            dst_len: 128,
+            src_len: 0,
+            metric: 1,
+            info: 0,
+            flags: (libc::RTF_UP | libc::RTF_HOST) as u32,
+            ifindex: self.index,
+        };
+        match ioctl(control.as_raw_fd(), libc::SIOCADDRT as _, &mut route) {
+            Err(e) if e.raw_os_error() != Some(libc::EEXIST) => {
+                return Err(e).with_context(|| format!("Adding route to {address}"))
+            }
+            _ => (),
+        }
+
+        log::debug!("[Tun] Routing {address} through device {}", self.name);
+        self.routes.borrow_mut().insert(address);
+        Ok(())
+    }
+
+    /// Reads single IP packet.
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            match guard.try_io(|fd| {
+                let len =
+                    unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr() as *mut _, buf.len()) };
+                match len {
+                    len if len < 0 => Err(io::Error::last_os_error()),
+                    len => Ok(len as usize),
+                }
+            }) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Writes single IP packet. Packet is dropped, if the device queue is full.
+    pub fn send(&self, packet: &[u8]) -> io::Result<()> {
+        let len = unsafe {
+            libc::write(
+                self.fd.as_raw_fd(),
+                packet.as_ptr() as *const _,
+                packet.len(),
+            )
+        };
+        match len {
+            len if len < 0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Checks that IPv6 packet is addressed from `src` to `dst`. Packets received from other Node
+/// must come from its virtual IP, otherwise it could impersonate other Nodes to applications.
+pub(crate) fn is_addressed(packet: &[u8], src: Ipv6Addr, dst: Ipv6Addr) -> bool {
+    packet.len() >= IpV6Packet::MIN_HEADER_LEN
+        && packet[0] >> 4 == 6
+        && packet[IpV6Field::SRC_ADDR] == src.octets()
+        && packet[IpV6Field::DST_ADDR] == dst.octets()
+}
+
+/// Mirrors `struct in6_rtmsg` from `<linux/ipv6_route.h>`. Fields of `libc::in6_rtmsg` are private.
+#[repr(C)]
+struct In6RtMsg {
+    dst: libc::in6_addr,
+    src: libc::in6_addr,
+    gateway: libc::in6_addr,
+    kind: u32,
+    dst_len: u16,
+    src_len: u16,
+    metric: u32,
+    info: libc::c_ulong,
+    flags: u32,
+    ifindex: libc::c_int,
+}
+
+fn control_socket() -> anyhow::Result<OwnedFd> {
+    let socket = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if socket < 0 {
+        return Err(io::Error::last_os_error()).context("Opening control socket");
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(socket) })
+}
+
+/// Sets MTU and address of the device and brings it up. Returns index of the device.
+fn configure(name: &str, address: Ipv6Addr, mtu: usize) -> anyhow::Result<libc::c_int> {
+    let control = control_socket()?;
+    let socket = control.as_raw_fd();
+
+    // Address is unique in the virtual network, so duplicate address detection
+    // would only delay using it. Failure isn't fatal.
+    let _ = std::fs::write(format!("/proc/sys/net/ipv6/conf/{name}/accept_dad"), "0");
+
+    let mut req = ifreq(name);
+    req.ifr_ifru.ifru_mtu = mtu as libc::c_int;
+    ioctl(socket, libc::SIOCSIFMTU as _, &mut req).context("Setting MTU")?;
+
+    let mut req = ifreq(name);
+    ioctl(socket, libc::SIOCGIFINDEX as _, &mut req).context("Reading index")?;
+    let index = unsafe { req.ifr_ifru.ifru_ifindex };
+    let mut addr = libc::in6_ifreq {
+        ifr6_addr: libc::in6_addr {
+            s6_addr: address.octets(),
+        },
+        ifr6_prefixlen: 128,
+        ifr6_ifindex: index,
+    };
+    ioctl(socket, libc::SIOCSIFADDR as _, &mut addr).context("Setting address")?;
+
+    let mut req = ifreq(name);
+    ioctl(socket, libc::SIOCGIFFLAGS as _, &mut req).context("Reading flags")?;
+    unsafe { req.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short };
+    ioctl(socket, libc::SIOCSIFFLAGS as _, &mut req).context("Bringing device up")?;
+    Ok(index)
+}
+
+fn ifreq(name: &str) -> libc::ifreq {
+    let mut req: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in req.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    req
+}
+
+fn ioctl<T>(fd: libc::c_int, request: libc::Ioctl, arg: &mut T) -> io::Result<()> {
+    match unsafe { libc::ioctl(fd, request, arg as *mut T) } {
+        result if result < 0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv6_packet(src: Ipv6Addr, dst: Ipv6Addr) -> Vec<u8> {
+        let mut packet = vec![0u8; IpV6Packet::MIN_HEADER_LEN + 4];
+        packet[0] = 0x60;
+        packet[IpV6Field::PAYLOAD_LEN].copy_from_slice(&4u16.to_be_bytes());
+        packet[IpV6Field::PROTOCOL][0] = 17;
+        packet[IpV6Field::SRC_ADDR].copy_from_slice(&src.octets());
+        packet[IpV6Field::DST_ADDR].copy_from_slice(&dst.octets());
+        packet
+    }
+
+    #[test]
+    fn test_spoofed_source_dropped() {
+        let node: Ipv6Addr = "fd00::1".parse().unwrap();
+        let other: Ipv6Addr = "fd00::2".parse().unwrap();
+        let ours: Ipv6Addr = "fd00::3".parse().unwrap();
+
+        assert!(is_addressed(&ipv6_packet(node, ours), node, ours));
+        assert!(!is_addressed(&ipv6_packet(other, ours), node, ours));
+        assert!(!is_addressed(&ipv6_packet(node, other), node, ours));
+        assert!(!is_addressed(&ipv6_packet(node, ours)[..20], node, ours));
+
+        let mut ipv4 = ipv6_packet(node, ours);
+        ipv4[0] = 0x45;
+        assert!(!is_addressed(&ipv4, node, ours));
+    }
+}
@@ -1,8 +1,11 @@
-use anyhow::Context;
+use anyhow::{bail, Context};
 use futures::{FutureExt, StreamExt};
 use std::cell::RefCell;
+use std::cmp::Reverse;
 use std::collections::HashSet;
+use std::convert::TryFrom;
 use std::io::Write;
+use std::net::Ipv6Addr;
 use std::path::PathBuf;
 use std::rc::Rc;
 use std::sync::Arc;
@@ -14,19 +17,25 @@ use ya_relay_core::crypto::PublicKey;
 use ya_relay_core::server_session::TransportType;
 use ya_relay_core::NodeId;
 use ya_relay_proto::proto::Payload;
+use ya_relay_stack::connection::ConnectionMeta;
 use ya_relay_stack::interface::{add_iface_address, add_iface_route, pcap_tun_iface, tun_iface};
-use ya_relay_stack::smoltcp::iface::Route;
+use ya_relay_stack::smoltcp::iface::{Route, SocketHandle};
 use ya_relay_stack::smoltcp::wire::{IpAddress, IpCidr, IpEndpoint};
 use ya_relay_stack::socket::{SocketEndpoint, TCP_CONN_TIMEOUT, TCP_DISCONN_TIMEOUT};
 use ya_relay_stack::{
-    Channel, ChannelMetrics, Connection, EgressEvent, IngressEvent, Network, Protocol, SocketDesc,
+    ChannelMetrics, Connection, EgressEvent, IngressEvent, Network, Protocol, SocketDesc,
     SocketState, Stack, StackConfig,
 };
 
+use super::datagram::{Datagrams, VirtualUdpSocket};
+use super::ingress::IngressChannel;
+use super::stream::{Streams, VirtualListener, VirtualStream};
 use super::tcp_registry::{
     channel_endpoint, to_ipv6, ChannelDesc, ChannelDirection, ChannelType, TcpConnection, TcpLock,
-    TcpPermit, TcpRegistry, TcpSender, VirtNode,
+    TcpPermit, TcpRegistry, TcpSender, VirtNode, MAX_CHANNEL_ID,
 };
+#[cfg(all(feature = "tun", target_os = "linux"))]
+use super::tun::{is_addressed, TunBridge, TunDevice};
 use crate::client::Forwarded;
 use crate::error::TcpError;
 use crate::session::SessionLayer;
@@ -45,15 +54,19 @@ pub struct TcpLayer {
 
     registry: TcpRegistry,
 
-    ingress: Channel<Forwarded>,
+    ingress: IngressChannel,
     virtual_tcp_fast_lane: Rc<RefCell<HashSet<NodeId>>>,
+    streams: Streams,
+    datagrams: Datagrams,
+    #[cfg(all(feature = "tun", target_os = "linux"))]
+    tun: Rc<RefCell<Option<TunBridge>>>,
 }
 
 impl TcpLayer {
-    pub fn new(
+    pub(crate) fn new(
         key: &PublicKey,
         config: &StackConfig,
-        ingress: &Channel<Forwarded>,
+        ingress: &IngressChannel,
         session_layer: SessionLayer,
     ) -> TcpLayer {
         let pcap = config.pcap_path.clone().map(|p| match pcap_writer(p) {
@@ -67,6 +80,10 @@ impl TcpLayer {
             ingress: ingress.clone(),
             registry: TcpRegistry::new(session_layer.clone()),
             virtual_tcp_fast_lane: Rc::new(RefCell::new(Default::default())),
+            streams: Default::default(),
+            datagrams: Default::default(),
+            #[cfg(all(feature = "tun", target_os = "linux"))]
+            tun: Default::default(),
             session_layer,
         }
     }
@@ -80,18 +97,109 @@ impl TcpLayer {
     }
 
     pub async fn spawn(&self, our_id: NodeId) -> anyhow::Result<()> {
-        let virt_endpoint = channel_endpoint(our_id, ChannelType::Messages);
-        let virt_transfer_endpoint = channel_endpoint(our_id, ChannelType::Transfer);
-
         self.net.spawn_local();
-        self.net.bind(Protocol::Tcp, virt_endpoint)?;
-        self.net.bind(Protocol::Tcp, virt_transfer_endpoint)?;
+        for channel in self.channels() {
+            self.net
+                .bind(Protocol::Tcp, channel_endpoint(our_id, channel))?;
+        }
 
         self.spawn_ingress_router().await?;
         self.spawn_egress_router().await?;
+        self.spawn_backpressure_handler();
+
+        #[cfg(all(feature = "tun", target_os = "linux"))]
+        self.spawn_tun(our_id)?;
         Ok(())
     }
 
+    /// Attaches virtual network to TUN device, if configured.
+    #[cfg(all(feature = "tun", target_os = "linux"))]
+    fn spawn_tun(&self, our_id: NodeId) -> anyhow::Result<()> {
+        let config = match &self.session_layer.config.tun {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+
+        let mtu = self.net.config.max_transmission_unit;
+        let device = Rc::new(TunDevice::create(&config.name, to_ipv6(our_id), mtu)?);
+        let router = tokio::task::spawn_local(self.clone().tun_router(device.clone(), mtu));
+        self.tun.replace(Some(TunBridge { device, router }));
+        Ok(())
+    }
+
+    /// Forwards packets written to TUN device by applications to other Nodes.
+    #[cfg(all(feature = "tun", target_os = "linux"))]
+    async fn tun_router(self, device: Rc<TunDevice>, mtu: usize) {
+        use ya_relay_stack::packet::{IpPacket, PeekPacket};
+
+        let mut buf = vec![0u8; mtu];
+        loop {
+            let len = match device.recv(&mut buf).await {
+                Ok(len) => len,
+                Err(e) => {
+                    log::warn!("[Tun] Reading from device {} failed: {e}", device.name());
+                    return;
+                }
+            };
+
+            let packet = &buf[..len];
+            if packet.is_empty() || IpPacket::peek(packet).is_err() {
+                continue;
+            }
+            let remote = IpPacket::packet(packet).dst_address().into();
+            self.clone()
+                .route_egress(EgressEvent {
+                    remote,
+                    payload: packet.into(),
+                    desc: None,
+                })
+                .await;
+        }
+    }
+
+    /// Virtual IP of the Node. Packets to this IP will be routed to the Node.
+    pub async fn virtual_address(&self, node_id: NodeId) -> Ipv6Addr {
+        if self.registry.resolve_node(node_id).await.is_err() {
+            self.registry.add_virt_node(node_id).await;
+        }
+        self.route_tun(node_id);
+        to_ipv6(node_id)
+    }
+
+    /// Routes virtual IP of the Node through TUN device, if it's attached.
+    fn route_tun(&self, _node_id: NodeId) {
+        #[cfg(all(feature = "tun", target_os = "linux"))]
+        if let Some(tun) = self.tun.borrow().as_ref() {
+            if let Err(e) = tun.device.add_route(to_ipv6(_node_id)) {
+                log::warn!("[Tun] Routing [{_node_id}] failed: {e:#}");
+            }
+        }
+    }
+
+    fn channels(&self) -> impl Iterator<Item = ChannelType> + '_ {
+        self.session_layer
+            .config
+            .channels
+            .keys()
+            .map(|id| ChannelType(*id))
+    }
+
+    /// Priority of channel, egress packet belongs to.
+    fn priority(&self, egress: &EgressEvent) -> u8 {
+        let channel = egress.desc.as_ref().and_then(|(desc, _)| {
+            let local = desc.local.ip_endpoint().ok()?.port;
+            let remote = desc.remote.ip_endpoint().ok()?.port;
+            // Connections initialized by us use ports outside of channels range.
+            Some(match remote <= MAX_CHANNEL_ID {
+                true => remote,
+                false => local,
+            })
+        });
+        channel
+            .and_then(|id| self.session_layer.config.channels.get(&id).cloned())
+            .unwrap_or_default()
+    }
+
     pub async fn resolve_node(&self, node: NodeId) -> anyhow::Result<VirtNode> {
         self.registry.resolve_node(node).await
     }
@@ -142,6 +250,129 @@ impl TcpLayer {
         })
     }
 
+    /// Listens for stream connections on virtual TCP port.
+    pub fn listen(&self, port: u16) -> anyhow::Result<VirtualListener> {
+        self.check_stream_port(port)?;
+        if self.streams.is_listening(port) {
+            bail!("Port {port} is already used");
+        }
+
+        let endpoint = channel_endpoint(self.session_layer.config.node_id, ChannelType(port));
+        self.net.bind(Protocol::Tcp, endpoint)?;
+
+        let rx = self.streams.add_listener(port);
+        Ok(VirtualListener::new(port, rx, self.clone()))
+    }
+
+    pub(crate) fn unlisten(&self, port: u16) {
+        self.streams.remove_listener(port);
+
+        let endpoint = channel_endpoint(self.session_layer.config.node_id, ChannelType(port));
+        self.net
+            .unbind(Protocol::Tcp, endpoint)
+            .map_err(|e| log::debug!("Unbinding stream port {port}: {e}"))
+            .ok();
+    }
+
+    /// Opens stream connection to port, on which other Node listens.
+    pub async fn connect_stream(
+        &self,
+        node_id: NodeId,
+        port: u16,
+    ) -> anyhow::Result<VirtualStream> {
+        self.check_stream_port(port)?;
+
+        let node = self.reachable_node(node_id).await?;
+
+        log::debug!("[VirtualTcp] Opening stream to node [{node_id}], port: {port}.");
+
+        let connection = self
+            .net
+            .connect(IpEndpoint::new(node.address, port), TCP_CONN_TIMEOUT)
+            .await?;
+        Ok(self
+            .streams
+            .add_stream(node_id, connection, self.net.clone()))
+    }
+
+    /// Binds virtual UDP socket to port.
+    pub fn bind_udp(&self, port: u16) -> anyhow::Result<VirtualUdpSocket> {
+        if port == 0 || port > MAX_CHANNEL_ID {
+            bail!("Port {port} out of range 1..={MAX_CHANNEL_ID}");
+        }
+        if self.datagrams.is_bound(port) {
+            bail!("Port {port} is already used");
+        }
+
+        let endpoint = channel_endpoint(self.session_layer.config.node_id, ChannelType(port));
+        let handle = self.net.bind(Protocol::Udp, endpoint)?;
+
+        let rx = self.datagrams.add_socket(port);
+        Ok(VirtualUdpSocket::new(port, handle, rx, self.clone()))
+    }
+
+    pub(crate) fn unbind_udp(&self, port: u16) {
+        self.datagrams.remove_socket(port);
+
+        let endpoint = channel_endpoint(self.session_layer.config.node_id, ChannelType(port));
+        self.net
+            .unbind(Protocol::Udp, endpoint)
+            .map_err(|e| log::debug!("Unbinding udp port {port}: {e}"))
+            .ok();
+    }
+
+    /// Sends datagram from socket bound on `local_port`. NodeId can be either default or secondary.
+    pub(crate) async fn send_datagram(
+        &self,
+        handle: SocketHandle,
+        local_port: u16,
+        data: Vec<u8>,
+        node_id: NodeId,
+        port: u16,
+    ) -> anyhow::Result<()> {
+        let node_id = self
+            .session_layer
+            .query_node_info(node_id)
+            .await?
+            .default_node_id();
+        let node = self.reachable_node(node_id).await?;
+
+        let local = channel_endpoint(self.session_layer.config.node_id, ChannelType(local_port));
+        let connection = Connection {
+            handle,
+            meta: ConnectionMeta::new(Protocol::Udp, local, IpEndpoint::new(node.address, port)),
+        };
+
+        let net = self.net.clone();
+        self.net
+            .stack
+            .send(data, connection, move || net.poll())
+            .await?;
+        Ok(())
+    }
+
+    /// Returns Node, with which we have a session. This allows us to
+    /// exit early if target Node is unreachable.
+    async fn reachable_node(&self, node_id: NodeId) -> anyhow::Result<VirtNode> {
+        let node = match self.registry.resolve_node(node_id).await {
+            Ok(node) => node,
+            Err(_) => self.registry.add_virt_node(node_id).await,
+        };
+        self.session_layer.session(node_id).await?;
+        Ok(node)
+    }
+
+    /// Streams share ports with channels, but can't use ports of configured channels.
+    fn check_stream_port(&self, port: u16) -> anyhow::Result<()> {
+        if port == 0 || port > MAX_CHANNEL_ID {
+            bail!("Port {port} out of range 1..={MAX_CHANNEL_ID}");
+        }
+        if self.session_layer.config.channels.contains_key(&port) {
+            bail!("Port {port} is used by channel");
+        }
+        Ok(())
+    }
+
     async fn connect_internal(
         &self,
         channel: ChannelDesc,
@@ -207,7 +438,7 @@ impl TcpLayer {
         };
 
         if exists {
-            self.inject(packet.payload);
+            self.inject(node_id, packet.payload);
             return;
         }
 
@@ -230,15 +461,34 @@ impl TcpLayer {
             );
             self.registry.add_virt_node(node).await;
         }
-        self.inject(payload);
+        // Applications using TUN device can reply to the Node.
+        self.route_tun(node);
+        self.inject(node, payload);
     }
 
     #[inline]
-    pub fn inject(&self, payload: Payload) {
+    pub fn inject(&self, _node: NodeId, payload: Payload) {
         log::trace!(
             "[inject]: start ({payload_len} B)",
             payload_len = payload.len()
         );
+
+        // Packets, which don't belong to our sockets, are meant for applications using TUN device.
+        #[cfg(all(feature = "tun", target_os = "linux"))]
+        if let Some(tun) = self.tun.borrow().as_ref() {
+            if !self.net.has_socket_for(payload.as_ref()) {
+                let ours = to_ipv6(self.session_layer.config.node_id);
+                if !is_addressed(payload.as_ref(), to_ipv6(_node), ours) {
+                    log::debug!("[Tun] Dropping packet from [{_node}] with spoofed address");
+                    return;
+                }
+                if let Err(e) = tun.device.send(payload.as_ref()) {
+                    log::trace!("[Tun] Dropping packet: {e}");
+                }
+                return;
+            }
+        }
+
         self.net.receive(payload);
         self.net.poll();
         log::trace!("[inject]: ...done");
@@ -246,18 +496,20 @@ impl TcpLayer {
 
     pub async fn shutdown(&self, id: NodeId) {
         let our_id = id;
-        let virt_endpoint = channel_endpoint(our_id, ChannelType::Messages);
-        let virt_transfer_endpoint = channel_endpoint(our_id, ChannelType::Transfer);
+        self.streams.close();
+        self.datagrams.close();
+        #[cfg(all(feature = "tun", target_os = "linux"))]
+        self.tun.replace(None);
 
         // Unbind listening sockets, so no new connection can be created.
-        self.net
-            .unbind(Protocol::Tcp, virt_endpoint)
-            .map_err(|e| log::warn!("Shutdown error when unbinding sockets (Messages): {e}"))
-            .ok();
-        self.net
-            .unbind(Protocol::Tcp, virt_transfer_endpoint)
-            .map_err(|e| log::warn!("Shutdown error when unbinding sockets (Transfer): {e}"))
-            .ok();
+        for channel in self.channels() {
+            self.net
+                .unbind(Protocol::Tcp, channel_endpoint(our_id, channel))
+                .map_err(|e| {
+                    log::warn!("Shutdown error when unbinding sockets (channel {channel}): {e}")
+                })
+                .ok();
+        }
 
         // Try to disconnect all connections gracefully. This requires sending TCP closing packets
         // to other Nodes, that's why it is important to first close everything related to TCP and
@@ -279,6 +531,57 @@ impl TcpLayer {
         futures::future::join_all(disconnect_futures).await;
     }
 
+    /// Passes connection accepted on stream port to the listener.
+    async fn accept_stream(&self, desc: SocketDesc) {
+        let meta = match ConnectionMeta::try_from(desc) {
+            Ok(meta) => meta,
+            Err(_) => return,
+        };
+        if meta.protocol != Protocol::Tcp || !self.streams.is_listening(meta.local.port) {
+            return;
+        }
+
+        let connection = match { self.net.connections().get(&meta).cloned() } {
+            Some(connection) => connection,
+            None => return,
+        };
+        let node = match self
+            .registry
+            .get_by_address(meta.remote.addr.as_bytes())
+            .await
+        {
+            Some(node) => node,
+            None => {
+                log::debug!(
+                    "[{}] stream connection from unknown address {}",
+                    self.net_id(),
+                    meta.remote
+                );
+                return;
+            }
+        };
+
+        self.streams.accept(node.id(), connection, self.net.clone());
+    }
+
+    /// Passes datagram received by virtual UDP socket to its owner.
+    async fn receive_datagram(&self, desc: SocketDesc, payload: Vec<u8>) {
+        let (remote, local) = match (desc.remote.ip_endpoint(), desc.local.ip_endpoint()) {
+            (Ok(remote), Ok(local)) => (remote, local),
+            _ => return,
+        };
+        match self.registry.get_by_address(remote.addr.as_bytes()).await {
+            Some(node) => self
+                .datagrams
+                .receive(local.port, (payload, node.id(), remote.port)),
+            None => log::trace!(
+                "[{}] datagram from unknown address {}",
+                self.net_id(),
+                remote
+            ),
+        }
+    }
+
     async fn spawn_ingress_router(&self) -> anyhow::Result<()> {
         let ingress_rx = self
             .net
@@ -289,6 +592,45 @@ impl TcpLayer {
         Ok(())
     }
 
+    fn spawn_backpressure_handler(&self) {
+        tokio::task::spawn_local(self.clone().backpressure_handler());
+    }
+
+    /// Resumes reading from virtual TCP sockets of Nodes, which no longer occupy their share
+    /// of the ingress queue and whose streams were read, after the consumer freed space.
+    async fn backpressure_handler(self) {
+        loop {
+            tokio::select! {
+                _ = self.ingress.tx.space_available() => {},
+                _ = self.streams.space_available() => {},
+            }
+
+            let mut resumed = false;
+            for address in self.net.tcp_ingress_paused() {
+                let full = self.streams.is_full(&address)
+                    || match self.registry.get_by_address(address.as_bytes()).await {
+                        Some(node) => self.ingress.tx.is_full(node.id()),
+                        None => false,
+                    };
+                if !full {
+                    log::trace!(
+                        "[{}] ingress has free space, resuming {address}",
+                        self.net_id()
+                    );
+                    self.net.pause_tcp_ingress(address, false);
+                    resumed = true;
+                }
+            }
+            if resumed {
+                self.net.poll();
+            }
+
+            if self.ingress.tx.is_closed() {
+                break;
+            }
+        }
+    }
+
     async fn spawn_egress_router(&self) -> anyhow::Result<()> {
         let egress_rx = self
             .net
@@ -312,6 +654,7 @@ impl TcpLayer {
                                 desc.remote,
                                 desc.local,
                             );
+                            myself.accept_stream(desc).await;
                             return;
                         }
                         IngressEvent::Disconnected { desc } => {
@@ -323,6 +666,16 @@ impl TcpLayer {
                                 desc.remote,
                             );
 
+                            // Closing stream or udp socket shouldn't affect other connections with the Node.
+                            if desc.protocol != Protocol::Tcp {
+                                return;
+                            }
+                            if let Ok(meta) = ConnectionMeta::try_from(desc) {
+                                if myself.streams.disconnected(&meta) {
+                                    return;
+                                }
+                            }
+
                             if let Ok(endpoint)= desc.remote.ip_endpoint() {
                                 if let Some(node) = myself.registry.get_by_address(endpoint.addr.as_bytes()).await {
                                     myself.remove_node(node.id()).await;
@@ -330,6 +683,12 @@ impl TcpLayer {
                             }
                             return;
                         }
+                        IngressEvent::Finished { desc } => {
+                            if let Ok(meta) = ConnectionMeta::try_from(desc) {
+                                myself.streams.finished(&meta);
+                            }
+                            return;
+                        }
                         IngressEvent::Packet { desc, payload, .. } => {
                             ya_packet_trace::packet_trace_maybe!("TcpLayer::ingress_router", {
                                 &ya_packet_trace::try_extract_from_ip_frame(&payload)
@@ -339,6 +698,11 @@ impl TcpLayer {
                         }
                     };
 
+                    if desc.protocol == Protocol::Udp {
+                        myself.receive_datagram(desc, payload).await;
+                        return;
+                    }
+
                     if desc.protocol != Protocol::Tcp {
                         log::trace!(
                             "[{}] ingress router: dropping {} payload",
@@ -348,6 +712,27 @@ impl TcpLayer {
                         return;
                     }
 
+                    let payload = match ConnectionMeta::try_from(desc) {
+                        Ok(meta) => match myself.streams.receive(&meta, payload) {
+                            Some(payload) => payload,
+                            None => {
+                                // Data will stay in TCP receive buffers, until the stream is read.
+                                let remote = meta.remote.addr;
+                                if myself.streams.is_full(&remote)
+                                    && !myself.net.is_tcp_ingress_paused(&remote)
+                                {
+                                    log::trace!(
+                                        "[{}] ingress router: stream buffer full, pausing {remote}",
+                                        myself.net_id()
+                                    );
+                                    myself.net.pause_tcp_ingress(remote, true);
+                                }
+                                return;
+                            }
+                        },
+                        Err(_) => payload,
+                    };
+
                     let (remote_address, local_port) = match (desc.remote, desc.local) {
                         (SocketEndpoint::Ip(remote), SocketEndpoint::Ip(local)) => {
                             (remote.addr, local.port)
@@ -371,11 +756,12 @@ impl TcpLayer {
                             let payload_len = payload.len();
                             let payload = Forwarded {
                                 transport: match ChannelType::from(local_port) {
-                                    ChannelType::Messages => TransportType::Reliable,
-                                    ChannelType::Transfer => TransportType::Transfer,
+                                    ChannelType::TRANSFER => TransportType::Transfer,
+                                    _ => TransportType::Reliable,
                                 },
                                 node_id,
                                 payload: payload.into(),
+                                channel: local_port,
                             };
 
                             if tx.send(payload).is_err() {
@@ -389,6 +775,17 @@ impl TcpLayer {
                                     myself.net_id()
                                 );
                             }
+
+                            // Data will stay in TCP receive buffers, until consumer catches up.
+                            if tx.is_full(node_id)
+                                && !myself.net.is_tcp_ingress_paused(&remote_address)
+                            {
+                                log::trace!(
+                                    "[{}] ingress router: ingress queue full, pausing {node_id}",
+                                    myself.net_id()
+                                );
+                                myself.net.pause_tcp_ingress(remote_address, true);
+                            }
                         }
                         _ => log::trace!(
                             "[{}] ingress router: unknown remote address {remote_address}",
@@ -400,60 +797,69 @@ impl TcpLayer {
             .await
     }
 
-    async fn egress_router(self, egress_rx: UnboundedReceiver<EgressEvent>) {
-        UnboundedReceiverStream::new(egress_rx)
-            .for_each(move |egress| {
-                let myself = self.clone();
-                async move {
-                    let mut node = match myself.registry.get_by_address(&egress.remote).await {
-                        Some(node) => node,
-                        None => {
-                            log::trace!(
-                                "[{}] egress router: unknown address {:02x?}",
-                                myself.net_id(),
-                                egress.remote
-                            );
-                            return;
-                        }
-                    };
+    async fn egress_router(self, mut egress_rx: UnboundedReceiver<EgressEvent>) {
+        while let Some(egress) = egress_rx.recv().await {
+            // Packets, which are already waiting, are sent in order of their channel priority.
+            // Sorting is stable, so order of packets within single connection is preserved.
+            let mut batch = vec![egress];
+            while let Ok(egress) = egress_rx.try_recv() {
+                batch.push(egress);
+            }
+            batch.sort_by_key(|egress| Reverse(self.priority(egress)));
 
-                    log::trace!("[egress_router]: node: {}", node.id());
+            for egress in batch {
+                self.clone().route_egress(egress).await;
+            }
+        }
+    }
 
-                    // `RoutingSender::send` will lazily create session with target Node.
-                    // In most cases session will exist, but if not, we need to protect from
-                    // blocking the rest of packets in the stream.
-                    //
-                    // Note that thanks to lazy sessions, even if we have unstable connection,
-                    // TCP sessions are able to survive disconnection on lower layer. In previous
-                    // implementation we disconnected TCP and all GSB messages in queue were lost.
-                    tokio::task::spawn_local(async move {
-                        log::trace!(
-                            "[{}] egress router: forwarding to [{}]",
-                            myself.net_id(),
-                            node.id()
-                        );
-                        if let Err(error) = node
-                            .routing
-                            .send(egress.payload.into(), TransportType::Reliable)
-                            .await
-                        {
-                            // TODO: In case of failure it would be nice to somehow send this error
-                            //       back to message sender. In current scenario GSB messages will
-                            //       wait until timeout. This makes error messages from this library
-                            //       really poor, because everything from outside looks like a timeout.
-                            log::trace!(
-                                "[{}] egress router: forward to [{}] failed: {}",
-                                myself.net_id(),
-                                node.id(),
-                                error
-                            );
+    async fn route_egress(self, egress: EgressEvent) {
+        let mut node = match self.registry.get_by_address(&egress.remote).await {
+            Some(node) => node,
+            None => {
+                log::trace!(
+                    "[{}] egress router: unknown address {:02x?}",
+                    self.net_id(),
+                    egress.remote
+                );
+                return;
+            }
+        };
 
-                            myself.remove_node(node.id()).await;
-                        }
-                    });
-                }
-            })
-            .await
+        log::trace!("[egress_router]: node: {}", node.id());
+
+        // `RoutingSender::send` will lazily create session with target Node.
+        // In most cases session will exist, but if not, we need to protect from
+        // blocking the rest of packets in the stream.
+        //
+        // Note that thanks to lazy sessions, even if we have unstable connection,
+        // TCP sessions are able to survive disconnection on lower layer. In previous
+        // implementation we disconnected TCP and all GSB messages in queue were lost.
+        tokio::task::spawn_local(async move {
+            log::trace!(
+                "[{}] egress router: forwarding to [{}]",
+                self.net_id(),
+                node.id()
+            );
+            if let Err(error) = node
+                .routing
+                .send(egress.payload.into(), TransportType::Reliable)
+                .await
+            {
+                // TODO: In case of failure it would be nice to somehow send this error
+                //       back to message sender. In current scenario GSB messages will
+                //       wait until timeout. This makes error messages from this library
+                //       really poor, because everything from outside looks like a timeout.
+                log::trace!(
+                    "[{}] egress router: forward to [{}] failed: {}",
+                    self.net_id(),
+                    node.id(),
+                    error
+                );
+
+                self.remove_node(node.id()).await;
+            }
+        });
     }
 }
 
@@ -509,18 +915,6 @@ fn default_network(
     Network::new(name, config.clone(), Stack::new(iface, config))
 }
 
-impl From<u16> for ChannelType {
-    fn from(port: u16) -> Self {
-        if port == ChannelType::Messages as u16 {
-            ChannelType::Messages
-        } else if port == ChannelType::Transfer as u16 {
-            ChannelType::Transfer
-        } else {
-            ChannelType::Messages
-        }
-    }
-}
-
 pub fn print_sockets(network: &Network) {
     log::trace!("[inet] existing sockets:");
     for (handle, meta, state) in network.sockets_meta() {
@@ -159,6 +159,81 @@ pub fn recover_default_node_id(request: &proto::request::Session) -> anyhow::Res
     }
 }
 
+/// Signs request moving session to a new address with the default identity of the session.
+pub async fn sign_rebind(
+    session_id: &[u8],
+    nonce: &[u8],
+    crypto: impl Crypto,
+) -> anyhow::Result<Vec<u8>> {
+    sign(
+        session_digest(REBIND_DOMAIN, session_id, nonce).as_slice(),
+        crypto,
+    )
+    .await
+}
+
+/// Recovers `NodeId`, which signed the rebind request.
+pub fn recover_rebind_node_id(
+    session_id: &[u8],
+    nonce: &[u8],
+    signature: &[u8],
+) -> anyhow::Result<NodeId> {
+    let key = recover(
+        signature,
+        session_digest(REBIND_DOMAIN, session_id, nonce).as_slice(),
+    )?;
+    Ok(Identity::from(key).node_id)
+}
+
+/// Signs new identity set of the session. The first signature is made by the default identity.
+pub async fn sign_identities<C: Crypto>(
+    session_id: &[u8],
+    timestamp: u64,
+    crypto_vec: Vec<C>,
+) -> anyhow::Result<Vec<Vec<u8>>> {
+    let digest = session_digest(IDENTITIES_DOMAIN, session_id, &timestamp.to_be_bytes());
+    futures::stream::iter(crypto_vec)
+        .then(|crypto| sign(digest.as_slice(), crypto))
+        .try_collect()
+        .await
+}
+
+/// Recovers identities, which signed the new identity set of the session.
+/// The default identity has to match `default_id`.
+pub fn recover_identities_from_signatures(
+    session_id: &[u8],
+    timestamp: u64,
+    signatures: &[Vec<u8>],
+    default_id: NodeId,
+) -> anyhow::Result<Vec<Identity>> {
+    let digest = session_digest(IDENTITIES_DOMAIN, session_id, &timestamp.to_be_bytes());
+    let identities = signatures
+        .iter()
+        .map(|sig| Ok(Identity::from(recover(sig, digest.as_slice())?)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    match identities.first() {
+        Some(ident) if ident.node_id == default_id => Ok(identities),
+        Some(ident) => bail!(
+            "Invalid default NodeId [{default_id}] vs [{}] (identities)",
+            ident.node_id
+        ),
+        None => bail!("Missing signature"),
+    }
+}
+
+const REBIND_DOMAIN: &[u8] = b"ya-relay-rebind";
+const IDENTITIES_DOMAIN: &[u8] = b"ya-relay-identities";
+
+/// Domain separates signatures of different messages bound to the same session.
+fn session_digest(domain: &[u8], session_id: &[u8], data: &[u8]) -> Output<sha2::Sha256> {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(domain);
+    hasher.update(session_id);
+    hasher.update(data);
+    hasher.finalize()
+}
+
 async fn sign(message: &[u8], crypto: impl Crypto) -> anyhow::Result<Vec<u8>> {
     let sig = crypto.sign(message).await?;
 
@@ -257,7 +332,7 @@ mod tests {
         let pairs: Vec<_> = futures::stream::iter((0..n).map(anyhow::Ok))
             .then(|_| async {
                 let bytes = rand::thread_rng().gen::<[u8; 32]>();
-                let secret = ethsign::SecretKey::from_raw(&bytes)?;
+                let secret = crate::key::SecretKey::from_raw(&bytes)?;
                 let public = secret.public();
 
                 let provider = FallbackCryptoProvider::new(secret);
@@ -300,4 +375,57 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn sign_recover_rebind() -> anyhow::Result<()> {
+        let (keys, mut crypto_vec) = gen_crypto(1).await?;
+        let session_id = [1u8; 16];
+        let nonce = [2u8; 32];
+
+        let signature = super::sign_rebind(&session_id, &nonce, crypto_vec.remove(0)).await?;
+        let node_id = super::recover_rebind_node_id(&session_id, &nonce, &signature)?;
+        assert_eq!(node_id, NodeId::from(keys[0].address().as_slice()));
+
+        let other = super::recover_rebind_node_id(&session_id, &[3u8; 32], &signature)?;
+        assert_ne!(other, node_id);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn sign_recover_identities() -> anyhow::Result<()> {
+        let (keys, crypto_vec) = gen_crypto(3).await?;
+        let node_ids = keys
+            .iter()
+            .map(|key| NodeId::from(key.address().as_slice()))
+            .collect::<Vec<_>>();
+        let session_id = [1u8; 16];
+        let timestamp = 7;
+
+        let signatures = super::sign_identities(&session_id, timestamp, crypto_vec).await?;
+        let identities = super::recover_identities_from_signatures(
+            &session_id,
+            timestamp,
+            &signatures,
+            node_ids[0],
+        )?;
+        assert_eq!(
+            identities.iter().map(|i| i.node_id).collect::<Vec<_>>(),
+            node_ids
+        );
+
+        assert!(super::recover_identities_from_signatures(
+            &session_id,
+            timestamp,
+            &signatures,
+            node_ids[1]
+        )
+        .is_err());
+        assert!(super::recover_identities_from_signatures(
+            &session_id,
+            timestamp + 1,
+            &signatures,
+            node_ids[0]
+        )
+        .is_err());
+        Ok(())
+    }
 }
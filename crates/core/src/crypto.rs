@@ -1,13 +1,55 @@
 use std::collections::HashMap;
+use std::convert::TryInto;
 use std::rc::Rc;
 
-pub use ethsign::{PublicKey, SecretKey, Signature};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+pub use ethsign::{PublicKey, Signature};
 use futures::future::LocalBoxFuture;
 use futures::FutureExt;
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use sha2::Sha256;
 
 use ya_client_model::NodeId;
 
 use crate::key::generate;
+pub use crate::key::SecretKey;
+
+pub mod keystore;
+
+/// Payload encryption scheme: ECDH key agreement on secp256k1 identities, HKDF-SHA256
+/// derivation of per session keys and XChaCha20-Poly1305 authenticated encryption.
+pub const ENCRYPTION_SCHEME: &str = "ecdh-secp256k1-hkdf-sha256-xchacha20poly1305";
+
+/// Size of random nonce contributed by each Node to the session keys.
+pub const SESSION_NONCE_SIZE: usize = 32;
+/// Size of tag authenticating key exchange messages.
+pub const KEY_EXCHANGE_TAG_SIZE: usize = 32;
+/// Number of most recent packet counters remembered to reject replayed packets.
+pub const REPLAY_WINDOW_SIZE: u64 = 128;
+
+const NONCE_SIZE: usize = 24;
+const COUNTER_SIZE: usize = 8;
+const SESSION_KEY_LABEL: &[u8] = b"ya-relay session key";
+const SEALED_KEY_LABEL: &[u8] = b"ya-relay sealed message key";
+const KEY_EXCHANGE_LABEL: &[u8] = b"ya-relay key exchange";
+
+pub type SessionNonce = [u8; SESSION_NONCE_SIZE];
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum CipherError {
+    #[error("Encrypted payload too short: {0} B")]
+    TooShort(usize),
+    #[error("Payload encryption failed")]
+    Encryption,
+    #[error("Payload authentication failed")]
+    Authentication,
+    #[error("Replayed or too old packet (counter {0})")]
+    Replayed(u64),
+    #[error("Packet counter exhausted, session keys have to be renegotiated")]
+    CounterExhausted,
+}
 
 pub trait CryptoProvider {
     fn default_id<'a>(&self) -> LocalBoxFuture<'a, anyhow::Result<NodeId>>;
@@ -23,6 +65,13 @@ pub trait Crypto {
         message: &'a [u8],
         remote_key: &'a PublicKey,
     ) -> LocalBoxFuture<'a, anyhow::Result<Vec<u8>>>;
+    /// Computes ECDH shared secret between this identity and `remote_key`.
+    /// Both sides of the connection get the same value.
+    /// Implementations without access to the secret key can leave the default,
+    /// which disables payload encryption.
+    fn ecdh<'a>(&self, _remote_key: &'a PublicKey) -> LocalBoxFuture<'a, anyhow::Result<[u8; 32]>> {
+        futures::future::err(anyhow::anyhow!("Key agreement is not supported")).boxed_local()
+    }
 }
 
 impl<C: CryptoProvider + ?Sized> CryptoProvider for Rc<C> {
@@ -55,6 +104,10 @@ impl<C: Crypto + ?Sized> Crypto for Rc<C> {
     ) -> LocalBoxFuture<'a, anyhow::Result<Vec<u8>>> {
         (**self).encrypt(message, remote_key)
     }
+
+    fn ecdh<'a>(&self, remote_key: &'a PublicKey) -> LocalBoxFuture<'a, anyhow::Result<[u8; 32]>> {
+        (**self).ecdh(remote_key)
+    }
 }
 
 #[derive(Clone)]
@@ -140,9 +193,374 @@ impl Crypto for FallbackCrypto {
 
     fn encrypt<'a>(
         &self,
-        _message: &'a [u8],
-        _remote_key: &'a PublicKey,
+        message: &'a [u8],
+        remote_key: &'a PublicKey,
     ) -> LocalBoxFuture<'a, anyhow::Result<Vec<u8>>> {
-        unimplemented!()
+        let result = ecdh(self.secret.raw(), remote_key)
+            .and_then(|secret| seal(&secret, message).map_err(anyhow::Error::from));
+        async move { result }.boxed_local()
+    }
+
+    fn ecdh<'a>(&self, remote_key: &'a PublicKey) -> LocalBoxFuture<'a, anyhow::Result<[u8; 32]>> {
+        let result = ecdh(self.secret.raw(), remote_key);
+        async move { result }.boxed_local()
+    }
+}
+
+fn ecdh(secret: &[u8], remote_key: &PublicKey) -> anyhow::Result<[u8; 32]> {
+    let secret = secp256k1::SecretKey::from_slice(secret)?;
+
+    let mut uncompressed = [0u8; 65];
+    uncompressed[0] = 0x04;
+    uncompressed[1..].copy_from_slice(remote_key.bytes());
+    let remote_key = secp256k1::PublicKey::from_slice(&uncompressed)?;
+
+    let shared = secp256k1::ecdh::SharedSecret::new(&remote_key, &secret);
+    let mut result = [0u8; 32];
+    result.copy_from_slice(&shared[..32]);
+    Ok(result)
+}
+
+/// HKDF-SHA256 (RFC 5869) producing single 32 B key.
+fn hkdf(salt: &[u8], ikm: &[u8], info: &[&[u8]]) -> [u8; 32] {
+    let mut extract = Hmac::<Sha256>::new_varkey(salt).expect("HMAC accepts keys of any size");
+    extract.update(ikm);
+    let prk = extract.finalize().into_bytes();
+
+    let mut expand = Hmac::<Sha256>::new_varkey(&prk).expect("HMAC accepts keys of any size");
+    for chunk in info {
+        expand.update(chunk);
+    }
+    expand.update(&[1u8]);
+
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&expand.finalize().into_bytes());
+    key
+}
+
+/// Encrypts one-off message for the owner of the other ECDH key. Message carries
+/// random nonce, since key derived from identities alone is the same every time.
+fn seal(shared_secret: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, CipherError> {
+    let key = hkdf(&[], shared_secret, &[SEALED_KEY_LABEL]);
+    let mut nonce = [0u8; NONCE_SIZE];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let ciphertext = XChaCha20Poly1305::new(&key.into())
+        .encrypt(XNonce::from_slice(&nonce), plaintext)
+        .map_err(|_| CipherError::Encryption)?;
+
+    let mut result = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+    result.extend_from_slice(&nonce);
+    result.extend_from_slice(&ciphertext);
+    Ok(result)
+}
+
+/// Tag authenticating key exchange `message` sent from `sender` to `receiver`.
+/// Only owners of the shared secret can compute it.
+pub fn key_exchange_tag(
+    shared_secret: &[u8; 32],
+    sender: NodeId,
+    receiver: NodeId,
+    message: &[u8],
+) -> [u8; KEY_EXCHANGE_TAG_SIZE] {
+    let mut tag = [0u8; KEY_EXCHANGE_TAG_SIZE];
+    tag.copy_from_slice(
+        &key_exchange_mac(shared_secret, sender, receiver, message)
+            .finalize()
+            .into_bytes(),
+    );
+    tag
+}
+
+/// Verifies tag computed with `key_exchange_tag` in constant time.
+pub fn verify_key_exchange_tag(
+    shared_secret: &[u8; 32],
+    sender: NodeId,
+    receiver: NodeId,
+    message: &[u8],
+    tag: &[u8],
+) -> bool {
+    key_exchange_mac(shared_secret, sender, receiver, message)
+        .verify(tag)
+        .is_ok()
+}
+
+fn key_exchange_mac(
+    shared_secret: &[u8; 32],
+    sender: NodeId,
+    receiver: NodeId,
+    message: &[u8],
+) -> Hmac<Sha256> {
+    let key = hkdf(&[], shared_secret, &[KEY_EXCHANGE_LABEL]);
+    let mut mac = Hmac::<Sha256>::new_varkey(&key).expect("HMAC accepts keys of any size");
+    mac.update(&sender.into_array());
+    mac.update(&receiver.into_array());
+    mac.update(message);
+    mac
+}
+
+pub fn new_session_nonce() -> SessionNonce {
+    let mut nonce = [0u8; SESSION_NONCE_SIZE];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+/// Authenticated cipher for payloads of a single session between 2 Nodes.
+///
+/// Separate key is derived for each direction from ECDH shared secret and fresh
+/// nonces of both Nodes, so keys differ between sessions, even if identities don't.
+/// Packets are numbered and the receiver rejects counters, which it has already seen.
+pub struct SessionCipher {
+    send: XChaCha20Poly1305,
+    recv: XChaCha20Poly1305,
+    counter: u64,
+    window: ReplayWindow,
+}
+
+impl SessionCipher {
+    /// Both Nodes derive the same keys, each passing its own id and nonce as `local`.
+    pub fn new(
+        shared_secret: &[u8; 32],
+        local: (NodeId, &SessionNonce),
+        remote: (NodeId, &SessionNonce),
+    ) -> Self {
+        let direction_key = |(from, from_nonce): (NodeId, &SessionNonce),
+                             (to, to_nonce): (NodeId, &SessionNonce)| {
+            let salt = [&from_nonce[..], &to_nonce[..]].concat();
+            let (from, to) = (from.into_array(), to.into_array());
+            let key = hkdf(&salt, shared_secret, &[SESSION_KEY_LABEL, &from, &to]);
+            XChaCha20Poly1305::new(&key.into())
+        };
+
+        SessionCipher {
+            send: direction_key(local, remote),
+            recv: direction_key(remote, local),
+            counter: 0,
+            window: ReplayWindow::default(),
+        }
+    }
+
+    /// Returns packet counter followed by ciphertext. `aad` is authenticated, but not encrypted.
+    pub fn encrypt(&mut self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, CipherError> {
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or(CipherError::CounterExhausted)?;
+        let counter = self.counter.to_be_bytes();
+
+        let ciphertext = self
+            .send
+            .encrypt(
+                &counter_nonce(&counter),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| CipherError::Encryption)?;
+
+        let mut result = Vec::with_capacity(COUNTER_SIZE + ciphertext.len());
+        result.extend_from_slice(&counter);
+        result.extend_from_slice(&ciphertext);
+        Ok(result)
+    }
+
+    pub fn decrypt(&mut self, message: &[u8], aad: &[u8]) -> Result<Vec<u8>, CipherError> {
+        if message.len() < COUNTER_SIZE {
+            return Err(CipherError::TooShort(message.len()));
+        }
+        let (counter, ciphertext) = message.split_at(COUNTER_SIZE);
+        let value = u64::from_be_bytes(counter.try_into().unwrap());
+        if !self.window.is_fresh(value) {
+            return Err(CipherError::Replayed(value));
+        }
+
+        let plaintext = self
+            .recv
+            .decrypt(
+                &counter_nonce(counter),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| CipherError::Authentication)?;
+
+        // Only authenticated counters can move the window.
+        self.window.insert(value);
+        Ok(plaintext)
+    }
+}
+
+fn counter_nonce(counter: &[u8]) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[NONCE_SIZE - COUNTER_SIZE..].copy_from_slice(counter);
+    nonce
+}
+
+/// Sliding window of the most recent packet counters. Bit `n` of `seen`
+/// marks counter `highest - n`.
+struct ReplayWindow {
+    highest: u64,
+    seen: u128,
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        // Counters start from 1, so 0 is never valid.
+        ReplayWindow {
+            highest: 0,
+            seen: 1,
+        }
+    }
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, counter: u64) -> bool {
+        if counter > self.highest {
+            return true;
+        }
+        let age = self.highest - counter;
+        age < REPLAY_WINDOW_SIZE && self.seen & (1 << age) == 0
+    }
+
+    fn insert(&mut self, counter: u64) {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = match shift < REPLAY_WINDOW_SIZE {
+                true => self.seen << shift,
+                false => 0,
+            };
+            self.seen |= 1;
+            self.highest = counter;
+        } else {
+            self.seen |= 1 << (self.highest - counter);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn crypto() -> Rc<dyn Crypto> {
+        let provider = FallbackCryptoProvider::default();
+        provider.get(provider.default_node_id()).await.unwrap()
+    }
+
+    fn session_pair() -> (SessionCipher, SessionCipher) {
+        let (id1, id2) = (NodeId::from([1u8; 20]), NodeId::from([2u8; 20]));
+        let (nonce1, nonce2) = (new_session_nonce(), new_session_nonce());
+        (
+            SessionCipher::new(&[7u8; 32], (id1, &nonce1), (id2, &nonce2)),
+            SessionCipher::new(&[7u8; 32], (id2, &nonce2), (id1, &nonce1)),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_ecdh_agreement() {
+        let crypto1 = crypto().await;
+        let crypto2 = crypto().await;
+        let key1 = crypto1.public_key().await.unwrap();
+        let key2 = crypto2.public_key().await.unwrap();
+
+        let secret1 = crypto1.ecdh(&key2).await.unwrap();
+        let secret2 = crypto2.ecdh(&key1).await.unwrap();
+        assert_eq!(secret1, secret2);
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_for_remote() {
+        let crypto1 = crypto().await;
+        let crypto2 = crypto().await;
+        let key1 = crypto1.public_key().await.unwrap();
+        let key2 = crypto2.public_key().await.unwrap();
+
+        let message = b"sensitive task data";
+        let encrypted = crypto1.encrypt(message, &key2).await.unwrap();
+        assert_ne!(&encrypted[NONCE_SIZE..], &message[..]);
+
+        let key = hkdf(
+            &[],
+            &crypto2.ecdh(&key1).await.unwrap(),
+            &[SEALED_KEY_LABEL],
+        );
+        let (nonce, ciphertext) = encrypted.split_at(NONCE_SIZE);
+        let decrypted = XChaCha20Poly1305::new(&key.into())
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .unwrap();
+        assert_eq!(decrypted, message.to_vec());
+    }
+
+    #[test]
+    fn test_session_keys() {
+        let (mut cipher1, mut cipher2) = session_pair();
+
+        let encrypted = cipher1.encrypt(b"payload", b"header").unwrap();
+        assert_eq!(cipher2.decrypt(&encrypted, b"header").unwrap(), b"payload");
+
+        // Packets can't be reflected back to the sender.
+        let encrypted = cipher1.encrypt(b"payload", b"header").unwrap();
+        assert_eq!(
+            cipher1.decrypt(&encrypted, b"header"),
+            Err(CipherError::Authentication)
+        );
+
+        // Other session of the same Nodes has different keys.
+        let (_, mut other) = session_pair();
+        assert_eq!(
+            other.decrypt(&encrypted, b"header"),
+            Err(CipherError::Authentication)
+        );
+    }
+
+    #[test]
+    fn test_tampered_payload_rejected() {
+        let (mut cipher1, mut cipher2) = session_pair();
+        let mut encrypted = cipher1.encrypt(b"payload", b"header").unwrap();
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 0x01;
+
+        assert_eq!(
+            cipher2.decrypt(&encrypted, b"header"),
+            Err(CipherError::Authentication)
+        );
+        assert_eq!(
+            cipher2.decrypt(&encrypted[..4], b"header"),
+            Err(CipherError::TooShort(4))
+        );
+
+        let encrypted = cipher1.encrypt(b"payload", b"header").unwrap();
+        assert_eq!(
+            cipher2.decrypt(&encrypted, b"other header"),
+            Err(CipherError::Authentication)
+        );
+        assert!(cipher2.decrypt(&encrypted, b"header").is_ok());
+    }
+
+    #[test]
+    fn test_replay_rejected() {
+        let (mut cipher1, mut cipher2) = session_pair();
+        let packets = (0..REPLAY_WINDOW_SIZE + 3)
+            .map(|_| cipher1.encrypt(b"payload", &[]).unwrap())
+            .collect::<Vec<_>>();
+
+        // Reordered packets are accepted once.
+        assert!(cipher2.decrypt(&packets[1], &[]).is_ok());
+        assert!(cipher2.decrypt(&packets[0], &[]).is_ok());
+        assert_eq!(
+            cipher2.decrypt(&packets[1], &[]),
+            Err(CipherError::Replayed(2))
+        );
+        assert_eq!(
+            cipher2.decrypt(&packets[0], &[]),
+            Err(CipherError::Replayed(1))
+        );
+
+        // Packets older than the window are rejected, even if not seen yet.
+        let last = packets.len() - 1;
+        assert!(cipher2.decrypt(&packets[last], &[]).is_ok());
+        assert!(cipher2.decrypt(&packets[2], &[]).is_err());
+        assert!(cipher2.decrypt(&packets[last - 1], &[]).is_ok());
     }
 }
@@ -0,0 +1,357 @@
+//! `CryptoProvider` backed by a directory of V3 keyfiles.
+//!
+//! Each identity is stored in `<node id>.json`, encrypted with the keystore password.
+//! The default identity is recorded in the `default` file.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+
+use ya_client_model::NodeId;
+
+use super::{Crypto, CryptoProvider, FallbackCrypto};
+use crate::key::{self, KeyError, Protected, SecretKey};
+
+const DEFAULT_FILE: &str = "default";
+const KEYFILE_EXTENSION: &str = "json";
+
+#[derive(thiserror::Error, Debug)]
+pub enum KeystoreError {
+    #[error(transparent)]
+    Key(#[from] KeyError),
+    #[error("Keystore {} IO error: {source}", .path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Keyfile {} contains other identity: {node_id}", .path.display())]
+    KeyfileMismatch { path: PathBuf, node_id: NodeId },
+    #[error("Invalid default identity in {}: {value}", .path.display())]
+    InvalidDefault { path: PathBuf, value: String },
+    #[error("Unknown identity: {0}")]
+    UnknownIdentity(NodeId),
+    #[error("Identity already exists: {0}")]
+    AlreadyExists(NodeId),
+    #[error("Can't remove default identity: {0}")]
+    RemoveDefault(NodeId),
+}
+
+impl KeystoreError {
+    fn io(path: &Path, source: io::Error) -> Self {
+        KeystoreError::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+}
+
+/// Identities loaded from keystore directory. Clones share the same state,
+/// so aliases can be changed at runtime while the provider is used by the Client.
+#[derive(Clone)]
+pub struct KeystoreCryptoProvider {
+    inner: Rc<RefCell<Keystore>>,
+}
+
+struct Keystore {
+    dir: PathBuf,
+    password: Protected,
+    default_id: NodeId,
+    keys: HashMap<NodeId, FallbackCrypto>,
+}
+
+impl KeystoreCryptoProvider {
+    /// Loads and unlocks all keyfiles in `dir`. Generates default identity
+    /// if the directory is empty or doesn't exist.
+    pub fn open(dir: impl AsRef<Path>, password: Protected) -> Result<Self, KeystoreError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(|e| KeystoreError::io(&dir, e))?;
+
+        let mut keys = HashMap::new();
+        for entry in fs::read_dir(&dir).map_err(|e| KeystoreError::io(&dir, e))? {
+            let path = entry.map_err(|e| KeystoreError::io(&dir, e))?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(KEYFILE_EXTENSION) {
+                continue;
+            }
+
+            let crypto = FallbackCrypto::from(key::load_from_file(&path, &password)?);
+            // Keyfiles are looked up by file name, which must match the identity inside.
+            let named: Option<NodeId> = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok());
+            if named != Some(crypto.id) {
+                return Err(KeystoreError::KeyfileMismatch {
+                    path,
+                    node_id: crypto.id,
+                });
+            }
+            log::debug!("Loaded identity {} from {}", crypto.id, path.display());
+            keys.insert(crypto.id, crypto);
+        }
+
+        let mut keystore = Keystore {
+            default_id: NodeId::default(),
+            dir,
+            password,
+            keys,
+        };
+
+        keystore.default_id = match keystore.read_default()? {
+            Some(default_id) => default_id,
+            None => {
+                let default_id = match keystore.keys.keys().min_by_key(|id| id.to_string()) {
+                    Some(id) => *id,
+                    None => keystore.insert(key::generate())?,
+                };
+                keystore.write_default(default_id)?;
+                default_id
+            }
+        };
+
+        log::info!(
+            "Opened keystore {}. default={}, identities={}",
+            keystore.dir.display(),
+            keystore.default_id,
+            keystore.keys.len()
+        );
+
+        Ok(Self {
+            inner: Rc::new(RefCell::new(keystore)),
+        })
+    }
+
+    pub fn default_node_id(&self) -> NodeId {
+        self.inner.borrow().default_id
+    }
+
+    /// All identities in the keystore, including the default one.
+    pub fn node_ids(&self) -> Vec<NodeId> {
+        self.inner.borrow().keys.keys().copied().collect()
+    }
+
+    /// Generates new alias and saves it in the keystore.
+    pub fn generate_alias(&self) -> Result<NodeId, KeystoreError> {
+        self.add_alias(key::generate())
+    }
+
+    /// Saves `secret` in the keystore as an alias.
+    pub fn add_alias(&self, secret: SecretKey) -> Result<NodeId, KeystoreError> {
+        self.inner.borrow_mut().insert(secret)
+    }
+
+    /// Removes alias and deletes its keyfile. The default identity can't be removed.
+    pub fn remove_alias(&self, node_id: NodeId) -> Result<(), KeystoreError> {
+        let mut keystore = self.inner.borrow_mut();
+        if node_id == keystore.default_id {
+            return Err(KeystoreError::RemoveDefault(node_id));
+        }
+        if !keystore.keys.contains_key(&node_id) {
+            return Err(KeystoreError::UnknownIdentity(node_id));
+        }
+
+        let path = keystore.keyfile_path(node_id);
+        fs::remove_file(&path).map_err(|e| KeystoreError::io(&path, e))?;
+        keystore.keys.remove(&node_id);
+
+        log::info!("Removed identity {} from keystore", node_id);
+        Ok(())
+    }
+
+    /// Makes one of the existing identities the default one.
+    pub fn set_default(&self, node_id: NodeId) -> Result<(), KeystoreError> {
+        let mut keystore = self.inner.borrow_mut();
+        if !keystore.keys.contains_key(&node_id) {
+            return Err(KeystoreError::UnknownIdentity(node_id));
+        }
+
+        keystore.write_default(node_id)?;
+        keystore.default_id = node_id;
+
+        log::info!("Default identity set to {}", node_id);
+        Ok(())
+    }
+}
+
+impl Keystore {
+    fn keyfile_path(&self, node_id: NodeId) -> PathBuf {
+        self.dir
+            .join(node_id.to_string())
+            .with_extension(KEYFILE_EXTENSION)
+    }
+
+    fn insert(&mut self, secret: SecretKey) -> Result<NodeId, KeystoreError> {
+        let crypto = FallbackCrypto::from(secret);
+        let node_id = crypto.id;
+        if self.keys.contains_key(&node_id) {
+            return Err(KeystoreError::AlreadyExists(node_id));
+        }
+
+        key::save_to_file(self.keyfile_path(node_id), &crypto.secret, &self.password)?;
+        self.keys.insert(node_id, crypto);
+
+        log::info!("Added identity {} to keystore", node_id);
+        Ok(node_id)
+    }
+
+    fn read_default(&self) -> Result<Option<NodeId>, KeystoreError> {
+        let path = self.dir.join(DEFAULT_FILE);
+        let value = match fs::read_to_string(&path) {
+            Ok(value) => value,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(KeystoreError::io(&path, e)),
+        };
+
+        let node_id: NodeId = value
+            .trim()
+            .parse()
+            .map_err(|_| KeystoreError::InvalidDefault {
+                path: path.clone(),
+                value: value.clone(),
+            })?;
+        match self.keys.contains_key(&node_id) {
+            true => Ok(Some(node_id)),
+            false => Err(KeystoreError::UnknownIdentity(node_id)),
+        }
+    }
+
+    /// Writes temporary file renamed over the `default` one, so it's never left half-written.
+    fn write_default(&self, node_id: NodeId) -> Result<(), KeystoreError> {
+        let path = self.dir.join(DEFAULT_FILE);
+        let tmp_path = path.with_extension("tmp");
+        let write = || -> io::Result<()> {
+            let mut file = fs::File::create(&tmp_path)?;
+            file.write_all(node_id.to_string().as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&tmp_path, &path)
+        };
+        write().map_err(|e| KeystoreError::io(&path, e))
+    }
+}
+
+impl CryptoProvider for KeystoreCryptoProvider {
+    fn default_id<'a>(&self) -> LocalBoxFuture<'a, anyhow::Result<NodeId>> {
+        futures::future::ok(self.default_node_id()).boxed_local()
+    }
+
+    fn aliases<'a>(&self) -> LocalBoxFuture<'a, anyhow::Result<Vec<NodeId>>> {
+        let keystore = self.inner.borrow();
+        let aliases = keystore
+            .keys
+            .keys()
+            .filter(|id| *id != &keystore.default_id)
+            .copied()
+            .collect();
+        futures::future::ok(aliases).boxed_local()
+    }
+
+    fn get<'a>(&self, node_id: NodeId) -> LocalBoxFuture<'a, anyhow::Result<Rc<dyn Crypto>>> {
+        let result = self
+            .inner
+            .borrow()
+            .keys
+            .get(&node_id)
+            .cloned()
+            .ok_or(KeystoreError::UnknownIdentity(node_id))
+            .map(|crypto| Rc::new(crypto) as Rc<dyn Crypto>);
+        async move { Ok(result?) }.boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn password() -> Protected {
+        Protected::from("secret")
+    }
+
+    #[tokio::test]
+    async fn test_keystore_aliases() {
+        let dir = tempfile::tempdir().unwrap();
+        let keystore = KeystoreCryptoProvider::open(dir.path(), password()).unwrap();
+        let default_id = keystore.default_node_id();
+        assert!(keystore.aliases().await.unwrap().is_empty());
+
+        let alias = keystore.generate_alias().unwrap();
+        assert_eq!(keystore.aliases().await.unwrap(), vec![alias]);
+        assert!(keystore.get(alias).await.is_ok());
+        assert!(matches!(
+            keystore.remove_alias(default_id),
+            Err(KeystoreError::RemoveDefault(_))
+        ));
+
+        let reopened = KeystoreCryptoProvider::open(dir.path(), password()).unwrap();
+        assert_eq!(reopened.default_node_id(), default_id);
+        assert_eq!(reopened.aliases().await.unwrap(), vec![alias]);
+
+        keystore.remove_alias(alias).unwrap();
+        assert!(keystore.get(alias).await.is_err());
+        assert!(matches!(
+            keystore.remove_alias(alias),
+            Err(KeystoreError::UnknownIdentity(_))
+        ));
+
+        let reopened = KeystoreCryptoProvider::open(dir.path(), password()).unwrap();
+        assert_eq!(reopened.node_ids(), vec![default_id]);
+    }
+
+    #[tokio::test]
+    async fn test_keystore_set_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let keystore = KeystoreCryptoProvider::open(dir.path(), password()).unwrap();
+        let first = keystore.default_node_id();
+        let alias = keystore.generate_alias().unwrap();
+
+        keystore.set_default(alias).unwrap();
+        assert_eq!(keystore.default_id().await.unwrap(), alias);
+        assert_eq!(keystore.aliases().await.unwrap(), vec![first]);
+
+        let reopened = KeystoreCryptoProvider::open(dir.path(), password()).unwrap();
+        assert_eq!(reopened.default_node_id(), alias);
+        assert!(matches!(
+            keystore.set_default(NodeId::default()),
+            Err(KeystoreError::UnknownIdentity(_))
+        ));
+    }
+
+    #[test]
+    fn test_keystore_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let keystore = KeystoreCryptoProvider::open(dir.path(), password()).unwrap();
+        let alias = keystore.generate_alias().unwrap();
+
+        assert!(matches!(
+            KeystoreCryptoProvider::open(dir.path(), Protected::from("other")),
+            Err(KeystoreError::Key(KeyError::InvalidPassword(_)))
+        ));
+
+        fs::write(dir.path().join(DEFAULT_FILE), "not a node id").unwrap();
+        assert!(matches!(
+            KeystoreCryptoProvider::open(dir.path(), password()),
+            Err(KeystoreError::InvalidDefault { .. })
+        ));
+        keystore.set_default(alias).unwrap();
+        assert!(!dir.path().join(DEFAULT_FILE).with_extension("tmp").exists());
+
+        let renamed = dir.path().join(format!("{}.json", NodeId::from([1u8; 20])));
+        fs::copy(keystore.inner.borrow().keyfile_path(alias), &renamed).unwrap();
+        assert!(matches!(
+            KeystoreCryptoProvider::open(dir.path(), password()),
+            Err(KeystoreError::KeyfileMismatch { node_id, .. }) if node_id == alias
+        ));
+        fs::remove_file(renamed).unwrap();
+
+        fs::write(dir.path().join("broken.json"), "{}").unwrap();
+        assert!(matches!(
+            KeystoreCryptoProvider::open(dir.path(), password()),
+            Err(KeystoreError::Key(KeyError::Corrupt { .. }))
+        ));
+    }
+}
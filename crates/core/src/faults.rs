@@ -0,0 +1,374 @@
+//! Fault injection for checking, how protocol copes with unreliable networks.
+//!
+//! Faults are described by `FaultConfig`, which can be parsed from string like
+//! `drop=0.1,delay=0.2,max-delay=50ms,duplicate=0.05,reorder=0.1,corrupt=0.01,kind=forward,seed=7`.
+use anyhow::{anyhow, bail, Context};
+use chrono::Utc;
+use futures::channel::mpsc;
+use futures::prelude::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio_util::codec::{Decoder, Encoder};
+
+use ya_relay_proto::codec::datagram::Codec;
+use ya_relay_proto::codec::{BytesMut, PacketKind};
+
+use crate::udp_stream::{InStream, OutStream};
+
+/// Faults applied, when they aren't configured explicitly. Read only by test builds.
+pub const FAULTS_ENV_VAR: &str = "YA_NET_FAULTS";
+/// Additional delay of reordered packets, which lets packets sent after them overtake them.
+pub const REORDER_DELAY: Duration = Duration::from_millis(20);
+
+/// Kind of packets affected by faults.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FaultTarget {
+    #[default]
+    All,
+    /// Protocol packets.
+    Packet,
+    /// Forwarded data.
+    Forward,
+}
+
+/// Probabilities of faults applied to each packet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FaultConfig {
+    pub drop: f64,
+    /// Probability of delaying packet by random time up to `max_delay`.
+    pub delay: f64,
+    pub max_delay: Duration,
+    pub duplicate: f64,
+    /// Probability of delaying packet by `REORDER_DELAY`.
+    pub reorder: f64,
+    /// Probability of flipping bits of a random byte. Packets, which can't be decoded
+    /// afterwards, are dropped.
+    pub corrupt: f64,
+    pub target: FaultTarget,
+    /// Seed for repeatable faults. Random, if not set.
+    pub seed: Option<u64>,
+}
+
+/// Decides on faults applied to consecutive packets.
+pub struct FaultInjector {
+    config: FaultConfig,
+    rng: StdRng,
+}
+
+impl FaultTarget {
+    pub fn matches(&self, is_forward: bool) -> bool {
+        match self {
+            FaultTarget::All => true,
+            FaultTarget::Packet => !is_forward,
+            FaultTarget::Forward => is_forward,
+        }
+    }
+}
+
+impl FromStr for FaultTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(FaultTarget::All),
+            "packet" => Ok(FaultTarget::Packet),
+            "forward" => Ok(FaultTarget::Forward),
+            _ => bail!("Invalid packet kind: {s}, expected one of: all, packet, forward"),
+        }
+    }
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        FaultConfig {
+            drop: 0.,
+            delay: 0.,
+            max_delay: Duration::from_millis(100),
+            duplicate: 0.,
+            reorder: 0.,
+            corrupt: 0.,
+            target: Default::default(),
+            seed: None,
+        }
+    }
+}
+
+impl FaultConfig {
+    /// Reads config from `FAULTS_ENV_VAR`, if it is set.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        match std::env::var(FAULTS_ENV_VAR) {
+            Ok(value) => Ok(Some(
+                value
+                    .parse()
+                    .with_context(|| format!("Invalid {FAULTS_ENV_VAR}"))?,
+            )),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+impl FromStr for FaultConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn probability(value: &str) -> anyhow::Result<f64> {
+            match value.parse::<f64>()? {
+                p if (0. ..=1.).contains(&p) => Ok(p),
+                p => bail!("Probability {p} out of range [0, 1]"),
+            }
+        }
+
+        let mut config = FaultConfig::default();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected key=value, got: {entry}"))?;
+            let value = value.trim();
+            match key.trim() {
+                "drop" => config.drop = probability(value)?,
+                "delay" => config.delay = probability(value)?,
+                "max-delay" => config.max_delay = humantime::parse_duration(value)?,
+                "duplicate" => config.duplicate = probability(value)?,
+                "reorder" => config.reorder = probability(value)?,
+                "corrupt" => config.corrupt = probability(value)?,
+                "kind" => config.target = value.parse()?,
+                "seed" => config.seed = Some(value.parse()?),
+                key => bail!("Unknown fault: {key}"),
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl FaultInjector {
+    pub fn new(config: &FaultConfig) -> Self {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        FaultInjector {
+            config: config.clone(),
+            rng,
+        }
+    }
+
+    /// Returns copies of the packet to deliver, with their delays. Empty, if the packet
+    /// is dropped. `corrupt` receives random offset of the damaged byte and returns `None`,
+    /// if damaged packet is no longer valid.
+    pub fn apply<T: Clone>(
+        &mut self,
+        packet: T,
+        is_forward: bool,
+        corrupt: impl FnOnce(T, usize) -> Option<T>,
+    ) -> Vec<(Duration, T)> {
+        if !self.config.target.matches(is_forward) {
+            return vec![(Duration::ZERO, packet)];
+        }
+        if self.happens(self.config.drop) {
+            return Vec::new();
+        }
+
+        let packet = match self.happens(self.config.corrupt) {
+            true => match corrupt(packet, self.rng.gen()) {
+                Some(packet) => packet,
+                None => return Vec::new(),
+            },
+            false => packet,
+        };
+
+        let mut delay = Duration::ZERO;
+        if self.happens(self.config.delay) {
+            delay += self.rng.gen_range(Duration::ZERO..=self.config.max_delay);
+        }
+        if self.happens(self.config.reorder) {
+            delay += REORDER_DELAY;
+        }
+
+        match self.happens(self.config.duplicate) {
+            true => vec![(delay, packet.clone()), (delay, packet)],
+            false => vec![(delay, packet)],
+        }
+    }
+
+    fn happens(&mut self, probability: f64) -> bool {
+        probability > 0. && self.rng.gen_bool(probability.min(1.))
+    }
+}
+
+/// Flips bits of the byte at `offset`, modulo length of the data.
+pub fn corrupt_bytes(data: &mut [u8], offset: usize) {
+    if !data.is_empty() {
+        let idx = offset % data.len();
+        data[idx] ^= 0xff;
+    }
+}
+
+fn corrupt_packet(packet: PacketKind, offset: usize) -> Option<PacketKind> {
+    let mut buf = BytesMut::new();
+    Codec.encode(packet, &mut buf).ok()?;
+    corrupt_bytes(&mut buf, offset);
+    Codec.decode(&mut buf).ok().flatten()
+}
+
+/// Wraps streams returned by `udp_bind`, applying faults to packets in both directions.
+pub fn inject_faults(
+    stream: InStream,
+    sink: OutStream,
+    config: &FaultConfig,
+) -> (InStream, OutStream) {
+    log::warn!("Injecting faults into UDP traffic: {config:?}");
+
+    let mut outgoing = FaultInjector::new(config);
+    let (tx, mut rx) = mpsc::channel::<(PacketKind, _)>(100);
+    tokio::task::spawn_local(async move {
+        while let Some((packet, addr)) = rx.next().await {
+            let is_forward = packet.is_forward();
+            for (delay, packet) in outgoing.apply(packet, is_forward, corrupt_packet) {
+                let mut sink = sink.clone();
+                if delay.is_zero() {
+                    if sink.send((packet, addr)).await.is_err() {
+                        return;
+                    }
+                } else {
+                    tokio::task::spawn_local(async move {
+                        tokio::time::sleep(delay).await;
+                        sink.send((packet, addr)).await.ok();
+                    });
+                }
+            }
+        }
+    });
+
+    // Directions use different, but still repeatable random sequences.
+    let mut incoming = FaultInjector::new(&FaultConfig {
+        seed: config.seed.map(|seed| seed.wrapping_add(1)),
+        ..config.clone()
+    });
+    let (in_tx, in_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::task::spawn_local(async move {
+        let mut stream = stream;
+        loop {
+            let (packet, from, timestamp) = tokio::select! {
+                item = stream.next() => match item {
+                    Some(item) => item,
+                    None => break,
+                },
+                _ = in_tx.closed() => break,
+            };
+
+            let is_forward = packet.is_forward();
+            for (delay, packet) in incoming.apply(packet, is_forward, corrupt_packet) {
+                if delay.is_zero() {
+                    in_tx.send((packet, from, timestamp)).ok();
+                } else {
+                    let in_tx = in_tx.clone();
+                    tokio::task::spawn_local(async move {
+                        tokio::time::sleep(delay).await;
+                        in_tx.send((packet, from, Utc::now())).ok();
+                    });
+                }
+            }
+        }
+    });
+
+    let stream = stream::unfold(in_rx, |mut rx| async move {
+        rx.recv().await.map(|item| (item, rx))
+    });
+    (Box::pin(stream), tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply_n(config: &FaultConfig, is_forward: bool, n: usize) -> Vec<Vec<(Duration, u8)>> {
+        let mut injector = FaultInjector::new(config);
+        (0..n)
+            .map(|i| injector.apply(i as u8, is_forward, |p, _| Some(p.wrapping_add(100))))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config: FaultConfig =
+            "drop=0.1, delay=0.2,max-delay=50ms,duplicate=0.3,reorder=0.4,corrupt=0.5,kind=forward,seed=7"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            config,
+            FaultConfig {
+                drop: 0.1,
+                delay: 0.2,
+                max_delay: Duration::from_millis(50),
+                duplicate: 0.3,
+                reorder: 0.4,
+                corrupt: 0.5,
+                target: FaultTarget::Forward,
+                seed: Some(7),
+            }
+        );
+        assert_eq!("".parse::<FaultConfig>().unwrap(), FaultConfig::default());
+        assert!("drop=1.5".parse::<FaultConfig>().is_err());
+        assert!("drop".parse::<FaultConfig>().is_err());
+        assert!("lose=0.1".parse::<FaultConfig>().is_err());
+        assert!("kind=other".parse::<FaultConfig>().is_err());
+    }
+
+    #[test]
+    fn test_injector_faults() {
+        let drop_all = FaultConfig {
+            drop: 1.,
+            target: FaultTarget::Packet,
+            ..Default::default()
+        };
+        assert!(apply_n(&drop_all, false, 10).iter().all(Vec::is_empty));
+        // Packets of other kind pass untouched.
+        assert!(apply_n(&drop_all, true, 10)
+            .iter()
+            .all(|p| p.len() == 1 && p[0].0.is_zero()));
+
+        let duplicate = FaultConfig {
+            duplicate: 1.,
+            corrupt: 1.,
+            reorder: 1.,
+            ..Default::default()
+        };
+        assert_eq!(
+            apply_n(&duplicate, true, 1),
+            vec![vec![(REORDER_DELAY, 100), (REORDER_DELAY, 100)]]
+        );
+    }
+
+    #[test]
+    fn test_injector_seed() {
+        let config = FaultConfig {
+            drop: 0.3,
+            delay: 0.3,
+            duplicate: 0.3,
+            seed: Some(11),
+            ..Default::default()
+        };
+        let faults = apply_n(&config, false, 100);
+        assert!(faults.iter().any(Vec::is_empty));
+        assert!(faults.iter().any(|p| p.len() == 2));
+        assert_eq!(faults, apply_n(&config, false, 100));
+    }
+
+    #[test]
+    fn test_corrupt_packet() {
+        use ya_relay_proto::proto::Forward;
+
+        let forward = PacketKind::Forward(Forward {
+            session_id: [1; 16],
+            slot: 2,
+            flags: 0,
+            payload: vec![0u8; 8].into(),
+        });
+        // Damages payload, so the packet remains valid.
+        let corrupted = corrupt_packet(forward.clone(), Forward::header_size() + 3).unwrap();
+        assert_ne!(corrupted, forward);
+        assert!(corrupted.is_forward());
+    }
+}
@@ -1,55 +1,219 @@
 use ethsign::keyfile::Bytes;
-pub use ethsign::{KeyFile, Protected, SecretKey};
+pub use ethsign::{KeyFile, Protected, PublicKey, Signature};
 use rand::Rng;
-use std::fs::File;
-use std::io::Write;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
-const KEY_ITERATIONS: u32 = 2;
+/// PBKDF2 rounds used to derive keyfile encryption key from the password.
+const KEY_ITERATIONS: u32 = 10240;
 const KEYSTORE_VERSION: u64 = 3;
 
+#[derive(thiserror::Error, Debug)]
+pub enum KeyError {
+    #[error("Keyfile {} not found", .0.display())]
+    NotFound(PathBuf),
+    #[error("Invalid password for keyfile {}", .0.display())]
+    InvalidPassword(PathBuf),
+    #[error("Corrupt keyfile {}: {reason}", .path.display())]
+    Corrupt { path: PathBuf, reason: String },
+    #[error("Keyfile {} IO error: {source}", .path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Failed to encrypt key: {0}")]
+    Encrypt(#[from] ethsign::Error),
+}
+
+impl KeyError {
+    fn io(path: &Path, source: io::Error) -> Self {
+        match source.kind() {
+            io::ErrorKind::NotFound => KeyError::NotFound(path.to_path_buf()),
+            _ => KeyError::Io {
+                path: path.to_path_buf(),
+                source,
+            },
+        }
+    }
+}
+
+/// Secret key of Node identity.
+///
+/// `ethsign::SecretKey` doesn't expose raw key bytes, which are necessary for ECDH,
+/// so they are kept next to it.
+#[derive(Clone)]
+pub struct SecretKey {
+    raw: Protected,
+    inner: ethsign::SecretKey,
+}
+
+impl SecretKey {
+    pub fn from_raw(slice: &[u8]) -> Result<Self, secp256k1::Error> {
+        Ok(SecretKey {
+            inner: ethsign::SecretKey::from_raw(slice)?,
+            raw: Protected::new(slice.to_vec()),
+        })
+    }
+
+    pub fn raw(&self) -> &[u8] {
+        self.raw.as_ref()
+    }
+
+    pub fn public(&self) -> PublicKey {
+        self.inner.public()
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Result<Signature, secp256k1::Error> {
+        self.inner.sign(message)
+    }
+
+    pub fn to_crypto(
+        &self,
+        password: &Protected,
+        iterations: u32,
+    ) -> Result<ethsign::keyfile::Crypto, ethsign::Error> {
+        self.inner.to_crypto(password, iterations)
+    }
+}
+
+impl std::fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecretKey(0x{})", hex::encode(self.public().address()))
+    }
+}
+
 pub fn generate() -> SecretKey {
     let random_bytes: [u8; 32] = rand::thread_rng().gen();
     SecretKey::from_raw(random_bytes.as_ref()).unwrap()
 }
 
-pub fn load_or_generate(path: &str, password: Option<Protected>) -> SecretKey {
-    log::debug!("load_or_generate({}, {:?})", path, &password);
+pub fn load_or_generate(
+    path: impl AsRef<Path>,
+    password: Option<Protected>,
+) -> Result<SecretKey, KeyError> {
+    let path = path.as_ref();
+    log::debug!("load_or_generate({}, {:?})", path.display(), &password);
 
     // Default password = "", only use for testing purposes!
     let password = password.unwrap_or_else(|| Protected::from("".to_string()));
 
-    if let Some(secret) = try_load_from_file(path, &password) {
-        log::info!("Loaded key. path={}", path);
-        return secret;
+    match load_from_file(path, &password) {
+        Ok(secret) => {
+            log::info!("Loaded key. path={}", path.display());
+            return Ok(secret);
+        }
+        Err(KeyError::NotFound(_)) => (),
+        Err(e) => return Err(e),
     }
     // File does not exist, create new key
     let secret = generate();
-    save_to_file(path, &secret, &password);
+    save_to_file(path, &secret, &password)?;
 
-    log::info!("Generated new key. path={}", path);
-    secret
+    log::info!("Generated new key. path={}", path.display());
+    Ok(secret)
 }
 
-fn try_load_from_file(path: &str, password: &Protected) -> Option<SecretKey> {
-    if let Ok(file) = File::open(path) {
-        // Broken keyfile should panic
-        let key: KeyFile = serde_json::from_reader(file).unwrap();
-        // Invalid password should panic
-        let secret = key.to_secret_key(password).unwrap();
-
-        return Some(secret);
-    }
-    None
+/// Loads and unlocks V3 keyfile.
+pub fn load_from_file(path: impl AsRef<Path>, password: &Protected) -> Result<SecretKey, KeyError> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| KeyError::io(path, e))?;
+    let key: KeyFile = serde_json::from_reader(file).map_err(|e| KeyError::Corrupt {
+        path: path.to_path_buf(),
+        reason: e.to_string(),
+    })?;
+    let corrupt = |reason: String| KeyError::Corrupt {
+        path: path.to_path_buf(),
+        reason,
+    };
+    let raw = key
+        .crypto
+        .decrypt(password)
+        .map(Protected::new)
+        .map_err(|e| match e {
+            ethsign::Error::InvalidPassword => KeyError::InvalidPassword(path.to_path_buf()),
+            e => corrupt(e.to_string()),
+        })?;
+    SecretKey::from_raw(raw.as_ref()).map_err(|e| corrupt(e.to_string()))
 }
 
-fn save_to_file(path: &str, secret: &SecretKey, password: &Protected) {
-    let mut file = File::create(path).unwrap();
+/// Saves key encrypted with `password` as V3 keyfile.
+pub fn save_to_file(
+    path: impl AsRef<Path>,
+    secret: &SecretKey,
+    password: &Protected,
+) -> Result<(), KeyError> {
+    let path = path.as_ref();
     let key_file = KeyFile {
         id: format!("{}", uuid::Uuid::new_v4()),
         version: KEYSTORE_VERSION,
-        crypto: secret.to_crypto(password, KEY_ITERATIONS).unwrap(),
+        crypto: secret.to_crypto(password, KEY_ITERATIONS)?,
         address: Some(Bytes(secret.public().address().to_vec())),
     };
-    let pretty_key_file_str = serde_json::to_string_pretty(&key_file).unwrap();
-    file.write_all(pretty_key_file_str.as_ref()).unwrap();
+    let pretty_key_file_str =
+        serde_json::to_string_pretty(&key_file).map_err(|e| KeyError::Io {
+            path: path.to_path_buf(),
+            source: e.into(),
+        })?;
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // Keyfile is readable only by its owner.
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path).map_err(|e| KeyError::io(path, e))?;
+    // Mode applies only to created files, existing ones may be readable by others.
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))
+        .map_err(|e| KeyError::io(path, e))?;
+    file.write_all(pretty_key_file_str.as_ref())
+        .map_err(|e| KeyError::io(path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key.json");
+        let password = Protected::from("secret");
+
+        assert!(matches!(
+            load_from_file(&path, &password),
+            Err(KeyError::NotFound(_))
+        ));
+
+        let secret = load_or_generate(&path, Some(password.clone())).unwrap();
+        let loaded = load_or_generate(&path, Some(password.clone())).unwrap();
+        assert_eq!(secret.public().address(), loaded.public().address());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+
+            // Overwritten keyfile is restricted as well.
+            let permissions = std::fs::Permissions::from_mode(0o644);
+            std::fs::set_permissions(&path, permissions).unwrap();
+            save_to_file(&path, &secret, &password).unwrap();
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        assert!(matches!(
+            load_or_generate(&path, Some(Protected::from("other"))),
+            Err(KeyError::InvalidPassword(_))
+        ));
+
+        std::fs::write(&path, b"{\"version\": 3").unwrap();
+        assert!(matches!(
+            load_from_file(&path, &password),
+            Err(KeyError::Corrupt { .. })
+        ));
+    }
 }
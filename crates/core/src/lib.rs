@@ -2,11 +2,13 @@ pub mod challenge;
 pub mod crypto;
 pub mod dispatch;
 pub mod error;
+pub mod faults;
 pub mod identity;
 pub mod key;
 pub mod server_session;
 pub mod session;
 pub mod sync;
+pub mod tcp_stream;
 #[cfg(feature = "test-utils")]
 pub mod testing;
 pub mod udp_stream;
@@ -84,7 +84,11 @@ impl Session {
 
         let response = self
             .request::<proto::response::Register>(
-                proto::request::Register { endpoints }.into(),
+                proto::request::Register {
+                    endpoints,
+                    hole_punching: false,
+                }
+                .into(),
                 self.id.to_vec(),
                 DEFAULT_REQUEST_TIMEOUT,
             )
@@ -0,0 +1,183 @@
+//! TCP transport between Node and relay server, used when UDP traffic is blocked.
+//!
+//! Packets are framed by `ya_relay_proto::codec::stream`: each one is prefixed with its size
+//! and contains exactly the same bytes as a UDP datagram would, so relay handles both
+//! transports the same way.
+
+use chrono::Utc;
+use futures::channel::mpsc;
+use futures::prelude::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+use tokio::io::AsyncRead;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc as tokio_mpsc;
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+use ya_relay_proto::codec::stream::{DecoderStream, EncoderSink};
+use ya_relay_proto::codec::{Error, PacketKind, MAX_PACKET_SIZE};
+
+use crate::udp_stream::{InStream, OutStream};
+
+/// Decodes packets received over TCP. Unlike `DecoderStream::from`, it accepts packets
+/// as large as datagrams and yields only complete `Forward` packets.
+pub fn packet_stream<R: AsyncRead + Unpin>(
+    read: R,
+) -> DecoderStream<FramedRead<R, BytesCodec>, std::io::Error> {
+    DecoderStream::with(
+        FramedRead::new(read, BytesCodec::new()),
+        MAX_PACKET_SIZE as usize,
+    )
+    .complete_forwards()
+}
+
+/// Packets waiting to be written to a single TCP connection. Packets sent to a relay,
+/// which doesn't keep up with reading them, are dropped as if they were lost datagrams.
+const WRITE_QUEUE_SIZE: usize = 256;
+
+type InItem = (PacketKind, SocketAddr, chrono::DateTime<Utc>);
+
+/// TCP connections to relay servers. Packets addressed to a relay with established
+/// TCP connection are sent through it instead of UDP socket.
+#[derive(Clone)]
+pub struct TcpConnections {
+    connections: Rc<RefCell<HashMap<SocketAddr, Rc<tokio_mpsc::Sender<PacketKind>>>>>,
+    ingress: mpsc::UnboundedSender<InItem>,
+}
+
+/// Wraps UDP streams, so they can be used with `TcpConnections`.
+pub fn with_tcp(stream: InStream, sink: OutStream) -> (InStream, OutStream, TcpConnections) {
+    let (ingress, ingress_rx) = mpsc::unbounded();
+    let connections = TcpConnections {
+        connections: Default::default(),
+        ingress,
+    };
+
+    let (tx, mut rx) = mpsc::channel::<(PacketKind, SocketAddr)>(100);
+    tokio::task::spawn_local({
+        let connections = connections.clone();
+        let mut sink = sink;
+        async move {
+            while let Some((packet, addr)) = rx.next().await {
+                let packet = match connections.send(packet, addr) {
+                    Some(packet) => packet,
+                    None => continue,
+                };
+                if sink.send((packet, addr)).await.is_err() {
+                    break;
+                }
+            }
+            connections.close();
+            sink.close().await.ok();
+        }
+    });
+
+    let stream = Box::pin(stream::select(stream, ingress_rx));
+    (stream, tx, connections)
+}
+
+impl TcpConnections {
+    pub fn is_connected(&self, addr: &SocketAddr) -> bool {
+        self.connections.borrow().contains_key(addr)
+    }
+
+    /// Connects to the relay. All subsequent traffic to `addr` goes through this connection,
+    /// until it is closed.
+    pub async fn connect(&self, addr: SocketAddr, timeout: Duration) -> anyhow::Result<()> {
+        if self.is_connected(&addr) {
+            return Ok(());
+        }
+
+        log::info!("Connecting to relay {addr} over TCP");
+
+        let stream = tokio::time::timeout(timeout, TcpStream::connect(addr))
+            .await
+            .map_err(|_| anyhow::anyhow!("TCP connection to {addr} timed out"))??;
+        stream.set_nodelay(true)?;
+        let (read, write) = stream.into_split();
+
+        let (tx, mut rx) = tokio_mpsc::channel::<PacketKind>(WRITE_QUEUE_SIZE);
+        let tx = Rc::new(tx);
+        // Writer stops after the sender is removed, so the reader can't keep it alive.
+        let weak_tx = Rc::downgrade(&tx);
+        self.connections.borrow_mut().insert(addr, tx);
+
+        tokio::task::spawn_local(async move {
+            let mut sink = EncoderSink::from(write);
+
+            while let Some(packet) = rx.recv().await {
+                match sink.send(packet).await {
+                    Ok(()) => (),
+                    Err(Error::Io(e)) => {
+                        log::debug!("TCP connection to {addr} failed: {e}");
+                        break;
+                    }
+                    Err(e) => log::warn!("Error encoding packet for: {addr}. Error: {e}"),
+                }
+            }
+        });
+
+        let myself = self.clone();
+        tokio::task::spawn_local(async move {
+            let mut packets = packet_stream(read);
+
+            while let Some(packet) = packets.next().await {
+                match packet {
+                    Ok(packet) => {
+                        if myself
+                            .ingress
+                            .unbounded_send((packet, addr, Utc::now()))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    Err(Error::Io(e)) => {
+                        log::debug!("TCP connection to {addr} failed: {e}");
+                        break;
+                    }
+                    Err(e) => log::warn!("Failed to decode packet from: {addr}. Error: {e}"),
+                }
+            }
+
+            log::info!("TCP connection to relay {addr} closed");
+            myself.remove(addr, &weak_tx);
+        });
+
+        Ok(())
+    }
+
+    /// Closes all connections, after sending already queued packets.
+    pub fn close(&self) {
+        self.connections.borrow_mut().clear();
+    }
+
+    /// Returns packet back, if there is no connection to `addr`.
+    fn send(&self, packet: PacketKind, addr: SocketAddr) -> Option<PacketKind> {
+        match self.connections.borrow().get(&addr) {
+            Some(tx) => match tx.try_send(packet) {
+                Ok(()) => None,
+                Err(tokio_mpsc::error::TrySendError::Full(_)) => {
+                    log::trace!("TCP connection to {addr} is congested, dropping packet");
+                    None
+                }
+                Err(tokio_mpsc::error::TrySendError::Closed(packet)) => Some(packet),
+            },
+            None => Some(packet),
+        }
+    }
+
+    fn remove(&self, addr: SocketAddr, tx: &Weak<tokio_mpsc::Sender<PacketKind>>) {
+        let mut connections = self.connections.borrow_mut();
+        if connections
+            .get(&addr)
+            .map(|current| Rc::as_ptr(current) == tx.as_ptr())
+            .unwrap_or(false)
+        {
+            connections.remove(&addr);
+        }
+    }
+}
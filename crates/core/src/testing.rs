@@ -2,6 +2,8 @@ use futures::future::LocalBoxFuture;
 use url::Url;
 use ya_client_model::NodeId;
 
+pub mod sim;
+
 pub trait TestServerWrapper<'a> {
     fn url(&self) -> Url;
 
@@ -0,0 +1,635 @@
+//! In-process network for tests, standing in for UDP sockets bound on its addresses.
+//!
+//! Hosts are either public or placed behind NAT. Packets pass through links of both hosts,
+//! which can delay, lose or reorder them, and can be cut off by partitions. Random decisions
+//! come from the seeded generator and delivery is driven by tokio timers, so scenarios are
+//! repeatable and can run on virtual time (`tokio::time::pause`).
+//!
+//! Network is passed explicitly to its users: clients get it as their `UdpBinder`
+//! and the relay server binds on it, when it is set in the server config.
+use chrono::Utc;
+use futures::future::LocalBoxFuture;
+use futures::prelude::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+use tokio::time::Instant;
+use tokio_util::codec::{Decoder, Encoder};
+use url::Url;
+
+use ya_relay_proto::codec::datagram::Codec;
+use ya_relay_proto::codec::BytesMut;
+
+use crate::faults::REORDER_DELAY;
+use crate::udp_stream::{InStream, OutStream, UdpBinder};
+use crate::utils::parse_udp_url;
+
+/// First port assigned to sockets bound on port 0 and to NAT mappings.
+const EPHEMERAL_PORT: u16 = 40000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NatType {
+    /// Host has public address.
+    None,
+    /// Local address is mapped to the same public port for all destinations.
+    /// Packets from any remote address are passed through.
+    FullCone,
+    /// Local address is mapped to the same public port for all destinations, but packets
+    /// are passed through only from remote addresses, to which the host sent something.
+    PortRestricted,
+    /// Each destination gets separate mapping, which accepts packets only from it.
+    Symmetric,
+}
+
+/// Conditions on the link between host and the network.
+#[derive(Clone, Debug, Default)]
+pub struct LinkConfig {
+    pub latency: Duration,
+    /// Probability of losing a packet.
+    pub loss: f64,
+    /// Probability of delaying a packet by `REORDER_DELAY`, so packets sent after it overtake it.
+    pub reorder: f64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SimStats {
+    /// Packets scheduled for delivery.
+    pub sent: usize,
+    /// Packets lost on links, blocked by NAT or partitions, or sent to unknown addresses.
+    pub dropped: usize,
+}
+
+/// Simulated network. Cloning returns handle to the same network.
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+/// Host in the simulated network.
+#[derive(Clone)]
+pub struct SimHost {
+    ip: IpAddr,
+    net: SimNetwork,
+}
+
+/// Socket bound on address of simulated host.
+pub struct SimSocket {
+    addr: SocketAddr,
+    net: SimNetwork,
+    inbox: AsyncMutex<Inbox>,
+}
+
+struct NetworkState {
+    rng: StdRng,
+    hosts: HashMap<IpAddr, HostState>,
+    /// Local addresses of hosts behind NAT by their public addresses.
+    gateways: HashMap<IpAddr, IpAddr>,
+    sockets: HashMap<SocketAddr, mpsc::UnboundedSender<Datagram>>,
+    partitions: HashSet<(IpAddr, IpAddr)>,
+    seq: u64,
+    stats: SimStats,
+}
+
+struct HostState {
+    nat: NatType,
+    public_ip: IpAddr,
+    link: LinkConfig,
+    online: bool,
+    next_port: u16,
+    /// Public ports of NAT mappings by local address and, for symmetric NAT, destination.
+    mappings: HashMap<(SocketAddr, Option<SocketAddr>), u16>,
+    ports: HashMap<u16, (SocketAddr, Option<SocketAddr>)>,
+    /// Remote addresses allowed to send to public ports of port restricted NAT.
+    allowed: HashSet<(u16, SocketAddr)>,
+}
+
+struct Inbox {
+    rx: mpsc::UnboundedReceiver<Datagram>,
+    queue: BinaryHeap<Reverse<Datagram>>,
+}
+
+struct Datagram {
+    at: Instant,
+    seq: u64,
+    data: Vec<u8>,
+    from: SocketAddr,
+}
+
+impl SimNetwork {
+    pub fn new(seed: u64) -> Self {
+        let state = Arc::new(Mutex::new(NetworkState {
+            rng: StdRng::seed_from_u64(seed),
+            hosts: Default::default(),
+            gateways: Default::default(),
+            sockets: Default::default(),
+            partitions: Default::default(),
+            seq: 0,
+            stats: Default::default(),
+        }));
+        SimNetwork { state }
+    }
+
+    /// Adds host with public address 198.51.100.x, or with local address 192.168.x.2
+    /// behind NAT with public address 203.0.113.x.
+    pub fn add_host(&self, nat: NatType) -> SimHost {
+        let mut state = self.state();
+        let idx = u8::try_from(state.hosts.len() + 1).expect("too many simulated hosts");
+        let (ip, public_ip) = match nat {
+            NatType::None => {
+                let ip = IpAddr::V4(Ipv4Addr::new(198, 51, 100, idx));
+                (ip, ip)
+            }
+            _ => (
+                IpAddr::V4(Ipv4Addr::new(192, 168, idx, 2)),
+                IpAddr::V4(Ipv4Addr::new(203, 0, 113, idx)),
+            ),
+        };
+
+        state.hosts.insert(
+            ip,
+            HostState {
+                nat,
+                public_ip,
+                link: Default::default(),
+                online: true,
+                next_port: EPHEMERAL_PORT,
+                mappings: Default::default(),
+                ports: Default::default(),
+                allowed: Default::default(),
+            },
+        );
+        if nat != NatType::None {
+            state.gateways.insert(public_ip, ip);
+        }
+
+        SimHost {
+            ip,
+            net: self.clone(),
+        }
+    }
+
+    /// Cuts off communication between hosts in both directions.
+    pub fn partition(&self, a: &SimHost, b: &SimHost) {
+        self.state().partitions.insert(pair(a.ip, b.ip));
+    }
+
+    pub fn heal(&self, a: &SimHost, b: &SimHost) {
+        self.state().partitions.remove(&pair(a.ip, b.ip));
+    }
+
+    pub fn stats(&self) -> SimStats {
+        self.state().stats
+    }
+
+    fn state(&self) -> MutexGuard<'_, NetworkState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Binds socket on address of one of the hosts. Port 0 picks a free port.
+    pub fn bind_socket(&self, addr: SocketAddr) -> io::Result<SimSocket> {
+        let mut state = self.state();
+        let NetworkState { hosts, sockets, .. } = &mut *state;
+        let host = hosts
+            .get_mut(&addr.ip())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+
+        let addr = match addr.port() {
+            0 => loop {
+                let addr = SocketAddr::new(addr.ip(), host.alloc_port());
+                if !sockets.contains_key(&addr) {
+                    break addr;
+                }
+            },
+            _ if sockets.contains_key(&addr) => {
+                return Err(io::Error::from(io::ErrorKind::AddrInUse))
+            }
+            _ => addr,
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        sockets.insert(addr, tx);
+        Ok(SimSocket {
+            addr,
+            net: self.clone(),
+            inbox: AsyncMutex::new(Inbox {
+                rx,
+                queue: Default::default(),
+            }),
+        })
+    }
+}
+
+impl SimHost {
+    /// Address, on which sockets of the host are bound.
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    /// Address seen by other hosts. Differs from `ip` for hosts behind NAT.
+    pub fn public_ip(&self) -> IpAddr {
+        self.net.state().hosts[&self.ip].public_ip
+    }
+
+    pub fn network(&self) -> &SimNetwork {
+        &self.net
+    }
+
+    /// Url for binding a client on the host.
+    pub fn bind_url(&self) -> Url {
+        format!("udp://{}:0", self.ip).parse().unwrap()
+    }
+
+    pub fn set_link(&self, link: LinkConfig) {
+        self.with_state(|host| host.link = link);
+    }
+
+    /// Disconnects host from the network or brings it back.
+    pub fn set_online(&self, online: bool) {
+        self.with_state(|host| host.online = online);
+    }
+
+    fn with_state(&self, f: impl FnOnce(&mut HostState)) {
+        let mut state = self.net.state();
+        if let Some(host) = state.hosts.get_mut(&self.ip) {
+            f(host);
+        }
+    }
+}
+
+impl SimSocket {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn network(&self) -> &SimNetwork {
+        &self.net
+    }
+
+    pub fn send_to(&self, data: &[u8], dst: SocketAddr) -> io::Result<usize> {
+        self.net.state().send(self.addr, dst, data.to_vec());
+        Ok(data.len())
+    }
+
+    /// Returns the next packet, which reached its delivery time.
+    pub async fn recv_from(&self) -> io::Result<(Vec<u8>, SocketAddr)> {
+        let mut inbox = self.inbox.lock().await;
+        let Inbox { rx, queue } = &mut *inbox;
+
+        loop {
+            while let Ok(datagram) = rx.try_recv() {
+                queue.push(Reverse(datagram));
+            }
+
+            let next = match queue.peek() {
+                Some(Reverse(datagram)) if datagram.at <= Instant::now() => {
+                    let datagram = queue.pop().unwrap().0;
+                    return Ok((datagram.data, datagram.from));
+                }
+                Some(Reverse(datagram)) => Some(datagram.at),
+                None => None,
+            };
+
+            let received = match next {
+                Some(at) => tokio::select! {
+                    received = rx.recv() => received,
+                    _ = tokio::time::sleep_until(at) => continue,
+                },
+                None => rx.recv().await,
+            };
+            match received {
+                Some(datagram) => queue.push(Reverse(datagram)),
+                None => return Err(io::Error::from(io::ErrorKind::NotConnected)),
+            }
+        }
+    }
+}
+
+impl Drop for SimSocket {
+    fn drop(&mut self) {
+        self.net.state().sockets.remove(&self.addr);
+    }
+}
+
+impl UdpBinder for SimNetwork {
+    fn bind<'a>(
+        &self,
+        addr: &'a Url,
+    ) -> LocalBoxFuture<'a, anyhow::Result<(InStream, OutStream, SocketAddr)>> {
+        let net = self.clone();
+        async move {
+            let addr = parse_udp_url(addr)?.parse()?;
+            Ok(split(net.bind_socket(addr)?))
+        }
+        .boxed_local()
+    }
+
+    /// Addresses of the simulated network never change.
+    fn route<'a>(
+        &self,
+        bind: SocketAddr,
+        _remote: SocketAddr,
+    ) -> LocalBoxFuture<'a, Option<IpAddr>> {
+        future::ready(Some(bind.ip())).boxed_local()
+    }
+}
+
+/// Wraps socket into streams returned by `UdpBinder::bind`.
+fn split(socket: SimSocket) -> (InStream, OutStream, SocketAddr) {
+    let socket = Arc::new(socket);
+    let addr = socket.local_addr();
+
+    let stream = stream::unfold(socket.clone(), |socket| async move {
+        loop {
+            let (data, from) = socket.recv_from().await.ok()?;
+            match Codec.decode(&mut BytesMut::from(data.as_slice())) {
+                Ok(Some(packet)) => return Some(((packet, from, Utc::now()), socket)),
+                _ => log::warn!("Failed to decode simulated packet from: {from}"),
+            }
+        }
+    });
+
+    let (tx, mut rx) = futures::channel::mpsc::channel(100);
+    tokio::task::spawn_local(async move {
+        let mut buf = BytesMut::new();
+        while let Some((packet, dst)) = rx.next().await {
+            buf.clear();
+            match Codec.encode(packet, &mut buf) {
+                Ok(()) => {
+                    socket.send_to(&buf, dst).ok();
+                }
+                Err(e) => log::warn!("Error encoding packet for: {dst}. Error: {e}"),
+            }
+        }
+    });
+
+    (Box::pin(stream), tx, addr)
+}
+
+impl NetworkState {
+    fn send(&mut self, src: SocketAddr, dst: SocketAddr, data: Vec<u8>) {
+        let route = self
+            .route(src, dst)
+            .and_then(|(from, to, delay)| Some((from, self.sockets.get(&to)?.clone(), delay)));
+
+        if let Some((from, tx, delay)) = route {
+            self.seq += 1;
+            let datagram = Datagram {
+                at: Instant::now() + delay,
+                seq: self.seq,
+                data,
+                from,
+            };
+            if tx.send(datagram).is_ok() {
+                self.stats.sent += 1;
+                return;
+            }
+        }
+        self.stats.dropped += 1;
+    }
+
+    /// Source address seen by the receiver, local destination address and delay
+    /// of the packet, unless it doesn't reach the destination.
+    fn route(
+        &mut self,
+        src: SocketAddr,
+        dst: SocketAddr,
+    ) -> Option<(SocketAddr, SocketAddr, Duration)> {
+        let dst_ip = self.gateways.get(&dst.ip()).copied().unwrap_or(dst.ip());
+        if self.partitions.contains(&pair(src.ip(), dst_ip)) {
+            return None;
+        }
+
+        let links = {
+            let (src_host, dst_host) = (self.hosts.get(&src.ip())?, self.hosts.get(&dst_ip)?);
+            if !src_host.online || !dst_host.online {
+                return None;
+            }
+            [src_host.link.clone(), dst_host.link.clone()]
+        };
+
+        let from = self.hosts.get_mut(&src.ip())?.map_outgoing(src, dst);
+        let to = self.hosts.get(&dst_ip)?.map_incoming(from, dst)?;
+
+        let mut delay = Duration::ZERO;
+        for link in links {
+            if self.rng.gen_bool(link.loss.clamp(0., 1.)) {
+                return None;
+            }
+            delay += link.latency;
+            if self.rng.gen_bool(link.reorder.clamp(0., 1.)) {
+                delay += REORDER_DELAY;
+            }
+        }
+        Some((from, to, delay))
+    }
+}
+
+impl HostState {
+    fn alloc_port(&mut self) -> u16 {
+        let port = self.next_port;
+        self.next_port = self.next_port.checked_add(1).unwrap_or(EPHEMERAL_PORT);
+        port
+    }
+
+    fn map_outgoing(&mut self, src: SocketAddr, dst: SocketAddr) -> SocketAddr {
+        let key = match self.nat {
+            NatType::None => return src,
+            NatType::FullCone | NatType::PortRestricted => (src, None),
+            NatType::Symmetric => (src, Some(dst)),
+        };
+
+        let port = match self.mappings.get(&key) {
+            Some(port) => *port,
+            None => {
+                let port = self.alloc_port();
+                self.mappings.insert(key, port);
+                self.ports.insert(port, key);
+                port
+            }
+        };
+        if self.nat == NatType::PortRestricted {
+            self.allowed.insert((port, dst));
+        }
+        SocketAddr::new(self.public_ip, port)
+    }
+
+    fn map_incoming(&self, from: SocketAddr, dst: SocketAddr) -> Option<SocketAddr> {
+        match self.nat {
+            NatType::None => return Some(dst),
+            NatType::PortRestricted if !self.allowed.contains(&(dst.port(), from)) => return None,
+            _ => (),
+        }
+
+        match self.ports.get(&dst.port())? {
+            (_, Some(remote)) if *remote != from => None,
+            (local, _) => Some(*local),
+        }
+    }
+}
+
+impl PartialEq for Datagram {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Datagram {}
+
+impl PartialOrd for Datagram {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Datagram {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+fn pair(a: IpAddr, b: IpAddr) -> (IpAddr, IpAddr) {
+    (a.min(b), a.max(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn recv(socket: &SimSocket) -> Option<(Vec<u8>, SocketAddr)> {
+        tokio::time::timeout(Duration::from_secs(1), socket.recv_from())
+            .await
+            .ok()?
+            .ok()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_nat_mappings() {
+        let net = SimNetwork::new(0);
+        let server = net.add_host(NatType::None);
+        let full_cone = net.add_host(NatType::FullCone);
+        let symmetric = net.add_host(NatType::Symmetric);
+        let restricted = net.add_host(NatType::PortRestricted);
+
+        let srv1 = net.bind_socket(SocketAddr::new(server.ip(), 7000)).unwrap();
+        let srv2 = net.bind_socket(SocketAddr::new(server.ip(), 0)).unwrap();
+        let a = net.bind_socket(SocketAddr::new(full_cone.ip(), 0)).unwrap();
+        let b = net.bind_socket(SocketAddr::new(symmetric.ip(), 0)).unwrap();
+        let c = net
+            .bind_socket(SocketAddr::new(restricted.ip(), 0))
+            .unwrap();
+        assert!(net.bind_socket("127.0.0.1:7000".parse().unwrap()).is_err());
+
+        // Mapping of full cone NAT accepts packets from anyone.
+        a.send_to(b"a", srv1.local_addr()).unwrap();
+        let (data, a_public) = recv(&srv1).await.unwrap();
+        assert_eq!(data, b"a");
+        assert_eq!(a_public.ip(), full_cone.public_ip());
+        srv2.send_to(b"probe", a_public).unwrap();
+        assert_eq!(
+            recv(&a).await.unwrap(),
+            (b"probe".to_vec(), srv2.local_addr())
+        );
+
+        // Mapping of symmetric NAT accepts packets only from the destination.
+        b.send_to(b"b", srv1.local_addr()).unwrap();
+        let (_, b_public) = recv(&srv1).await.unwrap();
+        srv2.send_to(b"probe", b_public).unwrap();
+        assert!(recv(&b).await.is_none());
+        srv1.send_to(b"reply", b_public).unwrap();
+        assert_eq!(recv(&b).await.unwrap().0, b"reply");
+
+        // Symmetric NAT uses other port for other destination.
+        b.send_to(b"b", srv2.local_addr()).unwrap();
+        assert_ne!(recv(&srv2).await.unwrap().1, b_public);
+
+        // Port restricted NAT keeps the mapping, but accepts packets only from
+        // addresses, which the host sent to before.
+        c.send_to(b"c", srv1.local_addr()).unwrap();
+        let (_, c_public) = recv(&srv1).await.unwrap();
+        srv2.send_to(b"probe", c_public).unwrap();
+        assert!(recv(&c).await.is_none());
+        c.send_to(b"c", srv2.local_addr()).unwrap();
+        assert_eq!(recv(&srv2).await.unwrap().1, c_public);
+        srv2.send_to(b"probe", c_public).unwrap();
+        assert_eq!(recv(&c).await.unwrap().0, b"probe");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_links() {
+        let net = SimNetwork::new(0);
+        let host1 = net.add_host(NatType::None);
+        let host2 = net.add_host(NatType::None);
+        let s1 = net.bind_socket(SocketAddr::new(host1.ip(), 0)).unwrap();
+        let s2 = net.bind_socket(SocketAddr::new(host2.ip(), 0)).unwrap();
+
+        host1.set_link(LinkConfig {
+            latency: Duration::from_millis(100),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        s1.send_to(b"1", s2.local_addr()).unwrap();
+        recv(&s2).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+
+        net.partition(&host1, &host2);
+        s1.send_to(b"2", s2.local_addr()).unwrap();
+        assert!(recv(&s2).await.is_none());
+        net.heal(&host1, &host2);
+
+        host2.set_online(false);
+        s1.send_to(b"3", s2.local_addr()).unwrap();
+        assert!(recv(&s2).await.is_none());
+        host2.set_online(true);
+        assert_eq!(
+            net.stats(),
+            SimStats {
+                sent: 1,
+                dropped: 2
+            }
+        );
+
+        host2.set_link(LinkConfig {
+            reorder: 1.,
+            ..Default::default()
+        });
+        s2.send_to(b"4", s1.local_addr()).unwrap();
+        host2.set_link(Default::default());
+        s2.send_to(b"5", s1.local_addr()).unwrap();
+        assert_eq!(recv(&s1).await.unwrap().0, b"5");
+        assert_eq!(recv(&s1).await.unwrap().0, b"4");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_loss_is_deterministic() {
+        async fn received(seed: u64) -> Vec<Vec<u8>> {
+            let net = SimNetwork::new(seed);
+            let host = net.add_host(NatType::None);
+            host.set_link(LinkConfig {
+                loss: 0.5,
+                ..Default::default()
+            });
+            let s1 = net.bind_socket(SocketAddr::new(host.ip(), 0)).unwrap();
+            let s2 = net.bind_socket(SocketAddr::new(host.ip(), 0)).unwrap();
+            for i in 0..32u8 {
+                s1.send_to(&[i], s2.local_addr()).unwrap();
+            }
+
+            let mut received = Vec::new();
+            while let Some((data, _)) = recv(&s2).await {
+                received.push(data);
+            }
+            received
+        }
+
+        let first = received(7).await;
+        assert!(!first.is_empty() && first.len() < 32);
+        assert_eq!(first, received(7).await);
+    }
+}
@@ -1,9 +1,10 @@
 use anyhow::bail;
 use chrono::Utc;
 use futures::channel::mpsc;
+use futures::future::LocalBoxFuture;
 use futures::prelude::*;
 use metrics::counter;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
 use std::sync::Arc;
 use tokio::net::UdpSocket;
@@ -22,8 +23,42 @@ pub type InStream =
     Pin<Box<dyn Stream<Item = (PacketKind, SocketAddr, chrono::DateTime<chrono::Utc>)>>>;
 pub type OutStream = mpsc::Sender<(PacketKind, SocketAddr)>;
 
+/// Source of UDP sockets used by the client. Tests replace it with the simulated
+/// network (`testing::sim::SimNetwork`).
+pub trait UdpBinder {
+    fn bind<'a>(
+        &self,
+        addr: &'a url::Url,
+    ) -> LocalBoxFuture<'a, anyhow::Result<(InStream, OutStream, SocketAddr)>>;
+    /// Local IP address used for sending packets from `bind` to `remote`.
+    fn route<'a>(&self, bind: SocketAddr, remote: SocketAddr)
+        -> LocalBoxFuture<'a, Option<IpAddr>>;
+}
+
+/// Binds sockets of the operating system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OsUdpBinder;
+
+impl UdpBinder for OsUdpBinder {
+    fn bind<'a>(
+        &self,
+        addr: &'a url::Url,
+    ) -> LocalBoxFuture<'a, anyhow::Result<(InStream, OutStream, SocketAddr)>> {
+        udp_bind(addr).boxed_local()
+    }
+
+    fn route<'a>(
+        &self,
+        bind: SocketAddr,
+        remote: SocketAddr,
+    ) -> LocalBoxFuture<'a, Option<IpAddr>> {
+        udp_route(bind, remote).boxed_local()
+    }
+}
+
 pub async fn udp_bind(addr: &url::Url) -> anyhow::Result<(InStream, OutStream, SocketAddr)> {
-    let sock = Arc::new(UdpSocket::bind(&parse_udp_url(addr)?).await?);
+    let addr = parse_udp_url(addr)?;
+    let sock = Arc::new(UdpSocket::bind(&addr).await?);
     let addr = sock.local_addr()?;
 
     log::info!("Server listening on: {}", addr);
@@ -34,6 +69,18 @@ pub async fn udp_bind(addr: &url::Url) -> anyhow::Result<(InStream, OutStream, S
     Ok((stream, sink, addr))
 }
 
+/// Local IP address chosen by the OS for sending packets from `bind` to `remote`.
+pub async fn udp_route(bind: SocketAddr, remote: SocketAddr) -> Option<IpAddr> {
+    if !bind.ip().is_unspecified() {
+        return Some(bind.ip());
+    }
+
+    // Connecting UDP socket doesn't send anything, only resolves route.
+    let socket = UdpSocket::bind(SocketAddr::new(bind.ip(), 0)).await.ok()?;
+    socket.connect(remote).await.ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
 pub fn udp_stream(
     socket: Arc<UdpSocket>,
 ) -> impl Stream<Item = (PacketKind, SocketAddr, chrono::DateTime<chrono::Utc>)> {
@@ -55,6 +55,11 @@ impl Decoder for Codec {
     }
 }
 
+/// Checks, whether encoded datagram carries `Forward` packet, without decoding it.
+pub fn is_forward(buf: &[u8]) -> bool {
+    matches!(peek_tag(buf), Ok(Some(1)))
+}
+
 #[cfg(test)]
 mod tests {
     use bytes::BytesMut;
@@ -79,6 +79,13 @@ where
     }
 }
 
+/// Prefixes already encoded datagram with its size, the same way `EncoderSink` does.
+pub fn encode_datagram(datagram: &[u8], dst: &mut BytesMut) -> Result<(), Error> {
+    reserve_and_encode(dst, datagram.len())?;
+    dst.extend_from_slice(datagram);
+    Ok(())
+}
+
 #[inline]
 fn reserve_and_encode(dst: &mut BytesMut, len: usize) -> Result<(), Error> {
     dst.reserve(prost::length_delimiter_len(len) + len);
@@ -94,6 +101,7 @@ where
     state: State,
     buf: BytesMut,
     limit: usize,
+    complete_forwards: bool,
 }
 
 impl<S, E> DecoderStream<S, E>
@@ -111,8 +119,16 @@ where
             state: State::AwaitingPrefix,
             buf: BytesMut::with_capacity(1024),
             limit,
+            complete_forwards: false,
         }
     }
+
+    /// Yields `Forward` packets after receiving them entirely, instead of splitting them
+    /// into `ForwardCtd` chunks. Then the stream carries the same packets as datagrams.
+    pub fn complete_forwards(mut self) -> Self {
+        self.complete_forwards = true;
+        self
+    }
 }
 
 impl<R> From<R> for DecoderStream<FramedRead<R, BytesCodec>, std::io::Error>
@@ -155,6 +171,44 @@ where
         if !self.buf.is_empty() {
             let max = self.limit;
             match std::mem::replace(self.state.borrow_mut(), State::Poisoned) {
+                State::AwaitingPrefix
+                    if self.complete_forwards && oversized_forward(&self.buf, max) =>
+                {
+                    // Drop it before buffering, limit is there to bound the memory use.
+                    let (total, off) = forward_size(&self.buf).unwrap();
+                    let available = total.min(self.buf.len() - off);
+                    let left = total - available;
+                    self.buf.advance(off + available);
+                    if left > 0 {
+                        self.transition(State::Discarding { left, read: 0 });
+                    } else {
+                        self.transition(State::AwaitingPrefix);
+                        self.maybe_wake(cx);
+                    }
+                    return Poll::Ready(Some(Err(PayloadTooLong { left }.into())));
+                }
+                State::AwaitingPrefix
+                    if self.complete_forwards && incomplete_forward(&self.buf) =>
+                {
+                    self.transition(State::AwaitingPrefix);
+                    // Reading in a loop, since recursion for every chunk of a large
+                    // packet could overflow the stack.
+                    loop {
+                        match Pin::new(&mut self.stream).poll_next(cx) {
+                            Poll::Ready(Some(Ok(bytes))) if !bytes.is_empty() => {
+                                self.buf.extend(bytes);
+                                if !incomplete_forward(&self.buf) {
+                                    return self.poll_next(cx);
+                                }
+                            }
+                            Poll::Ready(Some(Ok(_))) | Poll::Ready(None) => {
+                                return Poll::Ready(None)
+                            }
+                            Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e.into()))),
+                            Poll::Pending => return Poll::Pending,
+                        }
+                    }
+                }
                 State::AwaitingPrefix => match read_bytes(&mut self.buf, max) {
                     Ok(Some(bytes)) => {
                         self.transition(State::AwaitingPrefix);
@@ -245,6 +299,26 @@ where
     }
 }
 
+/// Size and prefix length of a size prefixed `Forward` packet.
+fn forward_size(buf: &[u8]) -> Option<(usize, usize)> {
+    match peek_size(buf) {
+        Ok((total, off)) if matches!(super::peek_tag(&buf[off..]), Ok(Some(1))) => {
+            Some((total, off))
+        }
+        _ => None,
+    }
+}
+
+/// Size prefixed `Forward` packet exceeds the limit.
+fn oversized_forward(buf: &[u8], max: usize) -> bool {
+    matches!(forward_size(buf), Some((total, _)) if total > max)
+}
+
+/// Size prefixed `Forward` packet is still being received.
+fn incomplete_forward(buf: &[u8]) -> bool {
+    matches!(forward_size(buf), Some((total, off)) if buf.len() < off + total)
+}
+
 enum State {
     AwaitingPrefix,
     /// Buffering a (small) message to parse
@@ -285,7 +359,7 @@ mod tests {
     use bytes::BytesMut;
     use futures::{SinkExt, StreamExt};
 
-    use crate::codec::stream::{DecoderStream, EncoderSink};
+    use crate::codec::stream::{encode_datagram, DecoderStream, EncoderSink};
     use crate::codec::*;
     use crate::proto::*;
 
@@ -363,6 +437,7 @@ mod tests {
                         address: "1.2.3.4".to_string(),
                         port: 12345,
                     }],
+                    hole_punching: false,
                 },
             )
             .into(),
@@ -393,6 +468,78 @@ mod tests {
         assert_eq!(packets, forward(packets.clone(), 64).await);
     }
 
+    #[tokio::test]
+    async fn receive_complete_forwards() {
+        let packets = vec![
+            PacketKind::Forward(Forward {
+                session_id: SESSION_ID,
+                slot: 42,
+                flags: 0,
+                payload: (0..8192).map(|_| rand::random::<u8>()).collect(),
+            }),
+            Packet::request(SESSION_ID.to_vec(), request::Ping {}).into(),
+        ];
+
+        let mut buf = BytesMut::new();
+        let mut codec = crate::codec::datagram::Codec;
+        for packet in packets.clone() {
+            let mut datagram = BytesMut::new();
+            tokio_util::codec::Encoder::encode(&mut codec, packet, &mut datagram).unwrap();
+            encode_datagram(&datagram, &mut buf).unwrap();
+        }
+
+        for chunk_size in [1, 7, 64, 1024] {
+            let chunks = buf
+                .chunks(chunk_size)
+                .map(|chunk| Ok::<_, Error>(BytesMut::from(chunk)))
+                .collect::<Vec<_>>();
+            let stream =
+                DecoderStream::with(futures::stream::iter(chunks), MAX_PACKET_SIZE as usize)
+                    .complete_forwards();
+            let received = stream.map(Result::unwrap).collect::<Vec<_>>().await;
+            assert_eq!(packets, received);
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_oversized_forwards() {
+        let limit = 1024;
+        let ping: PacketKind = Packet::request(SESSION_ID.to_vec(), request::Ping {}).into();
+        let packets = vec![
+            PacketKind::Forward(Forward {
+                session_id: SESSION_ID,
+                slot: 42,
+                flags: 0,
+                payload: (0..8192).map(|_| rand::random::<u8>()).collect(),
+            }),
+            ping.clone(),
+        ];
+
+        let mut buf = BytesMut::new();
+        let mut codec = crate::codec::datagram::Codec;
+        for packet in packets {
+            let mut datagram = BytesMut::new();
+            tokio_util::codec::Encoder::encode(&mut codec, packet, &mut datagram).unwrap();
+            encode_datagram(&datagram, &mut buf).unwrap();
+        }
+
+        for chunk_size in [64, 1024, buf.len()] {
+            let chunks = buf
+                .chunks(chunk_size)
+                .map(|chunk| Ok::<_, Error>(BytesMut::from(chunk)))
+                .collect::<Vec<_>>();
+            let mut stream =
+                DecoderStream::with(futures::stream::iter(chunks), limit).complete_forwards();
+
+            match stream.next().await {
+                Some(Err(Error::Decode(DecodeError::PayloadTooLong { .. }))) => {}
+                other => panic!("Expected oversized payload error, got {:?}", other),
+            }
+            assert_eq!(ping, stream.next().await.unwrap().unwrap());
+            assert!(stream.next().await.is_none());
+        }
+    }
+
     #[tokio::test]
     async fn receive_chunked_and_skip() {
         let random_node = PacketKind::Packet(Packet {
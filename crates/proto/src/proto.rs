@@ -24,6 +24,8 @@ pub const SESSION_ID_SIZE: usize = 16;
 pub const KEY_SIZE: usize = 1;
 pub const UNRELIABLE_FLAG: u16 = 0x01;
 pub const ENCRYPTED_FLAG: u16 = 0x02;
+/// Payload carries session key agreement instead of data.
+pub const KEY_EXCHANGE_FLAG: u16 = 0x04;
 
 static REQUEST_ID: AtomicU64 = AtomicU64::new(0);
 
@@ -80,12 +82,19 @@ impl Forward {
         self.flags & UNRELIABLE_FLAG != UNRELIABLE_FLAG
     }
 
+    #[inline]
     pub fn is_encrypted(&self) -> bool {
-        self.flags & ENCRYPTED_FLAG != ENCRYPTED_FLAG
+        self.flags & ENCRYPTED_FLAG == ENCRYPTED_FLAG
     }
 
+    #[inline]
     pub fn set_encrypted(&mut self) {
-        self.flags &= ENCRYPTED_FLAG
+        self.flags |= ENCRYPTED_FLAG
+    }
+
+    #[inline]
+    pub fn is_key_exchange(&self) -> bool {
+        self.flags & KEY_EXCHANGE_FLAG == KEY_EXCHANGE_FLAG
     }
 
     #[inline]
@@ -357,6 +366,9 @@ impl_convert_kind!(request, Node);
 impl_convert_kind!(request, Slot);
 impl_convert_kind!(request, Neighbours);
 impl_convert_kind!(request, ReverseConnection);
+impl_convert_kind!(request, HolePunch);
+impl_convert_kind!(request, Rebind);
+impl_convert_kind!(request, Identities);
 impl_convert_kind!(request, Ping);
 
 impl_convert_kind!(response, Session);
@@ -364,10 +376,42 @@ impl_convert_kind!(response, Register);
 impl_convert_kind!(response, Node);
 impl_convert_kind!(response, Neighbours);
 impl_convert_kind!(response, ReverseConnection);
+impl_convert_kind!(response, HolePunch);
+impl_convert_kind!(response, Rebind);
+impl_convert_kind!(response, Identities);
 impl_convert_kind!(response, Pong);
 
 impl_convert_kind!(control, ReverseConnection);
+impl_convert_kind!(control, HolePunch);
 impl_convert_kind!(control, PauseForwarding);
 impl_convert_kind!(control, ResumeForwarding);
 impl_convert_kind!(control, StopForwarding);
 impl_convert_kind!(control, Disconnected);
+impl_convert_kind!(control, Presence);
+impl_convert_kind!(control, ClusterSync);
+impl_convert_kind!(control, Identities);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forward_flags() {
+        let mut forward = Forward::unreliable([0u8; SESSION_ID_SIZE], 1, vec![1u8]);
+        assert!(!forward.is_reliable());
+        assert!(!forward.is_encrypted());
+
+        forward.set_encrypted();
+        assert!(forward.is_encrypted());
+        assert!(!forward.is_reliable());
+
+        let mut forward = Forward::new([0u8; SESSION_ID_SIZE], 1, vec![1u8]);
+        assert!(forward.is_reliable());
+        assert!(!forward.is_encrypted());
+        assert!(!forward.is_key_exchange());
+
+        forward.flags |= KEY_EXCHANGE_FLAG;
+        assert!(forward.is_key_exchange());
+        assert!(!forward.is_encrypted());
+    }
+}
@@ -7,10 +7,14 @@ pub const DEFAULT_EWMA_ALPHA_SHORT: f32 = 0.9;
 pub const DEFAULT_EWMA_ALPHA_MID: f32 = 0.7;
 pub const DEFAULT_EWMA_ALPHA_LONG: f32 = 0.2;
 
+/// Sent and received traffic of a channel
 #[derive(Clone, Default)]
 pub struct ChannelMetrics {
     pub tx: Metrics,
     pub rx: Metrics,
+    /// Received packets discarded, because consumer didn't keep up with reading them.
+    pub rx_dropped: u64,
+    pub rx_dropped_bytes: u64,
 }
 
 #[derive(Clone)]
@@ -256,6 +260,8 @@ impl Add for ChannelMetrics {
     fn add(mut self, rhs: Self) -> Self::Output {
         self.rx = self.rx + rhs.rx;
         self.tx = self.tx + rhs.tx;
+        self.rx_dropped += rhs.rx_dropped;
+        self.rx_dropped_bytes += rhs.rx_dropped_bytes;
 
         self
     }
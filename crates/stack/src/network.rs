@@ -9,7 +9,7 @@ use futures::channel::mpsc;
 use futures::future::{Either, LocalBoxFuture};
 use futures::{Future, FutureExt, SinkExt, StreamExt, TryFutureExt};
 use smoltcp::iface::SocketHandle;
-use smoltcp::wire::IpEndpoint;
+use smoltcp::wire::{IpAddress, IpEndpoint};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 use tokio::task::spawn_local;
 use tokio::time::MissedTickBehavior;
@@ -94,8 +94,11 @@ pub struct Network {
     pub bindings: Rc<RefCell<HashSet<SocketHandle>>>,
     pub connections: Rc<RefCell<HashMap<ConnectionMeta, Connection>>>,
     pub handles: Rc<RefCell<HashMap<SocketHandle, ConnectionMeta>>>,
+    /// Connections closed for writing by the remote side
+    finished: Rc<RefCell<HashSet<SocketHandle>>>,
     ingress: Channel<IngressEvent>,
     egress: Channel<EgressEvent>,
+    tcp_ingress_paused: Rc<RefCell<HashSet<IpAddress>>>,
 }
 
 impl Network {
@@ -115,10 +118,12 @@ impl Network {
             sender: Default::default(),
             poller: Default::default(),
             bindings: Default::default(),
+            finished: Default::default(),
             connections: Default::default(),
             handles: Default::default(),
             ingress: Default::default(),
             egress: Default::default(),
+            tcp_ingress_paused: Default::default(),
         };
 
         network.sender.net.borrow_mut().replace(network.clone());
@@ -165,6 +170,14 @@ impl Network {
         let endpoint = endpoint.into();
         let handle = self.stack.unbind(protocol, endpoint)?;
         self.bindings.borrow_mut().remove(&handle);
+
+        // Connectionless sockets register a connection for every remote endpoint.
+        if self.handles.borrow_mut().remove(&handle).is_some() {
+            self.sender.remove(&handle);
+            self.connections
+                .borrow_mut()
+                .retain(|_, conn| conn.handle != handle);
+        }
         Ok(())
     }
 
@@ -252,6 +265,33 @@ impl Network {
         self.connections.borrow()
     }
 
+    /// Checks whether IP packet is addressed to a TCP or UDP port with a socket in this stack.
+    pub fn has_socket_for(&self, packet: &[u8]) -> bool {
+        if packet.is_empty() || IpPacket::peek(packet).is_err() {
+            return false;
+        }
+        let ip = IpPacket::packet(packet);
+        let payload = ip.payload();
+
+        let (protocol, port) = match ip.protocol() {
+            6 if TcpPacket::peek(payload).is_ok() => {
+                (Protocol::Tcp, TcpPacket::packet(payload).dst_port())
+            }
+            17 if UdpPacket::peek(payload).is_ok() => {
+                (Protocol::Udp, UdpPacket::packet(payload).dst_port())
+            }
+            _ => return false,
+        };
+
+        let iface_rfc = self.stack.iface();
+        let iface = iface_rfc.borrow();
+        let found = iface.sockets().any(|(_, s)| {
+            s.protocol() == protocol
+                && matches!(s.local_endpoint(), SocketEndpoint::Ip(ep) if ep.port == port)
+        });
+        found
+    }
+
     pub fn sockets(&self) -> Vec<(SocketDesc, SocketState<ChannelMetrics>)> {
         let iface_rfc = self.stack.iface();
         let iface = iface_rfc.borrow();
@@ -322,6 +362,7 @@ impl Network {
     fn remove_connection(&self, meta: &ConnectionMeta, handle: SocketHandle) {
         self.stack.remove(meta, handle);
         self.handles.borrow_mut().remove(&handle);
+        self.finished.borrow_mut().remove(&handle);
         self.sender.remove(&handle);
 
         let ip_endpoint = smoltcp::wire::IpListenEndpoint::from(meta.remote);
@@ -371,6 +412,26 @@ impl Network {
         }
     }
 
+    /// Stops reading data from TCP sockets connected to `remote`. Unread data stays in socket
+    /// receive buffers, which shrinks the advertised TCP window and slows down the sender.
+    pub fn pause_tcp_ingress(&self, remote: IpAddress, paused: bool) {
+        let mut paused_set = self.tcp_ingress_paused.borrow_mut();
+        match paused {
+            true => paused_set.insert(remote),
+            false => paused_set.remove(&remote),
+        };
+    }
+
+    #[inline(always)]
+    pub fn is_tcp_ingress_paused(&self, remote: &IpAddress) -> bool {
+        self.tcp_ingress_paused.borrow().contains(remote)
+    }
+
+    /// Addresses of peers, from which TCP data is currently not read.
+    pub fn tcp_ingress_paused(&self) -> Vec<IpAddress> {
+        self.tcp_ingress_paused.borrow().iter().copied().collect()
+    }
+
     /// Take the ingress traffic receive channel
     #[inline(always)]
     pub fn ingress_receiver(&self) -> Option<IngressReceiver> {
@@ -392,6 +453,7 @@ impl Network {
         let mut events = Vec::new();
         let mut remove = Vec::new();
         let mut rebind = None;
+        let tcp_paused = self.tcp_ingress_paused.borrow();
 
         for (handle, socket) in iface.sockets_mut() {
             let mut desc = socket.desc();
@@ -425,8 +487,14 @@ impl Network {
             }
 
             let mut received = 0;
-
-            while socket.can_recv() {
+            let paused = socket.protocol() == Protocol::Tcp
+                && desc
+                    .remote
+                    .ip_endpoint()
+                    .map(|endpoint| tcp_paused.contains(&endpoint.addr))
+                    .unwrap_or(false);
+
+            while !paused && socket.can_recv() {
                 let (remote, payload) = match socket.recv() {
                     Ok(Some(tuple)) => tuple,
                     Ok(None) => break,
@@ -459,7 +527,26 @@ impl Network {
                 }
             }
 
+            if finished && !socket.can_recv() && socket.is_recv_finished() {
+                let meta = self.handles.borrow().get(&handle).copied();
+                if let Some(meta) = meta {
+                    if self.finished.borrow_mut().insert(handle) {
+                        log::debug!("{}: remote closed connection [{handle}]: {meta}", self.name);
+                        events.push(IngressEvent::Finished { desc: meta.into() });
+                    }
+                }
+            }
+
             if bindings.contains(&handle) && socket.remote_endpoint().is_specified() {
+                // Listening socket accepted connection. Report it, even if the other
+                // side didn't send any data yet.
+                if let Ok(meta) = ConnectionMeta::try_from(socket.desc()) {
+                    if !self.is_connected(&meta) {
+                        self.add_connection(Connection { handle, meta });
+                        events.push(IngressEvent::InboundConnection { desc: meta.into() });
+                    }
+                }
+
                 bindings.remove(&handle);
                 rebind = Some((socket.protocol(), socket.local_endpoint()));
 
@@ -550,6 +637,8 @@ pub enum IngressEvent {
     InboundConnection { desc: SocketDesc },
     /// Disconnection from a bound endpoint
     Disconnected { desc: SocketDesc },
+    /// Remote side of the connection won't send any more data
+    Finished { desc: SocketDesc },
     /// Bound endpoint packet
     Packet { desc: SocketDesc, payload: Vec<u8> },
 }
@@ -928,6 +1017,9 @@ mod tests {
                         IngressEvent::Disconnected { desc } => {
                             println!("disconnected: {:?}", desc);
                         }
+                        IngressEvent::Finished { desc } => {
+                            println!("finished: {:?}", desc);
+                        }
                         IngressEvent::InboundConnection { desc } => {
                             println!("inbound connection: {:?}", desc);
                         }
@@ -241,6 +241,8 @@ pub trait SocketExt {
     fn remote_endpoint(&self) -> SocketEndpoint;
 
     fn is_closed(&self) -> bool;
+    /// Remote side won't send any more data
+    fn is_recv_finished(&self) -> bool;
     fn close(&mut self);
 
     fn can_recv(&self) -> bool;
@@ -298,6 +300,19 @@ impl<'a> SocketExt for Socket<'a> {
         }
     }
 
+    fn is_recv_finished(&self) -> bool {
+        match &self {
+            Self::Tcp(s) => matches!(
+                s.state(),
+                tcp::State::CloseWait
+                    | tcp::State::LastAck
+                    | tcp::State::Closing
+                    | tcp::State::TimeWait
+            ),
+            _ => false,
+        }
+    }
+
     fn close(&mut self) {
         match self {
             Self::Tcp(s) => s.close(),
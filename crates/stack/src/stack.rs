@@ -151,8 +151,13 @@ impl<'a> Stack<'a> {
         let mut iface = self.iface.borrow_mut();
         let mut sockets = iface.sockets_mut();
 
+        // Connections accepted on the endpoint share it with the listening socket.
         let handle = sockets
-            .find(|(_, s)| s.local_endpoint() == endpoint)
+            .find(|(_, s)| {
+                s.protocol() == protocol
+                    && s.local_endpoint() == endpoint
+                    && !s.remote_endpoint().is_specified()
+            })
             .and_then(|(h, _)| match protocol {
                 Protocol::Tcp | Protocol::Udp | Protocol::Icmp | Protocol::Ipv6Icmp => Some(h),
                 _ => None,
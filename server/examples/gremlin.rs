@@ -43,7 +43,7 @@ async fn gen_crypto(n: usize) -> anyhow::Result<(Vec<PublicKey>, Vec<Rc<dyn Cryp
     let pairs: Vec<_> = futures::stream::iter((0..n).map(anyhow::Ok))
         .then(|_| async {
             let bytes = rand::thread_rng().gen::<[u8; 32]>();
-            let secret = ethsign::SecretKey::from_raw(&bytes)?;
+            let secret = ya_relay_core::key::SecretKey::from_raw(&bytes)?;
             let public = secret.public();
 
             let provider = FallbackCryptoProvider::new(secret);
@@ -67,7 +67,7 @@ async fn main() -> anyhow::Result<()> {
     for worker in 0..args.concurrent {
         let socket = UdpSocket::bind("0.0.0.0:0").await?;
         let cmd = args.command.clone();
-        let _: JoinHandle<anyhow::Result<()>> = local.spawn_local(async move {
+        let _worker: JoinHandle<anyhow::Result<()>> = local.spawn_local(async move {
             match cmd {
                 GremlinCmd::FloodSessionOpen {} => {
                     let mut request_id = 1;
@@ -1,15 +1,24 @@
 use clap::Parser;
 use std::collections::HashMap;
 use std::future;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
 
-use actix_web::{get, web, Responder};
+use actix_web::dev::ServiceRequest;
+use actix_web::{delete, get, post, web, HttpResponse, Responder};
 use serde::{Deserialize, Serialize};
 
+use ya_relay_core::server_session::SessionId;
 use ya_relay_core::NodeId;
 use ya_relay_server::metrics::register_metrics;
-use ya_relay_server::{AddrStatus, Config, Selector, SessionManager};
+use ya_relay_server::{
+    constant_time_eq, AddrStatus, Config, Selector, Server, Session, SessionManager,
+};
+
+struct StateDir(Option<PathBuf>);
 
 #[get("/sessions")]
 async fn sessions_list(sm: web::Data<Arc<SessionManager>>) -> impl Responder {
@@ -53,12 +62,7 @@ async fn nodes_list_prefix(
                             peer: session_ref.peer,
                             seen: format!("{:?}", session_ref.ts.age()),
                             supported_encryptions: session_ref.supported_encryptions.clone(),
-                            addr_status: match &*session_ref.addr_status.lock() {
-                                AddrStatus::Unknown => "Unknown".to_owned(),
-                                AddrStatus::Pending(ts) => format!("pending({:?})", ts.elapsed()),
-                                AddrStatus::Invalid(ts) => format!("invalid({:?})", ts.elapsed()),
-                                AddrStatus::Valid(ts) => format!("valid({:?})", ts.elapsed()),
-                            },
+                            addr_status: addr_status(&session_ref),
                         })
                     })
                     .collect(),
@@ -68,6 +72,260 @@ async fn nodes_list_prefix(
     Ok(web::Json(nodes))
 }
 
+fn addr_status(session: &Session) -> String {
+    format_status(&session.addr_status.lock())
+}
+
+fn format_status(status: &AddrStatus) -> String {
+    match status {
+        AddrStatus::Unknown => "Unknown".to_owned(),
+        AddrStatus::Pending(ts) => format!("pending({:?})", ts.elapsed()),
+        AddrStatus::Invalid(ts) => format!("invalid({:?})", ts.elapsed()),
+        AddrStatus::Valid(ts) => format!("valid({:?})", ts.elapsed()),
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct IdentityInfo {
+    node_id: NodeId,
+    public_key: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EndpointInfo {
+    protocol: String,
+    address: String,
+    port: u32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AdvertisedInfo {
+    protocol: String,
+    address: SocketAddr,
+    status: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionDetails {
+    session_id: String,
+    node_id: NodeId,
+    slot: Option<u32>,
+    peer: SocketAddr,
+    identities: Vec<IdentityInfo>,
+    endpoints: Vec<EndpointInfo>,
+    advertised: Vec<AdvertisedInfo>,
+    addr_status: String,
+    seen: String,
+    supported_encryptions: Vec<String>,
+    forwarded_bytes_in: u64,
+    forwarded_bytes_out: u64,
+}
+
+fn session_details(server: &Server, session: &Session) -> SessionDetails {
+    SessionDetails {
+        session_id: session.session_id.to_string(),
+        node_id: session.node_id,
+        slot: server.slots().get(session.node_id),
+        peer: session.peer,
+        identities: session
+            .keys
+            .iter()
+            .map(|key| IdentityInfo {
+                node_id: key.node_id,
+                public_key: hex::encode(key.public_key.bytes()),
+            })
+            .collect(),
+        endpoints: session
+            .endpoints()
+            .into_iter()
+            .map(|endpoint| EndpointInfo {
+                protocol: format!("{:?}", endpoint.protocol()),
+                address: endpoint.address,
+                port: endpoint.port,
+            })
+            .collect(),
+        advertised: session
+            .advertised
+            .lock()
+            .iter()
+            .map(|endpoint| AdvertisedInfo {
+                protocol: format!("{:?}", endpoint.protocol),
+                address: endpoint.addr,
+                status: format_status(&endpoint.status),
+            })
+            .collect(),
+        addr_status: addr_status(session),
+        seen: format!("{:?}", session.ts.age()),
+        supported_encryptions: session.supported_encryptions.clone(),
+        forwarded_bytes_in: session.forwarded.bytes_in.load(Ordering::Relaxed),
+        forwarded_bytes_out: session.forwarded.bytes_out.load(Ordering::Relaxed),
+    }
+}
+
+fn parse_node_id(node_id: &str) -> Result<NodeId, actix_web::Error> {
+    node_id.parse().map_err(actix_web::error::ErrorBadRequest)
+}
+
+fn parse_session_id(session_id: &str) -> Result<SessionId, actix_web::Error> {
+    SessionId::try_from(session_id).map_err(actix_web::error::ErrorBadRequest)
+}
+
+#[get("/node/{node_id}")]
+async fn node_details(
+    server: web::Data<Arc<Server>>,
+    node_id: web::Path<String>,
+) -> Result<impl Responder, actix_web::Error> {
+    let node_id = parse_node_id(&node_id)?;
+    let sessions: Vec<_> = server
+        .sessions()
+        .node_sessions(node_id)
+        .iter()
+        .map(|session| session_details(&server, session))
+        .collect();
+    if sessions.is_empty() {
+        return Err(actix_web::error::ErrorNotFound("node not connected"));
+    }
+    Ok(web::Json(sessions))
+}
+
+#[get("/session/{session_id}")]
+async fn session_info(
+    server: web::Data<Arc<Server>>,
+    session_id: web::Path<String>,
+) -> Result<impl Responder, actix_web::Error> {
+    let session_id = parse_session_id(&session_id)?;
+    let session = server
+        .sessions()
+        .session(&session_id)
+        .ok_or_else(|| actix_web::error::ErrorNotFound("session not found"))?;
+    Ok(web::Json(session_details(&server, &session)))
+}
+
+#[delete("/session/{session_id}")]
+async fn session_disconnect(
+    server: web::Data<Arc<Server>>,
+    session_id: web::Path<String>,
+) -> Result<impl Responder, actix_web::Error> {
+    let session_id = parse_session_id(&session_id)?;
+    if !server.disconnect(&session_id) {
+        return Err(actix_web::error::ErrorNotFound("session not found"));
+    }
+    Ok(HttpResponse::NoContent())
+}
+
+#[derive(Deserialize)]
+struct BanQuery {
+    duration: Option<String>,
+}
+
+impl BanQuery {
+    fn duration(&self) -> Result<Duration, actix_web::Error> {
+        match &self.duration {
+            Some(duration) => {
+                humantime::parse_duration(duration).map_err(actix_web::error::ErrorBadRequest)
+            }
+            None => Ok(Duration::from_secs(3600)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BanResult {
+    disconnected: usize,
+}
+
+#[post("/ban/node/{node_id}")]
+async fn ban_node(
+    server: web::Data<Arc<Server>>,
+    node_id: web::Path<String>,
+    query: web::Query<BanQuery>,
+) -> Result<impl Responder, actix_web::Error> {
+    let node_id = parse_node_id(&node_id)?;
+    let disconnected = server
+        .ban_node(node_id, query.duration()?)
+        .map_err(actix_web::error::ErrorBadRequest)?;
+    Ok(web::Json(BanResult { disconnected }))
+}
+
+#[delete("/ban/node/{node_id}")]
+async fn unban_node(
+    server: web::Data<Arc<Server>>,
+    node_id: web::Path<String>,
+) -> Result<impl Responder, actix_web::Error> {
+    let node_id = parse_node_id(&node_id)?;
+    if !server.bans().unban_node(node_id) {
+        return Err(actix_web::error::ErrorNotFound("node not banned"));
+    }
+    Ok(HttpResponse::NoContent())
+}
+
+#[post("/ban/ip/{ip}")]
+async fn ban_ip(
+    server: web::Data<Arc<Server>>,
+    ip: web::Path<IpAddr>,
+    query: web::Query<BanQuery>,
+) -> Result<impl Responder, actix_web::Error> {
+    let disconnected = server
+        .ban_ip(*ip, query.duration()?)
+        .map_err(actix_web::error::ErrorBadRequest)?;
+    Ok(web::Json(BanResult { disconnected }))
+}
+
+#[delete("/ban/ip/{ip}")]
+async fn unban_ip(
+    server: web::Data<Arc<Server>>,
+    ip: web::Path<IpAddr>,
+) -> Result<impl Responder, actix_web::Error> {
+    if !server.bans().unban_ip(*ip) {
+        return Err(actix_web::error::ErrorNotFound("address not banned"));
+    }
+    Ok(HttpResponse::NoContent())
+}
+
+#[post("/save-state")]
+async fn save_state(
+    server: web::Data<Arc<Server>>,
+    state_dir: web::Data<StateDir>,
+) -> Result<impl Responder, actix_web::Error> {
+    let state_dir = state_dir
+        .0
+        .as_ref()
+        .ok_or_else(|| actix_web::error::ErrorConflict("state dir not configured"))?;
+    log::info!("saving state to {state_dir:?}");
+    // Writing files blocks, so it doesn't run on the worker handling requests.
+    let state_dir = state_dir.clone();
+    let server = server.into_inner();
+    web::block(move || server.save_state(&state_dir))
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::NoContent())
+}
+
+#[post("/policy/reload")]
+async fn policy_reload(server: web::Data<Arc<Server>>) -> Result<impl Responder, actix_web::Error> {
+    server
+        .policy()
+        .reload()
+        .map_err(actix_web::error::ErrorBadRequest)?;
+    Ok(HttpResponse::NoContent())
+}
+
+/// Checks `Authorization: Bearer <token>` header of the admin API request.
+fn authorized(req: &ServiceRequest, token: &str) -> bool {
+    let provided = req
+        .headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    constant_time_eq(provided.as_bytes(), token.as_bytes())
+}
+
 #[actix_rt::main]
 async fn main() -> anyhow::Result<()> {
     dotenv::dotenv().ok();
@@ -84,7 +342,7 @@ async fn main() -> anyhow::Result<()> {
 
     let handle = register_metrics();
 
-    let server = ya_relay_server::run(&args).await?;
+    let server = Arc::new(ya_relay_server::run(&args).await?);
 
     let sessions = web::Data::new(server.sessions());
 
@@ -107,6 +365,47 @@ async fn main() -> anyhow::Result<()> {
 
     actix_rt::spawn(web_server);
 
+    if let (Some(admin_addr), Some(token)) = (args.admin_addr, args.admin_token.clone()) {
+        let admin = web::Data::new(server.clone());
+        let state_dir = web::Data::new(StateDir(args.state_dir.clone()));
+
+        let admin_server = actix_web::HttpServer::new(move || {
+            use actix_web::dev::Service;
+            use actix_web::*;
+
+            let token = token.clone();
+
+            App::new()
+                .app_data(admin.clone())
+                .app_data(state_dir.clone())
+                .service(node_details)
+                .service(session_info)
+                .service(session_disconnect)
+                .service(ban_node)
+                .service(unban_node)
+                .service(ban_ip)
+                .service(unban_ip)
+                .service(save_state)
+                .service(policy_reload)
+                .wrap_fn(move |req, srv| {
+                    let call = authorized(&req, &token).then(|| srv.call(req));
+                    async move {
+                        match call {
+                            Some(call) => call.await,
+                            None => Err(error::ErrorUnauthorized("invalid admin token")),
+                        }
+                    }
+                })
+        })
+        .workers(1)
+        .worker_max_blocking_threads(1)
+        .disable_signals()
+        .bind(admin_addr)?
+        .run();
+
+        actix_rt::spawn(admin_server);
+    }
+
     log::info!("started");
 
     let _a = tokio::signal::ctrl_c().await;
@@ -116,3 +415,22 @@ async fn main() -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+#[test]
+fn test_authorized() {
+    use actix_web::test::TestRequest;
+
+    let request = |value: &str| {
+        TestRequest::default()
+            .insert_header((actix_web::http::header::AUTHORIZATION, value))
+            .to_srv_request()
+    };
+    assert!(authorized(&request("Bearer secret"), "secret"));
+    assert!(!authorized(&request("Bearer secre"), "secret"));
+    assert!(!authorized(&request("Bearer other"), "secret"));
+    assert!(!authorized(&request("secret"), "secret"));
+    assert!(!authorized(
+        &TestRequest::default().to_srv_request(),
+        "secret"
+    ));
+}
@@ -1,15 +1,29 @@
 use crate::server::{ServerConfig, SessionHandlerConfig};
+use crate::state::cluster::ClusterConfig;
+use crate::state::federation::FederationConfig;
+use crate::state::policy::PolicyConfig;
+use crate::state::slot_manager::SlotManagerConfig;
 use crate::SessionManagerConfig;
 use clap::Parser;
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(version, about = "NET Server", long_about)]
 pub struct Config {
     #[arg(long, env, default_value = "127.0.0.1:9000")]
     pub metrics_scrape_addr: std::net::SocketAddr,
+    /// Address of the administrative API. Disabled when not set.
+    #[arg(long, env, requires = "admin_token")]
+    pub admin_addr: Option<std::net::SocketAddr>,
+    /// Bearer token required by the administrative API.
+    #[arg(long, env, hide_env_values = true)]
+    pub admin_token: Option<String>,
     #[arg(long, env = "STATE_DIRECTORY")]
     pub state_dir: Option<PathBuf>,
+    /// How often state is saved to `state_dir`. Zero saves it only on shutdown.
+    #[arg(long, env, value_parser = humantime::parse_duration, default_value = "1min")]
+    pub state_save_interval: Duration,
 
     #[command(flatten)]
     pub server: ServerConfig,
@@ -17,9 +31,21 @@ pub struct Config {
     #[command(flatten)]
     pub session_manager: SessionManagerConfig,
 
+    #[command(flatten)]
+    pub slot_manager: SlotManagerConfig,
+
     #[command(flatten)]
     pub session_handler: SessionHandlerConfig,
 
+    #[command(flatten)]
+    pub federation: FederationConfig,
+
+    #[command(flatten)]
+    pub cluster: ClusterConfig,
+
+    #[command(flatten)]
+    pub policy: PolicyConfig,
+
     #[command(flatten)]
     pub ip_check: crate::server::IpCheckerConfig,
 }
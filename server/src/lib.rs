@@ -7,7 +7,10 @@ mod state;
 pub mod testing;
 pub mod udp_server;
 
+pub use state::backend::StateBackend;
+pub use state::cluster::StateBackendKind;
+pub use state::peer_key::constant_time_eq;
 pub use state::session_manager::*;
 
 pub use config::Config;
-pub use server::run;
+pub use server::{run, Server};
@@ -23,6 +23,7 @@ pub fn register_metrics() -> PrometheusHandle {
     register_counter!("ya-relay.session.created");
     register_counter!("ya-relay.session.purged");
     register_counter!("ya-relay.session.removed");
+    register_counter!("ya-relay.session.rebound");
 
     register_counter!("ya-relay-core.packet.incoming.size");
     register_counter!("ya-relay-core.packet.outgoing.size");
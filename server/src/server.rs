@@ -1,17 +1,23 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use metrics::Counter;
+use parking_lot::Mutex;
 use quick_cache::sync::Cache;
 use rand::{thread_rng, Rng};
+use tokio::sync::mpsc;
 use tokio_util::codec::Decoder;
 
 use ya_relay_core::challenge;
 use ya_relay_core::challenge::ChallengeDigest;
+use ya_relay_core::faults::FaultConfig;
 use ya_relay_core::server_session::SessionId;
+#[cfg(feature = "test-utils")]
+use ya_relay_core::testing::sim::SimNetwork;
+use ya_relay_core::NodeId;
 use ya_relay_proto::codec::datagram::Codec;
 use ya_relay_proto::codec::{BytesMut, PacketKind};
 use ya_relay_proto::proto::{
@@ -19,10 +25,17 @@ use ya_relay_proto::proto::{
     StatusCode,
 };
 
+use crate::state::backend::{MemoryBackend, ReplicatedBackend, StateBackend};
+use crate::state::ban_list::BanList;
+use crate::state::cluster::{Cluster, StateBackendKind};
+use crate::state::difficulty::AdaptiveDifficulty;
+use crate::state::federation::Federation;
+use crate::state::policy::Policy;
+use crate::state::rate_limiter::{RateLimitConfig, RateLimiter};
 use crate::state::slot_manager::SlotManager;
 use crate::state::Clock;
 use crate::udp_server::{worker_err_fn, PacketType, UdpServer, UdpServerBuilder, UdpSocket};
-use crate::{Config, SessionManager};
+use crate::{Config, SessionManager, SessionRef};
 
 mod neighbours;
 mod session;
@@ -37,6 +50,16 @@ mod register;
 
 mod reverse_connection;
 
+mod hole_punch;
+
+mod rebind;
+
+mod identities;
+
+mod federation;
+
+mod cluster;
+
 mod state_decoder;
 
 mod ip_checker;
@@ -59,6 +82,22 @@ pub struct ServerConfig {
     pub workers: usize,
     #[arg(long, env = "RELAY_TASKS_PER_WORKER", default_value = "32")]
     pub tasks_per_worker: usize,
+    /// Accept TCP connections on the listening address, for Nodes with blocked UDP traffic
+    #[arg(long, env = "RELAY_TCP_LISTEN")]
+    pub tcp_listen: bool,
+    /// Maximum number of simultaneous TCP connections
+    #[arg(long, env = "RELAY_TCP_MAX_CONNECTIONS", default_value = "1024")]
+    pub tcp_max_connections: usize,
+    /// Inject faults into UDP traffic, for testing. Example: drop=0.1,delay=0.2,max-delay=50ms,
+    /// duplicate=0.05,reorder=0.1,corrupt=0.01,kind=forward,seed=7
+    #[arg(long)]
+    pub faults: Option<FaultConfig>,
+    #[command(flatten)]
+    pub rate_limit: RateLimitConfig,
+    /// Simulated network, on which the server binds instead of OS sockets.
+    #[cfg(feature = "test-utils")]
+    #[arg(skip)]
+    pub network: Option<SimNetwork>,
 }
 
 fn default_workers() -> usize {
@@ -68,10 +107,20 @@ fn default_workers() -> usize {
 
 type IpCache = Arc<Cache<SocketAddr, (Instant, bool)>>;
 
+/// Packets sent by the relay on its own initiative, not in response to a request.
+type Outbox = mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>;
+
 pub struct Server {
     udp_server: UdpServer,
     pub(crate) session_manager: Arc<SessionManager>,
     slot_manager: Arc<SlotManager>,
+    federation: Arc<Federation>,
+    state: Arc<dyn StateBackend>,
+    cluster: Arc<Cluster>,
+    ban_list: Arc<BanList>,
+    policy: Arc<Policy>,
+    difficulty: Arc<AdaptiveDifficulty>,
+    outbox: Outbox,
 }
 
 #[inline]
@@ -86,9 +135,7 @@ fn sessions_path<P: AsRef<Path>>(state_dir: P) -> PathBuf {
 
 impl Server {
     pub fn save_state(&self, state_dir: &Path) -> anyhow::Result<()> {
-        self.slot_manager.save(&slots_path(state_dir))?;
-        self.session_manager.save(&sessions_path(state_dir))?;
-        Ok(())
+        save_state(state_dir, &self.slot_manager, &self.session_manager)
     }
 
     pub fn bind_addr(&self) -> SocketAddr {
@@ -99,10 +146,137 @@ impl Server {
         self.session_manager.clone()
     }
 
+    pub fn federation(&self) -> Arc<Federation> {
+        self.federation.clone()
+    }
+
+    /// State used to answer requests. Includes sessions of cluster siblings,
+    /// when replicated backend is used.
+    pub fn state(&self) -> Arc<dyn StateBackend> {
+        self.state.clone()
+    }
+
+    pub fn cluster(&self) -> Arc<Cluster> {
+        self.cluster.clone()
+    }
+
+    pub fn slots(&self) -> Arc<SlotManager> {
+        self.slot_manager.clone()
+    }
+
+    pub fn bans(&self) -> Arc<BanList> {
+        self.ban_list.clone()
+    }
+
+    pub fn policy(&self) -> Arc<Policy> {
+        self.policy.clone()
+    }
+
+    /// Current challenge difficulty for new sessions.
+    pub fn difficulty(&self) -> u64 {
+        self.difficulty.current()
+    }
+
+    /// Removes session and notifies the Node with `Disconnected` message.
+    pub fn disconnect(&self, session_id: &SessionId) -> bool {
+        match self.session_manager.remove_session(session_id) {
+            Some(session) => {
+                self.notify_disconnected(&session);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn notify_disconnected(&self, session: &SessionRef) {
+        log::info!(
+            "[{}] disconnecting session {} of {}",
+            session.peer,
+            session.session_id,
+            session.node_id
+        );
+        let packet = Packet::control(
+            session.session_id.to_vec(),
+            control::Disconnected {
+                by: Some(control::disconnected::By::SessionId(
+                    session.session_id.to_vec(),
+                )),
+            },
+        );
+        self.outbox
+            .send((packet.encode_to_vec(), session.peer))
+            .ok();
+    }
+
+    /// Bans the Node and disconnects all its sessions. Returns number of disconnected sessions.
+    pub fn ban_node(&self, node_id: NodeId, duration: Duration) -> anyhow::Result<usize> {
+        self.ban_list.ban_node(node_id, duration)?;
+        Ok(self.disconnect_all(self.session_manager.node_sessions(node_id)))
+    }
+
+    /// Bans the address and disconnects all sessions from it. Returns number of disconnected sessions.
+    pub fn ban_ip(&self, ip: IpAddr, duration: Duration) -> anyhow::Result<usize> {
+        self.ban_list.ban_ip(ip, duration)?;
+        Ok(self.disconnect_all(
+            self.session_manager
+                .find_sessions(|session| session.peer.ip() == ip),
+        ))
+    }
+
+    fn disconnect_all(&self, sessions: Vec<SessionRef>) -> usize {
+        sessions
+            .into_iter()
+            .filter(|session| self.disconnect(&session.session_id))
+            .count()
+    }
+
     #[cfg(feature = "test-utils")]
     pub fn stop(&self) {}
 }
 
+fn save_state(
+    state_dir: &Path,
+    slot_manager: &SlotManager,
+    session_manager: &SessionManager,
+) -> anyhow::Result<()> {
+    slot_manager.save(&slots_path(state_dir))?;
+    session_manager.save(&sessions_path(state_dir))?;
+    Ok(())
+}
+
+/// Periodically saves state, so it survives crash of the process.
+fn start_state_saver(
+    state_dir: &Path,
+    interval: Duration,
+    slot_manager: &Arc<SlotManager>,
+    session_manager: &Arc<SessionManager>,
+) {
+    let state_dir = state_dir.to_path_buf();
+    let slot_manager = Arc::downgrade(slot_manager);
+    let session_manager = Arc::downgrade(session_manager);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let (slot_manager, session_manager) =
+                match (slot_manager.upgrade(), session_manager.upgrade()) {
+                    (Some(slots), Some(sm)) => (slots, sm),
+                    _ => break,
+                };
+            let state_dir = state_dir.clone();
+            let start = Instant::now();
+            let result = tokio::task::spawn_blocking(move || {
+                save_state(&state_dir, &slot_manager, &session_manager)
+            })
+            .await;
+            match result {
+                Ok(Ok(())) => log::debug!("state saved in {:?}", start.elapsed()),
+                Ok(Err(e)) => log::error!("failed to save state: {e:?}"),
+                Err(e) => log::error!("failed to save state: {e}"),
+            }
+        }
+    });
+}
+
 impl Drop for Server {
     fn drop(&mut self) {
         self.udp_server.stop_internal();
@@ -123,7 +297,7 @@ pub async fn run(config: &Config) -> anyhow::Result<Server> {
                 None
             }
         })
-        .unwrap_or_else(|| SlotManager::new());
+        .unwrap_or_else(SlotManager::new);
 
     let session_manager = config
         .state_dir
@@ -145,13 +319,52 @@ pub async fn run(config: &Config) -> anyhow::Result<Server> {
                 None
             }
         })
-        .unwrap_or_else(|| SessionManager::new());
+        .unwrap_or_else(SessionManager::new);
 
     let server_config = &config.server;
     let session_handler_config = config.session_handler.clone();
     let ip_check_config = config.ip_check.clone();
+    let faults = server_config.faults.clone();
+    #[cfg(feature = "test-utils")]
+    let faults = match faults {
+        None => FaultConfig::from_env()?,
+        faults => faults,
+    };
+
+    let federation = Federation::new(&config.federation)?;
+    let cluster = Cluster::new(&config.cluster)?;
+    let state: Arc<dyn StateBackend> = match config.cluster.state_backend {
+        StateBackendKind::Memory => Arc::new(MemoryBackend::new(&session_manager, &slot_manager)),
+        StateBackendKind::Replicated => Arc::new(ReplicatedBackend::new(
+            &session_manager,
+            &slot_manager,
+            &cluster,
+        )),
+    };
+    let replicated = config.cluster.state_backend == StateBackendKind::Replicated;
+    let ban_list: Arc<BanList> = Default::default();
+    let policy = Policy::new(&config.policy)?;
+    policy.start_reload_processor(config.policy.policy_reload_interval);
+    let difficulty = AdaptiveDifficulty::new(session_handler_config.difficulty_config());
+    difficulty.start_adjust_processor(session_handler_config.difficulty_adjust_interval);
+    let (outbox, outbox_rx) = mpsc::unbounded_channel();
+    let outbox_rx = Arc::new(Mutex::new(Some(outbox_rx)));
 
     session_manager.start_cleanup_processor(&config.session_manager);
+    if let Some(state_dir) = &config.state_dir {
+        if !config.state_save_interval.is_zero() {
+            start_state_saver(
+                state_dir,
+                config.state_save_interval,
+                &slot_manager,
+                &session_manager,
+            );
+        }
+    }
+    slot_manager.start_reclaim_processor(&session_manager, &federation, &config.slot_manager);
+
+    let rate_limiter = RateLimiter::new(&server_config.rate_limit);
+    rate_limiter.start_cleanup_processor(config.session_manager.session_cleaner_interval);
 
     let ip_test_cache: IpCache =
         Arc::new(quick_cache::sync::Cache::<SocketAddr, (Instant, bool)>::new(128));
@@ -159,20 +372,45 @@ pub async fn run(config: &Config) -> anyhow::Result<Server> {
     let server = {
         let session_manager = session_manager.clone();
         let slot_manager = slot_manager.clone();
-
-        UdpServerBuilder::new(move |reply: Rc<UdpSocket>| {
+        let federation = federation.clone();
+        let state = state.clone();
+        let cluster = cluster.clone();
+        let ban_list = ban_list.clone();
+        let policy = policy.clone();
+        let difficulty = difficulty.clone();
+
+        let builder = UdpServerBuilder::new(move |reply: Rc<UdpSocket>| {
             let session_manager = session_manager.clone();
             let slot_manager = slot_manager.clone();
-            let checker_ip = reply.local_addr()?.ip();
-
-            let session_handler = session::SessionHandler::new(&session_manager, &session_handler_config);
-            let ip_checker = ip_check_config.build(checker_ip)?;
-            let register_handler = register::RegisterHandler::new(&session_manager, &slot_manager, ip_checker, &reply, ip_test_cache.clone());
-            let neighbours_handler = neighbours::NeighboursHandler::new(&session_manager, &slot_manager);
-            let node_handler = node::NodeHandler::new(&session_manager, &slot_manager);
-            let slot_handler = slot::SlotHandler::new(&session_manager, &slot_manager);
-            let forward_handler = forward::ForwardHandler::new(&session_manager, &slot_manager, &reply);
+            let rate_limiter = rate_limiter.clone();
+            let federation = federation.clone();
+            let state = state.clone();
+            let cluster = cluster.clone();
+            let ban_list = ban_list.clone();
+            let policy = policy.clone();
+            let difficulty = difficulty.clone();
+
+            // Workers share the same address, so packets not being responses
+            // can be sent by any of them. The first one started takes this job.
+            if let Some(outbox_rx) = outbox_rx.lock().take() {
+                start_outbox(outbox_rx, &reply);
+                federation::start_presence_sync(&federation, &session_manager, &state, &reply);
+                if replicated {
+                    cluster::start_cluster_sync(&cluster, &session_manager, &state, &reply);
+                }
+            }
+
+            let session_handler = session::SessionHandler::new(&session_manager, &ban_list, &policy, &difficulty, &session_handler_config);
+            let ip_checker = Rc::new(ip_check_config.build(&reply)?);
+            let register_handler = register::RegisterHandler::new(&session_manager, &slot_manager, &ban_list, &policy, &ip_checker, &reply, ip_test_cache.clone());
+            let neighbours_handler = neighbours::NeighboursHandler::new(&session_manager, &state);
+            let node_handler = node::NodeHandler::new(&state, &federation);
+            let slot_handler = slot::SlotHandler::new(&state, &federation);
+            let forward_handler = forward::ForwardHandler::new(&state, &rate_limiter, &federation, &cluster, &ban_list, &policy, &reply);
             let rc_handler = reverse_connection::RcHandler::new(&session_manager, &reply);
+            let hole_punch_handler = hole_punch::HolePunchHandler::new(&session_manager, &reply);
+            let rebind_handler = rebind::RebindHandler::new(&session_manager, &policy, &ip_checker);
+            let identities_handler = identities::IdentitiesHandler::new(&session_manager, &ban_list, &policy);
 
             worker_err_fn(move |pt, mut packet: BytesMut, src| {
                 let mut codec = Codec;
@@ -214,6 +452,10 @@ pub async fn run(config: &Config) -> anyhow::Result<Server> {
                                 }
                             }
                         }
+                        PacketType::Data if ban_list.is_ip_banned(&src.ip()) => {
+                            log::trace!("[{src}] dropping packet from banned address");
+                            None
+                        }
                         PacketType::Data => match p {
                             PacketKind::Packet(Packet { session_id, kind: Some(packet::Kind::Request(Request { request_id, kind: Some(request) })) }) => {
                                 let session_id: Option<SessionId> = session_id.try_into().ok();
@@ -225,7 +467,7 @@ pub async fn run(config: &Config) -> anyhow::Result<Server> {
                                         session_handler.handle(&clock, src, request_id, session_id, &session)
                                     }
                                     request::Kind::Ping(_) => {
-                                        session_id.and_then(|session_id| handle_ping(&clock, src, request_id, session_id, &session_manager))
+                                        session_id.and_then(|session_id| handle_ping(&clock, src, request_id, session_id, &*state))
                                     }
                                     request::Kind::Neighbours(neighbours) => {
                                         session_id.and_then(|session_id|
@@ -243,6 +485,12 @@ pub async fn run(config: &Config) -> anyhow::Result<Server> {
                                             register_handler.handle(&clock, src, request_id, session_id, &register)),
                                     request::Kind::ReverseConnection(rc) =>
                                         session_id.and_then(|session_id| rc_handler.handle(&clock, src, request_id, session_id, &rc)),
+                                    request::Kind::HolePunch(hp) =>
+                                        session_id.and_then(|session_id| hole_punch_handler.handle(&clock, src, request_id, session_id, &hp)),
+                                    request::Kind::Rebind(rebind) =>
+                                        session_id.and_then(|session_id| rebind_handler.handle(&clock, src, request_id, session_id, &rebind)),
+                                    request::Kind::Identities(identities) =>
+                                        session_id.and_then(|session_id| identities_handler.handle(&clock, src, request_id, session_id, &identities)),
                                 }
                             }
                             PacketKind::Packet(Packet { session_id: _, kind: None }) => {
@@ -268,6 +516,14 @@ pub async fn run(config: &Config) -> anyhow::Result<Server> {
                                 // ignore
                                 None
                             }
+                            PacketKind::Packet(Packet { session_id: _, kind: Some(packet::Kind::Control(Control { kind: Some(control::Kind::Presence(presence)) })) }) => {
+                                federation::handle_presence(&federation, src, presence);
+                                None
+                            }
+                            PacketKind::Packet(Packet { session_id: _, kind: Some(packet::Kind::Control(Control { kind: Some(control::Kind::ClusterSync(sync)) })) }) => {
+                                cluster::handle_cluster_sync(&cluster, src, sync);
+                                None
+                            }
                             PacketKind::Forward(Forward { session_id, slot, flags, payload }) => {
                                 let session_id = session_id.into();
                                 forward_handler.handle(&clock, src, session_id, slot, flags, payload)
@@ -293,25 +549,53 @@ pub async fn run(config: &Config) -> anyhow::Result<Server> {
             })
         }).max_tasks_per_worker(server_config.tasks_per_worker)
             .workers(server_config.workers)
-            .start(bind_addr).await?
+            .tcp(server_config.tcp_listen)
+            .tcp_max_connections(server_config.tcp_max_connections)
+            .faults(faults);
+        #[cfg(feature = "test-utils")]
+        let builder = builder.network(server_config.network.clone());
+        builder.start(bind_addr).await?
     };
 
     Ok(Server {
         udp_server: server,
         session_manager,
         slot_manager,
+        federation,
+        state,
+        cluster,
+        ban_list,
+        policy,
+        difficulty,
+        outbox,
     })
 }
 
+fn start_outbox(mut rx: mpsc::UnboundedReceiver<(Vec<u8>, SocketAddr)>, socket: &Rc<UdpSocket>) {
+    let socket = Rc::downgrade(socket);
+    tokio::task::spawn_local(async move {
+        while let Some((bytes, dst)) = rx.recv().await {
+            let socket = match socket.upgrade() {
+                Some(socket) => socket,
+                None => break,
+            };
+            if let Err(e) = socket.send_to(&bytes, dst).await {
+                log::debug!("[{dst}] failed to send: {e}");
+            }
+        }
+    });
+}
+
 fn handle_ping(
     clock: &Clock,
     src: SocketAddr,
     request_id: u64,
     session_id: SessionId,
-    session_manager: &SessionManager,
+    state: &dyn StateBackend,
 ) -> Option<(CompletionHandler, Packet)> {
-    let is_ok = session_manager
-        .with_session(&session_id, |session| {
+    let is_ok = state
+        .session(&session_id)
+        .map(|session| {
             if session.peer == src {
                 clock.touch(&session.ts);
                 true
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use ya_relay_proto::proto::{control, Endpoint, Message, Packet, Protocol};
+
+use crate::server::state_decoder::decoder;
+use crate::state::backend::StateBackend;
+use crate::state::cluster::Cluster;
+use crate::state::session_manager::Selector;
+use crate::udp_server::UdpSocket;
+use crate::SessionManager;
+
+/// Number of sessions sent in a single `ClusterSync` message, so it fits in one datagram.
+const SYNC_CHUNK_SIZE: usize = 8;
+
+/// Periodically sends sessions held by this instance to its siblings in the cluster.
+pub fn start_cluster_sync(
+    cluster: &Arc<Cluster>,
+    session_manager: &Arc<SessionManager>,
+    state: &Arc<dyn StateBackend>,
+    socket: &Rc<UdpSocket>,
+) {
+    let cluster = Arc::downgrade(cluster);
+    let session_manager = Arc::downgrade(session_manager);
+    let state = Arc::downgrade(state);
+    let socket = Rc::downgrade(socket);
+
+    tokio::task::spawn_local(async move {
+        loop {
+            let (cluster, session_manager, state, socket) = match (
+                cluster.upgrade(),
+                session_manager.upgrade(),
+                state.upgrade(),
+                socket.upgrade(),
+            ) {
+                (Some(c), Some(sm), Some(state), Some(socket)) => (c, sm, state, socket),
+                _ => break,
+            };
+
+            cluster.cleanup();
+            let siblings = cluster.siblings();
+            if !siblings.is_empty() {
+                let packets = sessions(&cluster, &session_manager, &*state);
+                for sibling in siblings {
+                    for bytes in &packets {
+                        if let Err(e) = socket.send_to(bytes, sibling).await {
+                            log::debug!("[{sibling}] failed to send sessions: {e}");
+                        }
+                    }
+                }
+            }
+
+            let interval = cluster.sync_interval();
+            drop((cluster, session_manager, state, socket));
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+fn sessions(
+    cluster: &Cluster,
+    session_manager: &SessionManager,
+    state: &dyn StateBackend,
+) -> Vec<Vec<u8>> {
+    let decoder = decoder(state);
+    // Only registered sessions are linked to Nodes. Send each of them once.
+    let sessions = session_manager
+        .nodes_for(Selector::All, usize::MAX)
+        .into_keys()
+        .filter_map(|node_id| session_manager.node_session(node_id))
+        .map(|session| (session.session_id, session))
+        .collect::<HashMap<_, _>>();
+    let sessions = sessions
+        .values()
+        .filter_map(|session| {
+            Some(control::cluster_sync::Session {
+                session_id: session.session_id.to_vec(),
+                peer: Some(Endpoint {
+                    protocol: Protocol::Udp.into(),
+                    address: session.peer.ip().to_string(),
+                    port: session.peer.port().into(),
+                }),
+                node: Some(decoder.to_node_info(session)?),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let timestamp = chrono::Utc::now().timestamp_millis() as u64;
+    sessions
+        .chunks(SYNC_CHUNK_SIZE)
+        .map(|sessions| {
+            let mut sync = control::ClusterSync {
+                sessions: sessions.to_vec(),
+                ..Default::default()
+            };
+            cluster.seal_sync(&mut sync, timestamp);
+            Packet::control(Vec::new(), sync).encode_to_vec()
+        })
+        .collect()
+}
+
+pub fn handle_cluster_sync(cluster: &Cluster, src: SocketAddr, mut sync: control::ClusterSync) {
+    if !cluster.verify_sync(src, &mut sync) {
+        log::debug!("[{src}] sessions from unknown instance");
+        return;
+    }
+    log::trace!("[{src}] sync of {} sessions", sync.sessions.len());
+    cluster.update(src, sync.sessions);
+}
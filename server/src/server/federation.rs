@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use ya_relay_proto::proto::{control, Message, Packet};
+
+use crate::server::state_decoder::decoder;
+use crate::state::backend::StateBackend;
+use crate::state::federation::Federation;
+use crate::state::session_manager::Selector;
+use crate::udp_server::UdpSocket;
+use crate::SessionManager;
+
+/// Number of Nodes sent in a single `Presence` message, so it fits in one datagram.
+const PRESENCE_CHUNK_SIZE: usize = 8;
+
+/// Periodically sends information about locally connected Nodes to other relays in federation.
+pub fn start_presence_sync(
+    federation: &Arc<Federation>,
+    session_manager: &Arc<SessionManager>,
+    state: &Arc<dyn StateBackend>,
+    socket: &Rc<UdpSocket>,
+) {
+    let federation = Arc::downgrade(federation);
+    let session_manager = Arc::downgrade(session_manager);
+    let state = Arc::downgrade(state);
+    let socket = Rc::downgrade(socket);
+
+    tokio::task::spawn_local(async move {
+        loop {
+            let (federation, session_manager, state, socket) = match (
+                federation.upgrade(),
+                session_manager.upgrade(),
+                state.upgrade(),
+                socket.upgrade(),
+            ) {
+                (Some(f), Some(sm), Some(state), Some(socket)) => (f, sm, state, socket),
+                _ => break,
+            };
+
+            federation.cleanup();
+            let peers = federation.peers();
+            if !peers.is_empty() {
+                let packets = presence(&federation, &session_manager, &*state);
+                for peer in peers {
+                    for bytes in &packets {
+                        if let Err(e) = socket.send_to(bytes, peer).await {
+                            log::debug!("[{peer}] failed to send presence: {e}");
+                        }
+                    }
+                }
+            }
+
+            let interval = federation.sync_interval();
+            drop((federation, session_manager, state, socket));
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+fn presence(
+    federation: &Federation,
+    session_manager: &SessionManager,
+    state: &dyn StateBackend,
+) -> Vec<Vec<u8>> {
+    let decoder = decoder(state);
+    // Sessions are indexed by all Node identities. Send each of them once.
+    let sessions = session_manager
+        .nodes_for(Selector::All, usize::MAX)
+        .into_keys()
+        .filter_map(|node_id| session_manager.node_session(node_id))
+        .map(|session| (session.session_id, session))
+        .collect::<HashMap<_, _>>();
+    let nodes = sessions
+        .values()
+        .filter_map(|session| decoder.to_node_info(session))
+        .collect::<Vec<_>>();
+
+    let timestamp = chrono::Utc::now().timestamp_millis() as u64;
+    nodes
+        .chunks(PRESENCE_CHUNK_SIZE)
+        .map(|nodes| {
+            let mut presence = control::Presence {
+                nodes: nodes.to_vec(),
+                ..Default::default()
+            };
+            federation.seal_presence(&mut presence, timestamp);
+            Packet::control(Vec::new(), presence).encode_to_vec()
+        })
+        .collect()
+}
+
+pub fn handle_presence(federation: &Federation, src: SocketAddr, mut presence: control::Presence) {
+    if !federation.verify_presence(src, &mut presence) {
+        log::debug!("[{src}] presence from unknown relay");
+        return;
+    }
+    log::trace!("[{src}] presence of {} nodes", presence.nodes.len());
+    federation.update(src, presence.nodes);
+}
@@ -1,16 +1,24 @@
 use crate::server::CompletionHandler;
-use crate::state::slot_manager::{SlotId, SlotManager};
+use crate::state::backend::StateBackend;
+use crate::state::ban_list::BanList;
+use crate::state::cluster::Cluster;
+use crate::state::federation::Federation;
+use crate::state::policy::Policy;
+use crate::state::rate_limiter::{RateLimiter, Verdict};
+use crate::state::slot_manager::SlotId;
 use crate::state::Clock;
-use crate::SessionManager;
+use crate::SessionRef;
 use bytes::BytesMut;
 
 use crate::udp_server::UdpSocket;
 use std::net::SocketAddr;
 use std::rc::Rc;
 use std::sync::Arc;
+use std::time::Duration;
+use ya_relay_core::identity::Identity;
 use ya_relay_core::server_session::SessionId;
 
-use ya_relay_proto::proto::{control, Forward, Packet, Payload};
+use ya_relay_proto::proto::{control, Forward, Message, Packet, Payload, StatusCode};
 
 mod metric {
     use crate::server::DoneAck;
@@ -24,6 +32,12 @@ mod metric {
     static IN_SIZE: Key = Key::from_static_name("ya-relay.packet.forward.incoming.size");
     static OUT_SIZE: Key = Key::from_static_name("ya-relay.packet.forward.outgoing.size");
 
+    static PAUSED: Key = Key::from_static_name("ya-relay.packet.forward.paused");
+    static RESUMED: Key = Key::from_static_name("ya-relay.packet.forward.resumed");
+    static THROTTLED: Key = Key::from_static_name("ya-relay.packet.forward.throttled");
+    static FEDERATED: Key = Key::from_static_name("ya-relay.packet.forward.federated");
+    static SIBLING: Key = Key::from_static_name("ya-relay.packet.forward.sibling");
+
     #[derive(Clone)]
     pub struct ForwardMetric {
         pub start: Counter,
@@ -31,6 +45,11 @@ mod metric {
         pub error: Counter,
         pub in_bytes: Counter,
         pub out_bytes: Counter,
+        pub paused: Counter,
+        pub resumed: Counter,
+        pub throttled: Counter,
+        pub federated: Counter,
+        pub sibling: Counter,
     }
 
     impl Default for ForwardMetric {
@@ -41,12 +60,22 @@ mod metric {
             let error = recorder.register_counter(&ERROR);
             let in_bytes = recorder.register_counter(&IN_SIZE);
             let out_bytes = recorder.register_counter(&OUT_SIZE);
+            let paused = recorder.register_counter(&PAUSED);
+            let resumed = recorder.register_counter(&RESUMED);
+            let throttled = recorder.register_counter(&THROTTLED);
+            let federated = recorder.register_counter(&FEDERATED);
+            let sibling = recorder.register_counter(&SIBLING);
             Self {
                 start,
                 done,
                 error,
                 in_bytes,
                 out_bytes,
+                paused,
+                resumed,
+                throttled,
+                federated,
+                sibling,
             }
         }
     }
@@ -63,8 +92,12 @@ mod metric {
 }
 
 pub struct ForwardHandler {
-    session_manager: Arc<SessionManager>,
-    slot_manager: Arc<SlotManager>,
+    state: Arc<dyn StateBackend>,
+    rate_limiter: Arc<RateLimiter>,
+    federation: Arc<Federation>,
+    cluster: Arc<Cluster>,
+    ban_list: Arc<BanList>,
+    policy: Arc<Policy>,
     metrics: metric::ForwardMetric,
     ack: CompletionHandler,
     socket: Rc<UdpSocket>,
@@ -72,23 +105,218 @@ pub struct ForwardHandler {
 
 impl ForwardHandler {
     pub fn new(
-        session_manager: &Arc<SessionManager>,
-        slot_manager: &Arc<SlotManager>,
+        state: &Arc<dyn StateBackend>,
+        rate_limiter: &Arc<RateLimiter>,
+        federation: &Arc<Federation>,
+        cluster: &Arc<Cluster>,
+        ban_list: &Arc<BanList>,
+        policy: &Arc<Policy>,
         socket: &Rc<UdpSocket>,
     ) -> Self {
-        let session_manager = Arc::clone(session_manager);
-        let slot_manager = slot_manager.clone();
+        let state = state.clone();
+        let rate_limiter = rate_limiter.clone();
+        let federation = federation.clone();
+        let cluster = cluster.clone();
+        let ban_list = ban_list.clone();
+        let policy = policy.clone();
         let metrics = metric::ForwardMetric::default();
         let ack = Rc::new(metrics.clone());
         let socket = Rc::clone(socket);
         Self {
-            session_manager,
-            slot_manager,
+            state,
+            rate_limiter,
+            federation,
+            cluster,
+            ban_list,
+            policy,
             metrics,
             ack,
             socket,
         }
     }
+
+    /// Pauses forwarding from the sender and resumes it, after its budget is replenished.
+    fn pause(
+        &self,
+        src: SocketAddr,
+        src_session: &SessionRef,
+        src_slot: SlotId,
+        resume_after: Duration,
+    ) {
+        let session_id = src_session.session_id;
+        self.metrics.paused.increment(1);
+        src_session.forwarded.add_pause();
+        log::debug!(
+            "[{src}] forwarding rate exceeded. Pausing for {}",
+            humantime::format_duration(resume_after)
+        );
+
+        let pause = Packet::control(
+            session_id.to_vec(),
+            control::PauseForwarding { slot: src_slot },
+        )
+        .encode_to_vec();
+        let resume = Packet::control(
+            session_id.to_vec(),
+            control::ResumeForwarding { slot: src_slot },
+        )
+        .encode_to_vec();
+        let socket = self.socket.clone();
+        let resumed = self.metrics.resumed.clone();
+        let src_session = src_session.clone();
+
+        tokio::task::spawn_local(async move {
+            socket.send_to(&pause, src).await.ok();
+            tokio::time::sleep(resume_after).await;
+            log::debug!("[{src}] resuming forwarding");
+            if socket.send_to(&resume, src).await.is_ok() {
+                resumed.increment(1);
+                src_session.forwarded.add_resume();
+            }
+        });
+    }
+
+    fn send(&self, forward: Forward, dst_addr: SocketAddr) {
+        let payload_size = forward.payload.len();
+        let mut bytes = BytesMut::new();
+        bytes.reserve(forward.encoded_len());
+        forward.encode(&mut bytes);
+        let socket = self.socket.clone();
+
+        let out_bytes = self.metrics.out_bytes.clone();
+        let done = self.metrics.done.clone();
+        let error = self.metrics.error.clone();
+
+        tokio::task::spawn_local(async move {
+            match socket.send_to(&bytes, dst_addr).await {
+                Ok(v) => {
+                    out_bytes.increment(payload_size as u64);
+                    done.increment(1);
+                    log::debug!("forwarded {} bytes to {dst_addr}", v);
+                }
+                Err(e) => {
+                    error.increment(1);
+                    log::error!("fail {:?}", e);
+                }
+            }
+        });
+    }
+
+    /// Forwards packet received from other relay in federation. Payload is prefixed
+    /// with the tag and `NodeId` of the sender.
+    fn handle_federated(&self, src: SocketAddr, slot: SlotId, flags: u16, payload: Payload) {
+        let (remote, payload) = match self.federation.open_forward(src, slot, flags, payload) {
+            Some(it) => it,
+            None => {
+                log::debug!("[{src}] invalid federated forward");
+                self.metrics.error.increment(1);
+                return;
+            }
+        };
+        let src_node_id = remote.node_id;
+
+        // Sender is not connected to this relay, so the address from which packets came
+        // from is checked instead of the address of the Node.
+        let keys = remote
+            .info
+            .identities
+            .iter()
+            .filter_map(|ident| Identity::try_from(ident).ok())
+            .collect::<Vec<_>>();
+        if keys
+            .iter()
+            .any(|key| self.ban_list.is_node_banned(&key.node_id))
+            || !self.policy.allows(&src.ip(), &keys)
+        {
+            log::debug!(
+                "[{src}] federated forward from {src_node_id} banned or rejected by policy"
+            );
+            return;
+        }
+        if self.rate_limiter.check_node(src_node_id, payload.len()) != Verdict::Pass {
+            self.metrics.throttled.increment(1);
+            return;
+        }
+
+        let dst_session = match self
+            .state
+            .node(slot)
+            .and_then(|node_id| self.state.node_session(node_id))
+        {
+            Some(session) => session,
+            None => {
+                log::debug!("[{src}] federated forward to unknown slot {slot}");
+                return;
+            }
+        };
+
+        let src_slot = match self.state.slot(src_node_id) {
+            Some(src_slot) => src_slot,
+            None => {
+                log::warn!("[{src}] no slot for federated {src_node_id}, dropping forward");
+                return;
+            }
+        };
+
+        self.metrics.federated.increment(1);
+        dst_session.forwarded.add_out(payload.len());
+        self.send(
+            Forward {
+                session_id: dst_session.session_id.to_array(),
+                slot: src_slot,
+                flags,
+                payload,
+            },
+            dst_session.peer,
+        );
+    }
+
+    /// Forwards packet received from sibling in the cluster to the session held by this
+    /// instance. Sibling has already resolved the session and slot of the sender.
+    /// Payload is prefixed with the tag.
+    fn handle_sibling(
+        &self,
+        src: SocketAddr,
+        session_id: SessionId,
+        slot: SlotId,
+        flags: u16,
+        payload: Payload,
+    ) {
+        let payload = match self
+            .cluster
+            .open_forward(src, &session_id, slot, flags, payload)
+        {
+            Some(payload) => payload,
+            None => {
+                log::debug!("[{src}] invalid sibling forward");
+                return;
+            }
+        };
+        let dst_session = match self
+            .state
+            .session(&session_id)
+            .filter(|session| self.state.holder(&session.session_id).is_none())
+        {
+            Some(session) => session,
+            None => {
+                log::debug!("[{src}] sibling forward to unknown session {session_id}");
+                return;
+            }
+        };
+
+        self.metrics.sibling.increment(1);
+        dst_session.forwarded.add_out(payload.len());
+        self.send(
+            Forward {
+                session_id: session_id.to_array(),
+                slot,
+                flags,
+                payload,
+            },
+            dst_session.peer,
+        );
+    }
+
     pub fn handle(
         &self,
         clock: &Clock,
@@ -101,57 +329,134 @@ impl ForwardHandler {
         self.metrics.start.increment(1);
         self.metrics.in_bytes.increment(payload.len() as u64);
 
-        let src_info = self
-            .session_manager
-            .session(&session_id)
-            .and_then(|session_ref| {
-                if session_ref.peer != src {
-                    return None;
-                }
-                let src_node_id = session_ref.node_id;
-                let src_slot = self.slot_manager.slot(src_node_id);
-                clock.touch(&session_ref.ts);
+        if self.state.is_sibling(&src) {
+            self.handle_sibling(src, session_id, slot, flags, payload);
+            return None;
+        }
 
-                Some((src_node_id, src_slot))
-            });
-        let dst_info = self.slot_manager.node(slot).and_then(|node_id| {
-            let dst_session = self.session_manager.node_session(node_id)?;
-            let dst_addr = dst_session.peer;
+        if self.federation.is_peer(&src) {
+            self.handle_federated(src, slot, flags, payload);
+            return None;
+        }
 
-            Some((dst_addr, dst_session.session_id))
+        let src_info = self.state.session(&session_id).and_then(|session_ref| {
+            if session_ref.peer != src {
+                return None;
+            }
+            let src_node_id = session_ref.node_id;
+            clock.touch(&session_ref.ts);
+
+            Some((session_ref, src_node_id))
+        });
+        let dst_info = self.state.node(slot).and_then(|node_id| {
+            if let Some(dst_session) = self.state.node_session(node_id) {
+                // Session held by a sibling in the cluster is reached through it.
+                let dst_addr = self
+                    .state
+                    .holder(&dst_session.session_id)
+                    .unwrap_or(dst_session.peer);
+                return Some((dst_addr, Some(dst_session), slot));
+            }
+            // Node connected to other relay in federation.
+            let remote = self.federation.node(node_id)?;
+            Some((remote.relay, None, remote.info.slot))
         });
 
         match (src_info, dst_info) {
-            (Some((src_node_id, src_slot)), Some((dst_addr, dst_session_id))) => {
-                let payload_size = payload.len();
-                let forward = Forward {
-                    session_id: dst_session_id.to_array(),
-                    slot: src_slot,
-                    flags,
-                    payload,
+            (Some((src_session, src_node_id)), Some((dst_addr, dst_session, dst_slot))) => {
+                let src_slot = match self.state.slot(src_node_id) {
+                    Some(src_slot) => src_slot,
+                    None => {
+                        log::warn!("[{src}] no slot for {src_node_id}, dropping forward");
+                        return None;
+                    }
                 };
-                let mut bytes = BytesMut::new();
-                bytes.reserve(forward.encoded_len());
-                forward.encode(&mut bytes);
-                let socket = self.socket.clone();
-
-                let out_bytes = self.metrics.out_bytes.clone();
-                let done = self.metrics.done.clone();
-                let error = self.metrics.error.clone();
-
-                tokio::task::spawn_local(async move {
-                    match socket.send_to(&bytes, dst_addr).await {
-                        Ok(v) => {
-                            out_bytes.increment(payload_size as u64);
-                            done.increment(1);
-                            log::debug!("forwarded {} bytes from {src}:{src_node_id}:{src_slot} to {dst_addr}:{slot}", v);
+                let payload_size = payload.len();
+                if src_session
+                    .keys
+                    .iter()
+                    .any(|key| self.ban_list.is_node_banned(&key.node_id))
+                    || !self.policy.allows(&src.ip(), &src_session.keys)
+                {
+                    log::debug!(
+                        "[{src}] forwarding from {src_node_id} banned or rejected by policy"
+                    );
+                    return Some((
+                        self.ack.clone(),
+                        Packet::control(
+                            session_id.to_vec(),
+                            control::StopForwarding {
+                                slot: src_slot,
+                                code: StatusCode::Unauthorized.into(),
+                            },
+                        ),
+                    ));
+                }
+                match self
+                    .rate_limiter
+                    .check(session_id, src_node_id, payload_size)
+                {
+                    Verdict::Pass => {}
+                    Verdict::Pause(resume_after) => {
+                        self.metrics.throttled.increment(1);
+                        self.pause(src, &src_session, src_slot, resume_after);
+                        return None;
+                    }
+                    // Sender ignores `PauseForwarding`.
+                    Verdict::Drop { notify } => {
+                        self.metrics.throttled.increment(1);
+                        return notify.then(|| {
+                            (
+                                self.ack.clone(),
+                                Packet::control(
+                                    session_id.to_vec(),
+                                    control::StopForwarding {
+                                        slot: src_slot,
+                                        code: StatusCode::TooManyRequests.into(),
+                                    },
+                                ),
+                            )
+                        });
+                    }
+                }
+
+                src_session.forwarded.add_in(payload_size);
+                let forward = match dst_session {
+                    Some(dst_session) => {
+                        dst_session.forwarded.add_out(payload_size);
+                        let payload = match self.state.holder(&dst_session.session_id) {
+                            Some(_) => self.cluster.seal_forward(
+                                &dst_session.session_id,
+                                src_slot,
+                                flags,
+                                payload,
+                            )?,
+                            None => payload,
+                        };
+                        Forward {
+                            session_id: dst_session.session_id.to_array(),
+                            slot: src_slot,
+                            flags,
+                            payload,
                         }
-                        Err(e) => {
-                            error.increment(1);
-                            log::error!("fail {:?}", e);
+                    }
+                    None => {
+                        let payload =
+                            self.federation
+                                .seal_forward(src_node_id, dst_slot, flags, payload)?;
+                        self.metrics.federated.increment(1);
+                        Forward {
+                            session_id: Default::default(),
+                            slot: dst_slot,
+                            flags,
+                            payload,
                         }
                     }
-                });
+                };
+                log::trace!(
+                    "forwarding from {src}:{src_node_id}:{src_slot} to {dst_addr}:{dst_slot}"
+                );
+                self.send(forward, dst_addr);
                 None
             }
             (None, _) => Some((
@@ -0,0 +1,170 @@
+use crate::server::CompletionHandler;
+
+use crate::state::Clock;
+use crate::udp_server::UdpSocket;
+use crate::SessionManager;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use ya_relay_core::server_session::SessionId;
+use ya_relay_core::NodeId;
+use ya_relay_proto::proto::{
+    control, request, response, Endpoint, Message, Packet, Protocol, StatusCode,
+};
+
+mod metric {
+    use metrics::{recorder, Counter, Key};
+
+    static KEY_START: Key = Key::from_static_name("ya-relay.packet.hole-punch");
+    static KEY_ERROR: Key = Key::from_static_name("ya-relay.packet.hole-punch.error");
+    static KEY_DONE: Key = Key::from_static_name("ya-relay.packet.hole-punch.done");
+
+    #[derive(Clone)]
+    pub struct HolePunchMetric {
+        pub start: Counter,
+        pub done: Counter,
+        pub error: Counter,
+    }
+
+    impl Default for HolePunchMetric {
+        fn default() -> Self {
+            let recorder = recorder();
+            let start = recorder.register_counter(&KEY_START);
+            let done = recorder.register_counter(&KEY_DONE);
+            let error = recorder.register_counter(&KEY_ERROR);
+
+            Self { start, done, error }
+        }
+    }
+}
+
+/// Coordinates UDP hole punching between two Nodes.
+///
+/// Both Nodes get `HolePunch` control message with endpoint of the other Node,
+/// as observed by the server, so they can start sending probes at the same time.
+/// Unlike `ReverseConnection` none of the Nodes is required to have public IP.
+pub struct HolePunchHandler {
+    session_manager: Arc<SessionManager>,
+    metrics: metric::HolePunchMetric,
+    ack: CompletionHandler,
+    socket: Rc<UdpSocket>,
+}
+
+impl HolePunchHandler {
+    pub fn new(session_manager: &Arc<SessionManager>, socket: &Rc<UdpSocket>) -> Self {
+        let session_manager = Arc::clone(session_manager);
+        let metrics = metric::HolePunchMetric::default();
+        let ack = super::counter_ack(&metrics.done, &metrics.error);
+        let socket = socket.clone();
+        Self {
+            session_manager,
+            metrics,
+            ack,
+            socket,
+        }
+    }
+
+    fn response(&self, request_id: u64, session_id: SessionId, code: StatusCode) -> Packet {
+        Packet::response(
+            request_id,
+            session_id.to_vec(),
+            code,
+            response::HolePunch::default(),
+        )
+    }
+
+    pub fn handle(
+        &self,
+        clock: &Clock,
+        src: SocketAddr,
+        request_id: u64,
+        session_id: SessionId,
+        param: &request::HolePunch,
+    ) -> Option<(CompletionHandler, Packet)> {
+        self.metrics.start.increment(1);
+        let session_ref = match self.session_manager.session(&session_id) {
+            Some(session_ref) if session_ref.peer == src => session_ref,
+            _ => {
+                return Some((
+                    self.ack.clone(),
+                    self.response(request_id, session_id, StatusCode::Unauthorized),
+                ))
+            }
+        };
+
+        let request_node_id: NodeId = match param.node_id.as_slice().try_into() {
+            Ok(node_id) => node_id,
+            Err(_) => {
+                return Some((
+                    self.ack.clone(),
+                    self.response(request_id, session_id, StatusCode::BadRequest),
+                ))
+            }
+        };
+
+        clock.touch(&session_ref.ts);
+        let node_id = session_ref.node_id;
+        if node_id == request_node_id {
+            return Some((
+                self.ack.clone(),
+                self.response(request_id, session_id, StatusCode::BadRequest),
+            ));
+        }
+
+        let (dst_addr, dst_session_id) = match self.session_manager.node_session(request_node_id) {
+            Some(it) if it.hole_punching.load(Ordering::Relaxed) => (it.peer, it.session_id),
+            Some(_) => {
+                log::debug!("[{src}] node {request_node_id} doesn't take part in hole punching");
+                return Some((
+                    self.ack.clone(),
+                    self.response(request_id, session_id, StatusCode::BadRequest),
+                ));
+            }
+            None => {
+                return Some((
+                    self.ack.clone(),
+                    self.response(request_id, session_id, StatusCode::NotFound),
+                ))
+            }
+        };
+
+        let to_dst = Packet::control(
+            dst_session_id.to_vec(),
+            control::HolePunch {
+                node_id: node_id.into_array().to_vec(),
+                endpoints: vec![observed_endpoint(src)],
+            },
+        )
+        .encode_to_vec();
+        let to_src = Packet::control(
+            session_id.to_vec(),
+            control::HolePunch {
+                node_id: request_node_id.into_array().to_vec(),
+                endpoints: vec![observed_endpoint(dst_addr)],
+            },
+        )
+        .encode_to_vec();
+
+        let socket = self.socket.clone();
+        let h = tokio::task::spawn_local(async move {
+            log::debug!("[{src}] coordinating hole punching with {dst_addr}");
+            socket.send_to(&to_dst, dst_addr).await.ok();
+            socket.send_to(&to_src, src).await.ok();
+        });
+        drop(h);
+
+        Some((
+            self.ack.clone(),
+            self.response(request_id, session_id, StatusCode::Ok),
+        ))
+    }
+}
+
+fn observed_endpoint(addr: SocketAddr) -> Endpoint {
+    Endpoint {
+        protocol: Protocol::Udp.into(),
+        address: addr.ip().to_string(),
+        port: addr.port().into(),
+    }
+}
@@ -0,0 +1,148 @@
+use crate::server::CompletionHandler;
+
+use crate::state::ban_list::BanList;
+use crate::state::policy::Policy;
+use crate::state::Clock;
+use crate::SessionManager;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use ya_relay_core::challenge::recover_identities_from_signatures;
+use ya_relay_core::server_session::SessionId;
+use ya_relay_proto::proto::{request, response, Packet, StatusCode};
+
+mod metric {
+    use metrics::{recorder, Counter, Key};
+
+    static KEY_START: Key = Key::from_static_name("ya-relay.packet.identities");
+    static KEY_ERROR: Key = Key::from_static_name("ya-relay.packet.identities.error");
+    static KEY_DONE: Key = Key::from_static_name("ya-relay.packet.identities.done");
+
+    #[derive(Clone)]
+    pub struct IdentitiesMetric {
+        pub start: Counter,
+        pub done: Counter,
+        pub error: Counter,
+    }
+
+    impl Default for IdentitiesMetric {
+        fn default() -> Self {
+            let recorder = recorder();
+            let start = recorder.register_counter(&KEY_START);
+            let done = recorder.register_counter(&KEY_DONE);
+            let error = recorder.register_counter(&KEY_ERROR);
+
+            Self { start, done, error }
+        }
+    }
+}
+
+/// Replaces identities of the session, when the Node adds or removes aliases,
+/// without repeating the handshake.
+///
+/// Each identity signs the session id with a timestamp, so the Node proves ownership
+/// of all keys and the request can't be replayed to restore removed aliases.
+pub struct IdentitiesHandler {
+    session_manager: Arc<SessionManager>,
+    ban_list: Arc<BanList>,
+    policy: Arc<Policy>,
+    metrics: metric::IdentitiesMetric,
+    ack: CompletionHandler,
+}
+
+impl IdentitiesHandler {
+    pub fn new(
+        session_manager: &Arc<SessionManager>,
+        ban_list: &Arc<BanList>,
+        policy: &Arc<Policy>,
+    ) -> Self {
+        let session_manager = Arc::clone(session_manager);
+        let ban_list = Arc::clone(ban_list);
+        let policy = Arc::clone(policy);
+        let metrics = metric::IdentitiesMetric::default();
+        let ack = super::counter_ack(&metrics.done, &metrics.error);
+        Self {
+            session_manager,
+            ban_list,
+            policy,
+            metrics,
+            ack,
+        }
+    }
+
+    fn response(
+        &self,
+        request_id: u64,
+        session_id: SessionId,
+        code: StatusCode,
+    ) -> Option<(CompletionHandler, Packet)> {
+        Some((
+            self.ack.clone(),
+            Packet::response(
+                request_id,
+                session_id.to_vec(),
+                code,
+                response::Identities {},
+            ),
+        ))
+    }
+
+    pub fn handle(
+        &self,
+        clock: &Clock,
+        src: SocketAddr,
+        request_id: u64,
+        session_id: SessionId,
+        param: &request::Identities,
+    ) -> Option<(CompletionHandler, Packet)> {
+        self.metrics.start.increment(1);
+        let session_ref = match self.session_manager.session(&session_id) {
+            Some(session_ref) if session_ref.peer == src => session_ref,
+            _ => {
+                log::debug!(target: "request::identities", "[{src}] session not found {session_id}");
+                return self.response(request_id, session_id, StatusCode::Unauthorized);
+            }
+        };
+        clock.touch(&session_ref.ts);
+
+        if param.timestamp <= session_ref.identities_ts {
+            // Retransmitted request, which was already accepted.
+            let code = match param.timestamp == session_ref.identities_ts {
+                true => StatusCode::Ok,
+                false => StatusCode::Conflict,
+            };
+            log::debug!(target: "request::identities", "[{src}] outdated identities for session {session_id}");
+            return self.response(request_id, session_id, code);
+        }
+
+        let keys = match recover_identities_from_signatures(
+            &session_id.to_vec(),
+            param.timestamp,
+            &param.signatures,
+            session_ref.node_id,
+        ) {
+            Ok(keys) => keys,
+            Err(e) => {
+                log::debug!(target: "request::identities", "[{src}] invalid signatures for session {session_id}: {e}");
+                return self.response(request_id, session_id, StatusCode::BadRequest);
+            }
+        };
+
+        if keys
+            .iter()
+            .any(|key| self.ban_list.is_node_banned(&key.node_id))
+            || !self.policy.allows(&src.ip(), &keys)
+        {
+            log::info!(target: "request::identities", "[{src}] rejected identities of node {}", session_ref.node_id);
+            return self.response(request_id, session_id, StatusCode::Unauthorized);
+        }
+        drop(session_ref);
+
+        match self
+            .session_manager
+            .update_identities(clock, &session_id, keys, param.timestamp)
+        {
+            Some(_) => self.response(request_id, session_id, StatusCode::Ok),
+            None => self.response(request_id, session_id, StatusCode::Conflict),
+        }
+    }
+}
@@ -1,20 +1,27 @@
 use std::cell::RefCell;
 use std::collections::BTreeMap;
-use std::net::{IpAddr, SocketAddr};
+use std::future::Future;
+use std::net::SocketAddr;
 use std::ops::Not;
 use std::rc::Rc;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 use std::{io, mem};
 
+use anyhow::bail;
 use bytes::BytesMut;
 use clap::Args;
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 use tokio::task::{spawn_local, JoinHandle};
 use tokio::time;
 use tokio::time::sleep;
 
 use ya_relay_core::server_session::SessionId;
+use ya_relay_core::tcp_stream::packet_stream;
+use ya_relay_proto::codec::stream::EncoderSink;
+use ya_relay_proto::codec::PacketKind;
 use ya_relay_proto::proto::{packet, request, response, Message, Packet, Response};
 
 use crate::udp_server::{PacketType, UdpSocket, UdpSocketConfig};
@@ -42,8 +49,15 @@ pub struct IpCheckerConfig {
 }
 
 impl IpCheckerConfig {
-    pub fn build(&self, ip_addr: IpAddr) -> io::Result<IpChecker> {
-        checker(ip_addr, self.retry_after, self.timeout, self.retry_cnt)
+    /// Checker sends pings from a new socket bound on the address of `reply`.
+    pub fn build(&self, reply: &UdpSocket) -> io::Result<IpChecker> {
+        let checker_socket = reply.bind_next_to(UdpSocketConfig::new().recv_err())?;
+        checker(
+            checker_socket,
+            self.retry_after,
+            self.timeout,
+            self.retry_cnt,
+        )
     }
 }
 
@@ -51,28 +65,61 @@ pub struct IpChecker {
     tx: mpsc::UnboundedSender<CheckIpRequestRef>,
     retry_job: JoinHandle<()>,
     retry_cnt: usize,
+    timeout: Duration,
 }
 
 impl IpChecker {
     pub fn check_ip_status<F: FnOnce(bool, SessionRef) + 'static>(
         &self,
-        ts: Instant,
         session_ref: SessionRef,
         resolve: F,
     ) -> bool {
-        let session_w = Arc::downgrade(&session_ref);
         let addr = session_ref.peer;
+        self.check_addr_status(session_ref, addr, resolve)
+    }
+
+    /// Checks if Node responds to pings on UDP address other than the one,
+    /// it uses for the session.
+    pub fn check_addr_status<F: FnOnce(bool, SessionRef) + 'static>(
+        &self,
+        session_ref: SessionRef,
+        addr: SocketAddr,
+        resolve: F,
+    ) -> bool {
+        let session_w = Arc::downgrade(&session_ref);
         let retry_cnt = self.retry_cnt;
         let resolve = Box::new(resolve);
         let request = Box::new(CheckIpRequest {
             session_w,
             addr,
-            ts,
+            ts: time::Instant::now(),
             retry_cnt,
             resolve,
         });
         self.tx.send(request).is_ok()
     }
+
+    /// Checks if Node answers pings sent over TCP connection to the address.
+    pub fn check_tcp_status(
+        &self,
+        session_id: SessionId,
+        addr: SocketAddr,
+    ) -> impl Future<Output = bool> + 'static {
+        let timeout = self.timeout;
+        async move {
+            match time::timeout(timeout, tcp_ping(session_id, addr)).await {
+                Ok(Ok(())) => true,
+                Ok(Err(e)) => {
+                    log::debug!(target: "service::check_ip", "[{addr}] tcp ping failed: {e}");
+                    false
+                }
+                Err(_) => {
+                    log::debug!(target: "service::check_ip", "[{addr}] tcp ping timeout");
+                    false
+                }
+            }
+        }
+    }
 }
 
 impl Drop for IpChecker {
@@ -81,10 +128,35 @@ impl Drop for IpChecker {
     }
 }
 
+/// Accepting connection isn't enough, because any service could be listening on the address.
+/// Only the Node knows the session id, which has to be returned in the response.
+async fn tcp_ping(session_id: SessionId, addr: SocketAddr) -> anyhow::Result<()> {
+    let (read, write) = TcpStream::connect(addr).await?.into_split();
+    let mut sink = EncoderSink::from(write);
+    let mut stream = packet_stream(read);
+
+    let ping = Packet::request(session_id.to_vec(), request::Ping {});
+    sink.send(PacketKind::Packet(ping)).await?;
+
+    match stream.next().await {
+        Some(Ok(PacketKind::Packet(Packet {
+            session_id: id,
+            kind:
+                Some(packet::Kind::Response(Response {
+                    kind: Some(response::Kind::Pong(_)),
+                    ..
+                })),
+        }))) if id == session_id.to_vec() => Ok(()),
+        Some(Ok(_)) => bail!("unexpected response"),
+        Some(Err(e)) => Err(e.into()),
+        None => bail!("connection closed"),
+    }
+}
+
 struct CheckIpRequest {
     session_w: SessionWeakRef,
     addr: SocketAddr,
-    ts: Instant,
+    ts: time::Instant,
     retry_cnt: usize,
     resolve: Box<dyn FnOnce(bool, SessionRef)>,
 }
@@ -92,13 +164,12 @@ struct CheckIpRequest {
 type CheckIpRequestRef = Box<CheckIpRequest>;
 
 fn checker(
-    ip: IpAddr,
+    checker_socket: UdpSocket,
     retry_after: Duration,
     timeout: Duration,
     retry_cnt: usize,
 ) -> io::Result<IpChecker> {
     let (tx, rx) = mpsc::unbounded_channel::<CheckIpRequestRef>();
-    let checker_socket = UdpSocketConfig::new().recv_err().bind((ip, 0).into())?;
     let requests = RefCell::new(BTreeMap::new());
     let (worker_idx, queue_size_gauge) = metrics::new_ip_check_requests();
 
@@ -217,6 +288,7 @@ fn checker(
         tx,
         retry_job,
         retry_cnt,
+        timeout,
     })
 }
 
@@ -230,7 +302,7 @@ impl IpCheckerState {
         if let Some(session) = req.session_w.upgrade() {
             let session_id = session.session_id.to_vec();
             let data = Packet::request(session_id, request::Ping {}).encode_to_vec();
-            log::debug!(target: "service::check_ip", "[{}] sending ping for {}", session.peer, session.session_id);
+            log::debug!(target: "service::check_ip", "[{}] sending ping for {}", req.addr, session.session_id);
             self.checker_socket.send_to(&data, req.addr).await?;
         }
         Ok(())
@@ -260,7 +332,7 @@ impl IpCheckerState {
                         }
                         if req.retry_cnt > 0 {
                             req.retry_cnt -= 1;
-                            to_ping.push(session_ref);
+                            to_ping.push((session_ref, req.addr));
                         }
                         Some(req)
                     })
@@ -271,9 +343,8 @@ impl IpCheckerState {
             drop(g);
         };
 
-        for session in to_ping {
+        for (session, peer) in to_ping {
             let session_id = session.session_id.to_vec();
-            let peer = session.peer;
             let data = Packet::request(session_id, request::Ping {}).encode_to_vec();
             log::debug!(target: "service::check_ip", "[{peer}] sending ping retry for {}", session.session_id);
             self.checker_socket.send_to(&data, peer).await?;
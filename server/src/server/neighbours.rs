@@ -7,7 +7,7 @@ use ya_relay_proto::proto::response::Neighbours;
 use ya_relay_proto::proto::{request, Packet, StatusCode};
 
 use crate::server::CompletionHandler;
-use crate::state::slot_manager::SlotManager;
+use crate::state::backend::StateBackend;
 use crate::state::Clock;
 use crate::SessionManager;
 
@@ -63,20 +63,20 @@ mod metric {
 
 pub struct NeighboursHandler {
     session_manager: Arc<SessionManager>,
-    slot_manager: Arc<SlotManager>,
+    state: Arc<dyn StateBackend>,
     metrics: metric::NeighboursMetric,
     ack: CompletionHandler,
 }
 
 impl NeighboursHandler {
-    pub fn new(session_manager: &Arc<SessionManager>, slot_manager: &Arc<SlotManager>) -> Self {
+    pub fn new(session_manager: &Arc<SessionManager>, state: &Arc<dyn StateBackend>) -> Self {
         let session_manager = Arc::clone(session_manager);
-        let slot_manager = slot_manager.clone();
+        let state = state.clone();
         let metrics = metric::NeighboursMetric::default();
         let ack = Rc::new(metrics.clone());
         Self {
             session_manager,
-            slot_manager,
+            state,
             metrics,
             ack,
         }
@@ -90,9 +90,9 @@ impl NeighboursHandler {
         param: &request::Neighbours,
     ) -> Option<(CompletionHandler, Packet)> {
         self.metrics.start.increment(1);
-        let decoder = super::state_decoder::decoder(&self.session_manager, &self.slot_manager);
+        let decoder = super::state_decoder::decoder(&*self.state);
 
-        let session_ref = match self.session_manager.session(&session_id) {
+        let session_ref = match self.state.session(&session_id) {
             Some(session_ref) if session_ref.peer == src => session_ref,
             _ => {
                 return Some((
@@ -114,7 +114,7 @@ impl NeighboursHandler {
 
         let nodes = neighbours
             .into_iter()
-            .map(|session_ref| decoder.to_node_info(&session_ref))
+            .filter_map(|session_ref| decoder.to_node_info(&session_ref))
             .collect();
 
         let response = Packet::response(
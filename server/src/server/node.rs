@@ -1,8 +1,8 @@
 use crate::server::state_decoder::decoder;
 use crate::server::CompletionHandler;
-use crate::state::slot_manager::SlotManager;
+use crate::state::backend::StateBackend;
+use crate::state::federation::Federation;
 use crate::state::Clock;
-use crate::SessionManager;
 use std::net::SocketAddr;
 use std::rc::Rc;
 use std::sync::Arc;
@@ -59,21 +59,21 @@ mod metric {
 }
 
 pub struct NodeHandler {
-    session_manager: Arc<SessionManager>,
-    slot_manager: Arc<SlotManager>,
+    state: Arc<dyn StateBackend>,
+    federation: Arc<Federation>,
     metrics: metric::NodeMetric,
     ack: CompletionHandler,
 }
 
 impl NodeHandler {
-    pub fn new(session_manager: &Arc<SessionManager>, slot_manager: &Arc<SlotManager>) -> Self {
-        let session_manager = Arc::clone(session_manager);
-        let slot_manager = slot_manager.clone();
+    pub fn new(state: &Arc<dyn StateBackend>, federation: &Arc<Federation>) -> Self {
+        let state = state.clone();
+        let federation = federation.clone();
         let metrics = metric::NodeMetric::default();
         let ack = Rc::new(metrics.clone());
         Self {
-            session_manager,
-            slot_manager,
+            state,
+            federation,
             metrics,
             ack,
         }
@@ -88,7 +88,7 @@ impl NodeHandler {
         param: &request::Node,
     ) -> Option<(CompletionHandler, Packet)> {
         self.metrics.start.increment(1);
-        let session_ref = match self.session_manager.session(&session_id) {
+        let session_ref = match self.state.session(&session_id) {
             Some(session_ref) if session_ref.peer == src => session_ref,
             _ => {
                 return Some((
@@ -103,7 +103,7 @@ impl NodeHandler {
             }
         };
         clock.touch(&session_ref.ts);
-        let decoder = decoder(&self.session_manager, &self.slot_manager);
+        let decoder = decoder(&*self.state);
 
         let request_node_id: NodeId = match param.node_id.as_slice().try_into() {
             Ok(node_id) => node_id,
@@ -120,8 +120,26 @@ impl NodeHandler {
             }
         };
 
-        let node = match self.session_manager.node_session(request_node_id) {
-            Some(it) => decoder.to_node_info(&it),
+        let node = match self.state.node_session(request_node_id) {
+            Some(it) => Some(decoder.to_node_info(&it)),
+            None => self
+                .federation
+                .node(request_node_id)
+                .map(|node| decoder.to_remote_node_info(&node)),
+        };
+        let node = match node {
+            Some(Some(node)) => node,
+            Some(None) => {
+                return Some((
+                    self.ack.clone(),
+                    Packet::response(
+                        request_id,
+                        session_id.to_vec(),
+                        StatusCode::ServerError,
+                        response::Node::default(),
+                    ),
+                ))
+            }
             None => {
                 return Some((
                     self.ack.clone(),
@@ -0,0 +1,196 @@
+use crate::server::ip_checker::IpChecker;
+use crate::server::register::probe_advertised;
+use crate::server::CompletionHandler;
+
+use crate::state::policy::Policy;
+use crate::state::session_manager::PendingRebind;
+use crate::state::Clock;
+use crate::SessionManager;
+use futures::future;
+use rand::{thread_rng, Rng};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use ya_relay_core::challenge::recover_rebind_node_id;
+use ya_relay_core::server_session::SessionId;
+use ya_relay_proto::proto::{request, response, Packet, StatusCode};
+
+/// Time given to the Node for signing the nonce.
+const REBIND_TIMEOUT: Duration = Duration::from_secs(10);
+const NONCE_SIZE: usize = 32;
+/// Limit of addresses, to which a single session can be moving at the same time.
+const MAX_PENDING_REBINDS: usize = 4;
+
+mod metric {
+    use metrics::{recorder, Counter, Key};
+
+    static KEY_START: Key = Key::from_static_name("ya-relay.packet.rebind");
+    static KEY_ERROR: Key = Key::from_static_name("ya-relay.packet.rebind.error");
+    static KEY_DONE: Key = Key::from_static_name("ya-relay.packet.rebind.done");
+
+    #[derive(Clone)]
+    pub struct RebindMetric {
+        pub start: Counter,
+        pub done: Counter,
+        pub error: Counter,
+    }
+
+    impl Default for RebindMetric {
+        fn default() -> Self {
+            let recorder = recorder();
+            let start = recorder.register_counter(&KEY_START);
+            let done = recorder.register_counter(&KEY_DONE);
+            let error = recorder.register_counter(&KEY_ERROR);
+
+            Self { start, done, error }
+        }
+    }
+}
+
+/// Moves session to a new address of the Node (e.g. after NAT rebinding or network change),
+/// without repeating the handshake.
+///
+/// The first request sent from the new address gets a random nonce in response.
+/// The session is moved, when the nonce signed by the default identity of the session
+/// comes back from the same address. This way the Node proves both the ownership
+/// of the session and that it can receive packets on the new address.
+/// Endpoints advertised by the Node are probed again from the moved session.
+pub struct RebindHandler {
+    session_manager: Arc<SessionManager>,
+    policy: Arc<Policy>,
+    ip_checker: Rc<IpChecker>,
+    metrics: metric::RebindMetric,
+    ack: CompletionHandler,
+}
+
+impl RebindHandler {
+    pub fn new(
+        session_manager: &Arc<SessionManager>,
+        policy: &Arc<Policy>,
+        ip_checker: &Rc<IpChecker>,
+    ) -> Self {
+        let session_manager = Arc::clone(session_manager);
+        let policy = Arc::clone(policy);
+        let ip_checker = Rc::clone(ip_checker);
+        let metrics = metric::RebindMetric::default();
+        let ack = super::counter_ack(&metrics.done, &metrics.error);
+        Self {
+            session_manager,
+            policy,
+            ip_checker,
+            metrics,
+            ack,
+        }
+    }
+
+    fn response(
+        &self,
+        request_id: u64,
+        session_id: SessionId,
+        code: StatusCode,
+        nonce: Vec<u8>,
+    ) -> Option<(CompletionHandler, Packet)> {
+        Some((
+            self.ack.clone(),
+            Packet::response(
+                request_id,
+                session_id.to_vec(),
+                code,
+                response::Rebind { nonce },
+            ),
+        ))
+    }
+
+    pub fn handle(
+        &self,
+        clock: &Clock,
+        src: SocketAddr,
+        request_id: u64,
+        session_id: SessionId,
+        param: &request::Rebind,
+    ) -> Option<(CompletionHandler, Packet)> {
+        self.metrics.start.increment(1);
+        let session_ref = match self.session_manager.session(&session_id) {
+            Some(session_ref) => session_ref,
+            None => {
+                log::debug!(target: "request::rebind", "[{src}] session not found {session_id}");
+                return self.response(request_id, session_id, StatusCode::Unauthorized, vec![]);
+            }
+        };
+
+        if session_ref.peer == src {
+            clock.touch(&session_ref.ts);
+            return self.response(request_id, session_id, StatusCode::Ok, vec![]);
+        }
+
+        if !self.policy.allows(&src.ip(), &session_ref.keys) {
+            log::debug!(target: "request::rebind", "[{src}] node {} rejected by policy", session_ref.node_id);
+            return self.response(request_id, session_id, StatusCode::Unauthorized, vec![]);
+        }
+
+        let mut pending = session_ref.rebind.lock();
+        pending.retain(|p| p.created.elapsed() < REBIND_TIMEOUT);
+
+        if param.nonce.is_empty() {
+            // Retransmitted requests get the same nonce, otherwise they would invalidate
+            // the one being signed by the Node.
+            if let Some(p) = pending.iter().find(|p| p.addr == src) {
+                let nonce = p.nonce.clone();
+                return self.response(request_id, session_id, StatusCode::Ok, nonce);
+            }
+            if pending.len() >= MAX_PENDING_REBINDS {
+                log::debug!(target: "request::rebind", "[{src}] too many pending rebinds for session {session_id}");
+                return self.response(request_id, session_id, StatusCode::TooManyRequests, vec![]);
+            }
+            let nonce = thread_rng().gen::<[u8; NONCE_SIZE]>().to_vec();
+            pending.push(PendingRebind {
+                nonce: nonce.clone(),
+                addr: src,
+                created: Instant::now(),
+            });
+            return self.response(request_id, session_id, StatusCode::Ok, nonce);
+        }
+
+        // Nonce can be used only once, but requests from other addresses can't invalidate it.
+        let pending = pending
+            .iter()
+            .position(|p| p.addr == src)
+            .map(|idx| pending.swap_remove(idx));
+        let valid = match pending {
+            Some(pending) => pending.nonce == param.nonce,
+            None => false,
+        };
+        if !valid {
+            log::debug!(target: "request::rebind", "[{src}] invalid or expired nonce for session {session_id}");
+            return self.response(request_id, session_id, StatusCode::Unauthorized, vec![]);
+        }
+
+        match recover_rebind_node_id(&session_id.to_vec(), &param.nonce, &param.signature) {
+            Ok(node_id) if node_id == session_ref.node_id => (),
+            Ok(node_id) => {
+                log::debug!(target: "request::rebind", "[{src}] session {session_id} signed by [{node_id}] instead of [{}]", session_ref.node_id);
+                return self.response(request_id, session_id, StatusCode::Unauthorized, vec![]);
+            }
+            Err(e) => {
+                log::debug!(target: "request::rebind", "[{src}] invalid signature for session {session_id}: {e}");
+                return self.response(request_id, session_id, StatusCode::BadRequest, vec![]);
+            }
+        }
+
+        match self.session_manager.rebind_session(clock, &session_id, src) {
+            Some(session_ref) => {
+                let to_verify = session_ref
+                    .advertised
+                    .lock()
+                    .iter()
+                    .map(|endpoint| (endpoint.protocol, endpoint.addr))
+                    .collect();
+                let probes = probe_advertised(&self.ip_checker, clock, &session_ref, to_verify);
+                tokio::task::spawn_local(future::join_all(probes));
+                self.response(request_id, session_id, StatusCode::Ok, vec![])
+            }
+            None => self.response(request_id, session_id, StatusCode::NotFound, vec![]),
+        }
+    }
+}
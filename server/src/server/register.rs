@@ -1,21 +1,33 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::rc::{Rc, Weak};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
 
+use futures::future::{self, LocalBoxFuture};
+use futures::FutureExt;
 use quick_cache::sync::Cache;
+use tokio::sync::oneshot;
 use tokio::task::spawn_local;
 
 use ya_relay_core::server_session::SessionId;
-use ya_relay_proto::proto::{request, response, Message, Packet, StatusCode};
+use ya_relay_proto::proto::{request, response, Message, Packet, Protocol, StatusCode};
 
 use crate::server::ip_checker::IpChecker;
 use crate::server::{counter_ack, noop_ack, CompletionHandler, IpCache};
+use crate::state::ban_list::BanList;
+use crate::state::policy::Policy;
 use crate::state::slot_manager::SlotManager;
 use crate::state::Clock;
 use crate::udp_server::UdpSocket;
-use crate::{AddrStatus, SessionManager};
+use crate::{AdvertisedEndpoint, SessionManager, SessionRef};
+
+/// Limit of endpoints verified for a single Node.
+const MAX_ADVERTISED_ENDPOINTS: usize = 8;
+const ADVERTISED_RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Limit of advertised endpoints probed for a single session per `ADVERTISED_RECHECK_INTERVAL`.
+const MAX_PROBES: usize = 2 * MAX_ADVERTISED_ENDPOINTS;
 
 mod metric {
     use metrics::{recorder, Counter, Key};
@@ -44,11 +56,12 @@ mod metric {
 
 pub struct RegisterHandler {
     session_manager: Arc<SessionManager>,
-    #[allow(dead_code)]
     slot_manager: Arc<SlotManager>,
+    ban_list: Arc<BanList>,
+    policy: Arc<Policy>,
     metrics: metric::RegisterMetric,
     ack: CompletionHandler,
-    ip_checker: IpChecker,
+    ip_checker: Rc<IpChecker>,
     cache: Arc<Cache<SocketAddr, (Instant, bool)>>,
     reply_socket: Weak<UdpSocket>,
 }
@@ -57,12 +70,17 @@ impl RegisterHandler {
     pub fn new(
         session_manager: &Arc<SessionManager>,
         slot_manager: &Arc<SlotManager>,
-        ip_checker: IpChecker,
+        ban_list: &Arc<BanList>,
+        policy: &Arc<Policy>,
+        ip_checker: &Rc<IpChecker>,
         reply_socket: &Rc<UdpSocket>,
         cache: IpCache,
     ) -> Self {
         let session_manager = Arc::clone(session_manager);
         let slot_manager = slot_manager.clone();
+        let ban_list = ban_list.clone();
+        let policy = policy.clone();
+        let ip_checker = ip_checker.clone();
         let metrics: metric::RegisterMetric = Default::default();
         let ack = counter_ack(&metrics.done, &metrics.error);
 
@@ -71,6 +89,8 @@ impl RegisterHandler {
         Self {
             session_manager,
             slot_manager,
+            ban_list,
+            policy,
             metrics,
             ack,
             ip_checker,
@@ -85,7 +105,7 @@ impl RegisterHandler {
         src: SocketAddr,
         request_id: u64,
         session_id: SessionId,
-        _register: &request::Register,
+        register: &request::Register,
     ) -> Option<(CompletionHandler, Packet)> {
         let session_ref = match self.session_manager.session(&session_id) {
             Some(session_ref) => session_ref,
@@ -105,70 +125,304 @@ impl RegisterHandler {
         };
         clock.touch(&session_ref.ts);
         self.metrics.start.increment(1);
-        match self.cache.get(&src) {
+        if session_ref
+            .keys
+            .iter()
+            .any(|key| self.ban_list.is_node_banned(&key.node_id))
+            || !self.policy.allows(&src.ip(), &session_ref.keys)
+        {
+            log::debug!(target: "request::register", "[{src}] node {} is banned or rejected by policy", session_ref.node_id);
+            self.metrics.error.increment(1);
+            return Some((
+                noop_ack(),
+                Packet::response(
+                    request_id,
+                    session_id.to_vec(),
+                    StatusCode::Unauthorized,
+                    response::Register::default(),
+                ),
+            ));
+        }
+        // Other Nodes reach the Node through its slot, so registering fails without one.
+        if self.slot_manager.slot(session_ref.node_id).is_none() {
+            log::warn!(target: "request::register", "[{src}] no free slot for node {}", session_ref.node_id);
+            self.metrics.error.increment(1);
+            return Some((
+                noop_ack(),
+                Packet::response(
+                    request_id,
+                    session_id.to_vec(),
+                    StatusCode::ServerError,
+                    response::Register::default(),
+                ),
+            ));
+        }
+        let to_verify = update_advertised(&session_ref, src, register);
+        session_ref
+            .hole_punching
+            .store(register.hole_punching, Ordering::Relaxed);
+
+        let cached = match self.cache.get(&src) {
             Some((ts, v)) if ts.elapsed() < Duration::from_secs(60) => {
                 log::debug!(target: "request::register", "[{src}] resolving from cache: {v:?}");
-                let new_addr_status = if v {
-                    AddrStatus::Valid(Instant::now())
-                } else {
-                    AddrStatus::Invalid(Instant::now())
-                };
-                *session_ref.addr_status.lock() = new_addr_status;
-
-                let endpoints = session_ref.endpoint().into_iter().collect();
-                self.session_manager.link_sessions(&session_ref);
-                return Some((
-                    self.ack.clone(),
-                    Packet::response(
-                        request_id,
-                        session_id.to_vec(),
-                        StatusCode::Ok,
-                        response::Register { endpoints },
-                    ),
-                ));
+                session_ref.addr_status.lock().set_valid(v);
+                true
             }
-            _ => (),
-        }
-
-        {
-            let reply_socket = self.reply_socket.clone();
-            let ack = self.ack.clone();
-            let sm = self.session_manager.clone();
-            log::debug!(target: "request::register", "[{src}] resolving from ip_checker {session_id}");
-            self.ip_checker.check_ip_status(clock.time(), session_ref, move |status, session_ref| {
-                let reply_socket = match reply_socket.upgrade() {
-                    Some(v) => v,
-                    None => return
-                };
-                let mut g = session_ref.addr_status.lock();
-                g.set_valid(status);
-                drop(g);
-                log::debug!(target: "request::register", "[{src}] set_valid {session_id} {status}");
-                let endpoints = session_ref.endpoint().into_iter().collect();
-                let session_id = session_ref.session_id;
-                let peer = session_ref.peer;
-                sm.link_sessions(&session_ref);
-                drop(session_ref);
+            _ => false,
+        };
 
-                let data = Packet::response(
+        if cached && to_verify.is_empty() {
+            let endpoints = session_ref.endpoints();
+            self.session_manager.link_sessions(&session_ref);
+            return Some((
+                self.ack.clone(),
+                Packet::response(
                     request_id,
                     session_id.to_vec(),
                     StatusCode::Ok,
                     response::Register { endpoints },
-                );
-                spawn_local(async move {
-                    let result = reply_socket.send_to(&data.encode_to_vec(), peer).await;
-                    let clock = Clock::now();
-                    match  result {
-                        Ok(_) => ack.done(&clock),
-                        Err(e) => {
-                            log::error!("[{:?}] failed to send response: {:?}", peer, e);
-                            ack.error(&clock);
-                        }
-                    }
-                });
+                ),
+            ));
+        }
+
+        let observed = if cached {
+            None
+        } else {
+            let (tx, rx) = oneshot::channel();
+            log::debug!(target: "request::register", "[{src}] resolving from ip_checker {session_id}");
+            self.ip_checker.check_ip_status(session_ref.clone(), move |status, session_ref| {
+                session_ref.addr_status.lock().set_valid(status);
+                log::debug!(target: "request::register", "[{src}] set_valid {session_id} {status}");
+                let _ = tx.send(());
             });
+            Some(rx)
         };
+
+        let probes = probe_advertised(&self.ip_checker, clock, &session_ref, to_verify);
+
+        let reply_socket = self.reply_socket.clone();
+        let ack = self.ack.clone();
+        let sm = self.session_manager.clone();
+        let session_w = Arc::downgrade(&session_ref);
+        drop(session_ref);
+
+        spawn_local(async move {
+            if let Some(observed) = observed {
+                // Sender is dropped, when session was removed before checking finished.
+                if observed.await.is_err() {
+                    return;
+                }
+            }
+            future::join_all(probes).await;
+
+            let (reply_socket, session_ref) = match (reply_socket.upgrade(), session_w.upgrade()) {
+                (Some(reply_socket), Some(session_ref)) => (reply_socket, session_ref),
+                _ => return,
+            };
+            let endpoints = session_ref.endpoints();
+            let peer = session_ref.peer;
+            sm.link_sessions(&session_ref);
+            drop(session_ref);
+
+            let data = Packet::response(
+                request_id,
+                session_id.to_vec(),
+                StatusCode::Ok,
+                response::Register { endpoints },
+            );
+            let result = reply_socket.send_to(&data.encode_to_vec(), peer).await;
+            let clock = Clock::now();
+            match result {
+                Ok(_) => ack.done(&clock),
+                Err(e) => {
+                    log::error!("[{:?}] failed to send response: {:?}", peer, e);
+                    ack.error(&clock);
+                }
+            }
+        });
         None
     }
 }
+
+/// Probes endpoints advertised by the Node and stores results in the session.
+/// Registering repeatedly with changing endpoints mustn't turn the relay into a scanner,
+/// so endpoints over the probe limit of the session are marked invalid without probing.
+pub(crate) fn probe_advertised(
+    ip_checker: &IpChecker,
+    clock: &Clock,
+    session_ref: &SessionRef,
+    to_verify: Vec<(Protocol, SocketAddr)>,
+) -> Vec<LocalBoxFuture<'static, ()>> {
+    let allowed = session_ref.probes.lock().take(
+        to_verify.len(),
+        MAX_PROBES,
+        ADVERTISED_RECHECK_INTERVAL,
+        clock.time(),
+    );
+    for (protocol, addr) in &to_verify[allowed..] {
+        log::debug!(target: "request::register", "[{}] probe limit reached, not verifying {addr}", session_ref.peer);
+        session_ref.set_advertised_status(*protocol, *addr, false);
+    }
+    to_verify
+        .into_iter()
+        .take(allowed)
+        .map(|(protocol, addr)| verify_advertised(ip_checker, session_ref, protocol, addr))
+        .collect()
+}
+
+/// Probes advertised endpoint and stores the result in the session.
+fn verify_advertised(
+    ip_checker: &IpChecker,
+    session_ref: &SessionRef,
+    protocol: Protocol,
+    addr: SocketAddr,
+) -> LocalBoxFuture<'static, ()> {
+    match protocol {
+        Protocol::Udp => {
+            let (tx, rx) = oneshot::channel();
+            ip_checker.check_addr_status(session_ref.clone(), addr, move |status, session_ref| {
+                log::debug!(target: "request::register", "[{addr}] advertised udp endpoint valid: {status}");
+                session_ref.set_advertised_status(protocol, addr, status);
+                let _ = tx.send(());
+            });
+            rx.map(|_| ()).boxed_local()
+        }
+        _ => {
+            let session_w = Arc::downgrade(session_ref);
+            let check = ip_checker.check_tcp_status(session_ref.session_id, addr);
+            async move {
+                let status = check.await;
+                log::debug!(target: "request::register", "[{addr}] advertised tcp endpoint valid: {status}");
+                if let Some(session_ref) = session_w.upgrade() {
+                    session_ref.set_advertised_status(protocol, addr, status);
+                }
+            }
+            .boxed_local()
+        }
+    }
+}
+
+/// Replaces endpoints advertised by the Node. Returns endpoints, that need to be verified.
+/// Endpoints verified recently keep their status.
+fn update_advertised(
+    session_ref: &SessionRef,
+    src: SocketAddr,
+    register: &request::Register,
+) -> Vec<(Protocol, SocketAddr)> {
+    let mut requested = Vec::new();
+    for endpoint in &register.endpoints {
+        let protocol = match Protocol::try_from(endpoint.protocol) {
+            Ok(protocol @ (Protocol::Udp | Protocol::Tcp)) => protocol,
+            _ => continue,
+        };
+        let addr = match SocketAddr::try_from(endpoint.clone()) {
+            Ok(addr) if is_probeable(&addr, &src) => addr,
+            _ => {
+                log::debug!(target: "request::register", "[{src}] ignoring advertised endpoint {}:{}", endpoint.address, endpoint.port);
+                continue;
+            }
+        };
+        // Observed address is verified separately.
+        if (protocol == Protocol::Udp && addr == src) || requested.contains(&(protocol, addr)) {
+            continue;
+        }
+        requested.push((protocol, addr));
+    }
+    requested.truncate(MAX_ADVERTISED_ENDPOINTS);
+
+    let mut advertised = session_ref.advertised.lock();
+    let mut previous = std::mem::take(&mut *advertised);
+    let mut to_verify = Vec::new();
+
+    for (protocol, addr) in requested {
+        let known = previous
+            .iter()
+            .position(|e| e.protocol == protocol && e.addr == addr)
+            .map(|idx| previous.swap_remove(idx))
+            .filter(|e| e.status.is_valid() && e.status.age() < ADVERTISED_RECHECK_INTERVAL);
+
+        match known {
+            Some(endpoint) => advertised.push(endpoint),
+            None => {
+                advertised.push(AdvertisedEndpoint::new(protocol, addr));
+                to_verify.push((protocol, addr));
+            }
+        }
+    }
+    to_verify
+}
+
+/// Relay probes only globally reachable addresses, so Nodes can't use it to reach hosts
+/// in its internal network. The address, from which the Node connects, is allowed too,
+/// since it belongs to the Node anyway.
+fn is_probeable(addr: &SocketAddr, src: &SocketAddr) -> bool {
+    addr.port() != 0 && (addr.ip() == src.ip() || is_global(addr.ip()))
+}
+
+/// Stable replacement for `IpAddr::is_global`.
+fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // "This network", shared address space, benchmarking and reserved (including broadcast).
+                || a == 0
+                || (a == 100 && (b & 0xc0) == 64)
+                || (a == 198 && (b & 0xfe) == 18)
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_global(IpAddr::V4(ip)),
+            None => {
+                let segments = ip.segments();
+                !(ip.is_unspecified()
+                    || ip.is_loopback()
+                    || ip.is_multicast()
+                    // Unique local, link local and documentation.
+                    || (segments[0] & 0xfe00) == 0xfc00
+                    || (segments[0] & 0xffc0) == 0xfe80
+                    || (segments[0] == 0x2001 && segments[1] == 0xdb8))
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_probeable() {
+        let src: SocketAddr = "203.0.113.7:5000".parse().unwrap();
+        let probeable = |addr: &str| is_probeable(&addr.parse().unwrap(), &src);
+
+        assert!(probeable("8.8.8.8:53"));
+        assert!(probeable("[2606:4700::1111]:443"));
+        // Node's own address, even if it isn't global.
+        assert!(probeable("203.0.113.7:7000"));
+        assert!(!probeable("8.8.8.8:0"));
+
+        for addr in [
+            "127.0.0.1:80",
+            "10.0.0.1:22",
+            "172.16.5.4:22",
+            "192.168.1.1:80",
+            "169.254.169.254:80",
+            "100.64.0.1:80",
+            "0.0.0.0:80",
+            "255.255.255.255:80",
+            "224.0.0.1:80",
+            "[::1]:80",
+            "[fd00::1]:80",
+            "[fe80::1]:80",
+            "[::ffff:10.0.0.1]:80",
+        ] {
+            assert!(!probeable(addr), "{addr}");
+        }
+    }
+}
@@ -6,6 +6,9 @@ use tiny_keccak::Hasher;
 use ya_relay_core::challenge::RawChallenge;
 
 use crate::server::session::metric::SessionMetric;
+use crate::state::ban_list::BanList;
+use crate::state::difficulty::{AdaptiveDifficulty, DifficultyConfig};
+use crate::state::policy::Policy;
 
 use super::*;
 
@@ -48,27 +51,60 @@ mod metric {
 pub struct SessionHandlerConfig {
     #[arg(long, env, default_value = "16")]
     pub difficulty: u64,
+    /// Upper limit of the difficulty raised under load. Adaptive difficulty is disabled,
+    /// when it isn't greater than `difficulty`.
+    #[arg(long, env, default_value = "24")]
+    pub max_difficulty: u64,
+    /// Session establishment attempts per second, above which difficulty is raised.
+    #[arg(long, env, default_value = "200")]
+    pub difficulty_rate_threshold: u64,
+    /// Unsolved challenges from a single subnet, above which difficulty for it is raised.
+    #[arg(long, env, default_value = "32")]
+    pub difficulty_subnet_threshold: u64,
+    #[arg(long, env, value_parser = humantime::parse_duration, default_value = "10s")]
+    pub difficulty_adjust_interval: time::Duration,
     #[arg(long, env, value_parser = u128_from_hex)]
     pub salt: Option<u128>,
 }
 
+impl SessionHandlerConfig {
+    pub fn difficulty_config(&self) -> DifficultyConfig {
+        DifficultyConfig {
+            base: self.difficulty,
+            max: self.max_difficulty,
+            rate_threshold: self.difficulty_rate_threshold,
+            subnet_threshold: self.difficulty_subnet_threshold,
+        }
+    }
+}
+
 fn u128_from_hex(hex_str: &str) -> Result<u128, hex::FromHexError> {
     let bytes: [u8; 16] = hex::FromHex::from_hex(hex_str)?;
     Ok(u128::from_le_bytes(bytes))
 }
 
 pub struct SessionHandler {
-    difficulty: u64,
+    difficulty: Arc<AdaptiveDifficulty>,
     salt: [u8; 16],
     session_manager: Arc<SessionManager>,
+    ban_list: Arc<BanList>,
+    policy: Arc<Policy>,
     metrics: SessionMetric,
     challenge_send_ack: CompletionHandler,
     challenge_valid_ack: CompletionHandler,
 }
 
 impl SessionHandler {
-    pub fn new(session_manager: &Arc<SessionManager>, config: &SessionHandlerConfig) -> Self {
+    pub fn new(
+        session_manager: &Arc<SessionManager>,
+        ban_list: &Arc<BanList>,
+        policy: &Arc<Policy>,
+        difficulty: &Arc<AdaptiveDifficulty>,
+        config: &SessionHandlerConfig,
+    ) -> Self {
         let session_manager = Arc::clone(session_manager);
+        let ban_list = Arc::clone(ban_list);
+        let policy = Arc::clone(policy);
         let metrics = SessionMetric::default();
         let challenge_send_ack = counter_ack(&metrics.challenge_sent, &metrics.error);
         let challenge_valid_ack = counter_ack(&metrics.challenge_valid, &metrics.error);
@@ -76,12 +112,14 @@ impl SessionHandler {
             .salt
             .unwrap_or_else(|| thread_rng().gen())
             .to_ne_bytes();
-        let difficulty = config.difficulty;
+        let difficulty = difficulty.clone();
 
         Self {
             difficulty,
             salt,
             session_manager,
+            ban_list,
+            policy,
             metrics,
             challenge_send_ack,
             challenge_valid_ack,
@@ -102,19 +140,22 @@ impl SessionHandler {
         raw_challenge
     }
 
-    fn check_session_id(&self, session_id: SessionId, addr: SocketAddr) -> bool {
+    /// Finds difficulty of the challenge issued with given session id. Difficulty
+    /// might have changed since then, so all possible values are checked.
+    fn check_session_id(&self, session_id: SessionId, addr: SocketAddr) -> Option<u64> {
         let epoch = self.epoch();
         for n in 0..3 {
-            if session_id == self.gen_new_challenge(addr, epoch - n) {
-                return true;
+            for difficulty in self.difficulty.range() {
+                if session_id == self.gen_new_challenge(addr, epoch - n, difficulty) {
+                    return Some(difficulty);
+                }
             }
         }
 
-        false
+        None
     }
-    fn gen_new_challenge(&self, addr: SocketAddr, epoch: u64) -> SessionId {
+    fn gen_new_challenge(&self, addr: SocketAddr, epoch: u64, difficulty: u64) -> SessionId {
         let mut data = [0u8; 32];
-        let difficulty = self.difficulty;
 
         let mut h = tiny_keccak::Keccak::v256();
         match addr {
@@ -168,11 +209,29 @@ impl SessionHandler {
                         hex::encode(challenge.as_slice())
                     );
 
+                    let difficulty = match self.check_session_id(session_id, src) {
+                        Some(difficulty) => difficulty,
+                        None => {
+                            self.metrics.error.increment(1);
+                            return Some((
+                                noop_ack(),
+                                Packet {
+                                    session_id: session_id.to_vec(),
+                                    kind: Some(packet::Kind::Response(Response {
+                                        code: StatusCode::BadRequest.into(),
+                                        request_id,
+                                        kind: Some(response::Kind::Session(Default::default())),
+                                    })),
+                                },
+                            ));
+                        }
+                    };
+
                     let (node_id, keys) = match challenge::recover_identities_from_challenge::<
                         ChallengeDigest,
                     >(
                         &challenge,
-                        self.difficulty,
+                        difficulty,
                         Some(challenge_resp.clone()),
                         None,
                     ) {
@@ -193,15 +252,21 @@ impl SessionHandler {
                         }
                         Ok(v) => v,
                     };
+                    self.difficulty.challenge_solved(src.ip());
 
-                    if !self.check_session_id(session_id, src) {
+                    if keys
+                        .iter()
+                        .any(|key| self.ban_list.is_node_banned(&key.node_id))
+                        || !self.policy.allows(&src.ip(), &keys)
+                    {
                         self.metrics.error.increment(1);
+                        log::info!(target: "request::session", "[{src}] rejected session of node {node_id}");
                         return Some((
                             noop_ack(),
                             Packet {
                                 session_id: session_id.to_vec(),
                                 kind: Some(packet::Kind::Response(Response {
-                                    code: StatusCode::BadRequest.into(),
+                                    code: StatusCode::Unauthorized.into(),
                                     request_id,
                                     kind: Some(response::Kind::Session(Default::default())),
                                 })),
@@ -264,8 +329,10 @@ impl SessionHandler {
                 }
             }
         } else {
-            let (mut session, _challenge) = challenge::prepare_challenge_response(self.difficulty);
-            let session_id = self.gen_new_challenge(src, self.epoch());
+            let difficulty = self.difficulty.difficulty(src.ip());
+            let (mut session, _challenge) = challenge::prepare_challenge_response(difficulty);
+            let session_id = self.gen_new_challenge(src, self.epoch(), difficulty);
+            self.difficulty.challenge_sent(src.ip());
 
             if let Some(s) = &mut session.challenge_req {
                 s.challenge = self.session_challenge(session_id).to_vec();
@@ -1,8 +1,8 @@
 use crate::server::state_decoder::decoder;
 use crate::server::CompletionHandler;
-use crate::state::slot_manager::SlotManager;
+use crate::state::backend::StateBackend;
+use crate::state::federation::Federation;
 use crate::state::Clock;
-use crate::SessionManager;
 use std::net::SocketAddr;
 use std::rc::Rc;
 use std::sync::Arc;
@@ -59,21 +59,21 @@ mod metric {
 }
 
 pub struct SlotHandler {
-    session_manager: Arc<SessionManager>,
-    slot_manager: Arc<SlotManager>,
+    state: Arc<dyn StateBackend>,
+    federation: Arc<Federation>,
     metrics: metric::SlotMetric,
     ack: CompletionHandler,
 }
 
 impl SlotHandler {
-    pub fn new(session_manager: &Arc<SessionManager>, slot_manager: &Arc<SlotManager>) -> Self {
-        let session_manager = Arc::clone(session_manager);
-        let slot_manager = slot_manager.clone();
+    pub fn new(state: &Arc<dyn StateBackend>, federation: &Arc<Federation>) -> Self {
+        let state = state.clone();
+        let federation = federation.clone();
         let metrics = metric::SlotMetric::default();
         let ack = Rc::new(metrics.clone());
         Self {
-            session_manager,
-            slot_manager,
+            state,
+            federation,
             metrics,
             ack,
         }
@@ -88,7 +88,7 @@ impl SlotHandler {
         param: &request::Slot,
     ) -> Option<(CompletionHandler, Packet)> {
         self.metrics.start.increment(1);
-        let session_ref = match self.session_manager.session(&session_id) {
+        let session_ref = match self.state.session(&session_id) {
             Some(session_ref) if session_ref.peer == src => session_ref,
             _ => {
                 return Some((
@@ -104,13 +104,24 @@ impl SlotHandler {
         };
         clock.touch(&session_ref.ts);
 
-        let request_node_id: NodeId = self.slot_manager.node(param.slot)?;
-        let decoder = decoder(&self.session_manager, &self.slot_manager);
-        if let Some(session_ref) = self.session_manager.node_session(request_node_id) {
-            let node = decoder.to_node_info(&session_ref);
+        let request_node_id: NodeId = self.state.node(param.slot)?;
+        let decoder = decoder(&*self.state);
+        let node = match self.state.node_session(request_node_id) {
+            Some(session_ref) => Some(decoder.to_node_info(&session_ref)),
+            None => self
+                .federation
+                .node(request_node_id)
+                .map(|node| decoder.to_remote_node_info(&node)),
+        };
+        if let Some(node) = node {
+            // Node was found, but all slots are taken.
+            let (code, node) = match node {
+                Some(node) => (StatusCode::Ok, node),
+                None => (StatusCode::ServerError, response::Node::default()),
+            };
             Some((
                 self.ack.clone(),
-                Packet::response(request_id, session_id.to_vec(), StatusCode::Ok, node),
+                Packet::response(request_id, session_id.to_vec(), code, node),
             ))
         } else {
             Some((
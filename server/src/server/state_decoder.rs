@@ -1,37 +1,42 @@
-use crate::state::slot_manager::SlotManager;
+use crate::state::backend::StateBackend;
+use crate::state::federation::RemoteNode;
 use crate::state::TsDecoder;
-use crate::{Session, SessionManager};
+use crate::Session;
 use ya_relay_proto::proto::response::Node as NodeInfo;
 
-pub struct Decoder<'a, 'b> {
-    _session_manager: &'a SessionManager,
-    slot_manager: &'b SlotManager,
+pub struct Decoder<'a> {
+    state: &'a dyn StateBackend,
     ts_decoder: TsDecoder,
 }
 
-pub fn decoder<'a, 'b>(
-    _session_manager: &'a SessionManager,
-    slot_manager: &'b SlotManager,
-) -> Decoder<'a, 'b> {
+pub fn decoder(state: &dyn StateBackend) -> Decoder<'_> {
     let ts_decoder = TsDecoder::new();
 
-    Decoder {
-        _session_manager,
-        slot_manager,
-        ts_decoder,
-    }
+    Decoder { state, ts_decoder }
 }
 
-impl<'a, 'b> Decoder<'a, 'b> {
-    pub fn to_node_info(&self, session: &Session) -> NodeInfo {
+impl<'a> Decoder<'a> {
+    /// Information about the Node. Returns `None`, when no slot could be assigned to it.
+    pub fn to_node_info(&self, session: &Session) -> Option<NodeInfo> {
         let identities = session.keys.iter().map(Into::into).collect();
 
-        NodeInfo {
+        Some(NodeInfo {
             identities,
-            endpoints: session.endpoint().into_iter().collect(),
+            endpoints: session.endpoints(),
             seen_ts: self.ts_decoder.decode(&session.ts),
-            slot: self.slot_manager.slot(session.node_id),
+            slot: self.state.slot(session.node_id)?,
             supported_encryptions: session.supported_encryptions.clone(),
-        }
+            relay: None,
+        })
+    }
+
+    /// Information about Node connected to another relay in federation.
+    /// Slot is assigned by this relay, so packets can be forwarded through it.
+    pub fn to_remote_node_info(&self, node: &RemoteNode) -> Option<NodeInfo> {
+        Some(NodeInfo {
+            slot: self.state.slot(node.node_id)?,
+            relay: Some(node.relay_endpoint()),
+            ..node.info.clone()
+        })
     }
 }
@@ -1,5 +1,14 @@
 use ya_relay_core::NodeId;
 
+pub mod backend;
+pub mod ban_list;
+pub mod cluster;
+pub mod difficulty;
+pub mod federation;
+pub mod peer_key;
+pub mod persist;
+pub mod policy;
+pub mod rate_limiter;
 pub mod session_manager;
 pub mod slot_manager;
 
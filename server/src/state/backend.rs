@@ -0,0 +1,133 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use ya_relay_core::server_session::SessionId;
+use ya_relay_core::NodeId;
+
+use crate::state::cluster::{self, Cluster};
+use crate::state::slot_manager::{SlotId, SlotManager};
+use crate::{SessionManager, SessionRef};
+
+/// State used to answer requests about sessions, slots and Nodes.
+///
+/// Sessions are always created and modified by the instance they were established with.
+/// Backend decides, which sessions and slots are visible to the request handlers.
+pub trait StateBackend: Send + Sync {
+    fn session(&self, session_id: &SessionId) -> Option<SessionRef>;
+
+    /// The most recent session of the Node.
+    fn node_session(&self, node_id: NodeId) -> Option<SessionRef>;
+
+    /// Slot of the Node. New one is assigned, if the Node didn't have it.
+    /// Returns `None`, when all slots are taken.
+    fn slot(&self, node_id: NodeId) -> Option<SlotId>;
+
+    fn node(&self, slot: SlotId) -> Option<NodeId>;
+
+    /// Instance holding the session, if it isn't held by this one.
+    fn holder(&self, _session_id: &SessionId) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Whether packets from `addr` come from another instance of the same cluster.
+    fn is_sibling(&self, _addr: &SocketAddr) -> bool {
+        false
+    }
+}
+
+/// Sessions and slots of this instance only.
+pub struct MemoryBackend {
+    session_manager: Arc<SessionManager>,
+    slot_manager: Arc<SlotManager>,
+}
+
+impl MemoryBackend {
+    pub fn new(session_manager: &Arc<SessionManager>, slot_manager: &Arc<SlotManager>) -> Self {
+        Self {
+            session_manager: session_manager.clone(),
+            slot_manager: slot_manager.clone(),
+        }
+    }
+}
+
+impl StateBackend for MemoryBackend {
+    fn session(&self, session_id: &SessionId) -> Option<SessionRef> {
+        self.session_manager.session(session_id)
+    }
+
+    fn node_session(&self, node_id: NodeId) -> Option<SessionRef> {
+        self.session_manager.node_session(node_id)
+    }
+
+    fn slot(&self, node_id: NodeId) -> Option<SlotId> {
+        self.slot_manager.slot(node_id)
+    }
+
+    fn node(&self, slot: SlotId) -> Option<NodeId> {
+        self.slot_manager.node(slot)
+    }
+}
+
+/// Sessions of this instance together with sessions replicated from siblings in the cluster.
+///
+/// Slots assigned by each instance are tagged with its index, so slot ids are unique
+/// in the cluster and any instance can resolve them.
+pub struct ReplicatedBackend {
+    local: MemoryBackend,
+    cluster: Arc<Cluster>,
+}
+
+impl ReplicatedBackend {
+    pub fn new(
+        session_manager: &Arc<SessionManager>,
+        slot_manager: &Arc<SlotManager>,
+        cluster: &Arc<Cluster>,
+    ) -> Self {
+        slot_manager.limit_generation(cluster::GENERATION_BITS);
+        Self {
+            local: MemoryBackend::new(session_manager, slot_manager),
+            cluster: cluster.clone(),
+        }
+    }
+}
+
+impl StateBackend for ReplicatedBackend {
+    fn session(&self, session_id: &SessionId) -> Option<SessionRef> {
+        self.local
+            .session(session_id)
+            .or_else(|| Some(self.cluster.session(session_id)?.session))
+    }
+
+    fn node_session(&self, node_id: NodeId) -> Option<SessionRef> {
+        self.local
+            .node_session(node_id)
+            .or_else(|| Some(self.cluster.node(node_id)?.session))
+    }
+
+    fn slot(&self, node_id: NodeId) -> Option<SlotId> {
+        if self.local.node_session(node_id).is_none() {
+            if let Some(replica) = self.cluster.node(node_id) {
+                return Some(replica.slot);
+            }
+        }
+        Some(self.cluster.to_cluster_slot(self.local.slot(node_id)?))
+    }
+
+    fn node(&self, slot: SlotId) -> Option<NodeId> {
+        match self.cluster.to_local_slot(slot) {
+            Some(slot) => self.local.node(slot),
+            None => self.cluster.slot_node(slot),
+        }
+    }
+
+    fn holder(&self, session_id: &SessionId) -> Option<SocketAddr> {
+        if self.local.session(session_id).is_some() {
+            return None;
+        }
+        Some(self.cluster.session(session_id)?.sibling)
+    }
+
+    fn is_sibling(&self, addr: &SocketAddr) -> bool {
+        self.cluster.is_sibling(addr)
+    }
+}
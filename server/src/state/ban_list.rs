@@ -0,0 +1,82 @@
+use anyhow::Context;
+use dashmap::DashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use ya_relay_core::NodeId;
+
+/// Nodes and addresses temporarily banned by the operator.
+#[derive(Default)]
+pub struct BanList {
+    nodes: DashMap<NodeId, Instant>,
+    ips: DashMap<IpAddr, Instant>,
+}
+
+impl BanList {
+    pub fn ban_node(&self, node_id: NodeId, duration: Duration) -> anyhow::Result<()> {
+        self.nodes.insert(node_id, expiry(duration)?);
+        Ok(())
+    }
+
+    pub fn ban_ip(&self, ip: IpAddr, duration: Duration) -> anyhow::Result<()> {
+        self.ips.insert(ip, expiry(duration)?);
+        Ok(())
+    }
+
+    pub fn unban_node(&self, node_id: NodeId) -> bool {
+        self.nodes.remove(&node_id).is_some()
+    }
+
+    pub fn unban_ip(&self, ip: IpAddr) -> bool {
+        self.ips.remove(&ip).is_some()
+    }
+
+    pub fn is_node_banned(&self, node_id: &NodeId) -> bool {
+        is_banned(&self.nodes, node_id)
+    }
+
+    pub fn is_ip_banned(&self, ip: &IpAddr) -> bool {
+        is_banned(&self.ips, ip)
+    }
+}
+
+fn expiry(duration: Duration) -> anyhow::Result<Instant> {
+    Instant::now()
+        .checked_add(duration)
+        .with_context(|| format!("Ban duration {duration:?} is too long"))
+}
+
+fn is_banned<K: Eq + Hash>(bans: &DashMap<K, Instant>, key: &K) -> bool {
+    let expired = match bans.get(key) {
+        Some(until) => *until <= Instant::now(),
+        None => return false,
+    };
+    if expired {
+        bans.remove_if(key, |_, until| *until <= Instant::now());
+    }
+    !expired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ban_expires() {
+        let bans = BanList::default();
+        let node_id = NodeId::from([1u8; 20]);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        bans.ban_node(node_id, Duration::from_secs(60)).unwrap();
+        bans.ban_ip(ip, Duration::ZERO).unwrap();
+        assert!(bans.ban_ip(ip, Duration::MAX).is_err());
+
+        assert!(bans.is_node_banned(&node_id));
+        assert!(!bans.is_node_banned(&NodeId::from([2u8; 20])));
+        assert!(!bans.is_ip_banned(&ip));
+        assert!(bans.ips.is_empty());
+
+        assert!(bans.unban_node(node_id));
+        assert!(!bans.is_node_banned(&node_id));
+    }
+}
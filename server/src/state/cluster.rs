@@ -0,0 +1,559 @@
+use anyhow::{bail, Context};
+use dashmap::DashMap;
+use parking_lot::{Mutex, RwLock};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use ya_relay_core::identity::Identity;
+use ya_relay_core::server_session::SessionId;
+use ya_relay_core::NodeId;
+use ya_relay_proto::proto::control::{cluster_sync, ClusterSync};
+use ya_relay_proto::proto::{Message, Payload, Protocol};
+
+use crate::state::last_seen::LastSeen;
+use crate::state::peer_key::{PeerKey, TAG_SIZE};
+use crate::state::slot_manager::{SlotId, INDEX_BITS};
+use crate::{AddrStatus, AdvertisedEndpoint, Session, SessionRef};
+
+/// Highest bits of `SlotId` holding index of the instance, which assigned the slot.
+/// They are taken from slot generation, so each instance can still assign up to 2^20 slots,
+/// but their generation wraps around after 256 reuses.
+const INSTANCE_SHIFT: u32 = 28;
+const INSTANCE_MASK: u32 = 0xf << INSTANCE_SHIFT;
+/// Generation bits left to the slot manager of each instance.
+pub const GENERATION_BITS: u32 = INSTANCE_SHIFT - INDEX_BITS;
+
+pub const MAX_INSTANCES: u8 = 16;
+
+const SYNC_DOMAIN: &[u8] = b"ya-relay.cluster.sync";
+const FORWARD_DOMAIN: &[u8] = b"ya-relay.cluster.forward";
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StateBackendKind {
+    /// Sessions and slots of this instance only.
+    Memory,
+    /// Sessions and slots shared with siblings in the cluster.
+    Replicated,
+}
+
+#[derive(clap::Args, Clone)]
+#[command(next_help_heading = "Cluster options")]
+pub struct ClusterConfig {
+    #[arg(long, env, value_enum, default_value_t = StateBackendKind::Memory)]
+    pub state_backend: StateBackendKind,
+    /// Other instances of the cluster, running behind the same public address.
+    #[arg(
+        long = "cluster-sibling",
+        env = "RELAY_CLUSTER_SIBLINGS",
+        value_delimiter = ','
+    )]
+    pub cluster_siblings: Vec<SocketAddr>,
+    /// Secret shared by all instances of the cluster, which authenticates their packets.
+    /// Required when cluster siblings are configured.
+    #[arg(long, env, hide_env_values = true)]
+    pub cluster_key: Option<String>,
+    /// Index of this instance, unique in the cluster.
+    #[arg(
+        long,
+        env,
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u8).range(..MAX_INSTANCES as i64)
+    )]
+    pub cluster_instance: u8,
+    /// How often sessions are sent to siblings.
+    #[arg(long, env, value_parser = humantime::parse_duration, default_value = "2s")]
+    pub cluster_sync_interval: Duration,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            state_backend: StateBackendKind::Memory,
+            cluster_siblings: vec![],
+            cluster_key: None,
+            cluster_instance: 0,
+            cluster_sync_interval: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Session held by a sibling.
+#[derive(Clone)]
+pub struct Replica {
+    pub session: SessionRef,
+    /// Instance holding the session.
+    pub sibling: SocketAddr,
+    /// Slot of the Node assigned in the cluster.
+    pub slot: SlotId,
+    expires: Instant,
+}
+
+/// Sessions replicated from other instances of the cluster.
+///
+/// Instances periodically send each other `ClusterSync` control messages with all
+/// registered sessions they hold. Replicas expire after a few missed synchronizations.
+/// Packets from siblings are accepted only from addresses on the siblings list and only
+/// with a valid tag computed using the shared key.
+pub struct Cluster {
+    instance: u8,
+    siblings: RwLock<Vec<SocketAddr>>,
+    key: Option<PeerKey>,
+    /// Timestamp of the last `ClusterSync` accepted from each sibling.
+    sync_ts: DashMap<SocketAddr, u64>,
+    sync_interval: Duration,
+    sessions: DashMap<SessionId, Replica>,
+    nodes: DashMap<NodeId, SessionId>,
+    slots: DashMap<SlotId, NodeId>,
+}
+
+impl Cluster {
+    pub fn new(config: &ClusterConfig) -> anyhow::Result<Arc<Self>> {
+        let key = config
+            .cluster_key
+            .as_deref()
+            .map(PeerKey::new)
+            .transpose()?;
+        if key.is_none() && !config.cluster_siblings.is_empty() {
+            bail!("Cluster siblings configured without cluster key");
+        }
+
+        Ok(Arc::new(Self {
+            instance: config.cluster_instance % MAX_INSTANCES,
+            siblings: RwLock::new(config.cluster_siblings.clone()),
+            key,
+            sync_ts: Default::default(),
+            sync_interval: config.cluster_sync_interval,
+            sessions: Default::default(),
+            nodes: Default::default(),
+            slots: Default::default(),
+        }))
+    }
+
+    pub fn instance(&self) -> u8 {
+        self.instance
+    }
+
+    pub fn sync_interval(&self) -> Duration {
+        self.sync_interval
+    }
+
+    /// Siblings, which can be reached. Without the key nothing can be sent to them.
+    pub fn siblings(&self) -> Vec<SocketAddr> {
+        match self.key {
+            Some(_) => self.siblings.read().clone(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn add_sibling(&self, addr: SocketAddr) -> anyhow::Result<()> {
+        if self.key.is_none() {
+            bail!("Cluster key is not configured");
+        }
+        let mut siblings = self.siblings.write();
+        if !siblings.contains(&addr) {
+            siblings.push(addr);
+        }
+        Ok(())
+    }
+
+    pub fn is_sibling(&self, addr: &SocketAddr) -> bool {
+        self.key.is_some() && self.siblings.read().contains(addr)
+    }
+
+    /// Sets timestamp and tag of the message sent to siblings.
+    pub fn seal_sync(&self, sync: &mut ClusterSync, timestamp: u64) {
+        if let Some(key) = &self.key {
+            sync.timestamp = timestamp;
+            sync.tag.clear();
+            sync.tag = key.tag(SYNC_DOMAIN, &[&sync.encode_to_vec()]).to_vec();
+        }
+    }
+
+    /// Checks, that message comes from the sibling and it isn't replayed.
+    pub fn verify_sync(&self, sibling: SocketAddr, sync: &mut ClusterSync) -> bool {
+        let key = match &self.key {
+            Some(key) if self.is_sibling(&sibling) => key,
+            _ => return false,
+        };
+        let tag = std::mem::take(&mut sync.tag);
+        if !key.verify(&tag, SYNC_DOMAIN, &[&sync.encode_to_vec()]) {
+            return false;
+        }
+
+        // Sessions are sent in many messages with the same timestamp.
+        let mut last = self.sync_ts.entry(sibling).or_default();
+        if sync.timestamp < *last {
+            return false;
+        }
+        *last = sync.timestamp;
+        true
+    }
+
+    /// Payload forwarded to the sibling holding `session_id`, prefixed with the tag.
+    pub fn seal_forward(
+        &self,
+        session_id: &SessionId,
+        slot: SlotId,
+        flags: u16,
+        mut payload: Payload,
+    ) -> Option<Payload> {
+        let key = self.key.as_ref()?;
+        let tag = key.tag(
+            FORWARD_DOMAIN,
+            &[
+                &session_id.to_vec(),
+                &slot.to_be_bytes(),
+                &flags.to_be_bytes(),
+                payload.as_ref(),
+            ],
+        );
+        payload.prepend(&tag);
+        Some(payload)
+    }
+
+    /// Verifies packet forwarded by the sibling and returns the original payload.
+    pub fn open_forward(
+        &self,
+        sibling: SocketAddr,
+        session_id: &SessionId,
+        slot: SlotId,
+        flags: u16,
+        payload: Payload,
+    ) -> Option<Payload> {
+        let key = self.key.as_ref().filter(|_| self.is_sibling(&sibling))?;
+        let mut payload = payload.into_bytes();
+        if payload.len() < TAG_SIZE {
+            return None;
+        }
+
+        let tag = payload.split_to(TAG_SIZE);
+        key.verify(
+            &tag,
+            FORWARD_DOMAIN,
+            &[
+                &session_id.to_vec(),
+                &slot.to_be_bytes(),
+                &flags.to_be_bytes(),
+                payload.as_ref(),
+            ],
+        )
+        .then(|| payload.into())
+    }
+
+    pub fn num_sessions(&self) -> usize {
+        self.sessions.len()
+    }
+
+    fn ttl(&self) -> Duration {
+        self.sync_interval * 3
+    }
+
+    /// Tags slot assigned by this instance with its index.
+    pub fn to_cluster_slot(&self, slot: SlotId) -> SlotId {
+        match slot {
+            // Reserved for direct messages.
+            0 => 0,
+            slot => (slot & !INSTANCE_MASK) | ((self.instance as u32) << INSTANCE_SHIFT),
+        }
+    }
+
+    /// Slot of this instance, if it was assigned by it.
+    pub fn to_local_slot(&self, slot: SlotId) -> Option<SlotId> {
+        match slot {
+            0 => Some(0),
+            slot if (slot & INSTANCE_MASK) >> INSTANCE_SHIFT == self.instance as u32 => {
+                Some(slot & !INSTANCE_MASK)
+            }
+            _ => None,
+        }
+    }
+
+    /// Adds or refreshes sessions held by the sibling.
+    pub fn update(&self, sibling: SocketAddr, sessions: Vec<cluster_sync::Session>) {
+        let expires = Instant::now() + self.ttl();
+        for session in sessions {
+            let replica = match replica(session, sibling, expires) {
+                Ok(replica) => replica,
+                Err(e) => {
+                    log::debug!("[{sibling}] invalid replicated session: {e:#}");
+                    continue;
+                }
+            };
+            let session_id = replica.session.session_id;
+            for key in &replica.session.keys {
+                self.nodes.insert(key.node_id, session_id);
+            }
+            self.slots.insert(replica.slot, replica.session.node_id);
+            self.sessions.insert(session_id, replica);
+        }
+    }
+
+    pub fn session(&self, session_id: &SessionId) -> Option<Replica> {
+        self.sessions
+            .get(session_id)
+            .filter(|replica| replica.expires > Instant::now())
+            .map(|replica| replica.clone())
+    }
+
+    pub fn node(&self, node_id: NodeId) -> Option<Replica> {
+        let session_id = *self.nodes.get(&node_id)?;
+        self.session(&session_id)
+            .filter(|replica| replica.session.keys.iter().any(|k| k.node_id == node_id))
+    }
+
+    /// Node owning slot assigned by a sibling.
+    pub fn slot_node(&self, slot: SlotId) -> Option<NodeId> {
+        let node_id = *self.slots.get(&slot)?;
+        self.node(node_id)
+            .filter(|replica| replica.slot == slot)
+            .map(|_| node_id)
+    }
+
+    pub fn cleanup(&self) {
+        let now = Instant::now();
+        self.sessions.retain(|_, replica| replica.expires > now);
+        self.nodes
+            .retain(|_, session_id| self.sessions.contains_key(session_id));
+        self.slots
+            .retain(|_, node_id| self.nodes.contains_key(node_id));
+    }
+}
+
+fn replica(
+    session: cluster_sync::Session,
+    sibling: SocketAddr,
+    expires: Instant,
+) -> anyhow::Result<Replica> {
+    let session_id = SessionId::try_from(session.session_id)?;
+    let peer: SocketAddr = session.peer.context("missing peer")?.try_into()?;
+    let node = session.node.context("missing node")?;
+    let keys = node
+        .identities
+        .iter()
+        .map(|ident| Identity::try_from(ident.public_key.as_slice()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let node_id = keys.first().context("missing identity")?.node_id;
+
+    // Endpoints are verified by the instance holding the session.
+    let mut addr_status = AddrStatus::Unknown;
+    let mut advertised = Vec::new();
+    for endpoint in node.endpoints {
+        let protocol = endpoint.protocol();
+        let addr: SocketAddr = endpoint.try_into()?;
+        if protocol == Protocol::Udp && addr == peer {
+            addr_status.set_valid(true);
+        } else {
+            let mut endpoint = AdvertisedEndpoint::new(protocol, addr);
+            endpoint.status.set_valid(true);
+            advertised.push(endpoint);
+        }
+    }
+
+    Ok(Replica {
+        session: Arc::new(Session {
+            session_id,
+            peer,
+            ts: LastSeen::now(),
+            node_id,
+            keys,
+            supported_encryptions: node.supported_encryptions,
+            addr_status: Mutex::new(addr_status),
+            advertised: Mutex::new(advertised),
+            probes: Default::default(),
+            hole_punching: Default::default(),
+            forwarded: Default::default(),
+            rebind: Default::default(),
+            identities_ts: 0,
+        }),
+        sibling,
+        slot: node.slot,
+        expires,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ya_relay_core::crypto::PublicKey;
+    use ya_relay_proto::proto::response::Node as NodeInfo;
+    use ya_relay_proto::proto::Endpoint;
+
+    fn config(instance: u8) -> ClusterConfig {
+        ClusterConfig {
+            state_backend: StateBackendKind::Replicated,
+            cluster_siblings: vec!["127.0.0.1:7478".parse().unwrap()],
+            cluster_key: Some("secret".into()),
+            cluster_instance: instance,
+            cluster_sync_interval: Duration::from_secs(1),
+        }
+    }
+
+    fn replicated_session(slot: SlotId) -> (cluster_sync::Session, NodeId) {
+        let secret = ethsign::SecretKey::from_raw(&[slot as u8 + 1; 32]).unwrap();
+        let identity = Identity::from(PublicKey::from_slice(secret.public().bytes()).unwrap());
+        let peer = Endpoint {
+            protocol: Protocol::Udp.into(),
+            address: "10.0.0.1".into(),
+            port: 4000,
+        };
+        let session = cluster_sync::Session {
+            session_id: SessionId::generate().to_vec(),
+            peer: Some(peer.clone()),
+            node: Some(NodeInfo {
+                identities: vec![(&identity).into()],
+                endpoints: vec![peer],
+                slot,
+                ..Default::default()
+            }),
+        };
+        (session, identity.node_id)
+    }
+
+    #[test]
+    fn test_cluster_slots() {
+        let cluster = Cluster::new(&config(3)).unwrap();
+        let slot = cluster.to_cluster_slot(5);
+        assert_ne!(slot, 5);
+        assert_eq!(cluster.to_local_slot(slot), Some(5));
+        assert_eq!(cluster.to_cluster_slot(0), 0);
+        assert_eq!(cluster.to_local_slot(0), Some(0));
+
+        let other = Cluster::new(&config(4)).unwrap();
+        assert_eq!(other.to_local_slot(slot), None);
+
+        // Generation of the slot is kept.
+        let local = (0xff << INDEX_BITS) | 5;
+        let slot = cluster.to_cluster_slot(local);
+        assert_eq!(cluster.to_local_slot(slot), Some(local));
+        assert_eq!(other.to_local_slot(slot), None);
+    }
+
+    #[test]
+    fn test_replicated_sessions() {
+        let sibling: SocketAddr = "127.0.0.1:7478".parse().unwrap();
+        let cluster = Cluster::new(&config(0)).unwrap();
+        let other = Cluster::new(&config(1)).unwrap();
+        let slot = other.to_cluster_slot(7);
+
+        let (session, node_id) = replicated_session(slot);
+        let session_id = SessionId::try_from(session.session_id.clone()).unwrap();
+        cluster.update(sibling, vec![session]);
+
+        let replica = cluster.session(&session_id).unwrap();
+        assert_eq!(replica.sibling, sibling);
+        assert_eq!(replica.session.node_id, node_id);
+        assert_eq!(replica.session.peer, "10.0.0.1:4000".parse().unwrap());
+        assert!(replica.session.endpoint().is_some());
+        assert_eq!(
+            cluster.node(node_id).unwrap().session.session_id,
+            session_id
+        );
+        assert_eq!(cluster.slot_node(slot), Some(node_id));
+        assert_eq!(cluster.slot_node(other.to_cluster_slot(8)), None);
+    }
+
+    #[test]
+    fn test_replicas_expire() {
+        let sibling: SocketAddr = "127.0.0.1:7478".parse().unwrap();
+        let cluster = Cluster::new(&ClusterConfig {
+            cluster_sync_interval: Duration::ZERO,
+            ..config(0)
+        })
+        .unwrap();
+        let (session, node_id) = replicated_session(1);
+        cluster.update(sibling, vec![session]);
+
+        assert!(cluster.node(node_id).is_none());
+        cluster.cleanup();
+        assert_eq!(cluster.num_sessions(), 0);
+        assert!(cluster.nodes.is_empty());
+        assert!(cluster.slots.is_empty());
+    }
+
+    #[test]
+    fn test_key_required() {
+        let sibling: SocketAddr = "127.0.0.1:7478".parse().unwrap();
+        assert!(Cluster::new(&ClusterConfig {
+            cluster_key: None,
+            ..config(0)
+        })
+        .is_err());
+
+        let cluster = Cluster::new(&Default::default()).unwrap();
+        assert!(cluster.add_sibling(sibling).is_err());
+        assert!(!cluster.is_sibling(&sibling));
+    }
+
+    #[test]
+    fn test_sync_authentication() {
+        let sibling: SocketAddr = "127.0.0.1:7478".parse().unwrap();
+        let receiver = Cluster::new(&config(0)).unwrap();
+        let sender = Cluster::new(&config(1)).unwrap();
+        let spoofer = Cluster::new(&ClusterConfig {
+            cluster_key: Some("other".into()),
+            ..config(1)
+        })
+        .unwrap();
+
+        let mut sync = ClusterSync {
+            sessions: vec![replicated_session(1).0],
+            ..Default::default()
+        };
+        let mut forged = sync.clone();
+        spoofer.seal_sync(&mut forged, 10);
+        assert!(!receiver.verify_sync(sibling, &mut forged));
+
+        sender.seal_sync(&mut sync, 10);
+        let mut modified = sync.clone();
+        modified.sessions[0].session_id = SessionId::generate().to_vec();
+        assert!(!receiver.verify_sync(sibling, &mut modified));
+
+        let mut replayed = sync.clone();
+        assert!(!receiver.verify_sync("127.0.0.1:1".parse().unwrap(), &mut sync.clone()));
+        assert!(receiver.verify_sync(sibling, &mut sync));
+
+        // Older messages are rejected.
+        let mut newer = sync.clone();
+        sender.seal_sync(&mut newer, 20);
+        assert!(receiver.verify_sync(sibling, &mut newer));
+        assert!(!receiver.verify_sync(sibling, &mut replayed));
+    }
+
+    #[test]
+    fn test_forward_authentication() {
+        let sibling: SocketAddr = "127.0.0.1:7478".parse().unwrap();
+        let receiver = Cluster::new(&config(0)).unwrap();
+        let sender = Cluster::new(&config(1)).unwrap();
+        let spoofer = Cluster::new(&ClusterConfig {
+            cluster_key: Some("other".into()),
+            ..config(1)
+        })
+        .unwrap();
+
+        let session_id = SessionId::generate();
+        let payload = || Payload::from(vec![1u8, 2, 3].into_boxed_slice());
+
+        let sealed = sender.seal_forward(&session_id, 7, 0, payload()).unwrap();
+        let opened = receiver
+            .open_forward(sibling, &session_id, 7, 0, sealed.clone())
+            .unwrap();
+        assert_eq!(opened.as_ref(), &[1u8, 2, 3]);
+
+        // Header is covered by the tag.
+        let other_id = SessionId::generate();
+        assert!(receiver
+            .open_forward(sibling, &other_id, 7, 0, sealed.clone())
+            .is_none());
+        assert!(receiver
+            .open_forward(sibling, &session_id, 8, 0, sealed.clone())
+            .is_none());
+        assert!(receiver
+            .open_forward("127.0.0.1:1".parse().unwrap(), &session_id, 7, 0, sealed)
+            .is_none());
+
+        let forged = spoofer.seal_forward(&session_id, 7, 0, payload()).unwrap();
+        assert!(receiver
+            .open_forward(sibling, &session_id, 7, 0, forged)
+            .is_none());
+    }
+}
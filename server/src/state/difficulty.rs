@@ -0,0 +1,215 @@
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use ::metrics::{recorder, Gauge, Key};
+use dashmap::DashMap;
+use tokio::time;
+
+static KEY_DIFFICULTY: Key = Key::from_static_name("ya-relay.session.difficulty");
+
+#[derive(Clone)]
+pub struct DifficultyConfig {
+    /// Difficulty used when relay is not under load.
+    pub base: u64,
+    pub max: u64,
+    /// Session establishment attempts per second, above which difficulty is raised.
+    pub rate_threshold: u64,
+    /// Pending challenges from a single subnet, above which difficulty for it is raised.
+    pub subnet_threshold: u64,
+}
+
+/// Challenge difficulty adjusted to the session establishment load.
+///
+/// Difficulty is raised for everyone, when the rate of new sessions is too high, and
+/// additionally for subnets with many unsolved challenges.
+pub struct AdaptiveDifficulty {
+    config: DifficultyConfig,
+    /// Global difficulty increase.
+    level: AtomicU64,
+    /// Challenges sent since last adjustment.
+    started: AtomicU64,
+    pending: DashMap<IpAddr, u64>,
+    gauge: Gauge,
+}
+
+/// Subnet (/24 for IPv4, /48 for IPv6) of the address.
+fn subnet(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::from([a, b, c, 0])
+        }
+        IpAddr::V6(ip) => {
+            let mut octets = ip.octets();
+            octets[6..].fill(0);
+            IpAddr::from(octets)
+        }
+    }
+}
+
+impl AdaptiveDifficulty {
+    pub fn new(config: DifficultyConfig) -> Arc<Self> {
+        let gauge = recorder().register_gauge(&KEY_DIFFICULTY);
+        gauge.set(config.base as f64);
+        Arc::new(Self {
+            config,
+            level: Default::default(),
+            started: Default::default(),
+            pending: Default::default(),
+            gauge,
+        })
+    }
+
+    /// Difficulty for all candidates, that were possibly issued recently.
+    pub fn range(&self) -> impl Iterator<Item = u64> {
+        self.config.base..=self.max()
+    }
+
+    pub fn current(&self) -> u64 {
+        (self.config.base + self.level.load(Ordering::Relaxed)).min(self.max())
+    }
+
+    fn max(&self) -> u64 {
+        self.config.max.max(self.config.base)
+    }
+
+    /// Difficulty of the challenge for a new session from given address.
+    pub fn difficulty(&self, ip: IpAddr) -> u64 {
+        let pending = self
+            .pending
+            .get(&subnet(ip))
+            .map(|p| *p)
+            .unwrap_or_default();
+        let threshold = self.config.subnet_threshold.max(1);
+        // Each time the number of pending challenges doubles, difficulty grows by one.
+        let extra = match pending / threshold {
+            0 => 0,
+            n => 64 - n.leading_zeros() as u64,
+        };
+        (self.current() + extra).min(self.max())
+    }
+
+    pub fn challenge_sent(&self, ip: IpAddr) {
+        self.started.fetch_add(1, Ordering::Relaxed);
+        *self.pending.entry(subnet(ip)).or_default() += 1;
+    }
+
+    pub fn challenge_solved(&self, ip: IpAddr) {
+        if let Some(mut pending) = self.pending.get_mut(&subnet(ip)) {
+            *pending = pending.saturating_sub(1);
+        }
+    }
+
+    fn adjust(&self, interval: Duration) {
+        let started = self.started.swap(0, Ordering::Relaxed);
+        let rate = started as f64 / interval.as_secs_f64().max(0.001);
+        let threshold = self.config.rate_threshold as f64;
+        let max_level = self.max() - self.config.base;
+
+        let level = self.level.load(Ordering::Relaxed);
+        let level = if rate > threshold {
+            (level + 1).min(max_level)
+        } else if rate < threshold / 2.0 {
+            level.saturating_sub(1)
+        } else {
+            level
+        };
+        self.level.store(level, Ordering::Relaxed);
+
+        // Unsolved challenges are forgotten gradually.
+        self.pending.retain(|_, pending| {
+            *pending /= 2;
+            *pending > 0
+        });
+
+        let difficulty = self.current();
+        self.gauge.set(difficulty as f64);
+        log::trace!("session establish rate {rate:.1}/s, difficulty {difficulty}");
+    }
+
+    pub fn start_adjust_processor(self: &Arc<Self>, interval: Duration) {
+        if self.config.max <= self.config.base {
+            return;
+        }
+
+        let this = Arc::downgrade(self);
+        log::info!("start difficulty adjustment {:?}", thread::current().id());
+        tokio::spawn(async move {
+            loop {
+                time::sleep(interval).await;
+                match this.upgrade() {
+                    Some(difficulty) => difficulty.adjust(interval),
+                    None => break,
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> DifficultyConfig {
+        DifficultyConfig {
+            base: 10,
+            max: 14,
+            rate_threshold: 10,
+            subnet_threshold: 4,
+        }
+    }
+
+    #[test]
+    fn test_rate() {
+        let difficulty = AdaptiveDifficulty::new(config());
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let interval = Duration::from_secs(1);
+
+        for round in 1..=6 {
+            for i in 0..20u8 {
+                let ip = IpAddr::from([10, i, 0, 1]);
+                difficulty.challenge_sent(ip);
+                difficulty.challenge_solved(ip);
+            }
+            difficulty.adjust(interval);
+            assert_eq!(difficulty.difficulty(ip), (10 + round).min(14));
+        }
+
+        // Quiet period.
+        difficulty.adjust(interval);
+        assert_eq!(difficulty.current(), 13);
+        for _ in 0..10 {
+            difficulty.adjust(interval);
+        }
+        assert_eq!(difficulty.current(), 10);
+    }
+
+    #[test]
+    fn test_subnet() {
+        let difficulty = AdaptiveDifficulty::new(config());
+        let attacker: IpAddr = "10.0.0.1".parse().unwrap();
+        let neighbour: IpAddr = "10.0.0.200".parse().unwrap();
+        let other: IpAddr = "10.0.1.1".parse().unwrap();
+
+        for _ in 0..8 {
+            difficulty.challenge_sent(attacker);
+        }
+        assert_eq!(difficulty.difficulty(attacker), 12);
+        assert_eq!(difficulty.difficulty(neighbour), 12);
+        assert_eq!(difficulty.difficulty(other), 10);
+
+        difficulty.adjust(Duration::from_secs(10));
+        assert_eq!(difficulty.difficulty(attacker), 11);
+        difficulty.adjust(Duration::from_secs(10));
+        assert_eq!(difficulty.difficulty(attacker), 10);
+    }
+
+    #[test]
+    fn test_ipv6_subnet() {
+        let ip: IpAddr = "2001:db8:1:2:3::1".parse().unwrap();
+        assert_eq!(subnet(ip), "2001:db8:1::".parse::<IpAddr>().unwrap());
+    }
+}
@@ -0,0 +1,398 @@
+use anyhow::bail;
+use dashmap::DashMap;
+use parking_lot::RwLock;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use ya_relay_core::NodeId;
+use ya_relay_proto::proto::control::Presence;
+use ya_relay_proto::proto::response::Node as NodeInfo;
+use ya_relay_proto::proto::{Endpoint, Message, Payload, Protocol};
+
+use crate::state::peer_key::{PeerKey, TAG_SIZE};
+use crate::state::slot_manager::SlotId;
+
+const PRESENCE_DOMAIN: &[u8] = b"ya-relay.federation.presence";
+const FORWARD_DOMAIN: &[u8] = b"ya-relay.federation.forward";
+
+#[derive(clap::Args, Clone)]
+#[command(next_help_heading = "Federation options")]
+pub struct FederationConfig {
+    /// Other relays in federation. Nodes connected to them will be reachable
+    /// through this relay.
+    #[arg(
+        long = "federation-peer",
+        env = "RELAY_FEDERATION_PEERS",
+        value_delimiter = ','
+    )]
+    pub federation_peers: Vec<SocketAddr>,
+    /// Secret shared by all relays in federation, which authenticates their packets.
+    /// Required when federation peers are configured.
+    #[arg(long, env, hide_env_values = true)]
+    pub federation_key: Option<String>,
+    /// How often Nodes presence is sent to other relays.
+    #[arg(long, env, value_parser = humantime::parse_duration, default_value = "5s")]
+    pub federation_sync_interval: Duration,
+}
+
+impl Default for FederationConfig {
+    fn default() -> Self {
+        Self {
+            federation_peers: vec![],
+            federation_key: None,
+            federation_sync_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RemoteNode {
+    /// Default id of the Node.
+    pub node_id: NodeId,
+    /// Relay, that Node is connected to.
+    pub relay: SocketAddr,
+    /// Node information with slot assigned by the other relay.
+    pub info: NodeInfo,
+    expires: Instant,
+}
+
+impl RemoteNode {
+    pub fn relay_endpoint(&self) -> Endpoint {
+        Endpoint {
+            protocol: Protocol::Udp.into(),
+            address: self.relay.ip().to_string(),
+            port: self.relay.port().into(),
+        }
+    }
+}
+
+/// Nodes connected to other relays in federation.
+///
+/// Relays send each other `Presence` control messages with their Nodes. Packets from
+/// the federated relays are accepted only from addresses on the peers list and only
+/// with a valid tag computed using the shared key.
+pub struct Federation {
+    peers: RwLock<Vec<SocketAddr>>,
+    key: Option<PeerKey>,
+    sync_interval: Duration,
+    nodes: DashMap<NodeId, RemoteNode>,
+    /// Timestamp of the last `Presence` accepted from each peer.
+    presence_ts: DashMap<SocketAddr, u64>,
+}
+
+impl Federation {
+    pub fn new(config: &FederationConfig) -> anyhow::Result<Arc<Self>> {
+        let key = config
+            .federation_key
+            .as_deref()
+            .map(PeerKey::new)
+            .transpose()?;
+        if key.is_none() && !config.federation_peers.is_empty() {
+            bail!("Federation peers configured without federation key");
+        }
+
+        Ok(Arc::new(Self {
+            peers: RwLock::new(config.federation_peers.clone()),
+            key,
+            sync_interval: config.federation_sync_interval,
+            nodes: Default::default(),
+            presence_ts: Default::default(),
+        }))
+    }
+
+    pub fn sync_interval(&self) -> Duration {
+        self.sync_interval
+    }
+
+    /// Peers, which can be reached. Without the key nothing can be sent to them.
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        match self.key {
+            Some(_) => self.peers.read().clone(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn add_peer(&self, addr: SocketAddr) -> anyhow::Result<()> {
+        if self.key.is_none() {
+            bail!("Federation key is not configured");
+        }
+        let mut peers = self.peers.write();
+        if !peers.contains(&addr) {
+            peers.push(addr);
+        }
+        Ok(())
+    }
+
+    pub fn is_peer(&self, addr: &SocketAddr) -> bool {
+        self.key.is_some() && self.peers.read().contains(addr)
+    }
+
+    /// Sets timestamp and tag of the message sent to peers.
+    pub fn seal_presence(&self, presence: &mut Presence, timestamp: u64) {
+        if let Some(key) = &self.key {
+            presence.timestamp = timestamp;
+            presence.tag.clear();
+            presence.tag = key
+                .tag(PRESENCE_DOMAIN, &[&presence.encode_to_vec()])
+                .to_vec();
+        }
+    }
+
+    /// Checks, that message comes from the peer and it isn't replayed.
+    pub fn verify_presence(&self, relay: SocketAddr, presence: &mut Presence) -> bool {
+        let key = match &self.key {
+            Some(key) if self.is_peer(&relay) => key,
+            _ => return false,
+        };
+        let tag = std::mem::take(&mut presence.tag);
+        if !key.verify(&tag, PRESENCE_DOMAIN, &[&presence.encode_to_vec()]) {
+            return false;
+        }
+
+        // Nodes are sent in many messages with the same timestamp.
+        let mut last = self.presence_ts.entry(relay).or_default();
+        if presence.timestamp < *last {
+            return false;
+        }
+        *last = presence.timestamp;
+        true
+    }
+
+    /// Payload forwarded to the relay of `slot` Node: tag followed by the sender id
+    /// and the original payload.
+    pub fn seal_forward(
+        &self,
+        src_node_id: NodeId,
+        slot: SlotId,
+        flags: u16,
+        mut payload: Payload,
+    ) -> Option<Payload> {
+        let key = self.key.as_ref()?;
+        payload.prepend(&src_node_id.into_array());
+        let tag = key.tag(
+            FORWARD_DOMAIN,
+            &[&slot.to_be_bytes(), &flags.to_be_bytes(), payload.as_ref()],
+        );
+        payload.prepend(&tag);
+        Some(payload)
+    }
+
+    /// Verifies packet forwarded by the peer and returns its sender, which has to be
+    /// known as connected to this peer.
+    pub fn open_forward(
+        &self,
+        relay: SocketAddr,
+        slot: SlotId,
+        flags: u16,
+        payload: Payload,
+    ) -> Option<(RemoteNode, Payload)> {
+        let key = self.key.as_ref().filter(|_| self.is_peer(&relay))?;
+        let mut payload = payload.into_bytes();
+        let id_len = NodeId::default().into_array().len();
+        if payload.len() < TAG_SIZE + id_len {
+            return None;
+        }
+
+        let tag = payload.split_to(TAG_SIZE);
+        if !key.verify(
+            &tag,
+            FORWARD_DOMAIN,
+            &[&slot.to_be_bytes(), &flags.to_be_bytes(), payload.as_ref()],
+        ) {
+            return None;
+        }
+
+        let src_node_id = NodeId::try_from(payload.split_to(id_len).as_ref()).ok()?;
+        let node = self.node(src_node_id).filter(|node| node.relay == relay)?;
+        Some((node, payload.into()))
+    }
+
+    /// Presence expires after a few missed synchronizations.
+    fn ttl(&self) -> Duration {
+        self.sync_interval * 3
+    }
+
+    pub fn update(&self, relay: SocketAddr, nodes: Vec<NodeInfo>) {
+        let expires = Instant::now() + self.ttl();
+        for info in nodes {
+            let ids = info
+                .identities
+                .iter()
+                .filter_map(|ident| NodeId::try_from(ident.node_id.as_slice()).ok())
+                .collect::<Vec<_>>();
+            let node_id = match ids.first() {
+                Some(node_id) => *node_id,
+                None => continue,
+            };
+            let node = RemoteNode {
+                node_id,
+                relay,
+                info,
+                expires,
+            };
+            for id in ids {
+                self.nodes.insert(id, node.clone());
+            }
+        }
+    }
+
+    pub fn node(&self, node_id: NodeId) -> Option<RemoteNode> {
+        self.nodes
+            .get(&node_id)
+            .filter(|node| node.expires > Instant::now())
+            .map(|node| node.clone())
+    }
+
+    pub fn cleanup(&self) {
+        let now = Instant::now();
+        self.nodes.retain(|_, node| node.expires > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ya_relay_proto::proto::Identity;
+
+    fn node_info(ids: &[NodeId], slot: u32) -> NodeInfo {
+        NodeInfo {
+            identities: ids
+                .iter()
+                .map(|id| Identity {
+                    public_key: vec![],
+                    node_id: id.into_array().to_vec(),
+                })
+                .collect(),
+            slot,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_presence() {
+        let relay: SocketAddr = "127.0.0.1:7477".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:7478".parse().unwrap();
+        let federation = Federation::new(&FederationConfig {
+            federation_peers: vec![relay],
+            federation_key: Some("secret".into()),
+            federation_sync_interval: Duration::from_secs(1),
+        })
+        .unwrap();
+        assert!(federation.is_peer(&relay));
+        assert!(!federation.is_peer(&other));
+
+        let default_id = NodeId::from([1u8; 20]);
+        let alias = NodeId::from([2u8; 20]);
+        federation.update(relay, vec![node_info(&[default_id, alias], 5)]);
+
+        let node = federation.node(alias).unwrap();
+        assert_eq!(node.node_id, default_id);
+        assert_eq!(node.relay, relay);
+        assert_eq!(node.info.slot, 5);
+        assert!(federation.node(NodeId::from([3u8; 20])).is_none());
+
+        federation.add_peer(other).unwrap();
+        assert!(federation.is_peer(&other));
+    }
+
+    #[test]
+    fn test_presence_expires() {
+        let relay: SocketAddr = "127.0.0.1:7477".parse().unwrap();
+        let federation = Federation::new(&FederationConfig {
+            federation_peers: vec![relay],
+            federation_key: Some("secret".into()),
+            federation_sync_interval: Duration::ZERO,
+        })
+        .unwrap();
+        let node_id = NodeId::from([1u8; 20]);
+        federation.update(relay, vec![node_info(&[node_id], 5)]);
+
+        assert!(federation.node(node_id).is_none());
+        federation.cleanup();
+        assert!(federation.nodes.is_empty());
+    }
+
+    fn federation(peers: Vec<SocketAddr>, key: &str) -> Arc<Federation> {
+        Federation::new(&FederationConfig {
+            federation_peers: peers,
+            federation_key: Some(key.into()),
+            federation_sync_interval: Duration::from_secs(1),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_key_required() {
+        let relay: SocketAddr = "127.0.0.1:7477".parse().unwrap();
+        assert!(Federation::new(&FederationConfig {
+            federation_peers: vec![relay],
+            ..Default::default()
+        })
+        .is_err());
+
+        let federation = Federation::new(&Default::default()).unwrap();
+        assert!(federation.add_peer(relay).is_err());
+        assert!(!federation.is_peer(&relay));
+    }
+
+    #[test]
+    fn test_presence_authentication() {
+        let relay: SocketAddr = "127.0.0.1:7477".parse().unwrap();
+        let receiver = federation(vec![relay], "secret");
+        let sender = federation(vec![], "secret");
+        let spoofer = federation(vec![], "other");
+
+        let node_id = NodeId::from([1u8; 20]);
+        let mut presence = Presence {
+            nodes: vec![node_info(&[node_id], 5)],
+            ..Default::default()
+        };
+        let mut forged = presence.clone();
+        spoofer.seal_presence(&mut forged, 10);
+        assert!(!receiver.verify_presence(relay, &mut forged));
+
+        sender.seal_presence(&mut presence, 10);
+        let mut modified = presence.clone();
+        modified.nodes[0].slot = 6;
+        assert!(!receiver.verify_presence(relay, &mut modified));
+
+        let mut replayed = presence.clone();
+        assert!(!receiver.verify_presence("127.0.0.1:1".parse().unwrap(), &mut presence.clone()));
+        assert!(receiver.verify_presence(relay, &mut presence));
+
+        // Older messages are rejected.
+        let mut newer = presence.clone();
+        sender.seal_presence(&mut newer, 20);
+        assert!(receiver.verify_presence(relay, &mut newer));
+        assert!(!receiver.verify_presence(relay, &mut replayed));
+    }
+
+    #[test]
+    fn test_forward_authentication() {
+        let relay: SocketAddr = "127.0.0.1:7477".parse().unwrap();
+        let receiver = federation(vec![relay], "secret");
+        let sender = federation(vec![], "secret");
+        let spoofer = federation(vec![], "other");
+
+        let node_id = NodeId::from([1u8; 20]);
+        let unknown = NodeId::from([2u8; 20]);
+        receiver.update(relay, vec![node_info(&[node_id], 5)]);
+        let payload = || Payload::from(vec![1u8, 2, 3].into_boxed_slice());
+
+        let sealed = sender.seal_forward(node_id, 7, 0, payload()).unwrap();
+        let (node, opened) = receiver.open_forward(relay, 7, 0, sealed.clone()).unwrap();
+        assert_eq!(node.node_id, node_id);
+        assert_eq!(opened.as_ref(), &[1u8, 2, 3]);
+
+        // Header is covered by the tag.
+        assert!(receiver.open_forward(relay, 8, 0, sealed.clone()).is_none());
+        assert!(receiver.open_forward(relay, 7, 1, sealed).is_none());
+
+        let forged = spoofer.seal_forward(node_id, 7, 0, payload()).unwrap();
+        assert!(receiver.open_forward(relay, 7, 0, forged).is_none());
+
+        // Sender has to be connected to the relay, which forwarded the packet.
+        let sealed = sender.seal_forward(unknown, 7, 0, payload()).unwrap();
+        assert!(receiver.open_forward(relay, 7, 0, sealed).is_none());
+    }
+}
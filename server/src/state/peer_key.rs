@@ -0,0 +1,68 @@
+use anyhow::bail;
+use tiny_keccak::Hasher;
+
+/// Size of the tag attached to packets exchanged between relays.
+pub const TAG_SIZE: usize = 16;
+
+/// Secret shared by relays exchanging packets with each other.
+///
+/// Source address of UDP packet can be spoofed, so packets from other relays carry
+/// a tag, which is Keccak digest of the secret and packet content. Only relays
+/// knowing the secret can produce valid tags.
+#[derive(Clone)]
+pub struct PeerKey {
+    secret: Vec<u8>,
+}
+
+impl PeerKey {
+    pub fn new(secret: &str) -> anyhow::Result<Self> {
+        if secret.is_empty() {
+            bail!("Empty relay peer key");
+        }
+        Ok(Self {
+            secret: secret.as_bytes().to_vec(),
+        })
+    }
+
+    /// Tag of the message. `domain` separates different kinds of messages, so tag
+    /// of one of them is never valid for another one.
+    pub fn tag(&self, domain: &[u8], parts: &[&[u8]]) -> [u8; TAG_SIZE] {
+        let mut h = tiny_keccak::Keccak::v256();
+        for part in [self.secret.as_slice(), domain].iter().chain(parts) {
+            h.update(&(part.len() as u64).to_be_bytes());
+            h.update(part);
+        }
+        let mut data = [0u8; 32];
+        h.finalize(&mut data);
+        data[..TAG_SIZE].try_into().unwrap()
+    }
+
+    pub fn verify(&self, tag: &[u8], domain: &[u8], parts: &[&[u8]]) -> bool {
+        constant_time_eq(tag, &self.tag(domain, parts))
+    }
+}
+
+/// Compares secrets in time, which doesn't depend on the position of the first difference.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag() {
+        let key = PeerKey::new("secret").unwrap();
+        let tag = key.tag(b"domain", &[b"a", b"bc"]);
+
+        assert!(key.verify(&tag, b"domain", &[b"a", b"bc"]));
+        assert!(!key.verify(&tag, b"other", &[b"a", b"bc"]));
+        assert!(!key.verify(&tag, b"domain", &[b"ab", b"c"]));
+        assert!(!key.verify(&tag[1..], b"domain", &[b"a", b"bc"]));
+        assert!(!PeerKey::new("other")
+            .unwrap()
+            .verify(&tag, b"domain", &[b"a", b"bc"]));
+        assert!(PeerKey::new("").is_err());
+    }
+}
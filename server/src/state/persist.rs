@@ -0,0 +1,152 @@
+//! Helpers for state files, which have to survive crash of the process.
+use parking_lot::Mutex;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Temporary file of each state file has the same name, so saves can't run concurrently.
+static WRITE_LOCK: Mutex<()> = parking_lot::const_mutex(());
+
+/// Header of state file: 8 bytes of magic followed by version of the format.
+pub enum Header {
+    Versioned(u16),
+    /// File has no header. Contains bytes already read from it.
+    Legacy(Vec<u8>),
+}
+
+pub fn write_header(f: &mut impl Write, magic: &[u8; 8], version: u16) -> io::Result<()> {
+    f.write_all(magic)?;
+    f.write_all(&version.to_le_bytes())
+}
+
+pub fn read_header(f: &mut impl Read, magic: &[u8; 8]) -> io::Result<Header> {
+    let mut data = [0u8; 8];
+    let len = read_full(f, &mut data)?;
+    if len == data.len() && &data == magic {
+        Ok(Header::Versioned(u16::from_le_bytes(read_array(f)?)))
+    } else {
+        Ok(Header::Legacy(data[..len].to_vec()))
+    }
+}
+
+/// Replaces file at `path` with the data written by `write`. Data goes to a temporary
+/// file first, which is synced and renamed over `path`, so after a crash the file
+/// contains either the previous or the new state.
+pub fn write_atomic<E: From<io::Error>>(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<(), E>,
+) -> Result<(), E> {
+    let _guard = WRITE_LOCK.lock();
+    let tmp_path = tmp_path(path);
+    let mut f = BufWriter::new(
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?,
+    );
+    write(&mut f)?;
+    let f = f.into_inner().map_err(|e| e.into_error())?;
+    f.sync_all()?;
+    drop(f);
+
+    fs::rename(&tmp_path, path)?;
+    sync_parent(path)?;
+    Ok(())
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Makes the rename durable.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Reads until buffer is full or EOF is reached.
+pub fn read_full(f: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match f.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+pub fn read_array<const N: usize>(f: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut data = [0u8; N];
+    f.read_exact(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.state");
+        fs::write(&path, b"previous").unwrap();
+
+        let result: io::Result<()> = write_atomic(&path, |f| {
+            f.write_all(b"partial")?;
+            Err(io::Error::new(io::ErrorKind::Other, "crash"))
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"previous");
+
+        write_atomic(&path, |f| {
+            write_header(f, b"YATEST\0\0", 3)?;
+            f.write_all(b"next")
+        })
+        .unwrap();
+        assert!(!tmp_path(&path).exists());
+
+        let mut f = io::Cursor::new(fs::read(&path).unwrap());
+        assert!(matches!(
+            read_header(&mut f, b"YATEST\0\0").unwrap(),
+            Header::Versioned(3)
+        ));
+        let mut f = io::Cursor::new(b"next".to_vec());
+        match read_header(&mut f, b"YATEST\0\0").unwrap() {
+            Header::Legacy(data) => assert_eq!(data, b"next"),
+            Header::Versioned(_) => panic!("unexpected header"),
+        }
+    }
+
+    #[test]
+    fn test_write_atomic_concurrent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.state");
+        let data = |i: u8| vec![i; 64 * 1024];
+
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let path = &path;
+                scope.spawn(move || {
+                    for _ in 0..10 {
+                        write_atomic(path, |f| f.write_all(&data(i))).unwrap();
+                    }
+                });
+            }
+        });
+
+        let content = fs::read(&path).unwrap();
+        assert!((0..8).any(|i| content == data(i)));
+    }
+}
@@ -0,0 +1,381 @@
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use ::metrics::{recorder, Counter, Key};
+use anyhow::{anyhow, bail, Context};
+use parking_lot::{Mutex, RwLock};
+use tokio::time;
+use ya_relay_core::identity::Identity;
+use ya_relay_core::NodeId;
+
+static KEY_REJECTED: Key = Key::from_static_name("ya-relay.policy.rejected");
+
+#[derive(clap::Args, Clone)]
+#[command(next_help_heading = "Access policy options")]
+pub struct PolicyConfig {
+    /// File with allow and deny rules. Each line has format `<allow|deny> <node|cidr|pubkey> <value>`.
+    /// When any allow rule is present, only matching Nodes are accepted.
+    #[arg(long, env)]
+    pub policy_file: Option<PathBuf>,
+    /// How often policy file is checked for modifications.
+    #[arg(long, env, value_parser = humantime::parse_duration, default_value = "30s")]
+    pub policy_reload_interval: Duration,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        Self {
+            policy_file: None,
+            policy_reload_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V4(ip)) => prefix_eq(
+                &net.octets(),
+                &ip.to_ipv6_mapped().octets(),
+                self.prefix_len,
+            ),
+            (IpAddr::V4(_), IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(ip) => self.contains(&IpAddr::V4(ip)),
+                None => false,
+            },
+        }
+    }
+}
+
+fn prefix_eq(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+    let full = prefix_len as usize / 8;
+    let rest = prefix_len % 8;
+    if a[..full] != b[..full] {
+        return false;
+    }
+    if rest == 0 {
+        return true;
+    }
+    let mask = u8::MAX << (8 - rest);
+    (a[full] & mask) == (b[full] & mask)
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr.parse::<IpAddr>()?, Some(prefix_len.parse::<u8>()?)),
+            None => (s.parse::<IpAddr>()?, None),
+        };
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+        if prefix_len > max_len {
+            bail!("invalid prefix length {prefix_len} for {addr}");
+        }
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Matcher {
+    Node(NodeId),
+    Cidr(Cidr),
+    /// Prefix of the 64 byte public key.
+    PubKey(Vec<u8>),
+}
+
+impl Matcher {
+    fn matches_addr(&self, ip: &IpAddr) -> bool {
+        match self {
+            Self::Cidr(cidr) => cidr.contains(ip),
+            _ => false,
+        }
+    }
+
+    fn matches_identity(&self, identity: &Identity) -> bool {
+        match self {
+            Self::Node(node_id) => identity.node_id == *node_id,
+            Self::PubKey(prefix) => identity.public_key.bytes().starts_with(prefix),
+            Self::Cidr(_) => false,
+        }
+    }
+}
+
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct Rules {
+    allow: Vec<Matcher>,
+    deny: Vec<Matcher>,
+}
+
+impl FromStr for Rules {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules = Rules::default();
+        for (idx, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let parse = || -> anyhow::Result<(bool, Matcher)> {
+                let mut words = line.split_whitespace();
+                let (action, kind, value) = match (words.next(), words.next(), words.next()) {
+                    (Some(action), Some(kind), Some(value)) => (action, kind, value),
+                    _ => bail!("expected `<allow|deny> <node|cidr|pubkey> <value>`"),
+                };
+                if words.next().is_some() {
+                    bail!("unexpected trailing data");
+                }
+                let allow = match action {
+                    "allow" => true,
+                    "deny" => false,
+                    other => bail!("unknown action `{other}`"),
+                };
+                let matcher = match kind {
+                    "node" => Matcher::Node(value.parse().map_err(|e| anyhow!("{e}"))?),
+                    "cidr" => Matcher::Cidr(value.parse()?),
+                    "pubkey" => {
+                        let prefix = hex::decode(value.trim_start_matches("0x"))?;
+                        if prefix.is_empty() || prefix.len() > 64 {
+                            bail!("public key prefix should have 1 to 64 bytes");
+                        }
+                        Matcher::PubKey(prefix)
+                    }
+                    other => bail!("unknown rule kind `{other}`"),
+                };
+                Ok((allow, matcher))
+            };
+            let (allow, matcher) = parse().with_context(|| format!("line {}", idx + 1))?;
+            if allow {
+                rules.allow.push(matcher);
+            } else {
+                rules.deny.push(matcher);
+            }
+        }
+        Ok(rules)
+    }
+}
+
+impl Rules {
+    /// Node is accepted, if none of its identities is denied. With allowlist,
+    /// either address or one of the identities has to be allowed.
+    pub fn allows(&self, ip: &IpAddr, identities: &[Identity]) -> bool {
+        let denied = self.deny.iter().any(|m| {
+            m.matches_addr(ip) || identities.iter().any(|ident| m.matches_identity(ident))
+        });
+        if denied {
+            return false;
+        }
+        self.allow.is_empty()
+            || self.allow.iter().any(|m| {
+                m.matches_addr(ip) || identities.iter().any(|ident| m.matches_identity(ident))
+            })
+    }
+}
+
+/// Denylist and allowlist of Nodes, reloadable from file.
+pub struct Policy {
+    path: Option<PathBuf>,
+    rules: RwLock<Rules>,
+    modified: Mutex<Option<SystemTime>>,
+    rejected: Counter,
+}
+
+impl Policy {
+    pub fn new(config: &PolicyConfig) -> anyhow::Result<Arc<Self>> {
+        let policy = Self {
+            path: config.policy_file.clone(),
+            rules: Default::default(),
+            modified: Default::default(),
+            rejected: recorder().register_counter(&KEY_REJECTED),
+        };
+        policy.reload()?;
+        Ok(Arc::new(policy))
+    }
+
+    /// Reads rules from the policy file. Current rules are kept, when the file is invalid.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let modified = modified(path);
+        let rules: Rules = fs::read_to_string(path)
+            .with_context(|| format!("reading policy file {}", path.display()))?
+            .parse()
+            .with_context(|| format!("parsing policy file {}", path.display()))?;
+        log::info!(
+            "loaded policy: {} allow and {} deny rules",
+            rules.allow.len(),
+            rules.deny.len()
+        );
+        *self.rules.write() = rules;
+        *self.modified.lock() = modified;
+        Ok(())
+    }
+
+    pub fn allows(&self, ip: &IpAddr, identities: &[Identity]) -> bool {
+        self.record(self.rules.read().allows(ip, identities))
+    }
+
+    fn record(&self, allowed: bool) -> bool {
+        if !allowed {
+            self.rejected.increment(1);
+        }
+        allowed
+    }
+
+    fn reload_if_modified(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        if modified(path) == *self.modified.lock() {
+            return;
+        }
+        if let Err(e) = self.reload() {
+            log::error!("failed to reload policy: {e:?}");
+        }
+    }
+
+    pub fn start_reload_processor(self: &Arc<Self>, interval: Duration) {
+        if self.path.is_none() {
+            return;
+        }
+
+        let this = Arc::downgrade(self);
+        log::info!("start policy reload {:?}", thread::current().id());
+        tokio::spawn(async move {
+            loop {
+                time::sleep(interval).await;
+                match this.upgrade() {
+                    Some(policy) => policy.reload_if_modified(),
+                    None => break,
+                }
+            }
+        });
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use ya_relay_core::crypto::PublicKey;
+
+    fn identity(seed: u8) -> Identity {
+        let secret = ethsign::SecretKey::from_raw(&[seed; 32]).unwrap();
+        let public_key = PublicKey::from_slice(secret.public().bytes()).unwrap();
+        Identity {
+            node_id: NodeId::from(*public_key.address()),
+            public_key,
+        }
+    }
+
+    #[test]
+    fn test_cidr() {
+        let cidr: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(cidr.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains(&"10.2.0.1".parse().unwrap()));
+        assert!(cidr.contains(&"::ffff:10.1.0.1".parse().unwrap()));
+
+        let cidr: Cidr = "192.168.1.128/25".parse().unwrap();
+        assert!(cidr.contains(&"192.168.1.200".parse().unwrap()));
+        assert!(!cidr.contains(&"192.168.1.127".parse().unwrap()));
+
+        let cidr: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(cidr.contains(&"2001:db8::1".parse().unwrap()));
+        assert!(!cidr.contains(&"2001:db9::1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert_eq!(
+            "10.0.0.1".parse::<Cidr>().unwrap(),
+            "10.0.0.1/32".parse::<Cidr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_denylist() {
+        let banned = identity(1);
+        let other = identity(2);
+        let rules: Rules = format!(
+            "# comment\n\
+             deny node {}\n\
+             deny cidr 10.0.0.0/8 # inline comment\n",
+            banned.node_id
+        )
+        .parse()
+        .unwrap();
+
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        assert!(!rules.allows(&ip, &[banned.clone()]));
+        assert!(!rules.allows(&ip, &[other.clone(), banned]));
+        assert!(rules.allows(&ip, &[other.clone()]));
+        assert!(!rules.allows(&"10.1.1.1".parse().unwrap(), &[other]));
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let known = identity(1);
+        let other = identity(2);
+        let prefix = hex::encode(&known.public_key.bytes()[..8]);
+        let rules: Rules = format!("allow pubkey {prefix}\n").parse().unwrap();
+
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        assert!(rules.allows(&ip, &[known]));
+        assert!(!rules.allows(&ip, &[other]));
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!("deny node 0x12".parse::<Rules>().is_err());
+        assert!("deny host example.com".parse::<Rules>().is_err());
+        assert!("block cidr 10.0.0.0/8".parse::<Rules>().is_err());
+        assert!("deny cidr".parse::<Rules>().is_err());
+        assert!("deny pubkey zz".parse::<Rules>().is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "deny cidr 10.0.0.0/8").unwrap();
+        let policy = Policy::new(&PolicyConfig {
+            policy_file: Some(file.path().to_path_buf()),
+            ..Default::default()
+        })
+        .unwrap();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(!policy.allows(&ip, &[]));
+
+        // Invalid file doesn't replace current rules.
+        writeln!(file, "deny everything").unwrap();
+        assert!(policy.reload().is_err());
+        assert!(!policy.allows(&ip, &[]));
+
+        file.as_file().set_len(0).unwrap();
+        policy.reload().unwrap();
+        assert!(policy.allows(&ip, &[]));
+    }
+}
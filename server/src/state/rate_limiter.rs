@@ -0,0 +1,420 @@
+use dashmap::DashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::time;
+use ya_relay_core::server_session::SessionId;
+use ya_relay_core::NodeId;
+
+#[derive(clap::Args, Clone)]
+#[command(next_help_heading = "Rate limit options")]
+pub struct RateLimitConfig {
+    /// Bytes per second forwarded from a single session.
+    #[arg(long, env)]
+    pub session_bandwidth_limit: Option<u64>,
+    /// Packets per second forwarded from a single session.
+    #[arg(long, env)]
+    pub session_packet_limit: Option<u64>,
+    /// Bytes per second forwarded from all sessions of a Node.
+    #[arg(long, env)]
+    pub node_bandwidth_limit: Option<u64>,
+    /// Packets per second forwarded from all sessions of a Node.
+    #[arg(long, env)]
+    pub node_packet_limit: Option<u64>,
+    /// Size of token buckets expressed as time of sending with the maximum rate.
+    #[arg(long, env, value_parser = humantime::parse_duration, default_value = "1s")]
+    pub rate_limit_burst: Duration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            session_bandwidth_limit: None,
+            session_packet_limit: None,
+            node_bandwidth_limit: None,
+            node_packet_limit: None,
+            rate_limit_burst: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    fn is_enabled(&self) -> bool {
+        self.session_bandwidth_limit.is_some()
+            || self.session_packet_limit.is_some()
+            || self.node_bandwidth_limit.is_some()
+            || self.node_packet_limit.is_some()
+    }
+}
+
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64, burst: Duration, now: Instant) -> Self {
+        let rate = rate.max(1) as f64;
+        let capacity = (rate * burst.as_secs_f64()).max(1.0);
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Amount larger than capacity passes only through the full bucket and leaves it
+    /// with negative balance, which has to be paid off before anything else passes.
+    pub fn available(&mut self, amount: f64, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= amount.min(self.capacity)
+    }
+
+    pub fn take(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+
+    /// Time after which bucket will be full again.
+    pub fn time_to_full(&self) -> Duration {
+        Duration::from_secs_f64(((self.capacity - self.tokens) / self.rate).max(0.0))
+    }
+}
+
+/// Bandwidth and packet rate budget of a single session or Node.
+struct Budget {
+    bytes: Option<TokenBucket>,
+    packets: Option<TokenBucket>,
+    paused_until: Option<Instant>,
+    notified: bool,
+}
+
+impl Budget {
+    fn new(bandwidth: Option<u64>, packets: Option<u64>, burst: Duration, now: Instant) -> Self {
+        Self {
+            bytes: bandwidth.map(|rate| TokenBucket::new(rate, burst, now)),
+            packets: packets.map(|rate| TokenBucket::new(rate, burst, now)),
+            paused_until: None,
+            notified: false,
+        }
+    }
+
+    fn buckets(&mut self) -> impl Iterator<Item = (&mut TokenBucket, bool)> {
+        self.bytes
+            .iter_mut()
+            .map(|b| (b, true))
+            .chain(self.packets.iter_mut().map(|b| (b, false)))
+    }
+
+    fn is_paused(&mut self, now: Instant) -> bool {
+        match self.paused_until {
+            Some(until) if until > now => true,
+            Some(_) => {
+                self.paused_until = None;
+                self.notified = false;
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn available(&mut self, size: usize, now: Instant) -> bool {
+        self.buckets().all(|(bucket, is_bytes)| {
+            let amount = if is_bytes { size as f64 } else { 1.0 };
+            bucket.available(amount, now)
+        })
+    }
+
+    fn take(&mut self, size: usize) {
+        for (bucket, is_bytes) in self.buckets() {
+            bucket.take(if is_bytes { size as f64 } else { 1.0 });
+        }
+    }
+
+    fn pause(&mut self, now: Instant) -> Duration {
+        let resume_after = self
+            .buckets()
+            .map(|(bucket, _)| bucket.time_to_full())
+            .max()
+            .unwrap_or_default();
+        self.paused_until = Some(now + resume_after);
+        resume_after
+    }
+
+    fn is_idle(&mut self, now: Instant) -> bool {
+        !self.is_paused(now) && self.buckets().all(|(bucket, _)| bucket.is_full(now))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Packet can be forwarded.
+    Pass,
+    /// Sender exceeded it's budget. It should be paused and resumed after given time.
+    Pause(Duration),
+    /// Sender is paused, but keeps sending. `notify` is set only for the first dropped packet.
+    Drop { notify: bool },
+}
+
+/// Token bucket limits for packets forwarded by the relay.
+///
+/// Each session and each Node has separate bandwidth and packet rate budget.
+/// When one of them is exceeded, sender is paused until its budget is fully replenished.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    sessions: DashMap<SessionId, Budget>,
+    nodes: DashMap<NodeId, Budget>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Arc<Self> {
+        Arc::new(Self {
+            config: config.clone(),
+            sessions: Default::default(),
+            nodes: Default::default(),
+        })
+    }
+
+    pub fn check(&self, session_id: SessionId, node_id: NodeId, size: usize) -> Verdict {
+        if !self.config.is_enabled() {
+            return Verdict::Pass;
+        }
+
+        let now = Instant::now();
+        let burst = self.config.rate_limit_burst;
+
+        let mut session = self.sessions.entry(session_id).or_insert_with(|| {
+            Budget::new(
+                self.config.session_bandwidth_limit,
+                self.config.session_packet_limit,
+                burst,
+                now,
+            )
+        });
+        let mut node = self.nodes.entry(node_id).or_insert_with(|| {
+            Budget::new(
+                self.config.node_bandwidth_limit,
+                self.config.node_packet_limit,
+                burst,
+                now,
+            )
+        });
+
+        verdict(&mut [&mut *session, &mut *node], size, now)
+    }
+
+    /// Checks only budget of the Node. Used for packets without session on this relay,
+    /// forwarded by other relays.
+    pub fn check_node(&self, node_id: NodeId, size: usize) -> Verdict {
+        if !self.config.is_enabled() {
+            return Verdict::Pass;
+        }
+
+        let now = Instant::now();
+        let mut node = self.nodes.entry(node_id).or_insert_with(|| {
+            Budget::new(
+                self.config.node_bandwidth_limit,
+                self.config.node_packet_limit,
+                self.config.rate_limit_burst,
+                now,
+            )
+        });
+        verdict(&mut [&mut *node], size, now)
+    }
+
+    fn cleanup(&self) {
+        let now = Instant::now();
+        retain_active(&self.sessions, now);
+        retain_active(&self.nodes, now);
+    }
+
+    /// Removes budgets, that weren't used recently. New budget starts full,
+    /// so there is no difference for the sender.
+    pub fn start_cleanup_processor(self: &Arc<Self>, interval: Duration) {
+        if !self.config.is_enabled() {
+            return;
+        }
+
+        let this = Arc::downgrade(self);
+        log::info!("start rate limiter cleanup {:?}", thread::current().id());
+        tokio::spawn(async move {
+            loop {
+                time::sleep(interval).await;
+                match this.upgrade() {
+                    Some(limiter) => limiter.cleanup(),
+                    None => break,
+                }
+            }
+        });
+    }
+}
+
+fn verdict(budgets: &mut [&mut Budget], size: usize, now: Instant) -> Verdict {
+    for budget in budgets.iter_mut() {
+        if budget.is_paused(now) {
+            let notify = !budget.notified;
+            budget.notified = true;
+            return Verdict::Drop { notify };
+        }
+    }
+
+    let mut resume_after = None;
+    for budget in budgets.iter_mut() {
+        if !budget.available(size, now) {
+            let pause = budget.pause(now);
+            resume_after = resume_after.max(Some(pause));
+        }
+    }
+
+    match resume_after {
+        Some(resume_after) => Verdict::Pause(resume_after),
+        None => {
+            for budget in budgets.iter_mut() {
+                budget.take(size);
+            }
+            Verdict::Pass
+        }
+    }
+}
+
+fn retain_active<K: Eq + Hash>(budgets: &DashMap<K, Budget>, now: Instant) {
+    budgets.retain(|_, budget| !budget.is_idle(now));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RateLimitConfig {
+        RateLimitConfig {
+            session_packet_limit: Some(10),
+            node_bandwidth_limit: Some(1000),
+            rate_limit_burst: Duration::from_secs(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(100, Duration::from_secs(1), now);
+
+        assert!(bucket.available(100.0, now));
+        bucket.take(100.0);
+        assert!(!bucket.available(1.0, now));
+        assert_eq!(bucket.time_to_full(), Duration::from_secs(1));
+
+        let later = now + Duration::from_millis(500);
+        assert!(bucket.available(50.0, later));
+        assert!(!bucket.available(51.0, later));
+        assert!(!bucket.is_full(later));
+        assert!(bucket.is_full(now + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_token_bucket_oversize() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(100, Duration::from_secs(1), now);
+
+        assert!(bucket.available(300.0, now));
+        bucket.take(300.0);
+        assert_eq!(bucket.time_to_full(), Duration::from_secs(3));
+
+        let later = now + Duration::from_secs(2);
+        assert!(!bucket.available(1.0, later));
+        assert!(bucket.available(100.0, now + Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let limiter = RateLimiter::new(&Default::default());
+        let session_id = SessionId::generate();
+
+        for _ in 0..1000 {
+            assert_eq!(
+                limiter.check(session_id, NodeId::default(), 10000),
+                Verdict::Pass
+            );
+        }
+    }
+
+    #[test]
+    fn test_session_packet_limit() {
+        let limiter = RateLimiter::new(&config());
+        let session_id = SessionId::generate();
+        let node_id = NodeId::default();
+
+        for _ in 0..10 {
+            assert_eq!(limiter.check(session_id, node_id, 1), Verdict::Pass);
+        }
+        assert!(matches!(
+            limiter.check(session_id, node_id, 1),
+            Verdict::Pause(_)
+        ));
+        assert_eq!(
+            limiter.check(session_id, node_id, 1),
+            Verdict::Drop { notify: true }
+        );
+        assert_eq!(
+            limiter.check(session_id, node_id, 1),
+            Verdict::Drop { notify: false }
+        );
+
+        // Other sessions of the same Node have separate packet budget.
+        assert_eq!(
+            limiter.check(SessionId::generate(), node_id, 1),
+            Verdict::Pass
+        );
+    }
+
+    #[test]
+    fn test_node_bandwidth_limit() {
+        let limiter = RateLimiter::new(&config());
+        let node_id = NodeId::default();
+
+        assert_eq!(
+            limiter.check(SessionId::generate(), node_id, 600),
+            Verdict::Pass
+        );
+        // Node budget is shared between sessions.
+        assert!(matches!(
+            limiter.check(SessionId::generate(), node_id, 600),
+            Verdict::Pause(_)
+        ));
+        assert_eq!(
+            limiter.check(SessionId::generate(), node_id, 1),
+            Verdict::Drop { notify: true }
+        );
+    }
+
+    #[test]
+    fn test_check_node() {
+        let limiter = RateLimiter::new(&config());
+        let node_id = NodeId::default();
+
+        assert_eq!(limiter.check_node(node_id, 600), Verdict::Pass);
+        // Budget is shared with sessions of the Node.
+        assert!(matches!(
+            limiter.check(SessionId::generate(), node_id, 600),
+            Verdict::Pause(_)
+        ));
+        assert_eq!(
+            limiter.check_node(node_id, 1),
+            Verdict::Drop { notify: true }
+        );
+    }
+}
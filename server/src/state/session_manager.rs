@@ -1,5 +1,6 @@
 use crate::state::hamming_distance;
 use crate::state::last_seen::{Clock, LastSeen};
+use crate::state::persist::{read_full, read_header, write_atomic, write_header, Header};
 use crate::state::session_manager::metrics::SessionManagerMetrics;
 use anyhow::{anyhow, Context};
 use dashmap::DashMap;
@@ -9,10 +10,11 @@ use std::cmp::Reverse;
 use std::collections::hash_map::DefaultHasher;
 use std::collections::{BinaryHeap, HashMap};
 use std::hash::{Hash, Hasher};
-use std::io::{BufRead, Write};
+use std::io::{BufRead, Read, Write};
 use std::net::SocketAddr;
 use std::path::Path;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Weak};
 use std::time::{Duration, Instant};
 use std::{cmp, fs, io, iter, thread};
@@ -21,8 +23,8 @@ use ya_relay_core::crypto::PublicKey;
 use ya_relay_core::identity::Identity;
 use ya_relay_core::server_session::SessionId;
 use ya_relay_core::NodeId;
-use ya_relay_proto::proto::Endpoint;
 use ya_relay_proto::proto::Protocol::Udp;
+use ya_relay_proto::proto::{Endpoint, Protocol};
 
 #[derive(clap::Args)]
 #[command(next_help_heading = "Session manager options")]
@@ -44,12 +46,15 @@ mod metrics {
 
     static PURGED: Key = Key::from_static_name("ya_relay.session.purged");
 
+    static REBOUND: Key = Key::from_static_name("ya-relay.session.rebound");
+
     static PROCESSING: Key = Key::from_static_name("ya-relay.session.cleaner.processing-time");
 
     pub struct SessionManagerMetrics {
         pub created: Counter,
         pub removed: Counter,
         pub purged: Counter,
+        pub rebound: Counter,
         pub sessions: Gauge,
         pub nodes: Gauge,
         pub processing: Histogram,
@@ -61,6 +66,7 @@ mod metrics {
             let created = r.register_counter(&CREATED);
             let removed = r.register_counter(&REMOVED);
             let purged = r.register_counter(&PURGED);
+            let rebound = r.register_counter(&REBOUND);
             let sessions = r.register_gauge(&SESSIONS);
             let nodes = r.register_gauge(&NODES);
             let processing = r.register_histogram(&PROCESSING);
@@ -69,6 +75,7 @@ mod metrics {
                 created,
                 removed,
                 purged,
+                rebound,
                 sessions,
                 nodes,
                 processing,
@@ -137,6 +144,115 @@ pub struct Session {
     pub keys: Vec<Identity>,
     pub supported_encryptions: Vec<String>,
     pub addr_status: Mutex<AddrStatus>,
+    /// Endpoints declared by the Node in `Register` request.
+    pub advertised: Mutex<Vec<AdvertisedEndpoint>>,
+    /// Limits probes of advertised endpoints, which the Node can trigger.
+    pub probes: Mutex<ProbeBudget>,
+    /// Node declared in `Register` request, that it takes part in hole punching.
+    pub hole_punching: AtomicBool,
+    pub forwarded: ForwardCounters,
+    /// Nonces sent to new addresses of the Node, which wants to move the session there.
+    /// Each address has its own nonce, so requests from one can't invalidate the other.
+    pub rebind: Mutex<Vec<PendingRebind>>,
+    /// Timestamp of the last accepted `Identities` request.
+    pub identities_ts: u64,
+}
+
+pub struct PendingRebind {
+    pub nonce: Vec<u8>,
+    pub addr: SocketAddr,
+    pub created: Instant,
+}
+
+/// Endpoint declared by the Node and its verification status.
+pub struct AdvertisedEndpoint {
+    pub protocol: Protocol,
+    pub addr: SocketAddr,
+    pub status: AddrStatus,
+}
+
+impl AdvertisedEndpoint {
+    pub fn new(protocol: Protocol, addr: SocketAddr) -> Self {
+        Self {
+            protocol,
+            addr,
+            status: AddrStatus::Pending(Instant::now()),
+        }
+    }
+
+    pub fn to_endpoint(&self) -> Endpoint {
+        Endpoint {
+            protocol: self.protocol.into(),
+            address: self.addr.ip().to_string(),
+            port: self.addr.port().into(),
+        }
+    }
+}
+
+/// Number of advertised endpoints probed by the relay in the current window.
+#[derive(Default)]
+pub struct ProbeBudget {
+    window: Option<Instant>,
+    used: usize,
+}
+
+impl ProbeBudget {
+    /// Takes up to `count` probes from `limit` renewed every `interval`.
+    /// Returns the number of probes, which can be made.
+    pub fn take(&mut self, count: usize, limit: usize, interval: Duration, now: Instant) -> usize {
+        match self.window {
+            Some(window) if now.saturating_duration_since(window) < interval => (),
+            _ => {
+                self.window = Some(now);
+                self.used = 0;
+            }
+        }
+        let allowed = count.min(limit.saturating_sub(self.used));
+        self.used += allowed;
+        allowed
+    }
+}
+
+/// Traffic forwarded by the relay on behalf of a session.
+#[derive(Default)]
+pub struct ForwardCounters {
+    /// Sent by the session to other Nodes.
+    pub bytes_in: AtomicU64,
+    /// Received by the session from other Nodes.
+    pub bytes_out: AtomicU64,
+    /// `PauseForwarding` messages sent to the session after it exceeded its rate limit.
+    pub pauses: AtomicU64,
+    /// `ResumeForwarding` messages sent to the session after its budget was replenished.
+    pub resumes: AtomicU64,
+}
+
+impl ForwardCounters {
+    pub fn add_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_pause(&self) {
+        self.pauses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_resume(&self) {
+        self.resumes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counters continued by the session replacing this one.
+    fn carry_over(&self) -> Self {
+        let load = |counter: &AtomicU64| AtomicU64::new(counter.load(Ordering::Relaxed));
+        Self {
+            bytes_in: load(&self.bytes_in),
+            bytes_out: load(&self.bytes_out),
+            pauses: load(&self.pauses),
+            resumes: load(&self.resumes),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -147,7 +263,7 @@ struct PubKey {
 
 impl<'a> From<&'a Identity> for PubKey {
     fn from(value: &'a Identity) -> Self {
-        let inner = value.public_key.bytes().clone();
+        let inner = *value.public_key.bytes();
         Self { inner }
     }
 }
@@ -192,6 +308,13 @@ mod serde_bytes_array {
     }
 }
 
+const STATE_MAGIC: &[u8; 8] = b"YASESSN\0";
+/// Version 1 stores `SessionData` records prefixed with their length,
+/// version 2 stores `SessionRecord` records the same way.
+const STATE_VERSION: u16 = 2;
+/// Records of valid sessions are much smaller, bigger length means the file is corrupted.
+const MAX_RECORD_SIZE: usize = 64 * 1024;
+
 // WARN: Never change this struct.
 #[derive(Serialize, Deserialize)]
 struct SessionData {
@@ -204,6 +327,13 @@ struct SessionData {
     flags: u64,
 }
 
+/// Record of state version 2. Extends `SessionData` with fields added later.
+#[derive(Serialize, Deserialize)]
+struct SessionRecord {
+    data: SessionData,
+    identities_ts: u64,
+}
+
 impl Session {
     pub fn endpoint(&self) -> Option<Endpoint> {
         match &*self.addr_status.lock() {
@@ -215,6 +345,28 @@ impl Session {
             _ => None,
         }
     }
+
+    /// Observed address followed by verified advertised endpoints.
+    pub fn endpoints(&self) -> Vec<Endpoint> {
+        let mut endpoints: Vec<Endpoint> = self.endpoint().into_iter().collect();
+        for advertised in self.advertised.lock().iter() {
+            let endpoint = advertised.to_endpoint();
+            if advertised.status.is_valid() && !endpoints.contains(&endpoint) {
+                endpoints.push(endpoint);
+            }
+        }
+        endpoints
+    }
+
+    pub fn set_advertised_status(&self, protocol: Protocol, addr: SocketAddr, valid: bool) {
+        let mut advertised = self.advertised.lock();
+        if let Some(endpoint) = advertised
+            .iter_mut()
+            .find(|e| e.protocol == protocol && e.addr == addr)
+        {
+            endpoint.status.set_valid(valid);
+        }
+    }
 }
 
 pub enum AddrStatus {
@@ -426,6 +578,27 @@ impl SessionManager {
         None
     }
 
+    /// All live sessions of the Node, the most recent last.
+    pub fn node_sessions(&self, node_id: NodeId) -> Vec<SessionRef> {
+        self.node_sessions
+            .get(&node_id)
+            .map(|refs| refs.lock().iter().filter_map(Weak::upgrade).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn find_sessions(&self, f: impl Fn(&Session) -> bool) -> Vec<SessionRef> {
+        self.sessions
+            .iter()
+            .flat_map(|slot| {
+                slot.lock()
+                    .values()
+                    .filter(|session| f(session))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
     fn clean_node_sessions(&self) {
         self.node_sessions.retain(|&_node_id, sessions| {
             let mut g = sessions.lock();
@@ -453,6 +626,12 @@ impl SessionManager {
             keys,
             supported_encryptions,
             addr_status,
+            advertised: Default::default(),
+            probes: Default::default(),
+            hole_punching: Default::default(),
+            forwarded: Default::default(),
+            rebind: Default::default(),
+            identities_ts: 0,
         });
 
         let mut g = self.session_slot(&session_id).lock();
@@ -481,6 +660,12 @@ impl SessionManager {
             keys: vec![],
             supported_encryptions: vec![],
             addr_status: Mutex::new(AddrStatus::Unknown),
+            advertised: Default::default(),
+            probes: Default::default(),
+            hole_punching: Default::default(),
+            forwarded: Default::default(),
+            rebind: Default::default(),
+            identities_ts: 0,
         });
         self.session_slot(&session_id)
             .lock()
@@ -501,6 +686,12 @@ impl SessionManager {
             keys: Default::default(),
             supported_encryptions: Default::default(),
             addr_status: Mutex::new(AddrStatus::Unknown),
+            advertised: Default::default(),
+            probes: Default::default(),
+            hole_punching: Default::default(),
+            forwarded: Default::default(),
+            rebind: Default::default(),
+            identities_ts: 0,
         });
         self.session_slot(&session_id)
             .lock()
@@ -523,6 +714,131 @@ impl SessionManager {
         self.session(session_id).map(|session_ref| f(&session_ref))
     }
 
+    /// Moves session to a new address. Session is replaced with a copy bound to `peer`,
+    /// which keeps the id, identities and forwarding counters. Slot is assigned to `NodeId`,
+    /// so it doesn't change. The new address has to be verified again, as well as advertised
+    /// endpoints, which could be unreachable from the new network of the Node.
+    pub fn rebind_session(
+        &self,
+        clock: &Clock,
+        session_id: &SessionId,
+        peer: SocketAddr,
+    ) -> Option<SessionRef> {
+        let mut g = self.session_slot(session_id).lock();
+        let prev = g.get(session_id)?.clone();
+
+        let forwarded = prev.forwarded.carry_over();
+
+        let session_ref = Arc::new(Session {
+            session_id: prev.session_id,
+            peer,
+            ts: clock.last_seen(),
+            node_id: prev.node_id,
+            keys: prev.keys.clone(),
+            supported_encryptions: prev.supported_encryptions.clone(),
+            addr_status: Mutex::new(AddrStatus::Unknown),
+            advertised: Mutex::new(
+                prev.advertised
+                    .lock()
+                    .iter()
+                    .map(|endpoint| AdvertisedEndpoint::new(endpoint.protocol, endpoint.addr))
+                    .collect(),
+            ),
+            probes: Mutex::new(std::mem::take(&mut *prev.probes.lock())),
+            hole_punching: AtomicBool::new(prev.hole_punching.load(Ordering::Relaxed)),
+            forwarded,
+            rebind: Default::default(),
+            identities_ts: prev.identities_ts,
+        });
+        g.insert(*session_id, session_ref.clone());
+        drop(g);
+
+        // Session becomes visible to other Nodes after registration,
+        // so only identities linked to the previous session are linked again.
+        let node_ids = iter::once(prev.node_id).chain(prev.keys.iter().map(|key| key.node_id));
+        for node_id in node_ids {
+            let linked = self.node_sessions(node_id);
+            if linked.iter().any(|s| Arc::ptr_eq(s, &prev))
+                && !linked.iter().any(|s| Arc::ptr_eq(s, &session_ref))
+            {
+                self.link_session(node_id, &session_ref);
+            }
+        }
+        self.metrics.rebound.increment(1);
+
+        log::info!(
+            "[{}] session {session_id} moved from {} to {peer}",
+            session_ref.node_id,
+            prev.peer
+        );
+        Some(session_ref)
+    }
+
+    /// Replaces identities of the session. Session is replaced with a copy holding new `keys`,
+    /// which are linked instead of the previous ones, if the session was registered.
+    /// Requests with `timestamp` not newer than the last accepted one are ignored.
+    pub fn update_identities(
+        &self,
+        clock: &Clock,
+        session_id: &SessionId,
+        keys: Vec<Identity>,
+        timestamp: u64,
+    ) -> Option<SessionRef> {
+        let mut g = self.session_slot(session_id).lock();
+        let prev = g.get(session_id)?.clone();
+        if timestamp <= prev.identities_ts {
+            return None;
+        }
+
+        let forwarded = prev.forwarded.carry_over();
+
+        let session_ref = Arc::new(Session {
+            session_id: prev.session_id,
+            peer: prev.peer,
+            ts: clock.last_seen(),
+            node_id: prev.node_id,
+            keys,
+            supported_encryptions: prev.supported_encryptions.clone(),
+            addr_status: Mutex::new(std::mem::replace(
+                &mut *prev.addr_status.lock(),
+                AddrStatus::Unknown,
+            )),
+            advertised: Mutex::new(std::mem::take(&mut *prev.advertised.lock())),
+            probes: Mutex::new(std::mem::take(&mut *prev.probes.lock())),
+            hole_punching: AtomicBool::new(prev.hole_punching.load(Ordering::Relaxed)),
+            forwarded,
+            rebind: Mutex::new(std::mem::take(&mut *prev.rebind.lock())),
+            identities_ts: timestamp,
+        });
+        g.insert(*session_id, session_ref.clone());
+        drop(g);
+
+        let registered = self
+            .node_sessions(prev.node_id)
+            .iter()
+            .any(|s| Arc::ptr_eq(s, &prev));
+        let prev_w = Arc::downgrade(&prev);
+        for key in &prev.keys {
+            if let Some(entry) = self.node_sessions.get(&key.node_id) {
+                entry.lock().retain(|s| !Weak::ptr_eq(s, &prev_w));
+            }
+        }
+        if registered {
+            self.link_sessions(&session_ref);
+        }
+
+        log::info!(
+            "[{}] session {session_id} identities changed to {:?}",
+            session_ref.node_id,
+            session_ref
+                .keys
+                .iter()
+                .map(|key| key.node_id)
+                .collect::<Vec<_>>()
+        );
+        Some(session_ref)
+    }
+
     pub fn remove_session(&self, session: &SessionId) -> Option<SessionRef> {
         let prev = self.session_slot(session).lock().remove(session);
         if prev.is_some() {
@@ -539,65 +855,132 @@ impl SessionManager {
     }
 
     pub fn save(&self, path: &Path) -> anyhow::Result<()> {
-        let mut f = io::BufWriter::new(
-            fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(path)?,
-        );
+        write_atomic(path, |f| self.write(f))
+    }
 
+    fn write(&self, f: &mut impl Write) -> anyhow::Result<()> {
+        write_header(f, STATE_MAGIC, STATE_VERSION)?;
         for shard in &self.sessions {
             for s in shard.lock().values() {
-                let session_data = SessionData {
-                    session_id: s.session_id,
-                    peer: s.peer,
-                    session_key: None,
-                    flags: 0,
-                    supported_encryptions: s.supported_encryptions.clone(),
-                    keys: s.keys.iter().map(Into::into).collect(),
-                    addr_valid: s.addr_status.lock().is_valid(),
+                let session_record = SessionRecord {
+                    data: SessionData {
+                        session_id: s.session_id,
+                        peer: s.peer,
+                        session_key: None,
+                        flags: 0,
+                        supported_encryptions: s.supported_encryptions.clone(),
+                        keys: s.keys.iter().map(Into::into).collect(),
+                        addr_valid: s.addr_status.lock().is_valid(),
+                    },
+                    identities_ts: s.identities_ts,
                 };
-                rmp_serde::encode::write(&mut f, &session_data)?;
+                let record = rmp_serde::to_vec(&session_record)?;
+                if record.len() > MAX_RECORD_SIZE {
+                    log::warn!("session {} too big to save", s.session_id);
+                    continue;
+                }
+                f.write_all(&(record.len() as u32).to_le_bytes())?;
+                f.write_all(&record)?;
             }
         }
-        f.flush()?;
-
         Ok(())
     }
 
+    /// Loads sessions saved by `save`. Sessions read before the end of truncated file are kept.
     pub fn load(path: &Path) -> anyhow::Result<Arc<Self>> {
         let mut f = io::BufReader::new(fs::OpenOptions::new().read(true).open(path)?);
-
         let me = Self::new();
 
-        while has_data(&mut f)? {
-            let node_info: SessionData =
-                rmp_serde::decode::from_read(&mut f).context("decoding state")?;
-            let addr_status = if node_info.addr_valid {
-                AddrStatus::Valid(Instant::now())
-            } else {
-                AddrStatus::Invalid(Instant::now())
-            };
-            let keys: Vec<_> = node_info.keys.iter().map(PubKey::decode).collect();
-
-            let session = Arc::new(Session {
-                session_id: node_info.session_id,
-                peer: node_info.peer,
-                ts: LastSeen::now(),
-                node_id: keys.first().ok_or_else(|| anyhow!("invalid data"))?.node_id,
-                keys,
-                supported_encryptions: node_info.supported_encryptions,
-                addr_status: Mutex::new(addr_status),
-            });
-            me.session_slot(&session.session_id)
-                .lock()
-                .insert(session.session_id, session.clone());
-            me.link_sessions(&session);
+        match read_header(&mut f, STATE_MAGIC)? {
+            Header::Versioned(version @ 1..=STATE_VERSION) => me.read_records(&mut f, version)?,
+            Header::Versioned(version) => {
+                anyhow::bail!("unsupported sessions state version: {version}")
+            }
+            // Legacy format without header: `SessionData` records one after another.
+            Header::Legacy(prefix) => me.read_legacy(&mut io::Cursor::new(prefix).chain(f))?,
         }
 
         Ok(me)
     }
+
+    fn read_records(&self, f: &mut impl Read, version: u16) -> anyhow::Result<()> {
+        loop {
+            let mut len = [0u8; 4];
+            match read_full(f, &mut len)? {
+                0 => return Ok(()),
+                4 => {}
+                _ => break,
+            }
+            let len = u32::from_le_bytes(len) as usize;
+            if len > MAX_RECORD_SIZE {
+                break;
+            }
+            let mut record = vec![0u8; len];
+            if read_full(f, &mut record)? < record.len() {
+                break;
+            }
+            let record = match version {
+                1 => SessionRecord {
+                    data: rmp_serde::from_slice(&record).context("decoding state")?,
+                    identities_ts: 0,
+                },
+                _ => rmp_serde::from_slice(&record).context("decoding state")?,
+            };
+            self.insert_loaded(record.data, record.identities_ts)?;
+        }
+        log::warn!(
+            "sessions state truncated, loaded {} sessions",
+            self.num_sessions()
+        );
+        Ok(())
+    }
+
+    fn read_legacy(&self, f: &mut impl BufRead) -> anyhow::Result<()> {
+        while has_data(f)? {
+            match rmp_serde::decode::from_read::<_, SessionData>(&mut *f) {
+                Ok(data) => self.insert_loaded(data, 0)?,
+                // Record cut in the middle.
+                Err(e) => {
+                    log::warn!(
+                        "sessions state truncated, loaded {} sessions: {e}",
+                        self.num_sessions()
+                    );
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn insert_loaded(&self, node_info: SessionData, identities_ts: u64) -> anyhow::Result<()> {
+        let addr_status = if node_info.addr_valid {
+            AddrStatus::Valid(Instant::now())
+        } else {
+            AddrStatus::Invalid(Instant::now())
+        };
+        let keys: Vec<_> = node_info.keys.iter().map(PubKey::decode).collect();
+
+        let session = Arc::new(Session {
+            session_id: node_info.session_id,
+            peer: node_info.peer,
+            ts: LastSeen::now(),
+            node_id: keys.first().ok_or_else(|| anyhow!("invalid data"))?.node_id,
+            keys,
+            supported_encryptions: node_info.supported_encryptions,
+            addr_status: Mutex::new(addr_status),
+            advertised: Default::default(),
+            probes: Default::default(),
+            hole_punching: Default::default(),
+            forwarded: Default::default(),
+            rebind: Default::default(),
+            identities_ts,
+        });
+        self.session_slot(&session.session_id)
+            .lock()
+            .insert(session.session_id, session.clone());
+        self.link_sessions(&session);
+        Ok(())
+    }
 }
 
 fn has_data<R: BufRead>(r: &mut R) -> io::Result<bool> {
@@ -615,6 +998,18 @@ mod tests {
         thread_rng().gen::<[u8; 20]>().into()
     }
 
+    #[test]
+    fn test_probe_budget() {
+        let interval = Duration::from_secs(60);
+        let now = Instant::now();
+        let mut budget = ProbeBudget::default();
+
+        assert_eq!(budget.take(3, 8, interval, now), 3);
+        assert_eq!(budget.take(8, 8, interval, now), 5);
+        assert_eq!(budget.take(1, 8, interval, now + interval / 2), 0);
+        assert_eq!(budget.take(2, 8, interval, now + interval), 2);
+    }
+
     #[test_log::test]
     fn test_clean_sessions() {
         let sm = SessionManager::new();
@@ -707,4 +1102,202 @@ mod tests {
             log::info!("decoded {}", data.session_id);
         }
     }
+
+    fn add_session(sm: &SessionManager, seed: u8) -> SessionRef {
+        let secret = ethsign::SecretKey::from_raw(&[seed; 32]).unwrap();
+        let public_key = PublicKey::from_slice(secret.public().bytes()).unwrap();
+        let identity = Identity::from(public_key);
+        let session = sm
+            .new_session(
+                &Clock::now(),
+                SessionId::generate(),
+                "127.0.0.1:40".parse().unwrap(),
+                identity.node_id,
+                vec![identity],
+                vec![],
+            )
+            .unwrap_or_else(|_| panic!("duplicate session"));
+        sm.link_sessions(&session);
+        session
+    }
+
+    #[test_log::test]
+    fn test_save_load_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sessions.state");
+
+        let sm = SessionManager::new();
+        let sessions = (1..=3)
+            .map(|seed| add_session(&sm, seed))
+            .collect::<Vec<_>>();
+        sm.save(&path).unwrap();
+
+        let loaded = SessionManager::load(&path).unwrap();
+        assert_eq!(loaded.num_sessions(), 3);
+        for session in &sessions {
+            let loaded = loaded.node_session(session.node_id).unwrap();
+            assert_eq!(loaded.session_id, session.session_id);
+        }
+
+        // Cut in the middle of the last record.
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 5]).unwrap();
+        assert_eq!(SessionManager::load(&path).unwrap().num_sessions(), 2);
+
+        // Corrupted length of the record is treated as truncated tail.
+        let mut corrupted = data.clone();
+        corrupted.extend_from_slice(&u32::MAX.to_le_bytes());
+        corrupted.extend_from_slice(&[0u8; 16]);
+        fs::write(&path, &corrupted).unwrap();
+        assert_eq!(SessionManager::load(&path).unwrap().num_sessions(), 3);
+
+        // Header has to be complete.
+        fs::write(&path, &data[..9]).unwrap();
+        assert!(SessionManager::load(&path).is_err());
+    }
+
+    #[test_log::test]
+    fn test_save_load_identities_ts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sessions.state");
+
+        let sm = SessionManager::new();
+        let session = add_session(&sm, 1);
+        let updated = sm
+            .update_identities(&Clock::now(), &session.session_id, session.keys.clone(), 7)
+            .unwrap();
+        sm.save(&path).unwrap();
+
+        let loaded = SessionManager::load(&path).unwrap();
+        let loaded = loaded.session(&updated.session_id).unwrap();
+        assert_eq!(loaded.identities_ts, 7);
+
+        // Version 1 records don't have timestamp of identities.
+        let session_data = SessionData {
+            session_id: updated.session_id,
+            peer: updated.peer,
+            session_key: None,
+            keys: updated.keys.iter().map(Into::into).collect(),
+            supported_encryptions: vec![],
+            addr_valid: true,
+            flags: 0,
+        };
+        let record = rmp_serde::to_vec(&session_data).unwrap();
+        let mut data = Vec::new();
+        write_header(&mut data, STATE_MAGIC, 1).unwrap();
+        data.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        data.extend_from_slice(&record);
+        fs::write(&path, &data).unwrap();
+
+        let loaded = SessionManager::load(&path).unwrap();
+        let loaded = loaded.session(&updated.session_id).unwrap();
+        assert_eq!(loaded.identities_ts, 0);
+    }
+
+    #[test_log::test]
+    fn test_load_legacy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sessions.state");
+
+        let sm = SessionManager::new();
+        let sessions = (1..=2)
+            .map(|seed| add_session(&sm, seed))
+            .collect::<Vec<_>>();
+        let mut data = Vec::new();
+        for session in &sessions {
+            let session_data = SessionData {
+                session_id: session.session_id,
+                peer: session.peer,
+                session_key: None,
+                keys: session.keys.iter().map(Into::into).collect(),
+                supported_encryptions: vec![],
+                addr_valid: true,
+                flags: 0,
+            };
+            rmp_serde::encode::write(&mut data, &session_data).unwrap();
+        }
+        fs::write(&path, &data).unwrap();
+        assert_eq!(SessionManager::load(&path).unwrap().num_sessions(), 2);
+
+        fs::write(&path, &data[..data.len() - 5]).unwrap();
+        let loaded = SessionManager::load(&path).unwrap();
+        assert_eq!(loaded.num_sessions(), 1);
+        assert!(loaded.session(&sessions[0].session_id).is_some());
+    }
+
+    #[test_log::test]
+    fn test_rebind_session() {
+        let sm = SessionManager::new();
+        let node_id = gen_node_id();
+        let session = sm.add_est_session(node_id);
+        sm.link_session(node_id, &session);
+        session.forwarded.add_in(10);
+        session.forwarded.add_pause();
+        let advertised = "1.2.3.4:5".parse().unwrap();
+        session.advertised.lock().push(AdvertisedEndpoint {
+            protocol: Protocol::Udp,
+            addr: advertised,
+            status: AddrStatus::Valid(Instant::now()),
+        });
+
+        let clock = Clock::now();
+        let peer = "127.0.0.1:41".parse().unwrap();
+        let rebound = sm
+            .rebind_session(&clock, &session.session_id, peer)
+            .unwrap();
+        drop(session);
+
+        assert_eq!(rebound.peer, peer);
+        assert_eq!(rebound.forwarded.bytes_in.load(Ordering::Relaxed), 10);
+        assert_eq!(rebound.forwarded.pauses.load(Ordering::Relaxed), 1);
+        // Advertised endpoint isn't published, until it's verified from the new address.
+        assert!(rebound.endpoints().is_empty());
+        assert_eq!(rebound.advertised.lock()[0].addr, advertised);
+        assert!(!rebound.advertised.lock()[0].status.is_valid());
+        assert!(Arc::ptr_eq(
+            &sm.session(&rebound.session_id).unwrap(),
+            &rebound
+        ));
+        assert!(Arc::ptr_eq(&sm.node_session(node_id).unwrap(), &rebound));
+        assert!(sm
+            .rebind_session(&clock, &SessionId::generate(), peer)
+            .is_none());
+    }
+    #[test_log::test]
+    fn test_update_identities() {
+        let sm = SessionManager::new();
+        let default = Identity::from(ya_relay_core::key::generate().public());
+        let alias = Identity::from(ya_relay_core::key::generate().public());
+        let node_id = default.node_id;
+        let session = sm.add_est_session(node_id);
+        sm.link_session(node_id, &session);
+        let clock = Clock::now();
+
+        let updated = sm
+            .update_identities(
+                &clock,
+                &session.session_id,
+                vec![default.clone(), alias.clone()],
+                1,
+            )
+            .unwrap();
+        drop(session);
+        assert_eq!(updated.keys.len(), 2);
+        assert!(Arc::ptr_eq(&sm.node_session(node_id).unwrap(), &updated));
+        assert!(Arc::ptr_eq(
+            &sm.node_session(alias.node_id).unwrap(),
+            &updated
+        ));
+
+        let updated = sm
+            .update_identities(&clock, &updated.session_id, vec![default.clone()], 2)
+            .unwrap();
+        assert!(Arc::ptr_eq(&sm.node_session(node_id).unwrap(), &updated));
+        assert!(sm.node_session(alias.node_id).is_none());
+
+        // Replayed request can't restore removed alias.
+        assert!(sm
+            .update_identities(&clock, &updated.session_id, vec![default, alias], 2)
+            .is_none());
+    }
 }
@@ -1,125 +1,400 @@
 use std::collections::HashMap;
 use std::io::{Read, Write};
 use std::path::Path;
+use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::Arc;
-use std::{fs, io};
+use std::time::{Duration, Instant};
+use std::{fs, io, thread};
 
 use ::metrics::Counter;
 use parking_lot::{RwLock, RwLockUpgradableReadGuard};
+use tokio::time;
 
+use crate::state::federation::Federation;
+use crate::state::persist::{
+    read_array, read_full, read_header, write_atomic, write_header, Header,
+};
+use crate::state::session_manager::SessionManager;
 use ya_relay_core::NodeId;
 
 pub type SlotId = u32;
 
+/// Lower bits of `SlotId` are the index in slots table, higher bits are generation
+/// of the slot, incremented each time it is reused by another Node. Thanks to this
+/// packets addressed to the previous owner of the slot won't be forwarded to the new one.
+/// Generation wraps around after 4096 reuses of the slot, unless it's limited
+/// with `SlotManager::limit_generation`.
+pub(crate) const INDEX_BITS: u32 = 20;
+const INDEX_MASK: u32 = (1 << INDEX_BITS) - 1;
+const GENERATION_MASK: u16 = (1 << (u32::BITS - INDEX_BITS)) - 1;
+
+const STATE_MAGIC: &[u8; 8] = b"YASLOTS\0";
+const STATE_VERSION: u16 = 2;
+
+#[derive(clap::Args)]
+#[command(next_help_heading = "Slot manager options")]
+pub struct SlotManagerConfig {
+    #[arg(long, env, value_parser = humantime::parse_duration, default_value = "1min")]
+    pub slot_reclaim_interval: Duration,
+    /// Time after which slot of Node without sessions is released.
+    #[arg(long, env, value_parser = humantime::parse_duration, default_value = "30min")]
+    pub slot_reclaim_grace_period: Duration,
+}
+
+#[inline]
+fn slot_id(index: u32, generation: u16) -> SlotId {
+    ((generation as u32) << INDEX_BITS) | index
+}
+
+#[inline]
+fn split_slot_id(slot_id: SlotId) -> (u32, u16) {
+    (slot_id & INDEX_MASK, (slot_id >> INDEX_BITS) as u16)
+}
+
+#[derive(Default)]
+struct Slot {
+    node_id: Option<NodeId>,
+    generation: u16,
+    /// Set when Node was first seen without sessions.
+    idle_since: Option<Instant>,
+}
+
+impl Slot {
+    fn id(&self, index: u32) -> SlotId {
+        slot_id(index, self.generation)
+    }
+}
+
 struct Inner {
     nodes: HashMap<NodeId, SlotId>,
-    slots: Vec<NodeId>,
+    slots: Vec<Slot>,
+    free: Vec<u32>,
+}
+
+impl Inner {
+    fn new(slots: Vec<Slot>) -> Self {
+        let nodes = slots
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, slot)| Some((slot.node_id?, slot.id(idx as u32))))
+            .collect();
+        let free = slots
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, slot)| slot.node_id.is_none())
+            .map(|(idx, _)| idx as u32)
+            .collect();
+        Inner { nodes, slots, free }
+    }
 }
 
 pub struct SlotManager {
     inner: RwLock<Inner>,
+    generation_mask: AtomicU16,
     created_counter: Counter,
+    released_counter: Counter,
 }
 
 impl SlotManager {
     pub fn new() -> Arc<Self> {
-        let mut inner = Inner {
-            nodes: Default::default(),
-            slots: Default::default(),
-        };
-
-        let node_id = Default::default();
-        inner.slots.push(node_id);
-        inner.nodes.insert(node_id, 0);
+        // Slot 0 is reserved for direct messages.
+        let slots = vec![Slot {
+            node_id: Some(Default::default()),
+            ..Default::default()
+        }];
+        Self::with_slots(slots)
+    }
 
+    fn with_slots(slots: Vec<Slot>) -> Arc<Self> {
         Arc::new(Self {
-            inner: RwLock::new(inner),
+            inner: RwLock::new(Inner::new(slots)),
+            generation_mask: AtomicU16::new(GENERATION_MASK),
             created_counter: metrics::created_counter(),
+            released_counter: metrics::released_counter(),
         })
     }
 
     pub fn load(path: &Path) -> io::Result<Arc<Self>> {
         let mut f = io::BufReader::new(fs::OpenOptions::new().read(true).open(path)?);
 
+        let prefix = match read_header(&mut f, STATE_MAGIC)? {
+            Header::Versioned(version) => return Self::load_versioned(&mut f, version),
+            Header::Legacy(prefix) => prefix,
+        };
+
+        // Legacy format without header: NodeId of each slot.
         let mut data = [0u8; 20];
+        data[..prefix.len()].copy_from_slice(&prefix);
+        let mut filled = prefix.len();
         let mut slots = Vec::new();
         loop {
-            let len = f.read(&mut data)?;
-            if len == 0 {
+            filled += read_full(&mut f, &mut data[filled..])?;
+            if filled == 0 {
                 break;
             }
-            if len != 20 {
+            if filled != 20 {
                 return Err(io::Error::new(
                     io::ErrorKind::UnexpectedEof,
                     "wrong file size",
                 ));
             }
-            slots.push(data.into());
+            slots.push(Slot {
+                node_id: Some(data.into()),
+                ..Default::default()
+            });
+            filled = 0;
         }
-        let nodes = slots
-            .iter()
-            .enumerate()
-            .map(|(idx, &node_id)| (node_id, idx as SlotId))
-            .collect();
-        let inner = Inner { nodes, slots };
+        Ok(Self::with_slots(slots))
+    }
 
-        Ok(Arc::new(Self {
-            inner: RwLock::new(inner),
-            created_counter: metrics::created_counter(),
-        }))
+    fn load_versioned(f: &mut impl Read, version: u16) -> io::Result<Arc<Self>> {
+        if version != STATE_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported slots state version: {version}"),
+            ));
+        }
+
+        let len = u32::from_le_bytes(read_array(f)?) as usize;
+        if len > INDEX_MASK as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "too many slots"));
+        }
+        let mut slots = Vec::with_capacity(len);
+        slots.resize_with(len, Slot::default);
+
+        // Slots read before the end of truncated file are kept.
+        if let Err(e) = read_slots(f, &mut slots) {
+            if e.kind() != io::ErrorKind::UnexpectedEof {
+                return Err(e);
+            }
+            log::warn!(
+                "slots state truncated, loaded {} slots",
+                slots.iter().filter(|slot| slot.node_id.is_some()).count()
+            );
+        }
+
+        Ok(Self::with_slots(slots))
     }
 
+    /// Writes occupied slots with their Nodes. For free slots only generation is stored.
     pub fn save(&self, path: &Path) -> io::Result<()> {
-        let mut f = io::BufWriter::new(
-            fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(path)?,
-        );
-
-        for slot in &self.inner.read().slots {
-            f.write_all(slot.as_ref())?;
-        }
-        f.flush()?;
+        write_atomic(path, |f| self.write(f))
+    }
+
+    fn write(&self, f: &mut impl Write) -> io::Result<()> {
+        let g = self.inner.read();
+        let (used, free): (Vec<_>, Vec<_>) = g
+            .slots
+            .iter()
+            .enumerate()
+            .partition(|(_, slot)| slot.node_id.is_some());
+
+        write_header(f, STATE_MAGIC, STATE_VERSION)?;
+        // All slots are stored, even free ones at generation 0. Otherwise the slot
+        // would be created again after restart with the same id as its previous owner.
+        f.write_all(&(g.slots.len() as u32).to_le_bytes())?;
+
+        f.write_all(&(used.len() as u32).to_le_bytes())?;
+        for (idx, slot) in used {
+            f.write_all(&slot.id(idx as u32).to_le_bytes())?;
+            f.write_all(slot.node_id.unwrap_or_default().as_ref())?;
+        }
+        f.write_all(&(free.len() as u32).to_le_bytes())?;
+        for (idx, slot) in free {
+            f.write_all(&slot.id(idx as u32).to_le_bytes())?;
+        }
         Ok(())
     }
 
-    pub fn slot(&self, node_id: NodeId) -> SlotId {
+    /// Limits generation of slots to `bits` lowest bits, so the highest bits of `SlotId`
+    /// are always zero and can carry other data. Generations of existing slots are truncated.
+    pub fn limit_generation(&self, bits: u32) {
+        let mask = GENERATION_MASK & ((1u32 << bits) - 1) as u16;
+        self.generation_mask.store(mask, Ordering::Relaxed);
+
+        let mut g = self.inner.write();
+        let mut slots = std::mem::take(&mut g.slots);
+        for slot in slots.iter_mut() {
+            slot.generation &= mask;
+        }
+        *g = Inner::new(slots);
+    }
+
+    /// Slot of the Node. Assigns a new one, if the Node didn't have it.
+    /// Returns `None`, when all slots are taken.
+    pub fn slot(&self, node_id: NodeId) -> Option<SlotId> {
         let g = self.inner.upgradable_read();
-        if let Some(slot_id) = g.nodes.get(&node_id) {
-            return *slot_id;
+        if let Some(slot_id) = g.nodes.get(&node_id).copied() {
+            let (index, _) = split_slot_id(slot_id);
+            if g.slots[index as usize].idle_since.is_some() {
+                let mut gw = RwLockUpgradableReadGuard::upgrade(g);
+                gw.slots[index as usize].idle_since = None;
+            }
+            return Some(slot_id);
         }
         let mut gw = RwLockUpgradableReadGuard::upgrade(g);
-        let slot_id = gw.slots.len() as SlotId;
-        gw.slots.push(node_id);
+        let slot_id = match gw.free.pop() {
+            Some(index) => {
+                let slot = &mut gw.slots[index as usize];
+                slot.generation =
+                    slot.generation.wrapping_add(1) & self.generation_mask.load(Ordering::Relaxed);
+                slot.node_id = Some(node_id);
+                slot.idle_since = None;
+                slot.id(index)
+            }
+            None if gw.slots.len() < INDEX_MASK as usize => {
+                let index = gw.slots.len() as u32;
+                gw.slots.push(Slot {
+                    node_id: Some(node_id),
+                    ..Default::default()
+                });
+                slot_id(index, 0)
+            }
+            None => {
+                log::error!("No free slots left for Node [{node_id}]");
+                return None;
+            }
+        };
         gw.nodes.insert(node_id, slot_id);
-        debug_assert_eq!(gw.slots.len(), gw.nodes.len());
         drop(gw);
         self.created_counter.increment(1);
-        slot_id
+        Some(slot_id)
     }
 
+    /// Slot of the Node, if it was assigned. Unlike `slot` never assigns a new one.
+    pub fn get(&self, node_id: NodeId) -> Option<SlotId> {
+        self.inner.read().nodes.get(&node_id).copied()
+    }
+
+    /// Returns Node occupying the slot. Fails if slot was reused since `slot` was assigned.
     pub fn node(&self, slot: SlotId) -> Option<NodeId> {
-        let slot = slot as usize;
-        self.inner.read().slots.get(slot).cloned()
+        let (index, generation) = split_slot_id(slot);
+        let g = self.inner.read();
+        g.slots
+            .get(index as usize)
+            .filter(|slot| slot.generation == generation)
+            .and_then(|slot| slot.node_id)
     }
 
+    /// Number of occupied slots.
     pub fn len(&self) -> usize {
-        self.inner.read().slots.len()
+        self.inner.read().nodes.len()
+    }
+
+    /// Releases slots of Nodes, that had no sessions during the whole grace period.
+    pub fn reclaim(&self, grace_period: Duration, has_session: impl Fn(NodeId) -> bool) -> usize {
+        let now = Instant::now();
+        let mut g = self.inner.write();
+        let inner = &mut *g;
+
+        let mut released = 0;
+        // Slot 0 is reserved.
+        for (idx, slot) in inner.slots.iter_mut().enumerate().skip(1) {
+            let node_id = match slot.node_id {
+                Some(node_id) => node_id,
+                None => continue,
+            };
+            if has_session(node_id) {
+                slot.idle_since = None;
+                continue;
+            }
+            match slot.idle_since {
+                None => slot.idle_since = Some(now),
+                Some(since) if now.duration_since(since) >= grace_period => {
+                    inner.nodes.remove(&node_id);
+                    inner.free.push(idx as u32);
+                    slot.node_id = None;
+                    slot.idle_since = None;
+                    released += 1;
+                }
+                Some(_) => {}
+            }
+        }
+        drop(g);
+
+        if released > 0 {
+            self.released_counter.increment(released as u64);
+            log::debug!("released {released} slots");
+        }
+        released
+    }
+
+    pub fn start_reclaim_processor(
+        self: &Arc<Self>,
+        session_manager: &Arc<SessionManager>,
+        federation: &Arc<Federation>,
+        &SlotManagerConfig {
+            slot_reclaim_interval,
+            slot_reclaim_grace_period,
+        }: &SlotManagerConfig,
+    ) {
+        let this = Arc::downgrade(self);
+        let session_manager = Arc::downgrade(session_manager);
+        let federation = Arc::downgrade(federation);
+        log::info!("start slot reclaim {:?}", thread::current().id());
+        tokio::spawn(async move {
+            loop {
+                time::sleep(slot_reclaim_interval).await;
+                let (sm, session_manager, federation) = match (
+                    this.upgrade(),
+                    session_manager.upgrade(),
+                    federation.upgrade(),
+                ) {
+                    (Some(sm), Some(session_manager), Some(federation)) => {
+                        (sm, session_manager, federation)
+                    }
+                    _ => break,
+                };
+                sm.reclaim(slot_reclaim_grace_period, |node_id| {
+                    session_manager.node_session(node_id).is_some()
+                        || federation.node(node_id).is_some()
+                });
+            }
+        });
     }
 }
 
+fn read_slots(f: &mut impl Read, slots: &mut [Slot]) -> io::Result<()> {
+    let used = u32::from_le_bytes(read_array(f)?);
+    for _ in 0..used {
+        let (index, generation) = split_slot_id(u32::from_le_bytes(read_array(f)?));
+        let node_id: [u8; 20] = read_array(f)?;
+        let slot = slot_at(slots, index)?;
+        slot.generation = generation;
+        slot.node_id = Some(node_id.into());
+    }
+
+    let free = u32::from_le_bytes(read_array(f)?);
+    for _ in 0..free {
+        let (index, generation) = split_slot_id(u32::from_le_bytes(read_array(f)?));
+        slot_at(slots, index)?.generation = generation;
+    }
+    Ok(())
+}
+
+fn slot_at(slots: &mut [Slot], index: u32) -> io::Result<&mut Slot> {
+    slots
+        .get_mut(index as usize)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "slot index out of range"))
+}
+
 mod metrics {
     use metrics::{recorder, Counter, Key};
 
     const SLOT_CREATED: &str = "ya-relay.slot.created";
+    const SLOT_RELEASED: &str = "ya-relay.slot.released";
 
     static KEY_SLOT_CREATED: Key = Key::from_static_name(SLOT_CREATED);
+    static KEY_SLOT_RELEASED: Key = Key::from_static_name(SLOT_RELEASED);
 
     pub fn created_counter() -> Counter {
         recorder().register_counter(&KEY_SLOT_CREATED)
     }
+
+    pub fn released_counter() -> Counter {
+        recorder().register_counter(&KEY_SLOT_RELEASED)
+    }
 }
 
 #[cfg(test)]
@@ -128,11 +403,15 @@ mod tests {
 
     use super::*;
 
+    fn random_node_id() -> NodeId {
+        thread_rng().gen::<[u8; 20]>().into()
+    }
+
     #[test]
     fn test_init_slot() {
         let m = SlotManager::new();
 
-        assert_eq!(m.slot(NodeId::default()), 0);
+        assert_eq!(m.slot(NodeId::default()), Some(0));
     }
 
     #[test]
@@ -141,21 +420,195 @@ mod tests {
         let mut rng = thread_rng();
 
         let mut slots = (1..10)
-            .into_iter()
             .map(|_| {
                 let node_id: NodeId = rng.gen::<[u8; 20]>().into();
 
-                (node_id, m.slot(node_id))
+                (node_id, m.slot(node_id).unwrap())
             })
             .collect::<Vec<_>>();
 
-        assert_eq!(m.slot(NodeId::default()), 0);
+        assert_eq!(m.slot(NodeId::default()), Some(0));
         for _ in 0..100 {
             slots.shuffle(&mut rng);
             for &(node_id, slot_id) in &slots {
-                assert_eq!(m.slot(node_id), slot_id);
+                assert_eq!(m.slot(node_id).unwrap(), slot_id);
             }
             assert_eq!(m.len(), 10);
         }
     }
+
+    #[test]
+    fn test_reclaim_slot() {
+        let m = SlotManager::new();
+        let active = random_node_id();
+        let inactive = random_node_id();
+
+        let active_slot = m.slot(active).unwrap();
+        let old_slot = m.slot(inactive).unwrap();
+
+        let has_session = |node_id| node_id == active;
+        // Grace period starts.
+        assert_eq!(m.reclaim(Duration::from_secs(60), has_session), 0);
+        assert_eq!(m.reclaim(Duration::from_secs(60), has_session), 0);
+        assert_eq!(m.node(old_slot), Some(inactive));
+
+        assert_eq!(m.reclaim(Duration::ZERO, has_session), 1);
+        assert_eq!(m.node(old_slot), None);
+        assert_eq!(m.node(active_slot), Some(active));
+        assert_eq!(m.len(), 2);
+
+        // Slot is reused with the next generation, so stale slot won't reach new Node.
+        let node_id = random_node_id();
+        let new_slot = m.slot(node_id).unwrap();
+        assert_ne!(new_slot, old_slot);
+        assert_eq!(new_slot & INDEX_MASK, old_slot & INDEX_MASK);
+        assert_eq!(m.node(old_slot), None);
+        assert_eq!(m.node(new_slot), Some(node_id));
+
+        // Previous owner gets a new slot.
+        assert_ne!(m.slot(inactive).unwrap(), old_slot);
+    }
+
+    #[test]
+    fn test_generation_bits() {
+        let m = SlotManager::new();
+        let mut seen = std::collections::HashSet::new();
+
+        // Slot is reused many times and never gets id of its previous owners.
+        for _ in 0..1000 {
+            let slot = m.slot(random_node_id()).unwrap();
+            assert_eq!(slot & INDEX_MASK, 1);
+            assert!(seen.insert(slot));
+            m.reclaim(Duration::ZERO, |_| false);
+            m.reclaim(Duration::ZERO, |_| false);
+        }
+    }
+
+    #[test]
+    fn test_limit_generation() {
+        let m = SlotManager::new();
+        m.limit_generation(8);
+
+        // Highest bits stay free, generation wraps after 256 reuses.
+        for _ in 0..300 {
+            let slot = m.slot(random_node_id()).unwrap();
+            assert_eq!(slot & INDEX_MASK, 1);
+            assert_eq!(slot >> (INDEX_BITS + 8), 0);
+            m.reclaim(Duration::ZERO, |_| false);
+            m.reclaim(Duration::ZERO, |_| false);
+        }
+    }
+
+    #[test]
+    fn test_slot_used_during_grace_period() {
+        let m = SlotManager::new();
+        let node_id = random_node_id();
+        let slot = m.slot(node_id).unwrap();
+
+        assert_eq!(m.reclaim(Duration::ZERO, |_| false), 0);
+        // Node is active again.
+        assert_eq!(m.slot(node_id).unwrap(), slot);
+        assert_eq!(m.reclaim(Duration::ZERO, |_| false), 0);
+        assert_eq!(m.node(slot), Some(node_id));
+    }
+
+    #[test]
+    fn test_save_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("slots.state");
+
+        let m = SlotManager::new();
+        let nodes = (0..5).map(|_| random_node_id()).collect::<Vec<_>>();
+        let slots = nodes
+            .iter()
+            .map(|&n| m.slot(n).unwrap())
+            .collect::<Vec<_>>();
+
+        m.reclaim(Duration::ZERO, |n| n != nodes[1]);
+        m.reclaim(Duration::ZERO, |n| n != nodes[1]);
+        let reused = random_node_id();
+        let reused_slot = m.slot(reused).unwrap();
+        let kept = random_node_id();
+        let kept_slot = m.slot(kept).unwrap();
+        // The last slot is released before its generation was ever increased.
+        let last = random_node_id();
+        let last_slot = m.slot(last).unwrap();
+        m.reclaim(Duration::ZERO, |n| n != reused && n != last);
+        m.reclaim(Duration::ZERO, |n| n != reused && n != last);
+        m.save(&path).unwrap();
+
+        let loaded = SlotManager::load(&path).unwrap();
+        assert_eq!(loaded.len(), m.len());
+        assert_eq!(loaded.node(0), Some(NodeId::default()));
+        assert_eq!(loaded.node(kept_slot), Some(kept));
+        assert_eq!(loaded.node(reused_slot), None);
+        assert_eq!(loaded.node(last_slot), None);
+        assert_eq!(loaded.node(slots[1]), None);
+        for i in [0, 2, 3, 4] {
+            assert_eq!(loaded.node(slots[i]), Some(nodes[i]));
+            assert_eq!(loaded.slot(nodes[i]).unwrap(), slots[i]);
+        }
+
+        // Generation of free slot survives restart.
+        let slot = loaded.slot(random_node_id()).unwrap();
+        assert_eq!(slot & INDEX_MASK, reused_slot & INDEX_MASK);
+        assert_ne!(slot, reused_slot);
+        assert_ne!(slot, slots[1]);
+
+        // Free slot at the end of the table doesn't get id of its previous owner.
+        let slot = loaded.slot(random_node_id()).unwrap();
+        assert_eq!(slot & INDEX_MASK, last_slot & INDEX_MASK);
+        assert_ne!(slot, last_slot);
+    }
+
+    #[test]
+    fn test_load_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("slots.state");
+
+        let m = SlotManager::new();
+        let nodes = (0..5).map(|_| random_node_id()).collect::<Vec<_>>();
+        let slots = nodes
+            .iter()
+            .map(|&n| m.slot(n).unwrap())
+            .collect::<Vec<_>>();
+        m.save(&path).unwrap();
+
+        // Cut in the middle of the last slot.
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 4 - 10]).unwrap();
+
+        let loaded = SlotManager::load(&path).unwrap();
+        assert_eq!(loaded.len(), 5);
+        for i in 0..4 {
+            assert_eq!(loaded.node(slots[i]), Some(nodes[i]));
+        }
+        assert_eq!(loaded.node(slots[4]), None);
+
+        // Header has to be complete.
+        fs::write(&path, &data[..12]).unwrap();
+        assert!(SlotManager::load(&path).is_err());
+    }
+
+    #[test]
+    fn test_load_legacy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("slots.state");
+
+        let nodes = [NodeId::default(), random_node_id(), random_node_id()];
+        let data = nodes
+            .iter()
+            .flat_map(|n| n.into_array())
+            .collect::<Vec<_>>();
+        fs::write(&path, data).unwrap();
+
+        let m = SlotManager::load(&path).unwrap();
+        assert_eq!(m.len(), 3);
+        for (slot, node_id) in nodes.iter().enumerate() {
+            assert_eq!(m.node(slot as SlotId), Some(*node_id));
+        }
+
+        fs::write(&path, [0u8; 30]).unwrap();
+        assert!(SlotManager::load(&path).is_err());
+    }
 }
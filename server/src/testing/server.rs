@@ -1,12 +1,15 @@
 use crate::config::Config;
 
 use crate::server::{IpCheckerConfig, Server, ServerConfig, SessionHandlerConfig};
+use crate::state::slot_manager::SlotManagerConfig;
+use crate::state::Clock;
 use crate::SessionManagerConfig;
 use futures::future::LocalBoxFuture;
 use futures::FutureExt;
 use std::rc::Rc;
 use std::{future, net};
 use tokio::time::Duration;
+use ya_relay_core::testing::sim::SimHost;
 use ya_relay_core::testing::TestServerWrapper;
 use ya_relay_core::utils::Url;
 
@@ -24,11 +27,37 @@ impl<'a> TestServerWrapper<'a> for ServerWrapper {
     fn remove_node_endpoints(&'a self, node: ya_relay_core::NodeId) -> LocalBoxFuture<'a, ()> {
         if let Some(session_ref) = self.server.session_manager.node_session(node) {
             session_ref.addr_status.lock().set_valid(false);
+            for endpoint in session_ref.advertised.lock().iter_mut() {
+                endpoint.status.set_valid(false);
+            }
         }
         future::ready(()).boxed_local()
     }
 }
 
+impl ServerWrapper {
+    /// Hides encryption schemes supported by the Node from other Nodes,
+    /// like a malicious relay could do.
+    pub fn strip_encryption(&self, node_id: ya_relay_core::NodeId) {
+        let sessions = &self.server.session_manager;
+        let session = match sessions.node_session(node_id) {
+            Some(session) => session,
+            None => return,
+        };
+        sessions.remove_session(&session.session_id);
+        if let Ok(stripped) = sessions.new_session(
+            &Clock::now(),
+            session.session_id,
+            session.peer,
+            session.node_id,
+            session.keys.clone(),
+            vec![],
+        ) {
+            sessions.link_sessions(&stripped);
+        }
+    }
+}
+
 pub async fn init_test_server() -> anyhow::Result<ServerWrapper> {
     init_test_server_with_config(test_default_config()).await
 }
@@ -39,23 +68,50 @@ pub async fn init_test_server_with_config(config: Config) -> anyhow::Result<Serv
     Ok(ServerWrapper { server })
 }
 
+/// Starts server on a host of the simulated network. Server runs on the current thread.
+pub async fn init_sim_server(host: &SimHost) -> anyhow::Result<ServerWrapper> {
+    let mut config = test_default_config();
+    config.server.address = (host.ip(), 0).into();
+    config.server.network = Some(host.network().clone());
+    init_test_server_with_config(config).await
+}
+
 pub fn test_default_config() -> Config {
     Config {
         metrics_scrape_addr: (net::Ipv4Addr::LOCALHOST, 0).into(),
+        admin_addr: None,
+        admin_token: None,
         state_dir: None,
+        state_save_interval: Duration::from_secs(60),
         server: ServerConfig {
             address: (net::Ipv4Addr::LOCALHOST, 0).into(),
             workers: 1,
             tasks_per_worker: 1,
+            tcp_listen: false,
+            tcp_max_connections: 1024,
+            faults: None,
+            rate_limit: Default::default(),
+            network: None,
         },
         session_manager: SessionManagerConfig {
             session_cleaner_interval: Duration::from_secs(10),
             session_purge_timeout: Duration::from_secs(20),
         },
+        slot_manager: SlotManagerConfig {
+            slot_reclaim_interval: Duration::from_secs(10),
+            slot_reclaim_grace_period: Duration::from_secs(20),
+        },
         session_handler: SessionHandlerConfig {
             difficulty: 1,
+            max_difficulty: 1,
+            difficulty_rate_threshold: 200,
+            difficulty_subnet_threshold: 32,
+            difficulty_adjust_interval: Duration::from_secs(10),
             salt: None,
         },
+        federation: Default::default(),
+        cluster: Default::default(),
+        policy: Default::default(),
         ip_check: IpCheckerConfig {
             timeout: Duration::from_millis(300),
             retry_cnt: 1,
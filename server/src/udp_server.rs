@@ -13,10 +13,16 @@ use metrics::{Key, Label, Unit};
 use tokio::time;
 
 pub use socket::{PacketType, UdpSocket, UdpSocketConfig};
+pub use tcp::TcpPeers;
+
+use ya_relay_core::faults::FaultConfig;
+#[cfg(feature = "test-utils")]
+use ya_relay_core::testing::sim::SimNetwork;
 
 use crate::metrics::InstanceCountGuard;
 
 mod socket;
+mod tcp;
 
 static KEY_UDP_SERVER_WORKERS: &str = "udp-server.workers";
 
@@ -37,11 +43,18 @@ pub struct UdpServerBuilder<F> {
     workers: usize,
     max_tasks_per_worker: usize,
     max_packet_size: usize,
+    tcp: bool,
+    tcp_max_connections: usize,
+    faults: Option<FaultConfig>,
+    #[cfg(feature = "test-utils")]
+    network: Option<SimNetwork>,
 }
 
 pub struct UdpServer {
     bind_addr: SocketAddr,
     arbiters: Vec<Arbiter>,
+    /// Worker running on the current thread, used in the simulated network.
+    local: Option<tokio::task::JoinHandle<()>>,
 }
 
 impl<F: WorkerFactory + Sync + Send + 'static> UdpServerBuilder<F> {
@@ -51,6 +64,11 @@ impl<F: WorkerFactory + Sync + Send + 'static> UdpServerBuilder<F> {
             workers: 8,
             max_tasks_per_worker: 32,
             max_packet_size: 0x8000,
+            tcp: false,
+            tcp_max_connections: 1024,
+            faults: None,
+            #[cfg(feature = "test-utils")]
+            network: None,
         }
     }
 
@@ -64,7 +82,37 @@ impl<F: WorkerFactory + Sync + Send + 'static> UdpServerBuilder<F> {
         self
     }
 
+    /// Accept TCP connections on the same address. Connections are served by the first worker.
+    pub fn tcp(mut self, tcp: bool) -> Self {
+        self.tcp = tcp;
+        self
+    }
+
+    /// Limits the number of simultaneous TCP connections. Further ones are closed right away.
+    pub fn tcp_max_connections(mut self, max: usize) -> Self {
+        self.tcp_max_connections = max;
+        self
+    }
+
+    /// Injects faults into traffic of all workers, for testing.
+    pub fn faults(mut self, faults: Option<FaultConfig>) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    /// Binds on the simulated network instead of OS sockets. Server runs on the current thread.
+    #[cfg(feature = "test-utils")]
+    pub fn network(mut self, network: Option<SimNetwork>) -> Self {
+        self.network = network;
+        self
+    }
+
     pub async fn start(self, bind_addr: SocketAddr) -> anyhow::Result<UdpServer> {
+        #[cfg(feature = "test-utils")]
+        if let Some(network) = self.network.clone() {
+            return self.start_simulated(&network, bind_addr).await;
+        }
+
         let factory = Arc::new(self.factory);
         let max_packet_size = self.max_packet_size;
         let max_tasks_per_worker = self.max_tasks_per_worker;
@@ -83,16 +131,29 @@ impl<F: WorkerFactory + Sync + Send + 'static> UdpServerBuilder<F> {
         let key_workers = Key::from_static_name(KEY_UDP_SERVER_WORKERS)
             .with_extra_labels(vec![Label::new("addr", bind_addr.to_string())]);
         let g_workers = recorder.register_gauge(&key_workers);
+        let mut tcp_listener = match self.tcp {
+            true => Some(std::net::TcpListener::bind(bind_addr)?),
+            false => None,
+        };
+        let tcp_peers: TcpPeers = Default::default();
+
         let mut arbiters = Vec::new();
         let (start_tx, mut start_rx) = tokio::sync::mpsc::channel(self.workers);
 
         for worker_idx in 0..self.workers {
             let g_workers = g_workers.clone();
-            let socket = socket::UdpSocketConfig::new()
+            let mut socket = socket::UdpSocketConfig::new()
                 .multi_bind()
                 .min_recv_buffer(4 * 1024 * 1024)
                 .recv_err()
-                .bind(bind_addr)?;
+                .bind(bind_addr)?
+                .with_tcp_peers(tcp_peers.clone());
+            if let Some(faults) = &self.faults {
+                socket = socket.with_faults(faults);
+            }
+            let tcp_listener = tcp_listener.take();
+            let tcp_max_connections = self.tcp_max_connections;
+            let tcp_peers = tcp_peers.clone();
             let factory = factory.clone();
             let start_tx = start_tx.clone();
 
@@ -110,41 +171,35 @@ impl<F: WorkerFactory + Sync + Send + 'static> UdpServerBuilder<F> {
                 let h = tokio::task::spawn_local(async move {
                     let socket = Rc::new(socket);
                     let worker = match factory.new_worker(socket.clone()) {
-                        Ok(worker) => worker,
+                        Ok(worker) => Rc::new(worker),
                         Err(e) => {
                             log::error!("failed to start worker {worker_idx}");
                             start_tx.send(Some(e)).await?;
                             anyhow::bail!("failed to start worker");
                         }
                     };
-                    let max_packet_size = max_packet_size;
-                    let mut buf = BytesMut::with_capacity(max_packet_size * 4);
-                    let ws = Arc::new(tokio::sync::Semaphore::new(max_tasks_per_worker));
+                    if let Some(listener) = tcp_listener {
+                        if let Err(e) = tcp::start_listener(
+                            listener,
+                            tcp_max_connections,
+                            tcp_peers,
+                            worker.clone(),
+                        ) {
+                            start_tx.send(Some(e)).await?;
+                            anyhow::bail!("failed to start TCP listener");
+                        }
+                    }
                     log::info!("worker {} started on {:?}", worker_idx, bind_addr);
                     let _g = InstanceCountGuard::new(g_workers);
                     start_tx.send(None).await?;
-                    loop {
-                        let g = ws.clone().acquire_owned().await?;
-                        buf.reserve(max_packet_size);
-                        let (src_addr, pt) = match socket.recv_any(&mut buf).await {
-                            Ok(v) => v,
-                            Err(e) => {
-                                log::error!("[{worker_idx}] recv-any error: {:?}", e);
-                                time::sleep(Duration::from_millis(100)).await;
-                                continue;
-                            }
-                        };
-                        /*let src_addr= socket.recv_from(&mut buf).await?;
-                        let pt = PacketType::Data;*/
-                        let packet = buf.split();
-                        let task = worker.handle(packet, src_addr, pt);
-                        tokio::task::spawn_local(async move {
-                            if let Err(e) = task.await {
-                                log::error!("[{worker_idx}][{src_addr}] invalid request: {:?}", e);
-                            }
-                            drop(g);
-                        });
-                    }
+                    serve(
+                        socket,
+                        worker,
+                        worker_idx,
+                        max_packet_size,
+                        max_tasks_per_worker,
+                    )
+                    .await
                 });
 
                 let err = match h.await {
@@ -169,21 +224,108 @@ impl<F: WorkerFactory + Sync + Send + 'static> UdpServerBuilder<F> {
         Ok(UdpServer {
             arbiters,
             bind_addr,
+            local: None,
+        })
+    }
+
+    /// Runs single worker on the current thread, where the simulated network lives.
+    #[cfg(feature = "test-utils")]
+    async fn start_simulated(
+        self,
+        network: &SimNetwork,
+        bind_addr: SocketAddr,
+    ) -> anyhow::Result<UdpServer> {
+        if self.tcp {
+            anyhow::bail!("TCP is not supported in simulated network");
+        }
+
+        let mut socket = socket::UdpSocketConfig::new().bind_sim(network, bind_addr)?;
+        if let Some(faults) = &self.faults {
+            socket = socket.with_faults(faults);
+        }
+        let socket = Rc::new(socket);
+        let bind_addr = socket.local_addr()?;
+        let worker = Rc::new(self.factory.new_worker(socket.clone())?);
+        let (max_packet_size, max_tasks_per_worker) =
+            (self.max_packet_size, self.max_tasks_per_worker);
+        log::info!("simulated worker started on {:?}", bind_addr);
+
+        let local = tokio::task::spawn_local(async move {
+            let result = serve(socket, worker, 0, max_packet_size, max_tasks_per_worker);
+            if let Err(e) = result.await {
+                log::error!("simulated worker crashed: {:?}", e);
+            }
+        });
+
+        Ok(UdpServer {
+            arbiters: Vec::new(),
+            bind_addr,
+            local: Some(local),
         })
     }
 }
 
+async fn serve<W: Worker + 'static>(
+    socket: Rc<UdpSocket>,
+    worker: Rc<W>,
+    worker_idx: usize,
+    max_packet_size: usize,
+    max_tasks_per_worker: usize,
+) -> anyhow::Result<()> {
+    let mut buf = BytesMut::with_capacity(max_packet_size * 4);
+    let ws = Arc::new(tokio::sync::Semaphore::new(max_tasks_per_worker));
+    loop {
+        let g = ws.clone().acquire_owned().await?;
+        buf.reserve(max_packet_size);
+        let (src_addr, pt) = match socket.recv_any(&mut buf).await {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("[{worker_idx}] recv-any error: {:?}", e);
+                time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        /*let src_addr= socket.recv_from(&mut buf).await?;
+        let pt = PacketType::Data;*/
+        let packet = buf.split();
+
+        if socket.has_faults() && matches!(pt, PacketType::Data) {
+            // Delayed packets don't hold the worker's task slot.
+            drop(g);
+            for (delay, packet) in socket.incoming_faults(packet) {
+                let worker = worker.clone();
+                tokio::task::spawn_local(async move {
+                    time::sleep(delay).await;
+                    if let Err(e) = worker.handle(packet, src_addr, PacketType::Data).await {
+                        log::error!("[{worker_idx}][{src_addr}] invalid request: {:?}", e);
+                    }
+                });
+            }
+            continue;
+        }
+
+        let task = worker.handle(packet, src_addr, pt);
+        tokio::task::spawn_local(async move {
+            if let Err(e) = task.await {
+                log::error!("[{worker_idx}][{src_addr}] invalid request: {:?}", e);
+            }
+            drop(g);
+        });
+    }
+}
+
 impl UdpServer {
     pub fn stop(self) {
-        for arbiter in self.arbiters {
-            arbiter.stop();
-        }
+        self.stop_internal();
     }
 
     pub(crate) fn stop_internal(&self) {
         for arbiter in &self.arbiters {
             arbiter.stop();
         }
+        if let Some(local) = &self.local {
+            local.abort();
+        }
     }
 
     pub fn bind_addr(&self) -> SocketAddr {
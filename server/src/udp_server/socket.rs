@@ -1,7 +1,17 @@
 use actix_rt::net::UdpSocket as BaseUpdSocket;
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
+use parking_lot::Mutex;
 use std::net::SocketAddr;
+use std::time::Duration;
 use std::{io, mem, ptr};
+use tokio::sync::mpsc::error::TrySendError;
+
+use ya_relay_core::faults::{corrupt_bytes, FaultConfig, FaultInjector};
+#[cfg(feature = "test-utils")]
+use ya_relay_core::testing::sim::{SimNetwork, SimSocket};
+use ya_relay_proto::codec::datagram;
+
+use super::tcp::TcpPeers;
 
 pub struct UdpSocketConfig {
     min_recv_buffer: Option<usize>,
@@ -11,7 +21,22 @@ pub struct UdpSocketConfig {
 }
 
 pub struct UdpSocket {
-    inner: BaseUpdSocket,
+    inner: Inner,
+    tcp_peers: Option<TcpPeers>,
+    faults: Option<SocketFaults>,
+}
+
+/// Faults injected into traffic of the socket, for testing.
+struct SocketFaults {
+    incoming: Mutex<FaultInjector>,
+    outgoing: Mutex<FaultInjector>,
+}
+
+enum Inner {
+    Os(BaseUpdSocket),
+    /// Socket of the simulated network, see `ya_relay_core::testing::sim`.
+    #[cfg(feature = "test-utils")]
+    Sim(SimSocket),
 }
 
 #[derive(Debug)]
@@ -135,7 +160,29 @@ mod helpers {
     }
 }
 
+impl Inner {
+    /// Socket with the OS features, like ICMP errors.
+    #[cfg(target_os = "linux")]
+    fn os(&self) -> Option<&BaseUpdSocket> {
+        match self {
+            Inner::Os(inner) => Some(inner),
+            #[cfg(feature = "test-utils")]
+            Inner::Sim(_) => None,
+        }
+    }
+}
+
 impl UdpSocketConfig {
+    /// Binds socket on the simulated network. Socket options don't apply to it.
+    #[cfg(feature = "test-utils")]
+    pub fn bind_sim(self, network: &SimNetwork, bind_addr: SocketAddr) -> io::Result<UdpSocket> {
+        Ok(UdpSocket {
+            inner: Inner::Sim(network.bind_socket(bind_addr)?),
+            tcp_peers: None,
+            faults: None,
+        })
+    }
+
     #[cfg(windows)]
     pub fn bind(self, bind_addr: SocketAddr) -> io::Result<UdpSocket> {
         use helpers::*;
@@ -218,7 +265,9 @@ impl UdpSocketConfig {
         s.set_nonblocking(true)?;
 
         Ok(UdpSocket {
-            inner: BaseUpdSocket::from_std(s)?,
+            inner: Inner::Os(BaseUpdSocket::from_std(s)?),
+            tcp_peers: None,
+            faults: None,
         })
     }
 
@@ -281,7 +330,9 @@ impl UdpSocketConfig {
         s.set_nonblocking(true)?;
 
         Ok(UdpSocket {
-            inner: BaseUpdSocket::from_std(s)?,
+            inner: Inner::Os(BaseUpdSocket::from_std(s)?),
+            tcp_peers: None,
+            faults: None,
         })
     }
 
@@ -321,22 +372,124 @@ impl UdpSocketConfig {
 
 impl UdpSocket {
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.inner.local_addr()
+        match &self.inner {
+            Inner::Os(inner) => inner.local_addr(),
+            #[cfg(feature = "test-utils")]
+            Inner::Sim(inner) => Ok(inner.local_addr()),
+        }
+    }
+
+    /// Binds another socket on the IP address of this one and in the same network.
+    pub fn bind_next_to(&self, config: UdpSocketConfig) -> io::Result<UdpSocket> {
+        let addr = SocketAddr::new(self.local_addr()?.ip(), 0);
+        match &self.inner {
+            Inner::Os(_) => config.bind(addr),
+            #[cfg(feature = "test-utils")]
+            Inner::Sim(inner) => config.bind_sim(inner.network(), addr),
+        }
+    }
+
+    /// Drops, delays, duplicates, reorders or corrupts sent and received datagrams.
+    pub(crate) fn with_faults(mut self, config: &FaultConfig) -> Self {
+        let incoming = FaultConfig {
+            seed: config.seed.map(|seed| seed.wrapping_add(1)),
+            ..config.clone()
+        };
+        self.faults = Some(SocketFaults {
+            incoming: Mutex::new(FaultInjector::new(&incoming)),
+            outgoing: Mutex::new(FaultInjector::new(config)),
+        });
+        self
+    }
+
+    pub(crate) fn has_faults(&self) -> bool {
+        self.faults.is_some()
+    }
+
+    /// Applies faults to received datagram. Returns copies of the datagram to handle
+    /// with their delays.
+    pub(crate) fn incoming_faults(&self, data: BytesMut) -> Vec<(Duration, BytesMut)> {
+        let faults = match &self.faults {
+            Some(faults) => faults,
+            None => return vec![(Duration::ZERO, data)],
+        };
+        let is_forward = datagram::is_forward(&data);
+        faults
+            .incoming
+            .lock()
+            .apply(data, is_forward, |mut data, offset| {
+                corrupt_bytes(&mut data, offset);
+                Some(data)
+            })
+    }
+
+    /// Routes packets to peers connected over TCP via their connections.
+    pub(crate) fn with_tcp_peers(mut self, tcp_peers: TcpPeers) -> Self {
+        self.tcp_peers = Some(tcp_peers);
+        self
     }
 
     pub async fn send_to(&self, buffer: &[u8], dst: SocketAddr) -> io::Result<usize> {
-        let len = self.inner.send_to(buffer, dst).await?;
-        Ok(len)
+        let faults = match &self.faults {
+            Some(faults) => faults,
+            None => return self.send_raw(buffer, dst).await,
+        };
+
+        let is_forward = datagram::is_forward(buffer);
+        let datagrams =
+            faults
+                .outgoing
+                .lock()
+                .apply(buffer.to_vec(), is_forward, |mut data, offset| {
+                    corrupt_bytes(&mut data, offset);
+                    Some(data)
+                });
+        for (delay, data) in datagrams {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            self.send_raw(&data, dst).await?;
+        }
+        Ok(buffer.len())
+    }
+
+    async fn send_raw(&self, buffer: &[u8], dst: SocketAddr) -> io::Result<usize> {
+        if let Some(tx) = self.tcp_peers.as_ref().and_then(|peers| peers.get(&dst)) {
+            return match tx.try_send(Bytes::copy_from_slice(buffer)) {
+                Ok(()) => Ok(buffer.len()),
+                Err(TrySendError::Full(_)) => {
+                    log::trace!("[{dst}] TCP write queue full, dropping packet");
+                    Ok(buffer.len())
+                }
+                Err(TrySendError::Closed(_)) => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+            };
+        }
+
+        match &self.inner {
+            Inner::Os(inner) => inner.send_to(buffer, dst).await,
+            #[cfg(feature = "test-utils")]
+            Inner::Sim(inner) => inner.send_to(buffer, dst),
+        }
     }
 
     pub async fn recv_from(&self, buffer: &mut BytesMut) -> io::Result<SocketAddr> {
-        let (_len, src) = self.inner.recv_buf_from(buffer).await?;
-        Ok(src)
+        match &self.inner {
+            Inner::Os(inner) => {
+                let (_len, src) = inner.recv_buf_from(buffer).await?;
+                Ok(src)
+            }
+            #[cfg(feature = "test-utils")]
+            Inner::Sim(inner) => {
+                let (data, src) = inner.recv_from().await?;
+                buffer.extend_from_slice(&data);
+                Ok(src)
+            }
+        }
     }
 
     #[cfg(not(target_os = "linux"))]
     pub async fn recv_any(&self, buffer: &mut BytesMut) -> io::Result<(SocketAddr, PacketType)> {
-        let (_len, src) = self.inner.recv_buf_from(buffer).await?;
+        let src = self.recv_from(buffer).await?;
         Ok((src, PacketType::Data))
     }
 
@@ -347,7 +500,12 @@ impl UdpSocket {
         use std::net::{Ipv4Addr, SocketAddrV4};
         use tokio::io::Interest;
 
-        self.inner
+        let inner = match self.inner.os() {
+            Some(inner) => inner,
+            None => return Ok((self.recv_from(buffer).await?, PacketType::Data)),
+        };
+
+        inner
             .async_io(Interest::READABLE | Interest::ERROR, || unsafe {
                 let mut control_buffer = [mem::MaybeUninit::<u8>::uninit(); 1024];
                 let mut remote: sockaddr_in = mem::zeroed();
@@ -374,14 +532,14 @@ impl UdpSocket {
                         msg.msg_controllen = mem::size_of_val(&control_buffer) as size_t;
                     }
                 }
-                let mut res = recvmsg(self.inner.as_raw_fd(), ptr::addr_of_mut!(msg), MSG_DONTWAIT);
+                let mut res = recvmsg(inner.as_raw_fd(), ptr::addr_of_mut!(msg), MSG_DONTWAIT);
                 if res == -1 {
                     let err = std::io::Error::last_os_error();
                     /*if err.kind() == io::ErrorKind::WouldBlock {
                         return Err(err);
                     }*/
                     res = recvmsg(
-                        self.inner.as_raw_fd(),
+                        inner.as_raw_fd(),
                         ptr::addr_of_mut!(msg),
                         MSG_ERRQUEUE | MSG_DONTWAIT,
                     );
@@ -0,0 +1,118 @@
+//! TCP listener for Nodes unable to communicate with relay over UDP.
+//!
+//! Packets received over TCP are passed to the same `Worker` as datagrams. Responses
+//! are routed back to TCP connections by `UdpSocket::send_to`, based on `TcpPeers`.
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use dashmap::DashMap;
+use futures::prelude::*;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_util::codec::Encoder;
+
+use ya_relay_core::tcp_stream::packet_stream;
+use ya_relay_proto::codec::datagram::Codec;
+use ya_relay_proto::codec::stream::encode_datagram;
+use ya_relay_proto::codec::Error;
+
+use super::{PacketType, Worker};
+
+/// Packets waiting to be written to a single TCP connection. Packets sent to a peer,
+/// which doesn't keep up with reading them, are dropped as if they were lost datagrams.
+const WRITE_QUEUE_SIZE: usize = 256;
+
+/// Connected TCP peers, shared by all workers.
+pub type TcpPeers = Arc<DashMap<SocketAddr, mpsc::Sender<Bytes>>>;
+
+pub(super) fn start_listener<W: Worker + 'static>(
+    listener: std::net::TcpListener,
+    max_connections: usize,
+    peers: TcpPeers,
+    worker: Rc<W>,
+) -> anyhow::Result<()> {
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+
+    log::info!("TCP listener started on {:?}", listener.local_addr()?);
+
+    tokio::task::spawn_local(async move {
+        loop {
+            match listener.accept().await {
+                Ok((_, peer)) if peers.len() >= max_connections => {
+                    log::debug!(
+                        "[{peer}] TCP connection rejected, limit of {max_connections} reached"
+                    );
+                }
+                Ok((stream, peer)) => {
+                    let (tx, rx) = mpsc::channel(WRITE_QUEUE_SIZE);
+                    peers.insert(peer, tx);
+                    tokio::task::spawn_local(handle_connection(
+                        stream,
+                        peer,
+                        rx,
+                        peers.clone(),
+                        worker.clone(),
+                    ));
+                }
+                Err(e) => log::warn!("TCP accept error: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn handle_connection<W: Worker>(
+    stream: TcpStream,
+    peer: SocketAddr,
+    mut rx: mpsc::Receiver<Bytes>,
+    peers: TcpPeers,
+    worker: Rc<W>,
+) {
+    log::debug!("[{peer}] TCP connection accepted");
+
+    if let Err(e) = stream.set_nodelay(true) {
+        log::debug!("[{peer}] unable to set TCP_NODELAY: {e}");
+    }
+
+    let (read, mut write) = stream.into_split();
+
+    let writer = tokio::task::spawn_local(async move {
+        while let Some(datagram) = rx.recv().await {
+            let mut frame = BytesMut::new();
+            if let Err(e) = encode_datagram(&datagram, &mut frame) {
+                log::debug!("[{peer}] unable to frame packet: {e}");
+                continue;
+            }
+            if let Err(e) = write.write_all(&frame).await {
+                log::debug!("[{peer}] TCP send error: {e}");
+                break;
+            }
+        }
+    });
+
+    let mut packets = packet_stream(read);
+    while let Some(packet) = packets.next().await {
+        let mut datagram = BytesMut::new();
+        let result = packet.and_then(|packet| Codec.encode(packet, &mut datagram));
+        match result {
+            Ok(()) => {
+                if let Err(e) = worker.handle(datagram, peer, PacketType::Data).await {
+                    log::debug!("[{peer}] invalid request: {:?}", e);
+                }
+            }
+            Err(Error::Io(e)) => {
+                log::debug!("[{peer}] TCP receive error: {e}");
+                break;
+            }
+            Err(e) => log::debug!("[{peer}] invalid packet: {e}"),
+        }
+    }
+
+    log::debug!("[{peer}] TCP connection closed");
+    peers.remove(&peer);
+    writer.abort();
+}
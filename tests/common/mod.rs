@@ -1,11 +1,9 @@
 use anyhow::{bail, Context};
-use futures::StreamExt;
+use futures::{Stream, StreamExt};
 use std::rc::Rc;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::SeqCst;
 use std::time::Duration;
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::UnboundedReceiverStream;
 use ya_relay_core::testing::TestServerWrapper;
 use ya_relay_server::testing::server::ServerWrapper;
 
@@ -21,22 +19,21 @@ pub enum Mode {
 pub fn spawn_receive<T: std::fmt::Debug + 'static>(
     label: &'static str,
     received: Rc<AtomicBool>,
-    rx: mpsc::UnboundedReceiver<T>,
+    rx: impl Stream<Item = T> + 'static,
 ) {
     println!("Spawning {} receiver", label);
 
     tokio::task::spawn_local({
         let received = received;
         async move {
-            UnboundedReceiverStream::new(rx)
-                .for_each(|item| {
-                    let received = received.clone();
-                    async move {
-                        println!("{} received {:?}", label, item);
-                        received.clone().store(true, SeqCst)
-                    }
-                })
-                .await;
+            rx.for_each(|item| {
+                let received = received.clone();
+                async move {
+                    println!("{} received {:?}", label, item);
+                    received.clone().store(true, SeqCst)
+                }
+            })
+            .await;
         }
     });
 }
@@ -103,6 +100,37 @@ pub async fn check_forwarding(
     Ok(tx)
 }
 
+/// Sends `count` packets over reliable channel and checks, that all arrived in order.
+#[allow(dead_code)]
+pub async fn check_reliable_forward(
+    sender: &Client,
+    receiver: &Client,
+    count: u8,
+) -> anyhow::Result<()> {
+    let mut rx = receiver
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+    let mut tx = sender.forward_reliable(receiver.node_id()).await?;
+    for i in 0..count {
+        tx.send(vec![i].into()).await?;
+    }
+
+    let mut received = Vec::new();
+    tokio::time::timeout(Duration::from_secs(15), async {
+        while received.len() < count as usize {
+            let forwarded = rx.next().await.context("forward receiver closed")?;
+            received.extend_from_slice(forwarded.payload.as_ref());
+        }
+        anyhow::Ok(())
+    })
+    .await
+    .context("timeout waiting for forwarded data")??;
+
+    assert_eq!(received, (0..count).collect::<Vec<_>>());
+    Ok(())
+}
+
 #[allow(dead_code)]
 pub async fn check_broadcast(
     sender_client: &Client,
@@ -134,6 +162,6 @@ pub async fn check_broadcast(
 /// TODO: Should be moved to ServerWrapper, but we don't want to import Client in Server crate.
 #[allow(dead_code)]
 pub async fn hack_make_ip_private(wrapper: &ServerWrapper, client: &Client) {
-    wrapper.remove_node_endpoints(client.node_id());
+    wrapper.remove_node_endpoints(client.node_id()).await;
     client.set_public_addr(None).await;
 }
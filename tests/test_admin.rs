@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use ya_relay_client::{Client, ClientBuilder, FailFast};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::{init_test_server, ServerWrapper};
+
+async fn relay_session(wrapper: &ServerWrapper, client: &Client) -> bool {
+    let relay = wrapper.server.bind_addr();
+    client
+        .sessions()
+        .await
+        .into_iter()
+        .any(|desc| desc.remote == relay)
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_admin_disconnect_session() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let sessions = wrapper.server.sessions();
+    let session = sessions.node_session(client.node_id()).unwrap();
+    assert!(relay_session(&wrapper, &client).await);
+
+    assert!(wrapper.server.disconnect(&session.session_id));
+    assert!(!wrapper.server.disconnect(&session.session_id));
+    assert!(sessions.session(&session.session_id).is_none());
+
+    // Client should close its session after receiving `Disconnected`.
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(!relay_session(&wrapper, &client).await);
+
+    // Kicked Node is allowed to reconnect.
+    client.find_node(client.node_id()).await?;
+    assert!(sessions.node_session(client.node_id()).is_some());
+    assert!(relay_session(&wrapper, &client).await);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_admin_ban_node() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .session_request_timeout(Duration::from_millis(500))
+        .build()
+        .await?;
+
+    let sessions = wrapper.server.sessions();
+    assert_eq!(
+        wrapper
+            .server
+            .ban_node(client.node_id(), Duration::from_secs(60))?,
+        1
+    );
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(!relay_session(&wrapper, &client).await);
+
+    assert!(client.find_node(client.node_id()).await.is_err());
+    assert!(sessions.node_session(client.node_id()).is_none());
+
+    assert!(wrapper.server.bans().unban_node(client.node_id()));
+    client.find_node(client.node_id()).await?;
+    assert!(sessions.node_session(client.node_id()).is_some());
+    Ok(())
+}
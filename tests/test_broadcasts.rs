@@ -8,10 +8,8 @@ use std::time::Duration;
 use anyhow::Context;
 use futures::StreamExt;
 use itertools::Itertools;
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::UnboundedReceiverStream;
 
-use ya_relay_client::channels::Forwarded;
+use ya_relay_client::channels::ForwardReceiver;
 use ya_relay_client::{Client, ClientBuilder, FailFast};
 use ya_relay_core::testing::TestServerWrapper;
 use ya_relay_core::NodeId;
@@ -84,19 +82,18 @@ async fn test_broadcast() -> anyhow::Result<()> {
     let wrapper = init_test_server().await.unwrap();
     let mut clients = start_clients(&wrapper, NEIGHBOURHOOD_SIZE + 1).await;
 
-    fn spawn_receive(received: Rc<AtomicUsize>, rx: mpsc::UnboundedReceiver<Forwarded>) {
+    fn spawn_receive(received: Rc<AtomicUsize>, rx: ForwardReceiver) {
         tokio::task::spawn_local({
             let received = received;
             async move {
-                UnboundedReceiverStream::new(rx)
-                    .for_each(|item| {
-                        let received = received.clone();
-                        async move {
-                            println!("received {:?}", item);
-                            received.clone().fetch_add(item.payload.len(), SeqCst);
-                        }
-                    })
-                    .await;
+                rx.for_each(|item| {
+                    let received = received.clone();
+                    async move {
+                        println!("received {:?}", item);
+                        received.clone().fetch_add(item.payload.len(), SeqCst);
+                    }
+                })
+                .await;
             }
         });
     }
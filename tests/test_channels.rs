@@ -0,0 +1,76 @@
+mod common;
+
+use anyhow::Context;
+use futures::StreamExt;
+use std::time::Duration;
+
+use ya_relay_client::{ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::init_test_server;
+
+#[test_log::test(actix_rt::test)]
+async fn test_forward_numbered_channels() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .channel(10, 200)?
+        .channel(11, 10)?
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .channel(10, 200)?
+        .channel(11, 10)?
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let mut rx2 = client2
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+
+    let mut control = client1.forward_channel(client2.node_id(), 10).await?;
+    let mut logs = client1.forward_channel(client2.node_id(), 11).await?;
+    let mut reliable = client1.forward_reliable(client2.node_id()).await?;
+
+    control.send(vec![10u8].into()).await?;
+    logs.send(vec![11u8].into()).await?;
+    reliable.send(vec![1u8].into()).await?;
+
+    let mut received = Vec::new();
+    while received.len() < 3 {
+        let packet = tokio::time::timeout(Duration::from_secs(5), rx2.next())
+            .await
+            .context("receive timeout")?
+            .context("receiver closed")?;
+        assert_eq!(packet.node_id, client1.node_id());
+        assert_eq!(packet.payload.as_ref(), &[packet.channel as u8]);
+        received.push(packet.channel);
+    }
+    received.sort();
+    assert_eq!(received, vec![1, 10, 11]);
+
+    // Channels must be configured before use.
+    assert!(client1
+        .forward_channel(client2.node_id(), 12)
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_channel_id_range() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    assert!(ClientBuilder::from_url(wrapper.url())
+        .channel(0, 1)
+        .is_err());
+    assert!(ClientBuilder::from_url(wrapper.url())
+        .channel(1000, 1)
+        .is_err());
+    assert!(ClientBuilder::from_url(wrapper.url())
+        .channel(999, 1)
+        .is_ok());
+    Ok(())
+}
@@ -0,0 +1,133 @@
+mod common;
+
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::Duration;
+
+use anyhow::Context;
+
+use ya_relay_client::{ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::server_session::SessionId;
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::{
+    init_test_server_with_config, test_default_config, ServerWrapper,
+};
+use ya_relay_server::StateBackendKind;
+
+use common::{hack_make_ip_private, spawn_receive};
+
+const SYNC_INTERVAL: Duration = Duration::from_millis(200);
+
+async fn init_cluster() -> anyhow::Result<(ServerWrapper, ServerWrapper)> {
+    let mut wrappers = Vec::new();
+    for instance in 0..2 {
+        let mut config = test_default_config();
+        config.cluster.state_backend = StateBackendKind::Replicated;
+        config.cluster.cluster_instance = instance;
+        config.cluster.cluster_key = Some("secret".into());
+        config.cluster.cluster_sync_interval = SYNC_INTERVAL;
+        wrappers.push(init_test_server_with_config(config).await?);
+    }
+    let wrapper2 = wrappers.pop().unwrap();
+    let wrapper1 = wrappers.pop().unwrap();
+    wrapper1
+        .server
+        .cluster()
+        .add_sibling(wrapper2.server.bind_addr())?;
+    wrapper2
+        .server
+        .cluster()
+        .add_sibling(wrapper1.server.bind_addr())?;
+    Ok((wrapper1, wrapper2))
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_cluster_shares_sessions() -> anyhow::Result<()> {
+    let (wrapper1, wrapper2) = init_cluster().await?;
+
+    let client1 = ClientBuilder::from_url(wrapper1.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper2.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    tokio::time::sleep(SYNC_INTERVAL * 3).await;
+
+    // Session held by the first instance is visible to the second one.
+    let session = wrapper1
+        .server
+        .sessions()
+        .node_session(client1.node_id())
+        .context("no session")?;
+    let state2 = wrapper2.server.state();
+    assert!(state2.session(&session.session_id).is_some());
+    assert_eq!(
+        state2.holder(&session.session_id),
+        Some(wrapper1.server.bind_addr())
+    );
+    assert_eq!(state2.holder(&SessionId::generate()), None);
+
+    // Slots are unique in the cluster and resolved by both instances.
+    let state1 = wrapper1.server.state();
+    let slot1 = state1.slot(client1.node_id()).context("no slot")?;
+    let slot2 = state2.slot(client2.node_id()).context("no slot")?;
+    assert_ne!(slot1, slot2);
+    assert_eq!(state2.slot(client1.node_id()), Some(slot1));
+    assert_eq!(state1.slot(client2.node_id()), Some(slot2));
+    assert_eq!(state2.node(slot1), Some(client1.node_id()));
+    assert_eq!(state1.node(slot2), Some(client2.node_id()));
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_cluster_forward() -> anyhow::Result<()> {
+    let (wrapper1, wrapper2) = init_cluster().await?;
+
+    let client1 = ClientBuilder::from_url(wrapper1.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper2.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    hack_make_ip_private(&wrapper1, &client1).await;
+    hack_make_ip_private(&wrapper2, &client2).await;
+
+    // Wait until instances exchange their sessions.
+    tokio::time::sleep(SYNC_INTERVAL * 3).await;
+
+    let rx1 = client1
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+    let received1 = Rc::new(AtomicBool::new(false));
+    spawn_receive(">> 1", received1.clone(), rx1);
+
+    let rx2 = client2
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+    let received2 = Rc::new(AtomicBool::new(false));
+    spawn_receive(">> 2", received2.clone(), rx2);
+
+    let mut tx1 = client1.forward_reliable(client2.node_id()).await?;
+    tx1.send(vec![1u8].into()).await?;
+    let mut tx2 = client2.forward_reliable(client1.node_id()).await?;
+    tx2.send(vec![2u8].into()).await?;
+
+    for _ in 0..20 {
+        if received1.load(SeqCst) && received2.load(SeqCst) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(received1.load(SeqCst));
+    assert!(received2.load(SeqCst));
+    assert!(!client1.is_p2p(client2.node_id()).await);
+    Ok(())
+}
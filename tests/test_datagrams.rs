@@ -0,0 +1,83 @@
+mod common;
+
+use anyhow::Context;
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+use ya_relay_client::channels::VirtualUdpSocket;
+use ya_relay_client::{ClientBuilder, FailFast};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_core::NodeId;
+use ya_relay_server::testing::server::init_test_server;
+
+use common::{check_forwarding, spawn_receive, Mode};
+
+async fn recv(socket: &mut VirtualUdpSocket) -> anyhow::Result<(Vec<u8>, NodeId, u16)> {
+    tokio::time::timeout(Duration::from_secs(5), socket.recv_from())
+        .await
+        .context("receive timeout")?
+        .context("socket closed")
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_udp_sockets_on_ports() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let rx2 = client2
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+    let received2 = Rc::new(AtomicBool::new(false));
+    spawn_receive(">> 2", received2.clone(), rx2);
+
+    let mut socket1 = client1.bind_udp(50)?;
+    let mut socket2a = client2.bind_udp(60)?;
+    let mut socket2b = client2.bind_udp(61)?;
+    assert!(client2.bind_udp(60).is_err());
+    assert!(client2.bind_udp(0).is_err());
+    assert!(client2.bind_udp(1000).is_err());
+
+    // Udp and tcp ports are independent.
+    let _listener = client2.listen(60)?;
+
+    socket1.send_to(b"a", client2.node_id(), 60).await?;
+    socket1.send_to(b"b", client2.node_id(), 61).await?;
+
+    assert_eq!(
+        recv(&mut socket2a).await?,
+        (b"a".to_vec(), client1.node_id(), 50)
+    );
+    assert_eq!(
+        recv(&mut socket2b).await?,
+        (b"b".to_vec(), client1.node_id(), 50)
+    );
+
+    socket2b.send_to(b"c", client1.node_id(), 50).await?;
+    assert_eq!(
+        recv(&mut socket1).await?,
+        (b"c".to_vec(), client2.node_id(), 61)
+    );
+
+    // Port can be bound again after the socket was dropped.
+    drop(socket2a);
+    let mut socket2a = client2.bind_udp(60)?;
+    socket1.send_to(b"d", client2.node_id(), 60).await?;
+    assert_eq!(
+        recv(&mut socket2a).await?,
+        (b"d".to_vec(), client1.node_id(), 50)
+    );
+
+    // Datagrams don't go through `ForwardReceiver` and don't affect channels.
+    check_forwarding(&client1, &client2, received2, Mode::Reliable).await?;
+    Ok(())
+}
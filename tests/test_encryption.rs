@@ -0,0 +1,63 @@
+use anyhow::Context;
+use futures::StreamExt;
+use std::time::Duration;
+
+use ya_relay_client::{Client, ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::{init_test_server, ServerWrapper};
+
+async fn relayed_clients(
+    wrapper: &ServerWrapper,
+    allow_plaintext: bool,
+) -> anyhow::Result<(Client, Client)> {
+    let mut clients = Vec::new();
+    for _ in 0..2 {
+        let mut builder = ClientBuilder::from_url(wrapper.url()).connect(FailFast::Yes);
+        if allow_plaintext {
+            builder = builder.allow_plaintext();
+        }
+        let client = builder.build().await?;
+        // Relay claims, that the Node doesn't support encryption.
+        wrapper.strip_encryption(client.node_id());
+        client.set_public_addr(None).await;
+        clients.push(client);
+    }
+    let client2 = clients.pop().unwrap();
+    let client1 = clients.pop().unwrap();
+    Ok((client1, client2))
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_relay_stripped_encryption_refused() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let (client1, client2) = relayed_clients(&wrapper, false).await?;
+
+    let result = async {
+        let mut tx = client1.forward_reliable(client2.node_id()).await?;
+        tx.send(vec![1u8].into()).await?;
+        anyhow::Ok(())
+    }
+    .await;
+    assert!(result.is_err());
+    assert!(!client1.is_p2p(client2.node_id()).await);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_relay_stripped_encryption_plaintext_allowed() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let (client1, client2) = relayed_clients(&wrapper, true).await?;
+
+    let mut rx = client2
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+    let mut tx = client1.forward_reliable(client2.node_id()).await?;
+    tx.send(vec![1u8].into()).await?;
+    let forwarded = tokio::time::timeout(Duration::from_secs(5), rx.next())
+        .await?
+        .context("forward receiver closed")?;
+    assert_eq!(forwarded.payload.as_ref(), &[1u8]);
+    assert!(!client1.is_p2p(client2.node_id()).await);
+    Ok(())
+}
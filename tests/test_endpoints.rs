@@ -0,0 +1,151 @@
+use futures::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+
+use tokio::net::{TcpListener, UdpSocket};
+use url::Url;
+
+use ya_relay_client::ClientBuilder;
+use ya_relay_client::FailFast;
+use ya_relay_core::tcp_stream::packet_stream;
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_proto::codec::stream::EncoderSink;
+use ya_relay_proto::codec::PacketKind;
+use ya_relay_proto::proto::{
+    packet, request, response, Message, Packet, Protocol, Request, StatusCode,
+};
+use ya_relay_server::testing::server::init_test_server;
+
+fn pong(packet: Packet) -> Option<Packet> {
+    match packet.kind {
+        Some(packet::Kind::Request(Request {
+            request_id,
+            kind: Some(request::Kind::Ping(_)),
+        })) => Some(Packet::response(
+            request_id,
+            packet.session_id,
+            StatusCode::Ok,
+            response::Pong {},
+        )),
+        _ => None,
+    }
+}
+
+/// Imitates port forwarded to the Node, by answering relay pings.
+async fn spawn_pong_responder() -> anyhow::Result<SocketAddr> {
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let addr = socket.local_addr()?;
+
+    tokio::task::spawn_local(async move {
+        let mut buf = vec![0u8; 1500];
+        while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+            if let Some(pong) = Packet::decode(&buf[..len]).ok().and_then(pong) {
+                socket.send_to(&pong.encode_to_vec(), from).await.ok();
+            }
+        }
+    });
+    Ok(addr)
+}
+
+/// Imitates TCP port forwarded to the Node, by answering relay pings.
+async fn spawn_tcp_pong_responder() -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    tokio::task::spawn_local(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let (read, write) = stream.into_split();
+            let mut sink = EncoderSink::from(write);
+            let mut stream = packet_stream(read);
+            while let Some(Ok(PacketKind::Packet(packet))) = stream.next().await {
+                if let Some(pong) = pong(packet) {
+                    sink.send(PacketKind::Packet(pong)).await.ok();
+                }
+            }
+        }
+    });
+    Ok(addr)
+}
+
+/// Service unrelated to the Node, which accepts connections, but doesn't answer pings.
+async fn spawn_tcp_listener() -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    tokio::task::spawn_local(async move {
+        let mut connections = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            connections.push(stream);
+        }
+    });
+    Ok(addr)
+}
+
+fn url(protocol: &str, addr: SocketAddr) -> Url {
+    Url::parse(&format!("{protocol}://{addr}")).unwrap()
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_advertised_endpoints() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    let udp_valid = spawn_pong_responder().await?;
+    let udp_invalid = UdpSocket::bind("127.0.0.1:0").await?.local_addr()?;
+    let tcp_valid = spawn_tcp_pong_responder().await?;
+    let tcp_invalid = spawn_tcp_listener().await?;
+    let tcp_closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+    let internal: SocketAddr = "10.1.2.3:7464".parse()?;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .advertise(url("udp", udp_valid))?
+        .advertise(url("udp", udp_invalid))?
+        .advertise(url("tcp", tcp_valid))?
+        .advertise(url("tcp", tcp_invalid))?
+        .advertise(url("tcp", tcp_closed))?
+        .advertise(url("udp", internal))?
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let node = client2.find_node(client1.node_id()).await?;
+    let endpoints = node
+        .endpoints
+        .into_iter()
+        .map(|e| (e.protocol(), SocketAddr::try_from(e).unwrap()))
+        .collect::<Vec<_>>();
+
+    assert!(endpoints.contains(&(Protocol::Udp, udp_valid)));
+    assert!(endpoints.contains(&(Protocol::Tcp, tcp_valid)));
+    assert!(!endpoints.contains(&(Protocol::Udp, udp_invalid)));
+    assert!(!endpoints.contains(&(Protocol::Tcp, tcp_invalid)));
+    assert!(!endpoints.contains(&(Protocol::Tcp, tcp_closed)));
+
+    // Observed address is published first.
+    assert_eq!(endpoints[0].0, Protocol::Udp);
+    assert_eq!(endpoints[0].1.port(), client1.bind_addr().await?.port());
+
+    let session = wrapper
+        .server
+        .sessions()
+        .node_session(client1.node_id())
+        .unwrap();
+    // Address in internal network isn't even probed.
+    assert_eq!(session.advertised.lock().len(), 5);
+    assert!(session
+        .advertised
+        .lock()
+        .iter()
+        .all(|endpoint| endpoint.addr != internal));
+    assert!(session
+        .advertised
+        .lock()
+        .iter()
+        .all(|endpoint| !endpoint.status.is_pending()));
+
+    wrapper.remove_node_endpoints(client1.node_id()).await;
+    assert!(session.endpoints().is_empty());
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use futures::{Stream, StreamExt};
+use std::time::Duration;
+
+use ya_relay_client::model::SessionType;
+use ya_relay_client::{ClientBuilder, ClientEvent, FailFast, GenericSender};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::init_test_server;
+
+/// Waits for event, skipping others.
+async fn expect_event(
+    events: &mut (impl Stream<Item = ClientEvent> + Unpin),
+    expected: ClientEvent,
+) -> anyhow::Result<()> {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(event) = events.next().await {
+            log::info!("Event: {event:?}");
+            if event == expected {
+                return Ok(());
+            }
+        }
+        anyhow::bail!("Event stream finished")
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("Timeout waiting for {expected:?}"))?
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_events_p2p_session() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let node_id = client2.node_id();
+    let mut events = Box::pin(client1.events());
+
+    let mut tx = client1.forward_reliable(node_id).await?;
+    tx.send(vec![1u8].into()).await?;
+    expect_event(
+        &mut events,
+        ClientEvent::NodeReachable {
+            node_id,
+            route: SessionType::P2P,
+        },
+    )
+    .await?;
+    expect_event(
+        &mut events,
+        ClientEvent::ChannelConnected {
+            node_id,
+            channel: 1,
+        },
+    )
+    .await?;
+
+    client1.disconnect(node_id).await?;
+    expect_event(&mut events, ClientEvent::NodeUnreachable { node_id }).await?;
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_events_relay_session() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    for client in [&client1, &client2] {
+        wrapper.remove_node_endpoints(client.node_id()).await;
+        client.set_public_addr(None).await;
+    }
+
+    let node_id = client2.node_id();
+    let mut events = Box::pin(client1.events());
+
+    client1.forward_unreliable(node_id).await?;
+    expect_event(
+        &mut events,
+        ClientEvent::NodeReachable {
+            node_id,
+            route: SessionType::Relay,
+        },
+    )
+    .await?;
+
+    let addr = wrapper.server.bind_addr();
+    client1.reconnect_server().await;
+    expect_event(&mut events, ClientEvent::RelayDisconnected { addr }).await?;
+    expect_event(&mut events, ClientEvent::RelayConnected { addr }).await?;
+    Ok(())
+}
@@ -0,0 +1,63 @@
+mod common;
+
+use std::time::Duration;
+
+use ya_relay_client::{Client, ClientBuilder, FailFast};
+use ya_relay_core::faults::FaultConfig;
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::{
+    init_test_server, init_test_server_with_config, test_default_config, ServerWrapper,
+};
+
+use common::check_reliable_forward;
+
+async fn build_client(wrapper: &ServerWrapper, faults: Option<&str>) -> anyhow::Result<Client> {
+    let mut builder = ClientBuilder::from_url(wrapper.url())
+        .session_request_timeout(Duration::from_millis(300))
+        .connect(FailFast::Yes);
+    if let Some(faults) = faults {
+        builder = builder.faults(faults.parse()?);
+    }
+    builder.build().await
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_faults_on_forwarded_data() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let faults = "drop=0.1,duplicate=0.1,reorder=0.2,delay=0.2,max-delay=30ms,kind=forward,seed=1";
+    let client1 = build_client(&wrapper, Some(faults)).await?;
+    let client2 = build_client(&wrapper, Some(faults)).await?;
+
+    check_reliable_forward(&client1, &client2, 50).await?;
+    check_reliable_forward(&client2, &client1, 50).await?;
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_duplicated_protocol_packets() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let faults = "duplicate=1,kind=packet";
+    let client1 = build_client(&wrapper, Some(faults)).await?;
+    let client2 = build_client(&wrapper, Some(faults)).await?;
+
+    client1.find_node(client2.node_id()).await?;
+    check_reliable_forward(&client1, &client2, 5).await?;
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_server_faults() -> anyhow::Result<()> {
+    let mut config = test_default_config();
+    config.server.faults = Some(FaultConfig {
+        drop: 0.2,
+        reorder: 0.2,
+        seed: Some(3),
+        ..Default::default()
+    });
+    let wrapper = init_test_server_with_config(config).await?;
+
+    let client1 = build_client(&wrapper, None).await?;
+    let client2 = build_client(&wrapper, None).await?;
+    check_reliable_forward(&client1, &client2, 20).await?;
+    Ok(())
+}
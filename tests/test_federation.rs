@@ -0,0 +1,130 @@
+mod common;
+
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::Duration;
+
+use anyhow::Context;
+
+use ya_relay_client::{ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::{
+    init_test_server_with_config, test_default_config, ServerWrapper,
+};
+
+use common::{hack_make_ip_private, spawn_receive};
+
+const SYNC_INTERVAL: Duration = Duration::from_millis(200);
+
+async fn init_federated_server() -> anyhow::Result<ServerWrapper> {
+    init_server_with_key("secret").await
+}
+
+async fn init_server_with_key(key: &str) -> anyhow::Result<ServerWrapper> {
+    let mut config = test_default_config();
+    config.federation.federation_sync_interval = SYNC_INTERVAL;
+    config.federation.federation_key = Some(key.to_string());
+    init_test_server_with_config(config).await
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_federated_forward() -> anyhow::Result<()> {
+    let wrapper1 = init_federated_server().await?;
+    let wrapper2 = init_federated_server().await?;
+    wrapper1
+        .server
+        .federation()
+        .add_peer(wrapper2.server.bind_addr())?;
+    wrapper2
+        .server
+        .federation()
+        .add_peer(wrapper1.server.bind_addr())?;
+
+    let client1 = ClientBuilder::from_url(wrapper1.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper2.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    hack_make_ip_private(&wrapper1, &client1).await;
+    hack_make_ip_private(&wrapper2, &client2).await;
+
+    // Wait until relays exchange information about connected Nodes.
+    tokio::time::sleep(SYNC_INTERVAL * 3).await;
+
+    let rx2 = client2
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+    let received = Rc::new(AtomicBool::new(false));
+    spawn_receive(">> 2", received.clone(), rx2);
+
+    let mut tx = client1.forward_reliable(client2.node_id()).await?;
+    tx.send(vec![1u8].into()).await?;
+
+    for _ in 0..20 {
+        if received.load(SeqCst) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(received.load(SeqCst));
+    assert!(!client1.is_p2p(client2.node_id()).await);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_federation_unknown_node() -> anyhow::Result<()> {
+    let wrapper1 = init_federated_server().await?;
+    let wrapper2 = init_federated_server().await?;
+
+    let client1 = ClientBuilder::from_url(wrapper1.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper2.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    hack_make_ip_private(&wrapper2, &client2).await;
+    tokio::time::sleep(SYNC_INTERVAL * 3).await;
+
+    // Relays are not federated, so Node connected to the other relay can't be found.
+    assert!(client1.forward_reliable(client2.node_id()).await.is_err());
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_federation_wrong_key() -> anyhow::Result<()> {
+    let wrapper1 = init_server_with_key("secret").await?;
+    let wrapper2 = init_server_with_key("other").await?;
+    wrapper1
+        .server
+        .federation()
+        .add_peer(wrapper2.server.bind_addr())?;
+    wrapper2
+        .server
+        .federation()
+        .add_peer(wrapper1.server.bind_addr())?;
+
+    let client1 = ClientBuilder::from_url(wrapper1.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper2.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    hack_make_ip_private(&wrapper2, &client2).await;
+    tokio::time::sleep(SYNC_INTERVAL * 3).await;
+
+    // Presence with invalid tag is rejected.
+    assert!(client1.forward_reliable(client2.node_id()).await.is_err());
+    Ok(())
+}
@@ -6,13 +6,13 @@ use std::rc::Rc;
 use std::sync::atomic::Ordering::SeqCst;
 use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::time::Duration;
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::UnboundedReceiverStream;
 
-use ya_relay_client::channels::Forwarded;
-use ya_relay_client::{ClientBuilder, FailFast, GenericSender};
+use ya_relay_client::channels::ForwardReceiver;
+use ya_relay_client::{ClientBuilder, DropPolicy, FailFast, GenericSender};
 use ya_relay_core::testing::TestServerWrapper;
-use ya_relay_server::testing::server::init_test_server;
+use ya_relay_server::testing::server::{
+    init_test_server, init_test_server_with_config, test_default_config,
+};
 
 use common::hack_make_ip_private;
 use common::spawn_receive;
@@ -227,23 +227,18 @@ async fn test_rate_limiter() -> anyhow::Result<()> {
         .context("no forward receiver")?;
     let received2 = Rc::new(AtomicUsize::new(0));
 
-    fn spawn_receive_counted(
-        label: &'static str,
-        received: Rc<AtomicUsize>,
-        rx: mpsc::UnboundedReceiver<Forwarded>,
-    ) {
+    fn spawn_receive_counted(label: &'static str, received: Rc<AtomicUsize>, rx: ForwardReceiver) {
         tokio::task::spawn_local({
             let received = received;
             async move {
-                UnboundedReceiverStream::new(rx)
-                    .for_each(|item| {
-                        let received = received.clone();
-                        async move {
-                            let last_val = received.clone().fetch_add(item.payload.len(), SeqCst);
-                            println!("{} received {:?} last_val: {}", label, item, last_val + 1);
-                        }
-                    })
-                    .await;
+                rx.for_each(|item| {
+                    let received = received.clone();
+                    async move {
+                        let last_val = received.clone().fetch_add(item.payload.len(), SeqCst);
+                        println!("{} received {:?} last_val: {}", label, item, last_val + 1);
+                    }
+                })
+                .await;
             }
         });
     }
@@ -276,3 +271,155 @@ async fn test_rate_limiter() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test_log::test(actix_rt::test)]
+async fn test_session_bandwidth_limit() -> anyhow::Result<()> {
+    const LIMIT: usize = 2048;
+    let mut config = test_default_config();
+    config.server.rate_limit.session_bandwidth_limit = Some(LIMIT as u64);
+    let wrapper = init_test_server_with_config(config).await?;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    hack_make_ip_private(&wrapper, &client1).await;
+    hack_make_ip_private(&wrapper, &client2).await;
+
+    let mut rx2 = client2
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+    let mut tx1 = client1.forward_reliable(client2.node_id()).await?;
+
+    let payload = vec![7u8; 256];
+    let total = 4 * LIMIT;
+    tokio::task::spawn_local(async move {
+        for _ in 0..total / payload.len() {
+            tx1.send(payload.clone().into()).await.ok();
+        }
+    });
+
+    // Relay drops packets exceeding the budget, until the sender is resumed.
+    // TCP retransmits them, so everything arrives eventually.
+    let mut received = 0;
+    while received < total {
+        let packet = tokio::time::timeout(Duration::from_secs(30), rx2.recv())
+            .await?
+            .context("receiver closed")?;
+        received += packet.payload.len();
+    }
+    assert_eq!(received, total);
+
+    // Bucket holds 1s of traffic, so the sender had to be paused and resumed
+    // to get the rest through.
+    let session = wrapper
+        .server
+        .sessions()
+        .node_session(client1.node_id())
+        .context("no sender session")?;
+    assert!(session.forwarded.pauses.load(SeqCst) > 0);
+    assert!(session.forwarded.resumes.load(SeqCst) > 0);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_ingress_queue_backpressure() -> anyhow::Result<()> {
+    const QUEUE_SIZE: usize = 4;
+    let wrapper = init_test_server().await?;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    // Receive buffer must be much smaller than sent data, otherwise it would hold everything.
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .ingress_queue_size(QUEUE_SIZE)
+        .tcp_max_recv_buffer_size(128 * 1024)?
+        .build()
+        .await?;
+
+    let mut rx2 = client2
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+
+    let mut tx1 = client1.forward_reliable(client2.node_id()).await?;
+    let payload = vec![7u8; 1024];
+    let total = 1024 * payload.len();
+    let sent = Rc::new(AtomicUsize::new(0));
+
+    // Sending in the background, because receiver doesn't read anything yet.
+    tokio::task::spawn_local({
+        let sent = sent.clone();
+        async move {
+            for _ in 0..1024 {
+                tx1.send(payload.clone().into()).await.ok();
+                sent.fetch_add(payload.len(), SeqCst);
+            }
+        }
+    });
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // Virtual TCP window is closed, so the sender can't push everything.
+    assert!(sent.load(SeqCst) < total);
+    let queued = std::iter::from_fn(|| rx2.try_recv())
+        .map(|packet| packet.payload.len())
+        .sum::<usize>();
+    assert!(queued < total / 2, "queued {queued} B");
+
+    let mut received = queued;
+    while received < total {
+        let packet = tokio::time::timeout(Duration::from_secs(5), rx2.recv())
+            .await?
+            .context("receiver closed")?;
+        received += packet.payload.len();
+    }
+    assert_eq!(received, total);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_ingress_queue_drop_unreliable() -> anyhow::Result<()> {
+    const QUEUE_SIZE: usize = 4;
+    let wrapper = init_test_server().await?;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .ingress_queue_size(QUEUE_SIZE)
+        .unreliable_drop_policy(DropPolicy::DropNewest)
+        .build()
+        .await?;
+
+    let mut rx2 = client2
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+
+    let mut tx1 = client1.forward_unreliable(client2.node_id()).await?;
+    for i in 0..10u8 {
+        tx1.send(vec![i].into()).await?;
+    }
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let received = std::iter::from_fn(|| rx2.try_recv())
+        .map(|packet| packet.payload.as_ref()[0])
+        .collect::<Vec<_>>();
+    assert_eq!(received, vec![0, 1, 2, 3]);
+
+    assert_eq!(client2.metrics().rx_dropped, 6);
+    let session_metrics = client2.session_metrics().await;
+    assert_eq!(session_metrics[&client1.node_id()].rx_dropped, 6);
+    Ok(())
+}
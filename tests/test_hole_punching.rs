@@ -0,0 +1,81 @@
+use anyhow::Context;
+use futures::StreamExt;
+use std::time::Duration;
+
+use ya_relay_client::{Client, ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::testing::sim::{NatType, SimHost, SimNetwork};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::{init_sim_server, ServerWrapper};
+
+async fn build_client(
+    wrapper: &ServerWrapper,
+    host: &SimHost,
+    hole_punching: bool,
+) -> anyhow::Result<Client> {
+    ClientBuilder::from_url(wrapper.url())
+        .listen(host.bind_url())
+        .udp_binder(host.network().clone())
+        .session_request_timeout(Duration::from_millis(500))
+        .connect(FailFast::Yes)
+        .hole_punching(hole_punching)
+        .build()
+        .await
+}
+
+async fn check_forward(sender: &Client, receiver: &Client) -> anyhow::Result<()> {
+    let mut rx = receiver
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+    let mut tx = sender.forward_reliable(receiver.node_id()).await?;
+    tx.send(vec![1u8].into()).await?;
+
+    let forwarded = tokio::time::timeout(Duration::from_secs(10), rx.next())
+        .await
+        .context("timeout waiting for forwarded data")?
+        .context("forward receiver closed")?;
+    assert_eq!(forwarded.payload.as_ref(), &[1u8]);
+    Ok(())
+}
+
+/// Nodes behind port restricted NATs can't reach each other directly, until both
+/// of them send packets to the other one.
+async fn nat_hosts(seed: u64) -> anyhow::Result<(SimNetwork, ServerWrapper, SimHost, SimHost)> {
+    let net = SimNetwork::new(seed);
+    let relay = net.add_host(NatType::None);
+    let host1 = net.add_host(NatType::PortRestricted);
+    let host2 = net.add_host(NatType::PortRestricted);
+    let wrapper = init_sim_server(&relay).await?;
+    Ok((net, wrapper, host1, host2))
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_hole_punching_p2p() -> anyhow::Result<()> {
+    tokio::time::pause();
+    let (_net, wrapper, host1, host2) = nat_hosts(1).await?;
+    let client1 = build_client(&wrapper, &host1, true).await?;
+    let client2 = build_client(&wrapper, &host2, true).await?;
+
+    check_forward(&client1, &client2).await?;
+    check_forward(&client2, &client1).await?;
+
+    assert!(client1.is_p2p(client2.node_id()).await);
+    assert!(client2.is_p2p(client1.node_id()).await);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_hole_punching_disabled() -> anyhow::Result<()> {
+    tokio::time::pause();
+    let (_net, wrapper, host1, host2) = nat_hosts(2).await?;
+    let client1 = build_client(&wrapper, &host1, true).await?;
+    let client2 = build_client(&wrapper, &host2, false).await?;
+
+    // Receiver doesn't take part in hole punching, so both directions use relay.
+    check_forward(&client1, &client2).await?;
+    check_forward(&client2, &client1).await?;
+
+    assert!(!client1.is_p2p(client2.node_id()).await);
+    assert!(!client2.is_p2p(client1.node_id()).await);
+    Ok(())
+}
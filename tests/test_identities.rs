@@ -0,0 +1,187 @@
+use anyhow::Context;
+use futures::StreamExt;
+use std::time::Duration;
+
+use ya_relay_client::{Client, ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::crypto::keystore::KeystoreCryptoProvider;
+use ya_relay_core::key::Protected;
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_core::NodeId;
+use ya_relay_server::testing::server::{init_test_server, ServerWrapper};
+
+async fn build_client(
+    wrapper: &ServerWrapper,
+    keystore: &KeystoreCryptoProvider,
+) -> anyhow::Result<Client> {
+    ClientBuilder::from_url(wrapper.url())
+        .crypto(keystore.clone())
+        .connect(FailFast::Yes)
+        .build()
+        .await
+}
+
+async fn relay_identities(client: &Client, node_id: NodeId) -> anyhow::Result<Vec<NodeId>> {
+    let node = client.find_node(node_id).await?;
+    Ok(node
+        .identities
+        .iter()
+        .map(|ident| NodeId::from(ident.node_id.as_slice()))
+        .collect())
+}
+
+async fn wait_for_default_id(
+    client: &Client,
+    node_id: NodeId,
+    expected: Option<NodeId>,
+) -> anyhow::Result<()> {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while client.default_id(node_id).await != expected {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .with_context(|| format!("[{node_id}] default id isn't {expected:?}"))
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_add_remove_identity_on_relay() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let dir = tempfile::tempdir()?;
+    let keystore = KeystoreCryptoProvider::open(dir.path(), Protected::from("secret"))?;
+
+    let client1 = build_client(&wrapper, &keystore).await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let alias = keystore.generate_alias()?;
+    assert!(client2.find_node(alias).await.is_err());
+
+    client1.add_identity(alias).await?;
+    assert_eq!(
+        relay_identities(&client2, alias).await?,
+        vec![client1.node_id(), alias]
+    );
+
+    // Data sent to the new alias reaches the Node.
+    let mut rx = client1
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+    let mut tx = client2.forward_reliable(alias).await?;
+    tx.send(vec![7u8].into()).await?;
+    let forwarded = tokio::time::timeout(Duration::from_secs(5), rx.next())
+        .await?
+        .context("forward receiver closed")?;
+    assert_eq!(forwarded.payload.as_ref(), &[7u8]);
+
+    client1.remove_identity(alias).await?;
+    assert!(client1.remove_identity(alias).await.is_err());
+    assert_eq!(
+        relay_identities(&client2, client1.node_id()).await?,
+        vec![client1.node_id()]
+    );
+    assert!(client2.find_node(alias).await.is_err());
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_rejected_identity_not_applied() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let dir = tempfile::tempdir()?;
+    let keystore = KeystoreCryptoProvider::open(dir.path(), Protected::from("secret"))?;
+
+    let client1 = build_client(&wrapper, &keystore).await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let alias1 = keystore.generate_alias()?;
+    client1.add_identity(alias1).await?;
+
+    let alias2 = keystore.generate_alias()?;
+    wrapper
+        .server
+        .bans()
+        .ban_node(alias2, Duration::from_secs(60))?;
+    assert!(client1.add_identity(alias2).await.is_err());
+
+    // Node keeps the identities accepted by relay, also in sessions established again.
+    assert!(client1.remove_identity(alias2).await.is_err());
+    client1.reconnect_server().await;
+    assert_eq!(
+        relay_identities(&client2, client1.node_id()).await?,
+        vec![client1.node_id(), alias1]
+    );
+
+    assert!(wrapper.server.bans().unban_node(alias2));
+    client1.add_identity(alias2).await?;
+    assert_eq!(
+        relay_identities(&client2, alias2).await?,
+        vec![client1.node_id(), alias1, alias2]
+    );
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_identities_announced_to_p2p_peers() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let dir = tempfile::tempdir()?;
+    let keystore = KeystoreCryptoProvider::open(dir.path(), Protected::from("secret"))?;
+
+    let client1 = build_client(&wrapper, &keystore).await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let mut tx = client1.forward_reliable(client2.node_id()).await?;
+    tx.send(vec![1u8].into()).await?;
+    assert!(client2.is_p2p(client1.node_id()).await);
+
+    let alias = keystore.generate_alias()?;
+    client1.add_identity(alias).await?;
+    wait_for_default_id(&client2, alias, Some(client1.node_id())).await?;
+    assert!(client2.is_p2p(alias).await);
+
+    client1.remove_identity(alias).await?;
+    wait_for_default_id(&client2, alias, None).await?;
+    assert_eq!(
+        client2.default_id(client1.node_id()).await,
+        Some(client1.node_id())
+    );
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_new_sessions_use_current_identities() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let dir = tempfile::tempdir()?;
+    let keystore = KeystoreCryptoProvider::open(dir.path(), Protected::from("secret"))?;
+    let alias1 = keystore.generate_alias()?;
+
+    let client1 = build_client(&wrapper, &keystore).await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    assert_eq!(
+        relay_identities(&client2, alias1).await?,
+        vec![client1.node_id(), alias1]
+    );
+
+    let alias2 = keystore.generate_alias()?;
+    client1.remove_identity(alias1).await?;
+    client1.add_identity(alias2).await?;
+
+    // Session with relay established again carries identities changed at runtime.
+    client1.reconnect_server().await;
+    assert_eq!(
+        relay_identities(&client2, client1.node_id()).await?,
+        vec![client1.node_id(), alias2]
+    );
+    assert!(client2.find_node(alias1).await.is_err());
+    Ok(())
+}
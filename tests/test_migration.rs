@@ -0,0 +1,139 @@
+use anyhow::{bail, Context};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio_util::codec::Decoder;
+
+use ya_relay_client::model::SessionDesc;
+use ya_relay_client::{Client, ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::challenge::sign_rebind;
+use ya_relay_core::crypto::{Crypto, CryptoProvider, FallbackCryptoProvider};
+use ya_relay_core::server_session::SessionId;
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_proto::codec::datagram::Codec;
+use ya_relay_proto::codec::{BytesMut, PacketKind};
+use ya_relay_proto::proto::{packet, request, response, Message, Packet, Response, StatusCode};
+use ya_relay_server::testing::server::init_test_server;
+
+async fn session_with(client: &Client, addr: SocketAddr) -> anyhow::Result<SessionDesc> {
+    client
+        .sessions()
+        .await
+        .into_iter()
+        .find(|session| session.remote.port() == addr.port())
+        .with_context(|| format!("no session with {addr}"))
+}
+
+async fn rebind_request(
+    socket: &UdpSocket,
+    to: SocketAddr,
+    session_id: SessionId,
+    param: request::Rebind,
+) -> anyhow::Result<(StatusCode, Vec<u8>)> {
+    let packet = Packet::request(session_id.to_vec(), param);
+    socket.send_to(&packet.encode_to_vec(), to).await?;
+
+    let mut buf = vec![0u8; 4096];
+    let (size, _) = tokio::time::timeout(Duration::from_secs(2), socket.recv_from(&mut buf))
+        .await
+        .context("rebind response timeout")??;
+    let mut buf = BytesMut::from(&buf[..size]);
+    match Codec.decode(&mut buf)? {
+        Some(PacketKind::Packet(Packet {
+            kind:
+                Some(packet::Kind::Response(Response {
+                    code,
+                    kind: Some(response::Kind::Rebind(response)),
+                    ..
+                })),
+            ..
+        })) => Ok((StatusCode::try_from(code)?, response.nonce)),
+        other => bail!("Expected rebind response, got: {other:?}"),
+    }
+}
+
+/// Moves session to the address of `socket`, returns signed nonce.
+async fn rebind(
+    socket: &UdpSocket,
+    to: SocketAddr,
+    session_id: SessionId,
+    crypto: impl Crypto,
+) -> anyhow::Result<(StatusCode, request::Rebind)> {
+    let (code, nonce) = rebind_request(socket, to, session_id, Default::default()).await?;
+    if code != StatusCode::Ok {
+        bail!("Nonce request rejected with {code:?}");
+    }
+    let signature = sign_rebind(&session_id.to_vec(), &nonce, crypto).await?;
+    let signed = request::Rebind { nonce, signature };
+    let (code, _) = rebind_request(socket, to, session_id, signed.clone()).await?;
+    Ok((code, signed))
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_migrate_p2p_session() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    let crypto = FallbackCryptoProvider::default();
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .crypto(crypto.clone())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let mut tx1 = client1.forward_reliable(client2.node_id()).await?;
+    tx1.send(vec![1u8].into()).await?;
+    assert!(client2.is_p2p(client1.node_id()).await);
+
+    let addr1 = client1.bind_addr().await?;
+    let addr2: SocketAddr = format!("127.0.0.1:{}", client2.bind_addr().await?.port()).parse()?;
+    let session = session_with(&client2, addr1).await?;
+
+    // Socket simulates the new address of client1 after network change.
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let new_addr = socket.local_addr()?;
+
+    // Nonce signed by other identity is rejected.
+    let other = FallbackCryptoProvider::default();
+    let other = other.get(other.default_id().await?).await?;
+    let (code, _) = rebind(&socket, addr2, session.id, other).await?;
+    assert_eq!(code, StatusCode::Unauthorized);
+    assert_eq!(session_with(&client2, addr1).await?.id, session.id);
+
+    let signer = crypto.get(client1.node_id()).await?;
+    let (code, signed) = rebind(&socket, addr2, session.id, signer).await?;
+    assert_eq!(code, StatusCode::Ok);
+    let migrated = session_with(&client2, new_addr).await?;
+    assert_eq!(migrated.id, session.id);
+    assert!(session_with(&client2, addr1).await.is_err());
+    assert!(client2.is_p2p(client1.node_id()).await);
+
+    // Signed nonce replayed from other address can't move the session.
+    let attacker = UdpSocket::bind("127.0.0.1:0").await?;
+    let (code, nonce) = rebind_request(&attacker, addr2, session.id, Default::default()).await?;
+    assert_eq!(code, StatusCode::Ok);
+    assert_ne!(nonce, signed.nonce);
+    let (code, _) = rebind_request(&attacker, addr2, session.id, signed).await?;
+    assert_eq!(code, StatusCode::Unauthorized);
+    assert_eq!(session_with(&client2, new_addr).await?.id, session.id);
+
+    // Packets for client1 are sent to the new address without new session.
+    let mut tx2 = client2.forward_unreliable(client1.node_id()).await?;
+    tx2.send(vec![2u8].into()).await?;
+
+    let mut buf = vec![0u8; 4096];
+    let (size, _) = tokio::time::timeout(Duration::from_secs(2), socket.recv_from(&mut buf))
+        .await
+        .context("receive timeout")??;
+    let mut buf = BytesMut::from(&buf[..size]);
+    match Codec.decode(&mut buf)? {
+        Some(PacketKind::Forward(forward)) => {
+            assert_eq!(forward.session_id.to_vec(), session.id.to_vec())
+        }
+        other => bail!("Expected forwarded packet, got: {other:?}"),
+    }
+    Ok(())
+}
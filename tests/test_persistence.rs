@@ -0,0 +1,45 @@
+use std::time::Duration;
+
+use ya_relay_client::{ClientBuilder, FailFast};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::{init_test_server_with_config, test_default_config};
+
+const SAVE_INTERVAL: Duration = Duration::from_millis(200);
+
+#[test_log::test(actix_rt::test)]
+async fn test_state_saved_periodically() -> anyhow::Result<()> {
+    let state_dir = tempfile::tempdir()?;
+    let mut config = test_default_config();
+    config.state_dir = Some(state_dir.path().to_path_buf());
+    config.state_save_interval = SAVE_INTERVAL;
+    let wrapper = init_test_server_with_config(config).await?;
+
+    let client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let session_id = wrapper
+        .server
+        .sessions()
+        .node_session(client.node_id())
+        .expect("no session")
+        .session_id;
+    let slot = wrapper.server.slots().slot(client.node_id()).unwrap();
+
+    tokio::time::sleep(SAVE_INTERVAL * 3).await;
+    // Server is dropped without saving state on shutdown, as if it crashed.
+    drop(wrapper);
+
+    let mut config = test_default_config();
+    config.state_dir = Some(state_dir.path().to_path_buf());
+    let wrapper = init_test_server_with_config(config).await?;
+
+    let session = wrapper
+        .server
+        .sessions()
+        .node_session(client.node_id())
+        .expect("session not restored");
+    assert_eq!(session.session_id, session_id);
+    assert_eq!(wrapper.server.slots().node(slot), Some(client.node_id()));
+    Ok(())
+}
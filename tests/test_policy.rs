@@ -0,0 +1,55 @@
+use std::io::Write;
+use std::time::Duration;
+
+use ya_relay_client::{ClientBuilder, FailFast};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::{init_test_server_with_config, test_default_config};
+
+#[test_log::test(actix_rt::test)]
+async fn test_policy_deny_cidr() -> anyhow::Result<()> {
+    let mut file = tempfile::NamedTempFile::new()?;
+    writeln!(file, "deny cidr 127.0.0.0/8")?;
+
+    let mut config = test_default_config();
+    config.policy.policy_file = Some(file.path().to_path_buf());
+    let wrapper = init_test_server_with_config(config).await?;
+
+    let result = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .session_request_timeout(Duration::from_millis(500))
+        .build()
+        .await;
+    assert!(result.is_err());
+    assert_eq!(wrapper.server.sessions().num_sessions(), 0);
+
+    // Policy is reloaded at runtime.
+    file.as_file().set_len(0)?;
+    wrapper.server.policy().reload()?;
+
+    ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_policy_allowlist() -> anyhow::Result<()> {
+    let mut file = tempfile::NamedTempFile::new()?;
+    writeln!(file, "allow cidr 10.0.0.0/8")?;
+
+    let mut config = test_default_config();
+    config.policy.policy_file = Some(file.path().to_path_buf());
+    let wrapper = init_test_server_with_config(config).await?;
+
+    let client = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::No)
+        .build()
+        .await?;
+    assert!(client.find_node(client.node_id()).await.is_err());
+
+    writeln!(file, "allow node {}", client.node_id())?;
+    wrapper.server.policy().reload()?;
+    client.find_node(client.node_id()).await?;
+    Ok(())
+}
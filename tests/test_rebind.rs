@@ -0,0 +1,129 @@
+mod common;
+
+use anyhow::{bail, Context};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio_util::codec::Decoder;
+
+use ya_relay_client::{ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::challenge::sign_rebind;
+use ya_relay_core::crypto::{CryptoProvider, FallbackCryptoProvider};
+use ya_relay_core::server_session::SessionId;
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_proto::codec::datagram::Codec;
+use ya_relay_proto::codec::{BytesMut, PacketKind};
+use ya_relay_proto::proto::{packet, request, response, Message, Packet, StatusCode};
+use ya_relay_server::testing::server::init_test_server;
+
+use common::hack_make_ip_private;
+
+/// Sends `Rebind` request from `socket` and returns response status and nonce.
+async fn rebind(
+    socket: &UdpSocket,
+    relay: SocketAddr,
+    session_id: SessionId,
+    nonce: Vec<u8>,
+    signature: Vec<u8>,
+) -> anyhow::Result<(StatusCode, Vec<u8>)> {
+    let packet = Packet::request(session_id.to_vec(), request::Rebind { nonce, signature });
+    socket.send_to(&packet.encode_to_vec(), relay).await?;
+
+    let mut buf = BytesMut::from(&recv(socket).await?[..]);
+    match Codec.decode(&mut buf)? {
+        Some(PacketKind::Packet(Packet {
+            kind: Some(packet::Kind::Response(response)),
+            ..
+        })) => {
+            let code = StatusCode::try_from(response.code)?;
+            match response.kind {
+                Some(response::Kind::Rebind(rebind)) => Ok((code, rebind.nonce)),
+                other => bail!("Unexpected response: {other:?}"),
+            }
+        }
+        other => bail!("Unexpected packet: {other:?}"),
+    }
+}
+
+async fn recv(socket: &UdpSocket) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![0u8; 4096];
+    let (size, _) = tokio::time::timeout(Duration::from_secs(2), socket.recv_from(&mut buf))
+        .await
+        .context("receive timeout")??;
+    buf.truncate(size);
+    Ok(buf)
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_rebind_session() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+    let relay = wrapper.server.bind_addr();
+
+    let crypto = FallbackCryptoProvider::default();
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .crypto(crypto.clone())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    hack_make_ip_private(&wrapper, &client1).await;
+    hack_make_ip_private(&wrapper, &client2).await;
+
+    let node_id = client1.node_id();
+    let sessions = wrapper.server.sessions();
+    let session = sessions.node_session(node_id).context("no session")?;
+    let session_id = session.session_id;
+    let slot = wrapper.server.slots().slot(node_id);
+
+    // Socket simulates the new address of client1 after network change.
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let new_addr = socket.local_addr()?;
+
+    let (code, nonce) = rebind(&socket, relay, session_id, vec![], vec![]).await?;
+    assert_eq!(code, StatusCode::Ok);
+    assert!(!nonce.is_empty());
+
+    // Signature by other identity is rejected and invalidates the nonce.
+    let other = FallbackCryptoProvider::default();
+    let other = other.get(other.default_id().await?).await?;
+    let signature = sign_rebind(&session_id.to_vec(), &nonce, other).await?;
+    let (code, _) = rebind(&socket, relay, session_id, nonce.clone(), signature).await?;
+    assert_eq!(code, StatusCode::Unauthorized);
+    assert_eq!(sessions.session(&session_id).unwrap().peer, session.peer);
+
+    let (code, nonce) = rebind(&socket, relay, session_id, vec![], vec![]).await?;
+    assert_eq!(code, StatusCode::Ok);
+
+    let signer = crypto.get(node_id).await?;
+    let signature = sign_rebind(&session_id.to_vec(), &nonce, signer).await?;
+    let (code, nonce) = rebind(&socket, relay, session_id, nonce, signature).await?;
+    assert_eq!(code, StatusCode::Ok);
+    assert!(nonce.is_empty());
+
+    let rebound = sessions.session(&session_id).context("session removed")?;
+    assert!(!Arc::ptr_eq(&rebound, &session));
+    assert_eq!(rebound.peer, new_addr);
+    assert_eq!(wrapper.server.slots().slot(node_id), slot);
+    assert!(Arc::ptr_eq(
+        &sessions.node_session(node_id).context("node unlinked")?,
+        &rebound
+    ));
+
+    // Packets for client1 are forwarded to the new address. Sending stalls until the key
+    // exchange, which is forwarded first, is answered, so it can't be awaited here.
+    let mut tx = client2.forward_unreliable(node_id).await?;
+    tokio::task::spawn_local(async move { tx.send(vec![7u8].into()).await });
+
+    let mut buf = BytesMut::from(&recv(&socket).await?[..]);
+    match Codec.decode(&mut buf)? {
+        Some(PacketKind::Forward(forward)) => {
+            assert_eq!(forward.session_id.to_vec(), session_id.to_vec())
+        }
+        other => bail!("Expected forwarded packet, got: {other:?}"),
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+mod common;
+
+use anyhow::Context;
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::Duration;
+
+use ya_relay_client::{ClientBuilder, FailFast, GenericSender};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::init_test_server;
+
+use common::{hack_make_ip_private, spawn_receive};
+
+#[test_log::test(actix_rt::test)]
+async fn test_relay_failover() -> anyhow::Result<()> {
+    let wrapper1 = init_test_server().await?;
+    let wrapper2 = init_test_server().await?;
+    let urls = vec![wrapper1.url(), wrapper2.url()];
+
+    let client1 = ClientBuilder::from_urls(urls.clone())
+        .connect(FailFast::No)
+        .expire_session_after(Duration::from_secs(2))
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_urls(urls)
+        .connect(FailFast::No)
+        .expire_session_after(Duration::from_secs(2))
+        .build()
+        .await?;
+
+    // Wait until sessions with backup relay will be established.
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    for wrapper in [&wrapper1, &wrapper2] {
+        hack_make_ip_private(wrapper, &client1).await;
+        hack_make_ip_private(wrapper, &client2).await;
+    }
+
+    let rx2 = client2
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+    let received = Rc::new(AtomicBool::new(false));
+    spawn_receive(">> 2", received.clone(), rx2);
+
+    let mut tx = client1.forward_reliable(client2.node_id()).await?;
+    tx.send(vec![1u8].into()).await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(received.load(SeqCst));
+    assert!(!client1.is_p2p(client2.node_id()).await);
+
+    let relay1 = wrapper1.server.bind_addr();
+    assert_eq!(
+        client1
+            .sessions()
+            .await
+            .into_iter()
+            .filter(|desc| desc.remote == relay1)
+            .count(),
+        1
+    );
+
+    // Primary relay goes down. Sessions with it will expire and traffic should
+    // be routed through the backup relay using the same sender.
+    drop(wrapper1);
+    tokio::time::sleep(Duration::from_secs(5)).await;
+
+    received.store(false, SeqCst);
+    tx.send(vec![2u8].into()).await?;
+
+    for _ in 0..50 {
+        if received.load(SeqCst) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(received.load(SeqCst));
+    assert!(!client1.is_p2p(client2.node_id()).await);
+
+    assert!(client1
+        .sessions()
+        .await
+        .into_iter()
+        .all(|desc| desc.remote != relay1));
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_relay_failover_timeout() -> anyhow::Result<()> {
+    // Sockets which never respond, so each session attempt waits for its timeout.
+    let dead1 = std::net::UdpSocket::bind("127.0.0.1:0")?;
+    let dead2 = std::net::UdpSocket::bind("127.0.0.1:0")?;
+    let urls = [&dead1, &dead2]
+        .iter()
+        .map(|socket| Ok(format!("udp://{}", socket.local_addr()?).parse()?))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let client = ClientBuilder::from_urls(urls)
+        .session_request_timeout(Duration::from_secs(2))
+        .relay_failover_timeout(Duration::from_secs(1))
+        .build()
+        .await?;
+
+    let started = std::time::Instant::now();
+    assert!(client.find_node(client.node_id()).await.is_err());
+    assert!(started.elapsed() < Duration::from_secs(2));
+    Ok(())
+}
@@ -2,9 +2,12 @@ mod common;
 
 use common::{check_broadcast, spawn_receive_for_client};
 use std::net::UdpSocket;
+use std::time::Duration;
 use ya_relay_client::{ClientBuilder, FailFast};
 use ya_relay_core::testing::TestServerWrapper;
-use ya_relay_server::testing::server::init_test_server;
+use ya_relay_server::testing::server::{
+    init_test_server, init_test_server_with_config, test_default_config,
+};
 
 /// Server should not shutdown when receives junks (single, garbage bytes).
 /// Testing if server does not shutdown when receives junks.
@@ -39,3 +42,28 @@ async fn test_server_junks_received() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Clients should be able to connect, while difficulty is adjusted to the rate of new sessions.
+/// Difficulty levels are checked by unit tests of `AdaptiveDifficulty`.
+#[test_log::test(actix_rt::test)]
+async fn test_server_adaptive_difficulty() -> anyhow::Result<()> {
+    let mut config = test_default_config();
+    config.session_handler.max_difficulty = 4;
+    config.session_handler.difficulty_rate_threshold = 1;
+    config.session_handler.difficulty_adjust_interval = Duration::from_millis(100);
+    let wrapper = init_test_server_with_config(config).await?;
+
+    let mut clients = Vec::new();
+    for _ in 0..10 {
+        clients.push(
+            ClientBuilder::from_url(wrapper.url())
+                .connect(FailFast::Yes)
+                .build()
+                .await?,
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(wrapper.server.sessions().num_sessions(), clients.len());
+    assert!(wrapper.server.difficulty() <= 4);
+    Ok(())
+}
@@ -0,0 +1,103 @@
+mod common;
+
+use std::time::Duration;
+
+use ya_relay_client::{Client, ClientBuilder, FailFast};
+use ya_relay_core::testing::sim::{LinkConfig, NatType, SimHost, SimNetwork};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::{init_sim_server, ServerWrapper};
+
+use common::check_reliable_forward;
+
+async fn build_client(wrapper: &ServerWrapper, host: &SimHost) -> anyhow::Result<Client> {
+    ClientBuilder::from_url(wrapper.url())
+        .listen(host.bind_url())
+        .udp_binder(host.network().clone())
+        .session_request_timeout(Duration::from_millis(500))
+        .connect(FailFast::Yes)
+        .build()
+        .await
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_sim_full_cone_nat_p2p() -> anyhow::Result<()> {
+    tokio::time::pause();
+    let net = SimNetwork::new(1);
+    let relay = net.add_host(NatType::None);
+    let host1 = net.add_host(NatType::FullCone);
+    let host2 = net.add_host(NatType::FullCone);
+
+    let wrapper = init_sim_server(&relay).await?;
+    let client1 = build_client(&wrapper, &host1).await?;
+    let client2 = build_client(&wrapper, &host2).await?;
+    assert_eq!(
+        client1.public_addr().await.map(|addr| addr.ip()),
+        Some(host1.public_ip())
+    );
+
+    check_reliable_forward(&client1, &client2, 1).await?;
+    assert!(client1.is_p2p(client2.node_id()).await);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_sim_symmetric_nat_relayed() -> anyhow::Result<()> {
+    tokio::time::pause();
+    let net = SimNetwork::new(2);
+    let relay = net.add_host(NatType::None);
+    let host1 = net.add_host(NatType::Symmetric);
+    let host2 = net.add_host(NatType::Symmetric);
+
+    let wrapper = init_sim_server(&relay).await?;
+    let client1 = build_client(&wrapper, &host1).await?;
+    let client2 = build_client(&wrapper, &host2).await?;
+    // Server can't reach the Nodes from other port, than the one they registered from.
+    assert_eq!(client1.public_addr().await, None);
+
+    check_reliable_forward(&client1, &client2, 1).await?;
+    assert!(!client1.is_p2p(client2.node_id()).await);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_sim_lossy_link_reliable_forward() -> anyhow::Result<()> {
+    tokio::time::pause();
+    let net = SimNetwork::new(3);
+    let relay = net.add_host(NatType::None);
+    let host1 = net.add_host(NatType::None);
+    let host2 = net.add_host(NatType::None);
+
+    let wrapper = init_sim_server(&relay).await?;
+    let client1 = build_client(&wrapper, &host1).await?;
+    let client2 = build_client(&wrapper, &host2).await?;
+    client1.forward_reliable(client2.node_id()).await?;
+
+    host1.set_link(LinkConfig {
+        latency: Duration::from_millis(5),
+        loss: 0.1,
+        reorder: 0.2,
+    });
+    check_reliable_forward(&client1, &client2, 50).await?;
+    assert!(net.stats().dropped > 0);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_sim_partition_from_relay() -> anyhow::Result<()> {
+    tokio::time::pause();
+    let net = SimNetwork::new(4);
+    let relay = net.add_host(NatType::None);
+    let host1 = net.add_host(NatType::None);
+    let host2 = net.add_host(NatType::None);
+
+    let wrapper = init_sim_server(&relay).await?;
+    let client1 = build_client(&wrapper, &host1).await?;
+    let client2 = build_client(&wrapper, &host2).await?;
+
+    net.partition(&host1, &relay);
+    assert!(client1.find_node(client2.node_id()).await.is_err());
+
+    net.heal(&host1, &relay);
+    client1.find_node(client2.node_id()).await?;
+    Ok(())
+}
@@ -0,0 +1,190 @@
+mod common;
+
+use anyhow::Context;
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use ya_relay_client::{ClientBuilder, FailFast};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::init_test_server;
+
+use common::{check_forwarding, spawn_receive, Mode};
+
+#[test_log::test(actix_rt::test)]
+async fn test_stream_connect_listen() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let rx2 = client2
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+    let received2 = Rc::new(AtomicBool::new(false));
+    spawn_receive(">> 2", received2.clone(), rx2);
+
+    let mut listener = client2.listen(100)?;
+    assert!(client2.listen(100).is_err());
+    // Ports of channels are reserved.
+    assert!(client2.listen(1).is_err());
+
+    let mut stream1 = client1.connect_stream(client2.node_id(), 100).await?;
+    assert_eq!(stream1.remote_port(), 100);
+
+    // Connection is accepted before any data is sent, so listener can speak first.
+    let mut stream2 = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+        .await
+        .context("accept timeout")?
+        .context("listener closed")?;
+    assert_eq!(stream2.node_id(), client1.node_id());
+    assert_eq!(stream2.local_port(), 100);
+
+    stream2.write_all(b"hello").await?;
+    stream2.flush().await?;
+    let mut buf = [0u8; 5];
+    stream1.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    // Data bigger than single packet.
+    let data = (0..256 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let sent = data.clone();
+    let writer = tokio::task::spawn_local(async move {
+        stream1.write_all(&sent).await?;
+        stream1.shutdown().await?;
+        anyhow::Ok(stream1)
+    });
+
+    let mut received = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), stream2.read_to_end(&mut received))
+        .await
+        .context("read timeout")??;
+    assert_eq!(received.len(), data.len());
+    assert!(received == data);
+    drop(writer.await??);
+
+    // Closing stream doesn't affect channels with the Node.
+    check_forwarding(&client1, &client2, received2, Mode::Reliable).await?;
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_stream_slow_reader() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let mut listener = client2.listen(100)?;
+    let mut stream1 = client1.connect_stream(client2.node_id(), 100).await?;
+    let mut stream2 = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+        .await
+        .context("accept timeout")?
+        .context("listener closed")?;
+
+    // More data than stream buffers, so reading from the Node is paused until the reader catches up.
+    let data = (0..1024 * 1024)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    let sent = data.clone();
+    let writer = tokio::task::spawn_local(async move {
+        stream1.write_all(&sent).await?;
+        stream1.shutdown().await?;
+        anyhow::Ok(stream1)
+    });
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    let mut received = Vec::new();
+    tokio::time::timeout(Duration::from_secs(20), stream2.read_to_end(&mut received))
+        .await
+        .context("read timeout")??;
+    assert_eq!(received.len(), data.len());
+    assert!(received == data);
+    drop(writer.await??);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_stream_write_without_flush() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let mut listener = client2.listen(100)?;
+    let mut stream1 = client1.connect_stream(client2.node_id(), 100).await?;
+    let mut stream2 = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+        .await
+        .context("accept timeout")?
+        .context("listener closed")?;
+
+    // Request and response are delivered without flushing the streams.
+    stream1.write_all(b"request").await?;
+    let mut buf = [0u8; 7];
+    tokio::time::timeout(Duration::from_secs(5), stream2.read_exact(&mut buf))
+        .await
+        .context("request timeout")??;
+    assert_eq!(&buf, b"request");
+
+    stream2.write_all(b"response").await?;
+    let mut buf = [0u8; 8];
+    tokio::time::timeout(Duration::from_secs(5), stream1.read_exact(&mut buf))
+        .await
+        .context("response timeout")??;
+    assert_eq!(&buf, b"response");
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_stream_dropped_after_write() -> anyhow::Result<()> {
+    let wrapper = init_test_server().await?;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let mut listener = client2.listen(100)?;
+    let mut stream1 = client1.connect_stream(client2.node_id(), 100).await?;
+    let mut stream2 = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+        .await
+        .context("accept timeout")?
+        .context("listener closed")?;
+
+    // More data than fits into socket buffer, so the last write is still pending on drop.
+    let data = (0..256 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    stream1.write_all(&data).await?;
+    drop(stream1);
+
+    let mut received = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), stream2.read_to_end(&mut received))
+        .await
+        .context("read timeout")??;
+    assert_eq!(received.len(), data.len());
+    assert!(received == data);
+    Ok(())
+}
@@ -1,20 +1,29 @@
 mod common;
 
+use std::sync::Arc;
+
 use ya_relay_client::testing::init::MockSessionNetwork;
 use ya_relay_client::testing::private::*;
 use ya_relay_core::NodeId;
 use ya_relay_server::testing::server::init_test_server;
 
-/// `VirtNode` code assumes that channels are residing under index, that can be returned
-/// by `ChannelDesc::index` function.
+/// `VirtNode` creates channels on first use and returns the same channel later.
 #[actix_rt::test]
-async fn test_virt_node_creation_assumption() {
+async fn test_virt_node_channels_on_demand() {
     let server = init_test_server().await.unwrap();
     let mut network = MockSessionNetwork::new(server).unwrap();
     let layer = network.new_layer().await.unwrap().layer;
 
     let virt = VirtNode::new(NodeId::default(), layer);
-    for i in 0..4 {
-        assert_eq!(virt.channels[i].channel.index(), i);
-    }
+    assert!(virt.channels.lock().is_empty());
+
+    let desc = ChannelDesc(ChannelType(10), ChannelDirection::Out);
+    let channel = virt.channel(desc);
+    assert_eq!(channel.channel, desc);
+    assert_eq!(channel.channel.port(), 10);
+    assert!(Arc::ptr_eq(&channel.state, &virt.channel(desc).state));
+
+    let other = virt.channel(ChannelDesc(ChannelType(10), ChannelDirection::In));
+    assert!(!Arc::ptr_eq(&channel.state, &other.state));
+    assert_eq!(virt.channels.lock().len(), 2);
 }
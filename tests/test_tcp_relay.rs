@@ -0,0 +1,93 @@
+mod common;
+
+use anyhow::Context;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::Duration;
+use url::Url;
+
+use ya_relay_client::{ClientBuilder, FailFast, GenericSender};
+use ya_relay_server::testing::server::{init_test_server_with_config, test_default_config};
+
+use common::spawn_receive;
+
+fn tcp_url(addr: SocketAddr) -> Url {
+    format!("tcp://{addr}").parse().unwrap()
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_tcp_fallback() -> anyhow::Result<()> {
+    let mut config = test_default_config();
+    config.server.tcp_listen = true;
+    let wrapper = init_test_server_with_config(config).await?;
+    let relay = wrapper.server.bind_addr();
+
+    // Relay, which never responds over UDP, simulates blocked UDP traffic.
+    let blackhole = std::net::UdpSocket::bind("127.0.0.1:0")?;
+    let blocked: Url = format!("udp://{}", blackhole.local_addr()?).parse()?;
+
+    // TCP relay placed first on the list should be tried after UDP relays.
+    let client1 = ClientBuilder::from_urls(vec![tcp_url(relay), blocked])
+        .connect(FailFast::Yes)
+        .session_request_timeout(Duration::from_millis(500))
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(tcp_url(relay))
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let udp_port = client1.bind_addr().await?.port();
+    let sessions = wrapper.server.sessions();
+    let session = sessions
+        .node_session(client1.node_id())
+        .context("no relay session for client1")?;
+    assert_ne!(session.peer.port(), udp_port);
+
+    let rx2 = client2
+        .forward_receiver()
+        .await
+        .context("no forward receiver")?;
+    let received = Rc::new(AtomicBool::new(false));
+    spawn_receive(">> 2", received.clone(), rx2);
+
+    let mut tx = client1.forward_reliable(client2.node_id()).await?;
+    tx.send(vec![1u8].into()).await?;
+
+    for _ in 0..50 {
+        if received.load(SeqCst) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(received.load(SeqCst));
+    assert!(!client1.is_p2p(client2.node_id()).await);
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+async fn test_tcp_max_connections() -> anyhow::Result<()> {
+    let mut config = test_default_config();
+    config.server.tcp_listen = true;
+    config.server.tcp_max_connections = 1;
+    let wrapper = init_test_server_with_config(config).await?;
+    let relay = wrapper.server.bind_addr();
+
+    let _client1 = ClientBuilder::from_url(tcp_url(relay))
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    // Connection above the limit is accepted by the OS and closed by the relay.
+    let mut stream = tokio::net::TcpStream::connect(relay).await?;
+    let mut buf = [0u8; 1];
+    let read = tokio::time::timeout(
+        Duration::from_secs(5),
+        tokio::io::AsyncReadExt::read(&mut stream, &mut buf),
+    )
+    .await?;
+    assert!(matches!(read, Ok(0) | Err(_)));
+    Ok(())
+}
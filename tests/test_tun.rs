@@ -0,0 +1,85 @@
+#![cfg(target_os = "linux")]
+
+use anyhow::Context;
+use std::net::SocketAddrV6;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+use ya_relay_client::{ClientBuilder, FailFast};
+use ya_relay_core::testing::TestServerWrapper;
+use ya_relay_server::testing::server::init_test_server;
+
+/// Moves test thread to a new network namespace without outside network, so TUN device
+/// and routes don't affect the host. Requires `CAP_NET_ADMIN`.
+fn isolate_network() -> anyhow::Result<()> {
+    if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Creating network namespace");
+    }
+    let status = std::process::Command::new("ip")
+        .args(["link", "set", "lo", "up"])
+        .status()
+        .context("Bringing loopback up")?;
+    anyhow::ensure!(status.success(), "Bringing loopback up: {status}");
+    Ok(())
+}
+
+#[test_log::test(actix_rt::test)]
+#[ignore = "requires CAP_NET_ADMIN, run with `cargo test --test test_tun -- --ignored`"]
+async fn test_tun_device_reaches_virtual_ports() -> anyhow::Result<()> {
+    isolate_network()?;
+    let wrapper = init_test_server().await?;
+
+    let client1 = ClientBuilder::from_url(wrapper.url())
+        .tun_device("yatun0")
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+    let client2 = ClientBuilder::from_url(wrapper.url())
+        .connect(FailFast::Yes)
+        .build()
+        .await?;
+
+    let mut listener = client2.listen(100)?;
+    let mut udp2 = client2.bind_udp(60)?;
+    let address = client1.virtual_address(client2.node_id()).await?;
+
+    // Ordinary OS socket connects to Node's virtual stream port through the TUN device.
+    let mut stream1 = tokio::time::timeout(
+        Duration::from_secs(5),
+        TcpStream::connect(SocketAddrV6::new(address, 100, 0, 0)),
+    )
+    .await
+    .context("connect timeout")??;
+    let mut stream2 = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+        .await
+        .context("accept timeout")?
+        .context("listener closed")?;
+    assert_eq!(stream2.node_id(), client1.node_id());
+
+    stream1.write_all(b"hello").await?;
+    let mut buf = [0u8; 5];
+    tokio::time::timeout(Duration::from_secs(5), stream2.read_exact(&mut buf))
+        .await
+        .context("read timeout")??;
+    assert_eq!(&buf, b"hello");
+
+    stream2.write_all(b"world").await?;
+    stream2.flush().await?;
+    tokio::time::timeout(Duration::from_secs(5), stream1.read_exact(&mut buf))
+        .await
+        .context("read timeout")??;
+    assert_eq!(&buf, b"world");
+
+    let udp1 = UdpSocket::bind("[::]:5000").await?;
+    udp1.send_to(b"datagram", SocketAddrV6::new(address, 60, 0, 0))
+        .await?;
+    let (payload, node_id, port) = tokio::time::timeout(Duration::from_secs(5), udp2.recv_from())
+        .await
+        .context("receive timeout")?
+        .context("socket closed")?;
+    assert_eq!(payload, b"datagram");
+    assert_eq!(node_id, client1.node_id());
+    assert_eq!(port, 5000);
+    Ok(())
+}